@@ -113,3 +113,71 @@ fn test_fastcdc_8192(b: &mut Bencher) {
         while chunker.chunk(&mut cursor, &mut DevNull).unwrap() == ChunkerStatus::Continue {}
     })
 }
+
+
+#[bench]
+fn test_buzhash_init(b: &mut Bencher) {
+    b.iter(|| {
+        BuzhashChunker::new(8*1024, 0);
+    })
+}
+
+#[bench]
+fn test_buzhash_8192(b: &mut Bencher) {
+    let data = random_data(0, 1024*1024);
+    b.bytes = data.len() as u64;
+    b.iter(|| {
+        let mut chunker = BuzhashChunker::new(8*1024, 0);
+        let mut cursor = Cursor::new(&data);
+        while chunker.chunk(&mut cursor, &mut DevNull).unwrap() == ChunkerStatus::Continue {}
+    })
+}
+
+
+#[bench]
+fn test_gear_init(b: &mut Bencher) {
+    b.iter(|| {
+        GearChunker::new(8*1024, 0);
+    })
+}
+
+#[bench]
+fn test_gear_8192(b: &mut Bencher) {
+    let data = random_data(0, 1024*1024);
+    b.bytes = data.len() as u64;
+    b.iter(|| {
+        let mut chunker = GearChunker::new(8*1024, 0);
+        let mut cursor = Cursor::new(&data);
+        while chunker.chunk(&mut cursor, &mut DevNull).unwrap() == ChunkerStatus::Continue {}
+    })
+}
+
+
+#[bench]
+fn test_tttd_init(b: &mut Bencher) {
+    b.iter(|| {
+        TttdChunker::new(8*1024, 0);
+    })
+}
+
+#[bench]
+fn test_tttd_8192(b: &mut Bencher) {
+    let data = random_data(0, 1024*1024);
+    b.bytes = data.len() as u64;
+    b.iter(|| {
+        let mut chunker = TttdChunker::new(8*1024, 0);
+        let mut cursor = Cursor::new(&data);
+        while chunker.chunk(&mut cursor, &mut DevNull).unwrap() == ChunkerStatus::Continue {}
+    })
+}
+
+
+#[bench]
+fn test_fastcdc_cuts_8192(b: &mut Bencher) {
+    let data = random_data(0, 1024*1024);
+    b.bytes = data.len() as u64;
+    let chunker = FastCdcChunker::new(8*1024, 0);
+    b.iter(|| {
+        find_cuts(&chunker, &data)
+    })
+}
@@ -9,23 +9,52 @@ use std::ptr;
 pub struct AeChunker {
     buffer: [u8; 4096],
     buffered: usize,
+    min_size: usize,
+    max_size: usize,
     window_size: usize
 }
 
 impl AeChunker {
     pub fn new(avg_size: usize) -> AeChunker {
+        // Every cut point extension needs a strictly larger byte value, so chunks never get
+        // longer than 256 windows and this maximum does not change the chunking
+        Self::with_limits(0, avg_size, avg_size*256)
+    }
+
+    pub fn with_limits(min_size: usize, avg_size: usize, max_size: usize) -> AeChunker {
         // Experiments show that this claim from the paper is wrong and results in smaller chunks
         //let window_size = (avg_size as f64 / (consts::E - 1.0)) as usize;
         let window_size = avg_size - 256;
         AeChunker{
             buffer: [0; 4096],
             buffered: 0,
+            min_size: min_size,
+            max_size: max_size,
             window_size: window_size,
         }
     }
 }
 
 impl Chunker for AeChunker {
+    fn find_cut(&self, data: &[u8]) -> Option<usize> {
+        let mut max_pos = 0;
+        let mut max_val = 0;
+        for (pos, &val) in data.iter().enumerate() {
+            if pos >= self.max_size {
+                return Some(pos+1)
+            }
+            if val <= max_val {
+                if pos >= max_pos + self.window_size && pos >= self.min_size {
+                    return Some(pos+1)
+                }
+            } else {
+                max_val = val;
+                max_pos = pos;
+            }
+        }
+        None
+    }
+
     #[allow(unknown_lints,explicit_counter_loop)]
     fn chunk(&mut self, r: &mut Read, mut w: &mut Write) -> Result<ChunkerStatus, ChunkerError> {
         let mut max;
@@ -41,8 +70,14 @@ impl Chunker for AeChunker {
             }
             for i in 0..max {
                 let val = self.buffer[i];
+                if pos >= self.max_size {
+                    try!(w.write_all(&self.buffer[..i+1]).map_err(ChunkerError::Write));
+                    unsafe { ptr::copy(self.buffer[i+1..].as_ptr(), self.buffer.as_mut_ptr(), max-i-1) };
+                    self.buffered = max-i-1;
+                    return Ok(ChunkerStatus::Continue);
+                }
                 if val <= max_val {
-                    if pos == max_pos + self.window_size {
+                    if pos >= max_pos + self.window_size && pos >= self.min_size {
                         // Write all bytes from this chunk out to sink and store rest for next chunk
                         try!(w.write_all(&self.buffer[..i+1]).map_err(ChunkerError::Write));
                         unsafe { ptr::copy(self.buffer[i+1..].as_ptr(), self.buffer.as_mut_ptr(), max-i-1) };
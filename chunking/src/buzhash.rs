@@ -0,0 +1,116 @@
+use super::*;
+
+use std::ptr;
+
+// Buzhash Chunker
+// Paper: "Recursive Hashing Functions for n-Grams"
+// Paper-URL: http://www.serve.net/buz/Notes.1st.year/HTML/C6/rand.012.html
+// https://borgbackup.readthedocs.io/en/stable/internals/data-structures.html#chunks
+
+
+const WINDOW_SIZE: usize = 64;
+
+// Creating 256 pseudo-random values (based on Knuth's MMIX) from the seed, the table and thereby
+// the cut points are only secret if the seed is
+fn create_table(seed: u64) -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let a = 6364136223846793005;
+    let c = 1442695040888963407;
+    let mut v = seed;
+    for t in &mut table.iter_mut() {
+        v = v.wrapping_mul(a).wrapping_add(c);
+        *t = (v >> 32) as u32;
+    }
+    table
+}
+
+
+pub struct BuzhashChunker {
+    buffer: [u8; 4096],
+    buffered: usize,
+    table: [u32; 256],
+    window: [u8; WINDOW_SIZE],
+    min_size: usize,
+    max_size: usize,
+    chunk_mask: u32,
+}
+
+
+impl BuzhashChunker {
+    pub fn new(avg_size: usize, seed: u64) -> Self {
+        Self::with_limits(avg_size/4, avg_size, avg_size*4, seed)
+    }
+
+    pub fn with_limits(min_size: usize, avg_size: usize, max_size: usize, seed: u64) -> Self {
+        BuzhashChunker {
+            buffer: [0; 4096],
+            buffered: 0,
+            table: create_table(seed),
+            window: [0; WINDOW_SIZE],
+            min_size: min_size,
+            max_size: max_size,
+            chunk_mask: (avg_size as u32).next_power_of_two() - 1,
+        }
+    }
+}
+
+impl Chunker for BuzhashChunker {
+    fn find_cut(&self, data: &[u8]) -> Option<usize> {
+        let mut hash = 0u32;
+        for (pos, &val) in data.iter().enumerate() {
+            if pos >= self.max_size {
+                return Some(pos+1)
+            }
+            // Hash update
+            hash = hash.rotate_left(1) ^ self.table[val as usize];
+            if pos >= WINDOW_SIZE {
+                hash ^= self.table[data[pos-WINDOW_SIZE] as usize].rotate_left(WINDOW_SIZE as u32);
+                if pos >= self.min_size && hash & self.chunk_mask == 0 {
+                    return Some(pos+1)
+                }
+            }
+        }
+        None
+    }
+
+    #[allow(unknown_lints,explicit_counter_loop)]
+    fn chunk(&mut self, r: &mut Read, w: &mut Write) -> Result<ChunkerStatus, ChunkerError> {
+        let mut max;
+        let mut hash = 0u32;
+        let mut pos = 0;
+        loop {
+            // Fill the buffer, there might be some bytes still in there from last chunk
+            max = try!(r.read(&mut self.buffer[self.buffered..]).map_err(ChunkerError::Read)) + self.buffered;
+            // If nothing to do, finish
+            if max == 0 {
+                return Ok(ChunkerStatus::Finished)
+            }
+            for i in 0..max {
+                let val = self.buffer[i];
+                if pos >= self.max_size {
+                    try!(w.write_all(&self.buffer[..i+1]).map_err(ChunkerError::Write));
+                    unsafe { ptr::copy(self.buffer[i+1..].as_ptr(), self.buffer.as_mut_ptr(), max-i-1) };
+                    self.buffered = max-i-1;
+                    return Ok(ChunkerStatus::Continue);
+                }
+                // Hash update, the byte leaving the window has been rotated WINDOW_SIZE times
+                hash = hash.rotate_left(1) ^ self.table[val as usize];
+                let slot = pos % WINDOW_SIZE;
+                if pos >= WINDOW_SIZE {
+                    let take = self.window[slot];
+                    hash ^= self.table[take as usize].rotate_left(WINDOW_SIZE as u32);
+                    if pos >= self.min_size && hash & self.chunk_mask == 0 {
+                        try!(w.write_all(&self.buffer[..i+1]).map_err(ChunkerError::Write));
+                        unsafe { ptr::copy(self.buffer[i+1..].as_ptr(), self.buffer.as_mut_ptr(), max-i-1) };
+                        self.buffered = max-i-1;
+                        return Ok(ChunkerStatus::Continue);
+                    }
+                }
+                self.window[slot] = val;
+                pos += 1;
+            }
+            try!(w.write_all(&self.buffer[..max]).map_err(ChunkerError::Write));
+            self.buffered = 0;
+        }
+    }
+}
@@ -0,0 +1,79 @@
+use super::*;
+
+// Boundary-only chunking
+// Instead of copying every chunk into a writer, the cut points of an in-memory buffer are
+// returned so that the chunks can be hashed in place.
+// The basic chunkers start every chunk with a fresh state, so the cut points only depend on the
+// position of the previous cut point. This allows to chunk regions of a buffer in parallel:
+// each region is chunked from an arbitrary start and as soon as the cut points of the
+// previous region hit one of the cut points of a region, both agree from there on.
+
+
+/// Returns the end positions of all chunks of `data` that end at a cut point
+///
+/// The data after the last cut point forms the final chunk, it is empty if the data ends
+/// exactly at a cut point. This yields the same chunks as `Chunker::chunk`.
+pub fn find_cuts(chunker: &Chunker, data: &[u8]) -> Vec<usize> {
+    chunker.find_cuts(data)
+}
+
+
+pub struct RegionCuts {
+    pub end: usize,
+    /// The start of the region followed by all cut points found from there on
+    pub cuts: Vec<usize>
+}
+
+/// Chunks `data` starting at `start` until the first cut point at or behind `end`
+///
+/// This only works for chunkers that start every chunk with a fresh state, the cut points of a
+/// `FormatChunker` depend on the format of the whole data.
+pub fn find_region_cuts(chunker: &Chunker, data: &[u8], start: usize, end: usize) -> RegionCuts {
+    let mut cuts = vec![start];
+    let mut pos = start;
+    while pos < end {
+        match chunker.find_cut(&data[pos..]) {
+            Some(len) => {
+                pos += len;
+                cuts.push(pos);
+            },
+            None => break
+        }
+    }
+    RegionCuts {
+        end: end,
+        cuts: cuts
+    }
+}
+
+/// Combines the cut points of consecutive regions covering `data` into the cut points of the
+/// whole data (as returned by `find_cuts`)
+///
+/// The first region must start at 0. Parts of a region that are not synchronized with the
+/// cut points of the previous regions are chunked again.
+pub fn merge_region_cuts(chunker: &Chunker, data: &[u8], regions: &[RegionCuts]) -> Vec<usize> {
+    let mut cuts = vec![];
+    let mut pos = 0;
+    for region in regions {
+        loop {
+            match region.cuts.binary_search(&pos) {
+                Ok(idx) => {
+                    cuts.extend_from_slice(&region.cuts[idx+1..]);
+                    pos = *region.cuts.last().unwrap();
+                    break
+                },
+                Err(idx) if idx < region.cuts.len() || pos < region.end => {
+                    match chunker.find_cut(&data[pos..]) {
+                        Some(len) => {
+                            pos += len;
+                            cuts.push(pos);
+                        },
+                        None => return cuts
+                    }
+                },
+                Err(_) => break
+            }
+        }
+    }
+    cuts
+}
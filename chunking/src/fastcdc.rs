@@ -9,7 +9,7 @@ use std::ptr;
 
 
 // Creating 256 pseudo-random values (based on Knuth's MMIX)
-fn create_gear(seed: u64) -> [u64; 256] {
+pub fn create_gear(seed: u64) -> [u64; 256] {
     let mut table = [0u64; 256];
     let a = 6364136223846793005;
     let c = 1442695040888963407;
@@ -23,7 +23,7 @@ fn create_gear(seed: u64) -> [u64; 256] {
 
 fn get_masks(avg_size: usize, nc_level: usize, seed: u64) -> (u64, u64) {
     let bits = (avg_size.next_power_of_two() - 1).count_ones();
-    if bits == 13 {
+    if bits == 13 && nc_level == 2 {
         // From the paper
         return (0x0003590703530000, 0x0000d90003530000);
     }
@@ -58,13 +58,17 @@ pub struct FastCdcChunker {
 
 impl FastCdcChunker {
     pub fn new(avg_size: usize, seed: u64) -> Self {
-        let (mask_short, mask_long) = get_masks(avg_size, 2, seed);
+        Self::with_limits(avg_size/4, avg_size, avg_size*8, 2, seed)
+    }
+
+    pub fn with_limits(min_size: usize, avg_size: usize, max_size: usize, nc_level: usize, seed: u64) -> Self {
+        let (mask_short, mask_long) = get_masks(avg_size, nc_level, seed);
         FastCdcChunker {
             buffer: [0; 4096],
             buffered: 0,
             gear: create_gear(seed),
-            min_size: avg_size/4,
-            max_size: avg_size*8,
+            min_size: min_size,
+            max_size: max_size,
             avg_size: avg_size,
             mask_long: mask_long,
             mask_short: mask_short,
@@ -73,6 +77,20 @@ impl FastCdcChunker {
 }
 
 impl Chunker for FastCdcChunker {
+    fn find_cut(&self, data: &[u8]) -> Option<usize> {
+        let mut hash = 0u64;
+        for (pos, &val) in data.iter().enumerate().skip(self.min_size) {
+            // Hash update
+            hash = (hash << 1).wrapping_add(self.gear[val as usize]);
+            if pos < self.avg_size && hash & self.mask_short == 0
+            || pos >= self.avg_size && hash & self.mask_long == 0
+            || pos >= self.max_size {
+                return Some(pos+1)
+            }
+        }
+        None
+    }
+
     #[allow(unknown_lints,explicit_counter_loop,needless_range_loop)]
     fn chunk(&mut self, r: &mut Read, mut w: &mut Write) -> Result<ChunkerStatus, ChunkerError> {
         let mut max;
@@ -18,6 +18,14 @@ impl FixedChunker {
 }
 
 impl Chunker for FixedChunker {
+    fn find_cut(&self, data: &[u8]) -> Option<usize> {
+        if data.len() >= self.size {
+            Some(self.size)
+        } else {
+            None
+        }
+    }
+
     #[allow(unknown_lints,explicit_counter_loop)]
     fn chunk(&mut self, r: &mut Read, mut w: &mut Write) -> Result<ChunkerStatus, ChunkerError> {
         let mut todo = self.size;
@@ -0,0 +1,542 @@
+use super::*;
+
+use std::cmp::min;
+use std::collections::VecDeque;
+
+// Format-aware chunking
+// Content-defined cut points do not respect the structure of container formats, so chunks
+// straddle member headers and the same content packed differently results in different chunks.
+// This chunker recognizes some formats at the start of a stream, cuts at member or block
+// boundaries and lets the inner chunker chunk the contents independently.
+// Tar: https://www.gnu.org/software/tar/manual/html_node/Standard.html
+// Zip: https://pkware.cachefly.net/webdocs/casestudies/APPNOTE.TXT
+// Qcow2: https://github.com/qemu/qemu/blob/master/docs/interop/qcow2.txt
+// GPT: https://en.wikipedia.org/wiki/GUID_Partition_Table
+
+
+// MBR, GPT header and 128 GPT partition entries
+const PREFIX_SIZE: usize = 34*512;
+const TAR_BLOCK_SIZE: usize = 512;
+const ZIP_HEADER_SIZE: usize = 30;
+const UNBOUNDED: u64 = ::std::u64::MAX;
+
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Format {
+    Tar,
+    Zip,
+    Qcow2,
+    DiskImage
+}
+
+impl Format {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Format::Tar => "tar",
+            Format::Zip => "zip",
+            Format::Qcow2 => "qcow2",
+            Format::DiskImage => "disk image"
+        }
+    }
+}
+
+
+fn le_uint(data: &[u8]) -> u64 {
+    data.iter().rev().fold(0, |val, &b| val << 8 | b as u64)
+}
+
+fn be_uint(data: &[u8]) -> u64 {
+    data.iter().fold(0, |val, &b| val << 8 | b as u64)
+}
+
+fn parse_tar_number(data: &[u8]) -> Option<u64> {
+    if data[0] & 0x80 != 0 {
+        // GNU base-256 encoding for big numbers
+        let mut val = (data[0] & 0x7f) as u64;
+        for &b in &data[1..] {
+            if val >> 56 != 0 {
+                return None
+            }
+            val = val << 8 | b as u64;
+        }
+        return Some(val)
+    }
+    let mut val = 0u64;
+    let mut digits = 0;
+    for &b in data {
+        match b {
+            b'0'..=b'7' => {
+                if val >> 61 != 0 {
+                    return None
+                }
+                val = val << 3 | (b - b'0') as u64;
+                digits += 1;
+            },
+            b' ' if digits == 0 => (),
+            b' ' | 0 => break,
+            _ => return None
+        }
+    }
+    Some(val)
+}
+
+/// Returns the size of the data following the tar header or `None` if it is no valid header
+fn parse_tar_header(header: &[u8]) -> Option<u64> {
+    // The checksum is calculated with the checksum field filled with spaces
+    let checksum = header.iter().enumerate().map(|(i, &b)| {
+        if i >= 148 && i < 156 { b' ' as u64 } else { b as u64 }
+    }).sum::<u64>();
+    if header.iter().all(|&b| b == 0) || parse_tar_number(&header[148..156]) != Some(checksum) {
+        return None
+    }
+    match header[156] {
+        // Links, devices, directories and fifos have no data
+        b'1' | b'2' | b'3' | b'4' | b'5' | b'6' => Some(0),
+        _ => parse_tar_number(&header[124..136])
+    }
+}
+
+/// Returns the length of the zip header fields behind the fixed part and the size of the data if
+/// it is known or `None` if it is no valid header
+fn parse_zip_header(header: &[u8]) -> Option<(usize, Option<u64>)> {
+    if header.len() < ZIP_HEADER_SIZE || &header[..4] != b"PK\x03\x04" {
+        return None
+    }
+    let flags = le_uint(&header[6..8]);
+    let size = le_uint(&header[18..22]);
+    let extra = (le_uint(&header[26..28]) + le_uint(&header[28..30])) as usize;
+    if flags & 0x08 != 0 || size == 0xffff_ffff {
+        // The size is only known after the data (data descriptor) or in zip64 extra fields
+        Some((extra, None))
+    } else {
+        Some((extra, Some(size)))
+    }
+}
+
+fn parse_qcow2_header(header: &[u8]) -> Option<u64> {
+    if header.len() < 24 || &header[..4] != b"QFI\xfb" {
+        return None
+    }
+    let version = be_uint(&header[4..8]);
+    let cluster_bits = be_uint(&header[20..24]);
+    if version < 2 || version > 3 || cluster_bits < 9 || cluster_bits > 21 {
+        return None
+    }
+    Some(1 << cluster_bits)
+}
+
+fn gpt_boundaries(data: &[u8]) -> Vec<u64> {
+    let mut boundaries = vec![];
+    if data.len() < 1024 || &data[512..520] != b"EFI PART" {
+        return boundaries
+    }
+    let entries_lba = le_uint(&data[584..592]);
+    let entry_count = le_uint(&data[592..596]) as usize;
+    let entry_size = le_uint(&data[596..600]) as usize;
+    if entry_size < 128 || entries_lba >= (data.len() / 512) as u64 {
+        return boundaries
+    }
+    for entry in data[entries_lba as usize * 512..].chunks(entry_size).take(entry_count) {
+        if entry.len() < entry_size {
+            break
+        }
+        // Unused entries have no partition type
+        if entry[..16].iter().all(|&b| b == 0) {
+            continue
+        }
+        boundaries.push(le_uint(&entry[32..40]) * 512);
+        boundaries.push((le_uint(&entry[40..48]) + 1) * 512);
+    }
+    boundaries
+}
+
+/// Returns the start and end positions of all partitions of a disk image
+fn disk_boundaries(data: &[u8]) -> Vec<u64> {
+    let mut boundaries = vec![];
+    if data.len() < 512 || data[510] != 0x55 || data[511] != 0xaa {
+        return boundaries
+    }
+    for entry in data[446..510].chunks(16) {
+        match entry[4] {
+            0 => continue,
+            // Protective MBR of a GPT disk
+            0xee => return gpt_boundaries(data),
+            _ => ()
+        }
+        let (start, count) = (le_uint(&entry[8..12]), le_uint(&entry[12..16]));
+        if start == 0 || count == 0 {
+            // Probably just a boot sector and no partition table
+            return vec![]
+        }
+        boundaries.push(start * 512);
+        boundaries.push((start + count) * 512);
+    }
+    boundaries
+}
+
+/// Detects the container format of data from its first bytes
+pub fn detect_format(data: &[u8]) -> Option<Format> {
+    if parse_qcow2_header(data).is_some() {
+        Some(Format::Qcow2)
+    } else if data.len() >= ZIP_HEADER_SIZE && &data[..4] == b"PK\x03\x04" {
+        Some(Format::Zip)
+    } else if data.len() >= TAR_BLOCK_SIZE && parse_tar_header(&data[..TAR_BLOCK_SIZE]).is_some() {
+        Some(Format::Tar)
+    } else if !disk_boundaries(data).is_empty() {
+        Some(Format::DiskImage)
+    } else {
+        None
+    }
+}
+
+
+// The padding to full blocks only depends on the size of the data
+fn tar_padded_size(size: u64) -> u64 {
+    (size + TAR_BLOCK_SIZE as u64 - 1) / TAR_BLOCK_SIZE as u64 * TAR_BLOCK_SIZE as u64
+}
+
+fn read_full(r: &mut Read, buf: &mut [u8]) -> Result<usize, io::Error> {
+    let mut pos = 0;
+    while pos < buf.len() {
+        match try!(r.read(&mut buf[pos..])) {
+            0 => break,
+            n => pos += n
+        }
+    }
+    Ok(pos)
+}
+
+
+// Reads the bytes used for detecting the format before the rest of the stream
+struct PrefixReader<'a> {
+    prefix: &'a [u8],
+    pos: &'a mut usize,
+    inner: &'a mut Read
+}
+
+impl<'a> Read for PrefixReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        if *self.pos >= self.prefix.len() {
+            return self.inner.read(buf)
+        }
+        let len = min(buf.len(), self.prefix.len() - *self.pos);
+        buf[..len].copy_from_slice(&self.prefix[*self.pos..*self.pos+len]);
+        *self.pos += len;
+        Ok(len)
+    }
+}
+
+
+struct CountingWriter<'a> {
+    inner: &'a mut Write,
+    written: usize
+}
+
+impl<'a> Write for CountingWriter<'a> {
+    fn write(&mut self, data: &[u8]) -> Result<usize, io::Error> {
+        let len = try!(self.inner.write(data));
+        self.written += len;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> Result<(), io::Error> {
+        self.inner.flush()
+    }
+}
+
+
+enum State {
+    // At the start of a stream
+    Detect,
+    // No (more) structure is known, the rest is chunked by the inner chunker
+    Plain,
+    TarHeader,
+    ZipHeader,
+    Blocks(u64),
+    Boundaries(VecDeque<u64>)
+}
+
+impl State {
+    fn detect(prefix: &[u8]) -> Self {
+        match detect_format(prefix) {
+            Some(Format::Tar) => State::TarHeader,
+            Some(Format::Zip) => State::ZipHeader,
+            Some(Format::Qcow2) => State::Blocks(parse_qcow2_header(prefix).unwrap()),
+            Some(Format::DiskImage) => {
+                let mut boundaries = disk_boundaries(prefix);
+                boundaries.sort();
+                boundaries.dedup();
+                State::Boundaries(boundaries.into())
+            },
+            None => State::Plain
+        }
+    }
+}
+
+pub struct FormatChunker {
+    inner: Box<Chunker>,
+    state: State,
+    // Remaining bytes of the segment that is currently chunked by the inner chunker
+    segment: Option<u64>,
+    // Tar headers that are written in front of the first chunk of the following member
+    header: Vec<u8>,
+    prefix: Vec<u8>,
+    prefix_pos: usize,
+    pos: u64
+}
+
+impl FormatChunker {
+    pub fn new(inner: Box<Chunker>) -> Self {
+        FormatChunker {
+            inner: inner,
+            state: State::Detect,
+            segment: None,
+            header: Vec::with_capacity(TAR_BLOCK_SIZE),
+            prefix: Vec::with_capacity(PREFIX_SIZE),
+            prefix_pos: 0,
+            pos: 0
+        }
+    }
+
+    fn reset(&mut self) {
+        self.state = State::Detect;
+        self.segment = None;
+        self.header.clear();
+        self.prefix.clear();
+        self.prefix_pos = 0;
+        self.pos = 0;
+    }
+
+    fn read_header(&mut self, r: &mut Read, buf: &mut [u8]) -> Result<usize, ChunkerError> {
+        let len = try!(read_full(&mut PrefixReader{prefix: &self.prefix, pos: &mut self.prefix_pos, inner: r}, buf).map_err(ChunkerError::Read));
+        self.pos += len as u64;
+        Ok(len)
+    }
+
+    // Puts back the bytes of an invalid header and chunks the rest without structure
+    fn unread_header(&mut self, header: &[u8]) {
+        let mut prefix = header.to_vec();
+        prefix.extend_from_slice(&self.prefix[self.prefix_pos..]);
+        self.prefix = prefix;
+        self.prefix_pos = 0;
+        self.pos -= header.len() as u64;
+        self.plain();
+    }
+
+    fn plain(&mut self) {
+        self.state = State::Plain;
+        self.segment = Some(UNBOUNDED);
+    }
+
+    fn detect(&mut self, r: &mut Read) -> Result<(), ChunkerError> {
+        self.prefix.resize(PREFIX_SIZE, 0);
+        let len = try!(read_full(r, &mut self.prefix).map_err(ChunkerError::Read));
+        self.prefix.truncate(len);
+        self.state = State::detect(&self.prefix);
+        Ok(())
+    }
+
+    fn tar_header(&mut self, r: &mut Read) -> Result<(), ChunkerError> {
+        let mut header = [0u8; TAR_BLOCK_SIZE];
+        let len = try!(self.read_header(r, &mut header));
+        let size = if len == header.len() { parse_tar_header(&header) } else { None };
+        if let Some(size) = size {
+            // The header is kept for the first chunk of the member, so no tiny chunks are
+            // created for the headers
+            self.header.extend_from_slice(&header);
+            let padded = tar_padded_size(size);
+            self.segment = if padded > 0 { Some(padded) } else { None };
+        } else {
+            // The end of the archive or something unexpected
+            self.unread_header(&header[..len]);
+        }
+        Ok(())
+    }
+
+    fn zip_header(&mut self, r: &mut Read, w: &mut Write) -> Result<bool, ChunkerError> {
+        let mut header = vec![0u8; ZIP_HEADER_SIZE];
+        let mut len = try!(self.read_header(r, &mut header));
+        let (extra, size) = match parse_zip_header(&header[..len]) {
+            Some(res) => res,
+            None => {
+                // The central directory or something unexpected
+                self.unread_header(&header[..len]);
+                return Ok(false)
+            }
+        };
+        header.resize(ZIP_HEADER_SIZE + extra, 0);
+        len += try!(self.read_header(r, &mut header[ZIP_HEADER_SIZE..]));
+        if len < header.len() {
+            self.unread_header(&header[..len]);
+            return Ok(false)
+        }
+        try!(w.write_all(&header).map_err(ChunkerError::Write));
+        match size {
+            Some(size) => self.segment = if size > 0 { Some(size) } else { None },
+            None => self.plain()
+        }
+        Ok(true)
+    }
+
+    /// Returns the end positions of at most `max_cuts` chunks of `data` that end at a cut point,
+    /// these are the same chunks that `chunk` creates from a stream of `data`
+    fn find_format_cuts(&self, data: &[u8], max_cuts: usize) -> Vec<usize> {
+        let mut cuts = vec![];
+        let mut pos = 0;
+        let mut state = State::detect(&data[..min(data.len(), PREFIX_SIZE)]);
+        while pos < data.len() && cuts.len() < max_cuts {
+            let segment = match state {
+                State::Detect | State::Plain => UNBOUNDED,
+                State::TarHeader => {
+                    let size = if data.len() - pos >= TAR_BLOCK_SIZE {
+                        parse_tar_header(&data[pos..pos+TAR_BLOCK_SIZE])
+                    } else {
+                        None
+                    };
+                    if let Some(size) = size {
+                        // No cut point, the header belongs to the first chunk of the member
+                        pos += TAR_BLOCK_SIZE;
+                        tar_padded_size(size)
+                    } else {
+                        state = State::Plain;
+                        UNBOUNDED
+                    }
+                },
+                State::ZipHeader => {
+                    match parse_zip_header(&data[pos..]) {
+                        Some((extra, size)) if data.len() - pos >= ZIP_HEADER_SIZE + extra => {
+                            pos += ZIP_HEADER_SIZE + extra;
+                            cuts.push(pos);
+                            if let Some(size) = size {
+                                size
+                            } else {
+                                state = State::Plain;
+                                UNBOUNDED
+                            }
+                        },
+                        _ => {
+                            state = State::Plain;
+                            UNBOUNDED
+                        }
+                    }
+                },
+                State::Blocks(size) => size,
+                State::Boundaries(ref mut boundaries) => {
+                    while boundaries.front().map(|&b| b <= pos as u64).unwrap_or(false) {
+                        boundaries.pop_front();
+                    }
+                    boundaries.front().map(|&b| b - pos as u64).unwrap_or(UNBOUNDED)
+                }
+            };
+            if segment == UNBOUNDED {
+                state = State::Plain;
+            }
+            // Segments that end with the data are finished by the end of the stream instead of a
+            // cut point
+            let bounded = segment <= (data.len() - pos) as u64;
+            let end = if bounded { pos + segment as usize } else { data.len() };
+            while pos < end && cuts.len() < max_cuts {
+                pos += match self.inner.find_cut(&data[pos..end]) {
+                    Some(len) => len,
+                    None if bounded => end - pos,
+                    None => return cuts
+                };
+                cuts.push(pos);
+            }
+        }
+        cuts.truncate(max_cuts);
+        cuts
+    }
+
+    fn next_chunk(&mut self, r: &mut Read, w: &mut Write) -> Result<ChunkerStatus, ChunkerError> {
+        loop {
+            if let Some(remaining) = self.segment {
+                let mut reader = PrefixReader{prefix: &self.prefix, pos: &mut self.prefix_pos, inner: r}.take(remaining);
+                let mut writer = CountingWriter{inner: w, written: 0};
+                if !self.header.is_empty() {
+                    try!(writer.write_all(&self.header).map_err(ChunkerError::Write));
+                    self.header.clear();
+                }
+                let status = try!(self.inner.chunk(&mut reader, &mut writer));
+                let read = remaining - reader.limit();
+                if remaining != UNBOUNDED {
+                    self.pos += read;
+                    self.segment = Some(remaining - read);
+                }
+                if status == ChunkerStatus::Continue {
+                    return Ok(status)
+                }
+                if reader.limit() > 0 {
+                    // The inner chunker only finishes early at the end of the stream
+                    self.reset();
+                    return Ok(ChunkerStatus::Finished)
+                }
+                self.segment = None;
+                if writer.written > 0 {
+                    return Ok(ChunkerStatus::Continue)
+                }
+            }
+            let pos = self.pos;
+            let header = match self.state {
+                State::Detect => {
+                    try!(self.detect(r));
+                    if self.prefix.is_empty() {
+                        self.reset();
+                        return Ok(ChunkerStatus::Finished)
+                    }
+                    false
+                },
+                State::Plain => {
+                    self.plain();
+                    false
+                },
+                State::TarHeader => {
+                    try!(self.tar_header(r));
+                    false
+                },
+                State::ZipHeader => try!(self.zip_header(r, w)),
+                State::Blocks(size) => {
+                    self.segment = Some(size);
+                    false
+                },
+                State::Boundaries(ref mut boundaries) => {
+                    while boundaries.front().map(|&b| b <= pos).unwrap_or(false) {
+                        boundaries.pop_front();
+                    }
+                    if let Some(&boundary) = boundaries.front() {
+                        self.segment = Some(boundary - pos);
+                    }
+                    false
+                }
+            };
+            if header {
+                return Ok(ChunkerStatus::Continue)
+            }
+            if let State::Boundaries(ref boundaries) = self.state {
+                if boundaries.is_empty() {
+                    self.plain();
+                }
+            }
+        }
+    }
+}
+
+impl Chunker for FormatChunker {
+    // In-memory data is treated as a whole stream, the format is detected at its start
+    fn find_cut(&self, data: &[u8]) -> Option<usize> {
+        self.find_format_cuts(data, 1).pop()
+    }
+
+    fn find_cuts(&self, data: &[u8]) -> Vec<usize> {
+        self.find_format_cuts(data, ::std::usize::MAX)
+    }
+
+    fn chunk(&mut self, r: &mut Read, w: &mut Write) -> Result<ChunkerStatus, ChunkerError> {
+        let res = self.next_chunk(r, w);
+        if res.is_err() {
+            // The stream can not be continued, the next call starts with a new stream
+            self.reset();
+        }
+        res
+    }
+}
@@ -0,0 +1,92 @@
+use super::*;
+use super::fastcdc::create_gear;
+
+use std::ptr;
+
+// Gear Chunker
+// Paper: "Ddelta: A deduplication-inspired fast delta compression approach"
+// Paper-URL: https://doi.org/10.1016/j.peva.2014.07.016
+
+
+// The hash only depends on the last 64 bytes and its highest bits are the best mixed ones
+fn get_mask(avg_size: usize) -> u64 {
+    let bits = (avg_size.next_power_of_two() - 1).count_ones();
+    !0u64 << (64 - bits)
+}
+
+pub struct GearChunker {
+    buffer: [u8; 4096],
+    buffered: usize,
+    gear: [u64; 256],
+    min_size: usize,
+    max_size: usize,
+    mask: u64,
+}
+
+
+impl GearChunker {
+    pub fn new(avg_size: usize, seed: u64) -> Self {
+        Self::with_limits(avg_size/4, avg_size, avg_size*8, seed)
+    }
+
+    pub fn with_limits(min_size: usize, avg_size: usize, max_size: usize, seed: u64) -> Self {
+        GearChunker {
+            buffer: [0; 4096],
+            buffered: 0,
+            gear: create_gear(seed),
+            min_size: min_size,
+            max_size: max_size,
+            mask: get_mask(avg_size),
+        }
+    }
+}
+
+impl Chunker for GearChunker {
+    fn find_cut(&self, data: &[u8]) -> Option<usize> {
+        let mut hash = 0u64;
+        for (pos, &val) in data.iter().enumerate().skip(self.min_size) {
+            // Hash update
+            hash = (hash << 1).wrapping_add(self.gear[val as usize]);
+            if hash & self.mask == 0 || pos >= self.max_size {
+                return Some(pos+1)
+            }
+        }
+        None
+    }
+
+    #[allow(unknown_lints,explicit_counter_loop,needless_range_loop)]
+    fn chunk(&mut self, r: &mut Read, w: &mut Write) -> Result<ChunkerStatus, ChunkerError> {
+        let mut max;
+        let mut hash = 0u64;
+        let mut pos = 0;
+        let gear = &self.gear;
+        let buffer = &mut self.buffer;
+        let min_size = self.min_size;
+        let max_size = self.max_size;
+        let mask = self.mask;
+        loop {
+            // Fill the buffer, there might be some bytes still in there from last chunk
+            max = try!(r.read(&mut buffer[self.buffered..]).map_err(ChunkerError::Read)) + self.buffered;
+            // If nothing to do, finish
+            if max == 0 {
+                return Ok(ChunkerStatus::Finished)
+            }
+            for i in 0..max {
+                if pos >= min_size {
+                    // Hash update
+                    hash = (hash << 1).wrapping_add(gear[buffer[i] as usize]);
+                    if hash & mask == 0 || pos >= max_size {
+                        // Write all bytes from this chunk out to sink and store rest for next chunk
+                        try!(w.write_all(&buffer[..i+1]).map_err(ChunkerError::Write));
+                        unsafe { ptr::copy(buffer[i+1..].as_ptr(), buffer.as_mut_ptr(), max-i-1) };
+                        self.buffered = max-i-1;
+                        return Ok(ChunkerStatus::Continue);
+                    }
+                }
+                pos += 1;
+            }
+            try!(w.write_all(&buffer[..max]).map_err(ChunkerError::Write));
+            self.buffered = 0;
+        }
+    }
+}
@@ -6,11 +6,21 @@ mod fixed;
 mod ae;
 mod rabin;
 mod fastcdc;
+mod buzhash;
+mod gear;
+mod tttd;
+mod cuts;
+mod formats;
 
 pub use self::fixed::FixedChunker;
 pub use self::ae::AeChunker;
 pub use self::rabin::RabinChunker;
 pub use self::fastcdc::FastCdcChunker;
+pub use self::buzhash::BuzhashChunker;
+pub use self::gear::GearChunker;
+pub use self::tttd::TttdChunker;
+pub use self::cuts::{find_cuts, find_region_cuts, merge_region_cuts, RegionCuts};
+pub use self::formats::{FormatChunker, Format, detect_format};
 
 // https://moinakg.wordpress.com/2013/06/22/high-performance-content-defined-chunking/
 
@@ -50,4 +60,24 @@ pub enum ChunkerStatus {
 
 pub trait Chunker {
     fn chunk(&mut self, r: &mut Read, w: &mut Write) -> Result<ChunkerStatus, ChunkerError>;
+
+    /// Returns the length of the first chunk of `data`, which must start at a chunk boundary,
+    /// or `None` if the data ends before the next cut point
+    fn find_cut(&self, data: &[u8]) -> Option<usize>;
+
+    /// Returns the end positions of all chunks of `data` that end at a cut point, see `find_cuts`
+    fn find_cuts(&self, data: &[u8]) -> Vec<usize> {
+        let mut cuts = vec![];
+        let mut pos = 0;
+        while pos < data.len() {
+            match self.find_cut(&data[pos..]) {
+                Some(len) => {
+                    pos += len;
+                    cuts.push(pos);
+                },
+                None => break
+            }
+        }
+        cuts
+    }
 }
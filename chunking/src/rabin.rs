@@ -48,6 +48,10 @@ pub struct RabinChunker {
 
 impl RabinChunker {
     pub fn new(avg_size: usize, seed: u32) -> Self {
+        Self::with_limits(avg_size/4, avg_size, avg_size*4, seed)
+    }
+
+    pub fn with_limits(min_size: usize, avg_size: usize, max_size: usize, seed: u32) -> Self {
         let chunk_mask = (avg_size as u32).next_power_of_two() - 1;
         let window_size = avg_size/4-1;
         let alpha = 1664525;//153191;
@@ -57,8 +61,8 @@ impl RabinChunker {
             table: create_table(alpha, window_size),
             alpha: alpha,
             seed: seed,
-            min_size: avg_size/4,
-            max_size: avg_size*4,
+            min_size: min_size,
+            max_size: max_size,
             window_size: window_size,
             chunk_mask: chunk_mask,
         }
@@ -66,6 +70,24 @@ impl RabinChunker {
 }
 
 impl Chunker for RabinChunker {
+    fn find_cut(&self, data: &[u8]) -> Option<usize> {
+        let mut hash = 0u32;
+        for (pos, &val) in data.iter().enumerate() {
+            if pos >= self.max_size {
+                return Some(pos+1)
+            }
+            // Hash update
+            hash = hash.wrapping_mul(self.alpha).wrapping_add(val as u32);
+            if pos >= self.window_size {
+                hash = hash.wrapping_sub(self.table[data[pos-self.window_size] as usize]);
+                if pos >= self.min_size && ((hash ^ self.seed) & self.chunk_mask) == 0 {
+                    return Some(pos+1)
+                }
+            }
+        }
+        None
+    }
+
     #[allow(unknown_lints,explicit_counter_loop)]
     fn chunk(&mut self, r: &mut Read, mut w: &mut Write) -> Result<ChunkerStatus, ChunkerError> {
         let mut max;
@@ -0,0 +1,115 @@
+use super::*;
+use super::fastcdc::create_gear;
+
+use std::ptr;
+use std::cmp::min;
+
+// TTTD Chunker (Two Thresholds, Two Divisors)
+// Paper: "A Framework for Analyzing and Improving Content-Based Chunking Algorithms"
+// Paper-URL: http://www.hpl.hp.com/techreports/2005/HPL-2005-30R1.pdf
+// This variant uses the gear hash instead of the rabin fingerprint
+
+
+// The buffer only grows up to the maximum chunk size when no cut point is found earlier
+const INITIAL_BUFFER_SIZE: usize = 64*1024;
+
+
+fn get_masks(avg_size: usize) -> (u64, u64) {
+    let bits = (avg_size.next_power_of_two() - 1).count_ones();
+    let mask_main = !0u64 << (64 - bits);
+    // The backup divisor is half the main divisor
+    let mask_backup = mask_main << 1;
+    (mask_main, mask_backup)
+}
+
+pub struct TttdChunker {
+    // Chunks might be cut at the backup break point, so the whole chunk must be kept
+    buffer: Vec<u8>,
+    buffered: usize,
+    gear: [u64; 256],
+    min_size: usize,
+    max_size: usize,
+    mask_main: u64,
+    mask_backup: u64,
+}
+
+
+impl TttdChunker {
+    pub fn new(avg_size: usize, seed: u64) -> Self {
+        Self::with_limits(avg_size/4, avg_size, avg_size*4, seed)
+    }
+
+    pub fn with_limits(min_size: usize, avg_size: usize, max_size: usize, seed: u64) -> Self {
+        let (mask_main, mask_backup) = get_masks(avg_size);
+        TttdChunker {
+            buffer: vec![0; min(max_size.saturating_add(1), INITIAL_BUFFER_SIZE)],
+            buffered: 0,
+            gear: create_gear(seed),
+            min_size: min_size,
+            max_size: max_size,
+            mask_main: mask_main,
+            mask_backup: mask_backup,
+        }
+    }
+
+    fn fill_buffer(&mut self, r: &mut Read) -> Result<bool, ChunkerError> {
+        while self.buffered < self.buffer.len() {
+            let read = try!(r.read(&mut self.buffer[self.buffered..]).map_err(ChunkerError::Read));
+            if read == 0 {
+                return Ok(false)
+            }
+            self.buffered += read;
+        }
+        Ok(true)
+    }
+}
+
+impl Chunker for TttdChunker {
+    fn find_cut(&self, data: &[u8]) -> Option<usize> {
+        let mut hash = 0u64;
+        let mut backup = None;
+        for (pos, &val) in data.iter().enumerate().skip(self.min_size) {
+            // Hash update
+            hash = (hash << 1).wrapping_add(self.gear[val as usize]);
+            if hash & self.mask_main == 0 {
+                return Some(pos+1)
+            }
+            if hash & self.mask_backup == 0 {
+                backup = Some(pos+1);
+            }
+            if pos >= self.max_size {
+                return Some(backup.unwrap_or(pos+1))
+            }
+        }
+        None
+    }
+
+    fn chunk(&mut self, r: &mut Read, w: &mut Write) -> Result<ChunkerStatus, ChunkerError> {
+        let cut = loop {
+            let full = try!(self.fill_buffer(r));
+            // If nothing to do, finish
+            if self.buffered == 0 {
+                return Ok(ChunkerStatus::Finished)
+            }
+            match self.find_cut(&self.buffer[..self.buffered]) {
+                Some(cut) => break cut,
+                None if full && self.buffer.len() <= self.max_size => {
+                    let len = min(self.buffer.len() * 2, self.max_size.saturating_add(1));
+                    self.buffer.resize(len, 0);
+                },
+                None if full => break self.buffered,
+                None => {
+                    // End of the input
+                    try!(w.write_all(&self.buffer[..self.buffered]).map_err(ChunkerError::Write));
+                    self.buffered = 0;
+                    return Ok(ChunkerStatus::Finished)
+                }
+            }
+        };
+        // Write all bytes from this chunk out to sink and store rest for next chunk
+        try!(w.write_all(&self.buffer[..cut]).map_err(ChunkerError::Write));
+        unsafe { ptr::copy(self.buffer[cut..].as_ptr(), self.buffer.as_mut_ptr(), self.buffered-cut) };
+        self.buffered -= cut;
+        Ok(ChunkerStatus::Continue)
+    }
+}
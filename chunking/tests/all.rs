@@ -2,7 +2,7 @@ extern crate chunking;
 
 use chunking::*;
 
-use std::io::Cursor;
+use std::io::{self, Cursor, Read};
 
 
 fn random_data(seed: u64, size: usize) -> Vec<u8> {
@@ -84,3 +84,378 @@ fn test_fastcdc() {
         assert!(len <= data.len()/n/1024*4);
     }
 }
+#[test]
+fn test_buzhash() {
+    let data = random_data(0, 10*1024*1024);
+    for n in &[1usize,2,4,8,16,32,64,128,256,512,1024] {
+        let mut chunker = BuzhashChunker::new(1024*n, 0);
+        let len = test_chunking(&mut chunker, &data);
+        assert!(len >= data.len()/n/1024/4);
+        assert!(len <= data.len()/n/1024*4);
+    }
+}
+
+#[test]
+fn test_gear() {
+    let data = random_data(0, 10*1024*1024);
+    for n in &[1usize,2,4,8,16,32,64,128,256,512,1024] {
+        let mut chunker = GearChunker::new(1024*n, 0);
+        let len = test_chunking(&mut chunker, &data);
+        assert!(len >= data.len()/n/1024/4);
+        assert!(len <= data.len()/n/1024*4);
+    }
+}
+
+#[test]
+fn test_tttd() {
+    let data = random_data(0, 10*1024*1024);
+    for n in &[1usize,2,4,8,16,32,64,128,256,512,1024] {
+        let mut chunker = TttdChunker::new(1024*n, 0);
+        let len = test_chunking(&mut chunker, &data);
+        assert!(len >= data.len()/n/1024/4);
+        assert!(len <= data.len()/n/1024*4);
+    }
+}
+
+#[test]
+fn test_tttd_huge_limit() {
+    // The buffer must not be allocated for the maximum size up front
+    let data = random_data(0, 1024*1024);
+    let mut chunker = TttdChunker::with_limits(2*1024, 8*1024, usize::max_value() / 2, 0);
+    let mut limited = TttdChunker::with_limits(2*1024, 8*1024, 1024*1024, 0);
+    assert_eq!(chunk_sizes(&mut chunker, &data), chunk_sizes(&mut limited, &data));
+    let mut data = random_data(1, 1024*1024);
+    // Long runs without a cut point let the buffer grow
+    for b in &mut data[100*1024..400*1024] {
+        *b = 0;
+    }
+    let mut chunker = TttdChunker::with_limits(2*1024, 8*1024, 256*1024, 0);
+    assert!(chunk_sizes(&mut chunker, &data).iter().any(|&size| size == 256*1024+1));
+    test_chunking(&mut TttdChunker::with_limits(2*1024, 8*1024, 256*1024, 0), &data);
+}
+
+#[test]
+fn test_seed() {
+    let data = random_data(0, 1024*1024);
+    let mut chunkers: Vec<(Box<Chunker>, Box<Chunker>)> = vec![
+        (Box::new(BuzhashChunker::new(8*1024, 0)), Box::new(BuzhashChunker::new(8*1024, 1))),
+        (Box::new(GearChunker::new(8*1024, 0)), Box::new(GearChunker::new(8*1024, 1))),
+        (Box::new(TttdChunker::new(8*1024, 0)), Box::new(TttdChunker::new(8*1024, 1)))
+    ];
+    for &mut (ref mut a, ref mut b) in &mut chunkers {
+        assert!(chunk_sizes(a.as_mut(), &data) != chunk_sizes(b.as_mut(), &data));
+    }
+}
+
+
+fn chunk_sizes(chunker: &mut Chunker, data: &[u8]) -> Vec<usize> {
+    let mut cursor = Cursor::new(&data);
+    let mut sizes = vec![];
+    let mut chunk = vec![];
+    while chunker.chunk(&mut cursor, &mut chunk).unwrap() == ChunkerStatus::Continue {
+        sizes.push(chunk.len());
+        chunk.clear();
+    }
+    sizes
+}
+
+#[test]
+fn test_limits() {
+    let data = random_data(0, 10*1024*1024);
+    let (min, avg, max) = (6*1024, 8*1024, 12*1024);
+    let mut chunkers: Vec<Box<Chunker>> = vec![
+        Box::new(AeChunker::with_limits(min, avg, max)),
+        Box::new(RabinChunker::with_limits(min, avg, max, 0)),
+        Box::new(FastCdcChunker::with_limits(min, avg, max, 1, 0)),
+        Box::new(FastCdcChunker::with_limits(min, avg, max, 3, 0)),
+        Box::new(BuzhashChunker::with_limits(min, avg, max, 0)),
+        Box::new(GearChunker::with_limits(min, avg, max, 0)),
+        Box::new(TttdChunker::with_limits(min, avg, max, 0))
+    ];
+    for chunker in &mut chunkers {
+        for size in chunk_sizes(chunker.as_mut(), &data) {
+            assert!(size > min);
+            assert!(size <= max+1);
+        }
+    }
+}
+
+fn all_chunkers() -> Vec<Box<Chunker>> {
+    vec![
+        Box::new(FixedChunker::new(8*1024)),
+        Box::new(AeChunker::new(8*1024)),
+        Box::new(RabinChunker::new(8*1024, 0)),
+        Box::new(FastCdcChunker::new(8*1024, 0)),
+        Box::new(BuzhashChunker::new(8*1024, 0)),
+        Box::new(GearChunker::new(8*1024, 0)),
+        Box::new(TttdChunker::new(8*1024, 0))
+    ]
+}
+
+fn cut_sizes(cuts: &[usize], len: usize) -> Vec<usize> {
+    let mut sizes = vec![];
+    let mut last = 0;
+    for &cut in cuts.iter().chain(Some(len).iter()) {
+        sizes.push(cut - last);
+        last = cut;
+    }
+    sizes
+}
+
+#[test]
+fn test_cuts() {
+    let mut data = random_data(0, 1024*1024);
+    // Some data without any structure
+    for i in 0..64*1024 {
+        data[128*1024+i] = 0;
+    }
+    for chunker in &mut all_chunkers() {
+        let mut expected = chunk_sizes(chunker.as_mut(), &data);
+        expected.push(data.len() - expected.iter().sum::<usize>());
+        assert_eq!(cut_sizes(&find_cuts(chunker.as_ref(), &data), data.len()), expected);
+    }
+}
+
+#[test]
+fn test_region_cuts() {
+    let data = random_data(0, 1024*1024);
+    for chunker in &all_chunkers() {
+        let expected = find_cuts(chunker.as_ref(), &data);
+        for n in &[1usize,2,3,7,16,100] {
+            let step = (data.len() + n - 1) / n;
+            let regions: Vec<_> = (0..*n).map(|i| {
+                let start = std::cmp::min(i * step, data.len());
+                let end = std::cmp::min((i + 1) * step, data.len());
+                find_region_cuts(chunker.as_ref(), &data, start, end)
+            }).collect();
+            assert_eq!(merge_region_cuts(chunker.as_ref(), &data, &regions), expected);
+        }
+    }
+}
+
+fn all_chunks(chunker: &mut Chunker, data: &[u8]) -> Vec<Vec<u8>> {
+    let mut cursor = Cursor::new(&data);
+    let mut chunks = vec![];
+    loop {
+        let mut chunk = vec![];
+        let status = chunker.chunk(&mut cursor, &mut chunk).unwrap();
+        chunks.push(chunk);
+        if status == ChunkerStatus::Finished {
+            break
+        }
+    }
+    assert_eq!(chunks.concat(), data);
+    chunks
+}
+
+fn chunk_starts(chunks: &[Vec<u8>]) -> Vec<usize> {
+    let mut pos = 0;
+    chunks.iter().map(|chunk| {
+        let start = pos;
+        pos += chunk.len();
+        start
+    }).collect()
+}
+
+fn tar_header(name: &str, size: usize) -> Vec<u8> {
+    let mut header = vec![0; 512];
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    header[100..108].copy_from_slice(b"0000644\0");
+    header[124..136].copy_from_slice(format!("{:011o}\0", size).as_bytes());
+    header[136..148].copy_from_slice(b"00000000000\0");
+    header[156] = b'0';
+    header[257..263].copy_from_slice(b"ustar\0");
+    let checksum = header.iter().map(|&b| b as usize).sum::<usize>() + 8 * b' ' as usize;
+    header[148..156].copy_from_slice(format!("{:06o}\0 ", checksum).as_bytes());
+    header
+}
+
+fn tar_file(files: &[(&str, &[u8])]) -> (Vec<u8>, Vec<usize>) {
+    let mut data = vec![];
+    let mut boundaries = vec![];
+    for &(name, content) in files {
+        boundaries.push(data.len());
+        data.extend_from_slice(&tar_header(name, content.len()));
+        data.extend_from_slice(content);
+        let padded = (content.len() + 511) / 512 * 512;
+        data.resize(data.len() + padded - content.len(), 0);
+    }
+    boundaries.push(data.len());
+    data.resize(data.len() + 1024, 0);
+    (data, boundaries)
+}
+
+#[test]
+fn test_format_plain() {
+    let data = random_data(0, 1024*1024);
+    assert_eq!(detect_format(&data), None);
+    let plain = all_chunks(&mut FastCdcChunker::new(8*1024, 0), &data);
+    let mut chunker = FormatChunker::new(Box::new(FastCdcChunker::new(8*1024, 0)));
+    assert_eq!(all_chunks(&mut chunker, &data), plain);
+    // The chunker must be reusable for the next stream
+    assert_eq!(all_chunks(&mut chunker, &data), plain);
+    assert_eq!(all_chunks(&mut chunker, &[]), vec![vec![]]);
+}
+
+#[test]
+fn test_format_tar() {
+    let files: Vec<Vec<u8>> = (0..20).map(|i| random_data(i, 4*(i as usize+1)*1000)).collect();
+    let (data1, boundaries) = tar_file(&files.iter().enumerate().map(|(i, f)| (["a", "b"][i % 2], &f as &[u8])).collect::<Vec<_>>());
+    let (data2, _) = tar_file(&files.iter().rev().map(|f| ("other", &f as &[u8])).collect::<Vec<_>>());
+    assert_eq!(detect_format(&data1), Some(Format::Tar));
+    let mut chunker = FormatChunker::new(Box::new(FastCdcChunker::new(8*1024, 0)));
+    let chunks1 = all_chunks(&mut chunker, &data1);
+    let starts = chunk_starts(&chunks1);
+    for boundary in &boundaries {
+        assert!(starts.contains(boundary));
+    }
+    // Only the first chunks of the members differ between both archives as they contain the headers
+    let chunks2 = all_chunks(&mut chunker, &data2);
+    let new_chunks: Vec<_> = chunks2.iter().filter(|c| !chunks1.contains(c)).collect();
+    assert_eq!(new_chunks.len(), files.len());
+    assert!(new_chunks.iter().all(|c| c.len() > 512 && c.starts_with(b"other\0")));
+}
+
+#[test]
+fn test_format_tar_empty_members() {
+    let file = random_data(0, 64*1000);
+    let (data, boundaries) = tar_file(&[("dir1", &[]), ("dir2", &[]), ("file", &file), ("dir3", &[])]);
+    let chunks = all_chunks(&mut FormatChunker::new(Box::new(FastCdcChunker::new(8*1024, 0))), &data);
+    // Headers of members without data are attached to the following member
+    assert!(chunks[0].len() > 3*512);
+    assert!(chunks[0][boundaries[2]..].starts_with(b"file\0"));
+    assert!(chunks.last().unwrap().starts_with(b"dir3\0"));
+    assert_eq!(chunk_starts(&chunks).last(), Some(&boundaries[3]));
+}
+
+fn zip_file(files: &[Vec<u8>]) -> (Vec<u8>, Vec<usize>) {
+    let mut data = vec![];
+    let mut boundaries = vec![];
+    for (i, file) in files.iter().enumerate() {
+        let name = format!("file{}", i);
+        boundaries.push(data.len());
+        data.extend_from_slice(b"PK\x03\x04\x14\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00");
+        for _ in 0..2 {
+            data.extend_from_slice(&[file.len() as u8, (file.len() >> 8) as u8, 0, 0]);
+        }
+        data.extend_from_slice(&[name.len() as u8, 0, 0, 0]);
+        data.extend_from_slice(name.as_bytes());
+        boundaries.push(data.len());
+        data.extend_from_slice(file);
+    }
+    boundaries.push(data.len());
+    data.extend_from_slice(b"PK\x01\x02");
+    data.resize(data.len() + 1000, 0);
+    (data, boundaries)
+}
+
+#[test]
+fn test_format_zip() {
+    let files: Vec<Vec<u8>> = (0..10).map(|i| random_data(i, 16*1000)).collect();
+    let (data, boundaries) = zip_file(&files);
+    assert_eq!(detect_format(&data), Some(Format::Zip));
+    let chunks = all_chunks(&mut FormatChunker::new(Box::new(FastCdcChunker::new(8*1024, 0))), &data);
+    let starts = chunk_starts(&chunks);
+    for boundary in &boundaries {
+        assert!(starts.contains(boundary));
+    }
+}
+
+fn qcow2_image() -> Vec<u8> {
+    let mut data = random_data(0, 1024*1024);
+    data[..24].copy_from_slice(b"QFI\xfb\x00\x00\x00\x03\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x10");
+    data
+}
+
+#[test]
+fn test_format_qcow2() {
+    let data = qcow2_image();
+    assert_eq!(detect_format(&data), Some(Format::Qcow2));
+    let chunks = all_chunks(&mut FormatChunker::new(Box::new(FastCdcChunker::new(8*1024, 0))), &data);
+    let starts = chunk_starts(&chunks);
+    for boundary in (0..data.len()).step_by(64*1024) {
+        assert!(starts.contains(&boundary));
+    }
+}
+
+fn disk_image() -> Vec<u8> {
+    let mut data = random_data(0, 4*1024*1024);
+    for b in &mut data[446..510] {
+        *b = 0;
+    }
+    // Two partitions from 1 MiB to 2 MiB and from 2 MiB to 3.5 MiB
+    data[446+4] = 0x83;
+    data[446+8..446+16].copy_from_slice(&[0x00, 0x08, 0, 0, 0x00, 0x08, 0, 0]);
+    data[462+4] = 0x83;
+    data[462+8..462+16].copy_from_slice(&[0x00, 0x10, 0, 0, 0x00, 0x0c, 0, 0]);
+    data[510] = 0x55;
+    data[511] = 0xaa;
+    data
+}
+
+#[test]
+fn test_format_disk_image() {
+    let data = disk_image();
+    assert_eq!(detect_format(&data), Some(Format::DiskImage));
+    let chunks = all_chunks(&mut FormatChunker::new(Box::new(FastCdcChunker::new(8*1024, 0))), &data);
+    let starts = chunk_starts(&chunks);
+    for boundary in &[1024*1024, 2*1024*1024, 3584*1024] {
+        assert!(starts.contains(boundary));
+    }
+}
+
+#[test]
+fn test_format_cuts() {
+    let files: Vec<Vec<u8>> = (0..10).map(|i| random_data(i, 16*1000)).collect();
+    let (tar, _) = tar_file(&[("dir", &[]), ("a", &files[0]), ("b", &files[1]), ("c", &[])]);
+    let (zip, _) = zip_file(&files);
+    let mut samples = vec![random_data(0, 1024*1024), tar.clone(), zip, qcow2_image(), disk_image(), vec![]];
+    // Truncated archives
+    samples.push(tar[..1000].to_vec());
+    samples.push(tar[..512+16*1000].to_vec());
+    for data in &samples {
+        let mut chunker = FormatChunker::new(Box::new(FastCdcChunker::new(8*1024, 0)));
+        let expected: Vec<usize> = all_chunks(&mut chunker, data).iter().map(|c| c.len()).collect();
+        let cuts = find_cuts(&chunker, data);
+        assert_eq!(cut_sizes(&cuts, data.len()), expected);
+        assert_eq!(chunker.find_cut(data), cuts.first().cloned());
+    }
+}
+
+struct FailingReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    fail_at: usize
+}
+
+impl<'a> Read for FailingReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        if self.pos >= self.fail_at {
+            return Err(io::Error::new(io::ErrorKind::Other, "read failed"))
+        }
+        let len = std::cmp::min(buf.len(), self.fail_at - self.pos);
+        buf[..len].copy_from_slice(&self.data[self.pos..self.pos+len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+#[test]
+fn test_format_reset_on_error() {
+    let files: Vec<Vec<u8>> = (0..4).map(|i| random_data(i, 16*1000)).collect();
+    let (tar, boundaries) = tar_file(&files.iter().map(|f| ("file", &f as &[u8])).collect::<Vec<_>>());
+    let data = qcow2_image();
+    let mut chunker = FormatChunker::new(Box::new(FastCdcChunker::new(8*1024, 0)));
+    let expected = all_chunks(&mut chunker, &data);
+    // Fail while reading the header of the third member
+    let mut reader = FailingReader{data: &tar, pos: 0, fail_at: boundaries[2] + 100};
+    loop {
+        match chunker.chunk(&mut reader, &mut vec![]) {
+            Ok(ChunkerStatus::Continue) => continue,
+            Ok(ChunkerStatus::Finished) => panic!("the stream should fail"),
+            Err(_) => break
+        }
+    }
+    // The next stream is detected again instead of being parsed as a tar file
+    assert_eq!(all_chunks(&mut chunker, &data), expected);
+}
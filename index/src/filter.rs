@@ -0,0 +1,165 @@
+use super::{u64_to_bytes, bytes_to_u64, checksum, CHECKSUM_INIT};
+
+use std::path::Path;
+use std::fs::File;
+use std::io::{self, Read, Write, BufWriter};
+use std::cmp::{min, max};
+use std::f64::consts::LN_2;
+use std::mem;
+use std::slice;
+
+
+const FILTER_MAGIC: [u8; 8] = *b"zvfilter";
+const FILTER_HEADER_SIZE: usize = 8 + 7 * 8;
+const MAX_HASHES: u32 = 16;
+
+
+/// A bloom filter on the key hashes. Keys can not be removed from the filter, so removed keys
+/// just increase the false-positive rate until the filter is rebuilt.
+pub struct Filter {
+    bits: Vec<u64>,
+    num_bits: u64,
+    hashes: u32,
+    capacity: usize,
+    fp_rate: f64,
+    max_memory: usize
+}
+
+impl Filter {
+    /// Creates a filter for the given number of keys with the given false-positive rate. If the
+    /// filter would need more than `max_memory` bytes, it is limited to that size and has a
+    /// higher false-positive rate.
+    pub fn new(capacity: usize, fp_rate: f64, max_memory: usize) -> Self {
+        let ideal_bits = -(capacity as f64) * fp_rate.ln() / (LN_2 * LN_2);
+        let num_bits = max(64, min(ideal_bits.ceil() as u64, max_memory as u64 * 8));
+        let words = (num_bits + 63) / 64;
+        let num_bits = words * 64;
+        let hashes = (num_bits as f64 / capacity as f64 * LN_2).round() as u32;
+        Filter {
+            bits: vec![0; words as usize],
+            num_bits: num_bits,
+            hashes: max(1, min(MAX_HASHES, hashes)),
+            capacity: capacity,
+            fp_rate: fp_rate,
+            max_memory: max_memory
+        }
+    }
+
+    #[inline]
+    fn step(hash: u64) -> u64 {
+        hash.rotate_left(32).wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1
+    }
+
+    #[inline]
+    pub fn insert(&mut self, hash: u64) {
+        let step = Self::step(hash);
+        let mut hash = hash;
+        for _ in 0..self.hashes {
+            let bit = hash % self.num_bits;
+            self.bits[(bit / 64) as usize] |= 1 << (bit % 64);
+            hash = hash.wrapping_add(step);
+        }
+    }
+
+    #[inline]
+    pub fn may_contain(&self, hash: u64) -> bool {
+        let step = Self::step(hash);
+        let mut hash = hash;
+        for _ in 0..self.hashes {
+            let bit = hash % self.num_bits;
+            if self.bits[(bit / 64) as usize] & (1 << (bit % 64)) == 0 {
+                return false
+            }
+            hash = hash.wrapping_add(step);
+        }
+        true
+    }
+
+    #[inline]
+    pub fn clear(&mut self) {
+        for bits in &mut self.bits {
+            *bits = 0;
+        }
+    }
+
+    /// Number of keys the filter has been created for
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    #[inline]
+    pub fn fp_rate(&self) -> f64 {
+        self.fp_rate
+    }
+
+    #[inline]
+    pub fn max_memory(&self) -> usize {
+        self.max_memory
+    }
+
+    #[inline]
+    pub fn size(&self) -> usize {
+        self.bits.len() * mem::size_of::<u64>()
+    }
+
+    #[inline]
+    pub fn matches_config(&self, fp_rate: f64, max_memory: usize) -> bool {
+        self.fp_rate == fp_rate && self.max_memory == max_memory
+    }
+
+    /// The current false-positive rate, calculated from the number of set bits. This includes
+    /// the bits of removed keys.
+    pub fn current_fp_rate(&self) -> f64 {
+        let set_bits: u64 = self.bits.iter().map(|b| b.count_ones() as u64).sum();
+        (set_bits as f64 / self.num_bits as f64).powi(self.hashes as i32)
+    }
+
+    /// Saves the filter together with the size of the index it belongs to
+    pub fn save(&self, path: &Path, index_entries: usize, index_capacity: usize) -> Result<(), io::Error> {
+        let mut file = BufWriter::new(try!(File::create(path)));
+        let mut data = Vec::with_capacity(FILTER_HEADER_SIZE);
+        data.extend_from_slice(&FILTER_MAGIC);
+        for val in &[self.fp_rate.to_bits(), self.max_memory as u64, self.hashes as u64, self.num_bits,
+            self.capacity as u64, index_entries as u64, index_capacity as u64] {
+            data.extend_from_slice(&u64_to_bytes(*val));
+        }
+        let bits = unsafe { slice::from_raw_parts(self.bits.as_ptr() as *const u8, self.size()) };
+        let hash = checksum(checksum(CHECKSUM_INIT, &data), bits);
+        try!(file.write_all(&data));
+        try!(file.write_all(bits));
+        try!(file.write_all(&u64_to_bytes(hash)));
+        file.flush()
+    }
+
+    /// Loads a saved filter, filters with a different configuration, filters for a different
+    /// index state and damaged filters are rejected
+    pub fn load(path: &Path, fp_rate: f64, max_memory: usize, index_entries: usize, index_capacity: usize) -> Option<Self> {
+        let mut data = Vec::new();
+        if File::open(path).and_then(|mut f| f.read_to_end(&mut data)).is_err() {
+            return None
+        }
+        if data.len() < FILTER_HEADER_SIZE + 8 || data[..FILTER_MAGIC.len()] != FILTER_MAGIC {
+            return None
+        }
+        let (data, stored) = data.split_at(data.len() - 8);
+        if checksum(CHECKSUM_INIT, data) != bytes_to_u64(stored) {
+            return None
+        }
+        let header: Vec<u64> = data[FILTER_MAGIC.len()..FILTER_HEADER_SIZE].chunks(8).map(bytes_to_u64).collect();
+        let bits: Vec<u64> = data[FILTER_HEADER_SIZE..].chunks(8).filter(|c| c.len() == 8).map(bytes_to_u64).collect();
+        let filter = Filter {
+            fp_rate: f64::from_bits(header[0]),
+            max_memory: header[1] as usize,
+            hashes: header[2] as u32,
+            num_bits: header[3],
+            capacity: header[4] as usize,
+            bits: bits
+        };
+        if !filter.matches_config(fp_rate, max_memory) || header[5] as usize != index_entries
+            || header[6] as usize != index_capacity || filter.num_bits != filter.bits.len() as u64 * 64 {
+            return None
+        }
+        Some(filter)
+    }
+}
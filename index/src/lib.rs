@@ -1,21 +1,41 @@
 extern crate mmap;
+extern crate libc;
 #[macro_use] extern crate quick_error;
 
-use std::path::Path;
-use std::fs::{File, OpenOptions};
+mod filter;
+#[cfg(test)] mod test_util;
+
+use std::path::{Path, PathBuf};
+use std::fs::{self, File, OpenOptions};
 use std::mem;
 use std::ptr;
-use std::io;
+use std::cmp::{min, max};
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::io::{self, Read, Write, BufReader, BufWriter};
 use std::slice;
 use std::os::unix::io::AsRawFd;
+use std::os::unix::fs::FileExt;
 
 use mmap::{MemoryMap, MapOption, MapError};
 
+pub use filter::Filter;
+
 
 pub const MAX_USAGE: f64 = 0.9;
 pub const MIN_USAGE: f64 = 0.35;
 pub const INITIAL_SIZE: usize = 1024;
 
+pub const DISPLACEMENT_BUCKETS: usize = 16;
+
+const JOURNAL_MAGIC: [u8; 8] = *b"zvjournl";
+const CHECKSUM_INIT: u64 = 0xcbf29ce484222325;
+const HISTORY_ENTRY_SIZE: usize = 24;
+/// Number of changed entries after which the index should be flushed, this limits the size of
+/// the journal and the number of changes that are lost after a crash
+const FLUSH_CHANGES: usize = 1 << 18;
+/// Maximal number of flushes in the history file, the oldest half is dropped when it is full
+const MAX_HISTORY_ENTRIES: usize = 4096;
+
 
 quick_error!{
     #[derive(Debug)]
@@ -44,10 +64,22 @@ quick_error!{
             description("Key at wrong position")
             display("Index error: key has wrong position, expected at: {}, but is at: {:?}", should, is)
         }
+        WrongEntrySize(key: usize, value: usize) {
+            description("Wrong entry size")
+            display("Index error: index file does not consist of entries with {} byte keys and {} byte values", key, value)
+        }
         WrongEntryCount(header: usize, actual: usize) {
             description("Wrong entry count")
             display("Index error: index has wrong entry count, expected {}, but is {}", header, actual)
         }
+        ReadOnly {
+            description("Index is read-only")
+            display("Index error: the index has been opened read-only")
+        }
+        PendingJournal {
+            description("Pending journal")
+            display("Index error: the index has a pending journal and can not be opened read-only")
+        }
     }
 }
 
@@ -57,10 +89,35 @@ pub struct Header {
     magic: [u8; 7],
     version: u8,
     entries: u64,
-    capacity: u64,
+    capacity: u64
 }
 
 
+/// All versions of the header start with the magic, the version, the number of entries and the
+/// capacity
+const COMMON_HEADER_SIZE: usize = 24;
+
+
+/// Converts the entries of an index file from an older version to the next version. Migrations
+/// can be chained, the entries are converted by all migrations up to the current version and
+/// then inserted into a new index.
+pub struct Migration {
+    pub from: u8,
+    pub to: u8,
+    /// Size of the header, the keys and the values in the old version
+    pub header_size: usize,
+    pub key_size: usize,
+    pub value_size: usize,
+    /// Whether the raw key of the old version belongs to a used entry, this is only called for
+    /// the first migration of a chain
+    pub is_used: fn(&[u8]) -> bool,
+    /// Converts the raw key and value to the layout of the next version
+    pub convert: fn(&[u8], &[u8]) -> (Vec<u8>, Vec<u8>)
+}
+
+
+/// Keys are stored in place, so they must have a fixed size. Longer keys (e.g. wider hashes)
+/// just need a bigger type, the version in the header has to identify the entry layout then.
 pub trait Key: Clone + Eq + Copy + Default {
     fn hash(&self) -> u64;
     fn is_used(&self) -> bool;
@@ -90,6 +147,39 @@ impl<K: Key, V> Entry<K, V> {
     }
 }
 
+/// Statistics on the layout of the index
+#[derive(Debug, Clone, Default)]
+pub struct IndexStats {
+    pub entries: usize,
+    pub capacity: usize,
+    pub size: usize,
+    pub resident_size: Option<usize>,
+    pub load_factor: f64,
+    /// Number of entries by their distance from their ideal position, the last bucket also
+    /// contains all larger distances
+    pub displacement: Vec<usize>,
+    pub max_displacement: usize,
+    pub avg_displacement: f64,
+    /// Average number of slots that are read to find a key that is in the index
+    pub avg_hit_probes: f64,
+    /// Average number of slots that are read to find out that a key is not in the index
+    pub avg_miss_probes: f64,
+    /// Longest run of used slots
+    pub max_cluster: usize,
+    pub filter_size: Option<usize>,
+    pub filter_fp_rate: Option<f64>
+}
+
+
+/// The size of the index at one flush
+#[derive(Debug, Clone)]
+pub struct IndexHistoryEntry {
+    pub timestamp: i64,
+    pub entries: usize,
+    pub capacity: usize
+}
+
+
 #[derive(Debug)]
 pub enum LocateResult {
     Found(usize), // Found the key at this position
@@ -113,18 +203,26 @@ impl<'a, K: Key, V> Iterator for Iter<'a, K, V> {
     }
 }
 
-pub struct IterMut<'a, K: 'static, V: 'static> (&'a mut [Entry<K, V>]);
+/// Iterates over the used entries and marks every returned entry as changed
+pub struct IterMut<'a, K: 'static, V: 'static> {
+    data: &'a mut [Entry<K, V>],
+    changed: &'a mut [u64],
+    pos: usize
+}
 
 impl<'a, K: Key, V> Iterator for IterMut<'a, K, V> {
     type Item = (&'a K, &'a mut V);
     fn next(&mut self) -> Option<Self::Item> {
         loop {
-            let slice = mem::replace(&mut self.0, &mut []);
+            let slice = mem::replace(&mut self.data, &mut []);
             match slice.split_first_mut() {
                 None => return None,
                 Some((first, rest)) => {
-                    self.0 = rest;
+                    self.data = rest;
+                    let pos = self.pos;
+                    self.pos += 1;
                     if first.is_used() {
+                        self.changed[pos / 64] |= 1 << (pos % 64);
                         return Some((&first.key, &mut first.data))
                     }
                 }
@@ -134,6 +232,166 @@ impl<'a, K: Key, V> Iterator for IterMut<'a, K, V> {
 }
 
 
+/// A read-only view of the index that can be shared between threads. As long as a reader
+/// exists, the index can not be modified.
+#[derive(Clone, Copy)]
+pub struct IndexReader<'a, K: 'static, V: 'static> {
+    data: &'a [Entry<K, V>],
+    filter: Option<&'a Filter>,
+    mask: usize,
+    capacity: usize,
+    entries: usize
+}
+
+impl<'a, K: Key, V: Value> IndexReader<'a, K, V> {
+    /// Finds the position for this key
+    /// If the key is in the table, it will be the position of the key,
+    /// otherwise it will be the position where this key should be inserted
+    fn locate(&self, key: &K) -> LocateResult {
+        let mut pos = key.hash() as usize & self.mask;
+        let mut dist = 0;
+        loop {
+            let entry = &self.data[pos];
+            if !entry.is_used() {
+                return LocateResult::Hole(pos);
+            }
+            if entry.key == *key {
+                return LocateResult::Found(pos);
+            }
+            let odist = (pos + self.capacity - (entry.key.hash() as usize & self.mask)) & self.mask;
+            if dist > odist {
+                return LocateResult::Steal(pos);
+            }
+            pos = (pos + 1) & self.mask;
+            dist += 1;
+        }
+    }
+
+    #[inline]
+    fn displacement(&self, pos: usize) -> usize {
+        (pos + self.capacity - (self.data[pos].key.hash() as usize & self.mask)) & self.mask
+    }
+
+    pub fn stats(&self) -> IndexStats {
+        let mut stats = IndexStats {
+            entries: self.entries,
+            capacity: self.capacity,
+            size: mem::size_of::<Header>() + self.capacity * mem::size_of::<Entry<K, V>>(),
+            load_factor: self.entries as f64 / self.capacity as f64,
+            displacement: vec![0; DISPLACEMENT_BUCKETS],
+            .. Default::default()
+        };
+        let mut total_displacement = 0;
+        let mut cluster = 0;
+        for pos in 0..self.capacity {
+            if !self.data[pos].is_used() {
+                cluster = 0;
+                continue
+            }
+            cluster += 1;
+            stats.max_cluster = max(stats.max_cluster, cluster);
+            let dist = self.displacement(pos);
+            stats.displacement[min(dist, DISPLACEMENT_BUCKETS - 1)] += 1;
+            stats.max_displacement = max(stats.max_displacement, dist);
+            total_displacement += dist;
+        }
+        // A failed lookup stops at a hole or at an entry that is closer to its ideal position
+        let mut miss_probes = 0;
+        for start in 0..self.capacity {
+            let mut pos = start;
+            let mut dist = 0;
+            while self.data[pos].is_used() && dist <= self.displacement(pos) {
+                pos = (pos + 1) & self.mask;
+                dist += 1;
+            }
+            miss_probes += dist + 1;
+        }
+        if self.entries > 0 {
+            stats.avg_displacement = total_displacement as f64 / self.entries as f64;
+            stats.avg_hit_probes = stats.avg_displacement + 1.0;
+        }
+        stats.avg_miss_probes = miss_probes as f64 / self.capacity as f64;
+        stats
+    }
+
+    /// Checks that all keys in the given range of positions can be found and returns the number
+    /// of used entries in this range. Ranges can be checked in parallel.
+    pub fn check_range(&self, start: usize, end: usize) -> Result<usize, IndexError> {
+        let mut entries = 0;
+        for pos in start..end {
+            let entry = &self.data[pos];
+            if !entry.is_used() {
+                continue;
+            }
+            entries += 1;
+            match self.locate(&entry.key) {
+                LocateResult::Found(p) if p == pos => true,
+                found => return Err(IndexError::WrongPosition(pos, found))
+            };
+        }
+        Ok(entries)
+    }
+
+    #[inline]
+    fn may_contain(&self, key: &K) -> bool {
+        self.filter.map(|filter| filter.may_contain(key.hash())).unwrap_or(true)
+    }
+
+    #[inline]
+    pub fn contains(&self, key: &K) -> bool {
+        if !self.may_contain(key) {
+            return false
+        }
+        match self.locate(key) {
+            LocateResult::Found(_) => true,
+            _ => false
+        }
+    }
+
+    #[inline]
+    pub fn pos(&self, key: &K) -> Option<usize> {
+        if !self.may_contain(key) {
+            return None
+        }
+        match self.locate(key) {
+            LocateResult::Found(pos) => Some(pos),
+            _ => None
+        }
+    }
+
+    #[inline]
+    pub fn get(&self, key: &K) -> Option<V> {
+        if !self.may_contain(key) {
+            return None
+        }
+        match self.locate(key) {
+            LocateResult::Found(pos) => Some(self.data[pos].data),
+            _ => None
+        }
+    }
+
+    #[inline]
+    pub fn iter(&self) -> Iter<'a, K, V> {
+        Iter(self.data)
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.entries
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.entries == 0
+    }
+
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
+
+
 /// This method is unsafe as it potentially creates references to uninitialized memory
 unsafe fn mmap_as_ref<K, V>(mmap: &MemoryMap, len: usize) -> (&'static mut Header, &'static mut [Entry<K, V>]) {
     if mmap.len() < mem::size_of::<Header>() + len * mem::size_of::<Entry<K, V>>() {
@@ -145,6 +403,85 @@ unsafe fn mmap_as_ref<K, V>(mmap: &MemoryMap, len: usize) -> (&'static mut Heade
     (header, data)
 }
 
+#[inline]
+fn u64_to_bytes(val: u64) -> [u8; 8] {
+    unsafe { mem::transmute(val) }
+}
+
+#[inline]
+fn bytes_to_u64(data: &[u8]) -> u64 {
+    assert!(data.len() >= 8);
+    unsafe { ptr::read_unaligned(data.as_ptr() as *const u64) }
+}
+
+/// FNV-1a, only used to detect journals that have not been written completely
+fn checksum(mut hash: u64, data: &[u8]) -> u64 {
+    for b in data {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+/// Makes the creation or removal of the file durable by syncing its directory
+fn sync_dir(path: &Path) -> Result<(), io::Error> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new(".")
+    };
+    File::open(dir).and_then(|dir| dir.sync_all())
+}
+
+fn write_all_at(fd: &File, mut data: &[u8], mut offset: u64) -> Result<(), io::Error> {
+    while !data.is_empty() {
+        let written = try!(fd.write_at(data, offset));
+        if written == 0 {
+            return Err(io::Error::new(io::ErrorKind::WriteZero, "failed to write whole buffer"))
+        }
+        data = &data[written..];
+        offset += written as u64;
+    }
+    Ok(())
+}
+
+
+struct JournalWriter {
+    file: BufWriter<File>,
+    checksum: u64
+}
+
+impl JournalWriter {
+    fn create(path: &Path) -> Result<Self, io::Error> {
+        Ok(JournalWriter {
+            file: BufWriter::new(try!(File::create(path))),
+            checksum: CHECKSUM_INIT
+        })
+    }
+
+    #[inline]
+    fn write(&mut self, data: &[u8]) -> Result<(), io::Error> {
+        self.checksum = checksum(self.checksum, data);
+        self.file.write_all(data)
+    }
+
+    fn finish(mut self) -> Result<(), io::Error> {
+        let checksum = u64_to_bytes(self.checksum);
+        try!(self.file.write_all(&checksum));
+        try!(self.file.flush());
+        self.file.get_ref().sync_all()
+    }
+}
+
+
+/// The index file always holds the state of the last flush. Writable indices map a working copy
+/// of it (`index.work`) shared, so the kernel can write changed pages back at any time and the
+/// index does not have to fit into memory. A flush writes all changed entries to the journal,
+/// then copies them into the index file and finally removes the journal. After a crash, a
+/// complete journal is replayed on opening, otherwise the index file is still in the state of
+/// the last flush and the working copy is created again from it.
+///
+/// Indices that are opened read-only can be used by multiple processes at the same time, the
+/// file is not changed then.
 pub struct Index<K: 'static, V: 'static> {
     capacity: usize,
     mask: usize,
@@ -152,18 +489,60 @@ pub struct Index<K: 'static, V: 'static> {
     max_entries: usize,
     min_entries: usize,
     fd: File,
+    // The mapped working copy of writable indices, read-only indices map the index file
+    work: Option<File>,
+    work_path: PathBuf,
+    journal_path: PathBuf,
+    history_path: PathBuf,
+    filter_path: PathBuf,
+    filter: Option<Filter>,
     mmap: MemoryMap,
     header: &'static mut Header,
-    data: &'static mut [Entry<K, V>]
+    data: &'static mut [Entry<K, V>],
+    // Bitmap of the positions that have been changed since the last flush
+    changed: Vec<u64>,
+    // Number of positions that have been changed since the last flush
+    changes: usize,
+    modified: bool,
+    writable: bool
 }
 
 impl<K: Key, V: Value> Index<K, V> {
+    #[inline]
     pub fn new(path: &Path, create: bool, magic: &[u8; 7], version: u8) -> Result<Self, IndexError> {
-        let fd = try!(OpenOptions::new().read(true).write(true).create(create).open(path));
+        Index::load(path, create, true, magic, version)
+    }
+
+    fn load(path: &Path, create: bool, writable: bool, magic: &[u8; 7], version: u8) -> Result<Self, IndexError> {
+        let fd = try!(OpenOptions::new().read(true).write(writable).create(create).truncate(create).open(path));
+        let work_path = path.with_extension("work");
+        let journal_path = path.with_extension("journal");
+        let history_path = path.with_extension("history");
+        let filter_path = path.with_extension("filter");
         if create {
-            try!(Self::resize_fd(&fd, INITIAL_SIZE));
+            // The new index is only valid once it has been flushed
+            if journal_path.exists() {
+                try!(fs::remove_file(&journal_path));
+            }
+        } else if writable {
+            try!(Self::recover(&fd, &journal_path));
+        } else if journal_path.exists() {
+            return Err(IndexError::PendingJournal);
         }
-        let mmap = try!(Self::map_fd(&fd));
+        if !create && try!(fd.metadata()).len() < mem::size_of::<Header>() as u64 {
+            return Err(IndexError::WrongMagic);
+        }
+        let work = if writable {
+            try!(fs::copy(path, &work_path));
+            let work = try!(OpenOptions::new().read(true).write(true).open(&work_path));
+            if create {
+                try!(work.set_len(Self::file_size(INITIAL_SIZE)));
+            }
+            Some(work)
+        } else {
+            None
+        };
+        let mmap = try!(Self::map_fd(work.as_ref().unwrap_or(&fd), writable));
         if mmap.len() < mem::size_of::<Header>() {
             return Err(IndexError::WrongMagic);
         }
@@ -185,18 +564,35 @@ impl<K: Key, V: Value> Index<K, V> {
         if header.version != version {
             return Err(IndexError::UnsupportedVersion(header.version));
         }
+        if mmap.len() as u64 != Self::file_size(header.capacity as usize) {
+            return Err(IndexError::WrongEntrySize(mem::size_of::<K>(), mem::size_of::<V>()));
+        }
         let (header, data) = unsafe { mmap_as_ref(&mmap, header.capacity as usize) };
-        let index = Index{
+        let mut index = Index{
             capacity: header.capacity as usize,
             mask: header.capacity as usize -1,
             max_entries: (header.capacity as f64 * MAX_USAGE) as usize,
             min_entries: (header.capacity as f64 * MIN_USAGE) as usize,
             entries: header.entries as usize,
             fd: fd,
+            work: work,
+            work_path: work_path,
+            journal_path: journal_path,
+            history_path: history_path,
+            filter_path: filter_path,
+            filter: None,
             mmap: mmap,
+            changed: vec![0; (header.capacity as usize + 63) / 64],
+            changes: 0,
+            modified: false,
+            writable: writable,
             data: data,
             header: header
         };
+        if create {
+            index.mark_all_changed();
+            try!(index.flush());
+        }
         debug_assert!(index.check().is_ok(), "Inconsistent after creation");
         Ok(index)
     }
@@ -208,25 +604,445 @@ impl<K: Key, V: Value> Index<K, V> {
         Index::new(path.as_ref(), false, magic, version)
     }
 
+    /// Opens the index without write access to the file, all methods that change the index will
+    /// fail. This method is unsafe for the same reasons as `open`.
+    #[inline]
+    pub unsafe fn open_readonly<P: AsRef<Path>>(path: P, magic: &[u8; 7], version: u8) -> Result<Self, IndexError> {
+        Index::load(path.as_ref(), false, false, magic, version)
+    }
+
     #[inline]
     pub fn create<P: AsRef<Path>>(path: P, magic: &[u8; 7], version: u8) -> Result<Self, IndexError> {
         Index::new(path.as_ref(), true, magic, version)
     }
 
+    /// Upgrades an index file of an older version to the given version using the migrations.
+    /// The migrated index is written to a new file that replaces the old one when it is
+    /// complete. Returns the old version if the index has been migrated.
+    ///
+    /// This method is unsafe as the converted entries are interpreted as keys and values.
+    pub unsafe fn migrate<P: AsRef<Path>>(path: P, magic: &[u8; 7], version: u8, migrations: &[Migration]) -> Result<Option<u8>, IndexError> {
+        let path = path.as_ref();
+        let mut file = BufReader::new(try!(File::open(path)));
+        let mut header = [0u8; COMMON_HEADER_SIZE];
+        try!(file.read_exact(&mut header));
+        if header[..magic.len()] != magic[..] {
+            return Err(IndexError::WrongMagic);
+        }
+        let old_version = header[magic.len()];
+        if old_version == version {
+            return Ok(None)
+        }
+        let mut chain = vec![];
+        let mut current = old_version;
+        while current != version {
+            match migrations.iter().find(|m| m.from == current) {
+                Some(migration) => {
+                    chain.push(migration);
+                    current = migration.to;
+                },
+                None => return Err(IndexError::UnsupportedVersion(old_version))
+            }
+        }
+        if path.with_extension("journal").exists() {
+            return Err(IndexError::PendingJournal)
+        }
+        let entries = bytes_to_u64(&header[8..16]) as usize;
+        let capacity = bytes_to_u64(&header[16..24]) as usize;
+        let mut rest = vec![0; chain[0].header_size.saturating_sub(COMMON_HEADER_SIZE)];
+        try!(file.read_exact(&mut rest));
+        let mut temp_name = path.file_name().unwrap_or_default().to_owned();
+        temp_name.push("-migration");
+        let temp_path = path.with_file_name(temp_name);
+        let result = Self::migrate_entries(&mut file, &temp_path, entries, capacity, magic, version, &chain);
+        for ext in &["history", "filter", "journal", "work"] {
+            let _ = fs::remove_file(temp_path.with_extension(ext));
+        }
+        if let Err(err) = result {
+            let _ = fs::remove_file(&temp_path);
+            return Err(err)
+        }
+        try!(fs::rename(&temp_path, path));
+        try!(sync_dir(path));
+        let filter_path = path.with_extension("filter");
+        if filter_path.exists() {
+            try!(fs::remove_file(&filter_path));
+        }
+        Ok(Some(old_version))
+    }
+
+    unsafe fn migrate_entries<R: Read>(file: &mut R, temp_path: &Path, entries: usize, capacity: usize, magic: &[u8; 7], version: u8, chain: &[&Migration]) -> Result<(), IndexError> {
+        let mut index = try!(Index::<K, V>::create(temp_path, magic, version));
+        let first = chain[0];
+        let mut entry = vec![0; first.key_size + first.value_size];
+        let mut count = 0;
+        for _ in 0..capacity {
+            try!(file.read_exact(&mut entry));
+            let (key, value) = entry.split_at(first.key_size);
+            if !(first.is_used)(key) {
+                continue
+            }
+            let (mut key, mut value) = (first.convert)(key, value);
+            for migration in &chain[1..] {
+                let (k, v) = (migration.convert)(&key, &value);
+                key = k;
+                value = v;
+            }
+            if key.len() != mem::size_of::<K>() || value.len() != mem::size_of::<V>() {
+                return Err(IndexError::WrongEntrySize(key.len(), value.len()))
+            }
+            let key = ptr::read_unaligned(key.as_ptr() as *const K);
+            let value = ptr::read_unaligned(value.as_ptr() as *const V);
+            try!(index.set(&key, &value));
+            count += 1;
+        }
+        if count != entries {
+            return Err(IndexError::WrongEntryCount(entries, count))
+        }
+        index.flush()
+    }
+
+    #[inline]
+    pub fn is_writable(&self) -> bool {
+        self.writable
+    }
+
+    #[inline]
+    fn check_writable(&self) -> Result<(), IndexError> {
+        if self.writable {
+            Ok(())
+        } else {
+            Err(IndexError::ReadOnly)
+        }
+    }
+
+    /// Returns a view of the index that can be used to look up keys from multiple threads
+    #[inline]
+    pub fn reader(&self) -> IndexReader<K, V> {
+        IndexReader {
+            data: &self.data[..],
+            filter: self.filter.as_ref(),
+            mask: self.mask,
+            capacity: self.capacity,
+            entries: self.entries
+        }
+    }
+
     #[inline]
-    fn map_fd(fd: &File) -> Result<MemoryMap, IndexError> {
+    fn map_fd(fd: &File, writable: bool) -> Result<MemoryMap, IndexError> {
+        let mut options = vec![
+            MapOption::MapReadable,
+            MapOption::MapFd(fd.as_raw_fd()),
+            MapOption::MapNonStandardFlags(libc::MAP_SHARED)
+        ];
+        if writable {
+            options.push(MapOption::MapWritable);
+        }
         MemoryMap::new(
             try!(fd.metadata().map_err(IndexError::Io)).len() as usize,
-            &[MapOption::MapReadable,
-            MapOption::MapWritable,
-            MapOption::MapFd(fd.as_raw_fd()),
-            MapOption::MapNonStandardFlags(0x0001) //libc::consts::os::posix88::MAP_SHARED
-        ]).map_err(IndexError::Mmap)
+            &options
+        ).map_err(IndexError::Mmap)
+    }
+
+    #[inline]
+    fn file_size(capacity: usize) -> u64 {
+        (mem::size_of::<Header>() + capacity * mem::size_of::<Entry<K, V>>()) as u64
     }
 
     #[inline]
-    fn resize_fd(fd: &File, capacity: usize) -> Result<(), IndexError> {
-        fd.set_len((mem::size_of::<Header>() + capacity * mem::size_of::<Entry<K, V>>()) as u64).map_err(IndexError::Io)
+    fn entry_offset(pos: usize) -> u64 {
+        (mem::size_of::<Header>() + pos * mem::size_of::<Entry<K, V>>()) as u64
+    }
+
+    #[inline]
+    fn header_bytes(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(&*self.header as *const Header as *const u8, mem::size_of::<Header>()) }
+    }
+
+    #[inline]
+    fn entry_bytes(&self, pos: usize) -> &[u8] {
+        unsafe { slice::from_raw_parts(&self.data[pos] as *const Entry<K, V> as *const u8, mem::size_of::<Entry<K, V>>()) }
+    }
+
+    #[inline]
+    fn mark_changed(&mut self, pos: usize) {
+        let bit = 1 << (pos % 64);
+        if self.changed[pos / 64] & bit == 0 {
+            self.changed[pos / 64] |= bit;
+            self.changes += 1;
+        }
+        self.modified = true;
+    }
+
+    /// Whether so many entries have been changed that the index should be flushed
+    #[inline]
+    pub fn needs_flush(&self) -> bool {
+        self.changes >= FLUSH_CHANGES
+    }
+
+    #[inline]
+    fn mark_all_changed(&mut self) {
+        for bits in &mut self.changed {
+            *bits = !0;
+        }
+        self.modified = true;
+    }
+
+    fn changed_positions(&self) -> Vec<usize> {
+        let mut positions = Vec::new();
+        for (i, &bits) in self.changed.iter().enumerate() {
+            if bits == 0 {
+                continue
+            }
+            for bit in 0..64 {
+                let pos = i * 64 + bit;
+                if bits & (1 << bit) != 0 && pos < self.capacity {
+                    positions.push(pos);
+                }
+            }
+        }
+        positions
+    }
+
+    /// Parses a journal and returns the header and the changed entries, incomplete journals are
+    /// rejected
+    fn parse_journal(data: &[u8]) -> Option<(&[u8], Vec<(usize, &[u8])>)> {
+        let header_size = mem::size_of::<Header>();
+        let entry_size = mem::size_of::<Entry<K, V>>();
+        if data.len() < JOURNAL_MAGIC.len() + header_size + 16 || data[..JOURNAL_MAGIC.len()] != JOURNAL_MAGIC {
+            return None
+        }
+        let (data, stored) = data.split_at(data.len() - 8);
+        if checksum(CHECKSUM_INIT, data) != bytes_to_u64(stored) {
+            return None
+        }
+        let data = &data[JOURNAL_MAGIC.len()..];
+        let (header, data) = data.split_at(header_size);
+        let count = bytes_to_u64(data) as usize;
+        let mut data = &data[8..];
+        if data.len() != count * (8 + entry_size) {
+            return None
+        }
+        let mut entries = Vec::with_capacity(count);
+        while !data.is_empty() {
+            entries.push((bytes_to_u64(data) as usize, &data[8..8+entry_size]));
+            data = &data[8+entry_size..];
+        }
+        Some((header, entries))
+    }
+
+    /// Completes a flush that has been interrupted after writing the journal. An incomplete
+    /// journal is discarded as the index file is only changed once the journal is complete, so
+    /// the index is still in the state of the last flush.
+    fn recover(fd: &File, journal_path: &Path) -> Result<(), IndexError> {
+        if !journal_path.exists() {
+            return Ok(())
+        }
+        let mut data = Vec::new();
+        try!(File::open(journal_path).and_then(|mut f| f.read_to_end(&mut data)));
+        if let Some((header, entries)) = Self::parse_journal(&data) {
+            let capacity = unsafe { (*(header.as_ptr() as *const Header)).capacity } as usize;
+            try!(Self::write_entries(fd, header, capacity, entries.into_iter()));
+        }
+        try!(fs::remove_file(journal_path));
+        try!(sync_dir(journal_path));
+        Ok(())
+    }
+
+    /// Writes the header and the entries to the index file and resizes it to the capacity
+    fn write_entries<'a, I>(fd: &File, header: &[u8], capacity: usize, entries: I) -> Result<(), IndexError> where I: Iterator<Item=(usize, &'a [u8])> {
+        try!(fd.set_len(Self::file_size(capacity)));
+        try!(write_all_at(fd, header, 0));
+        for (pos, entry) in entries {
+            try!(write_all_at(fd, entry, Self::entry_offset(pos)));
+        }
+        try!(fd.sync_all());
+        Ok(())
+    }
+
+    /// Writes the header and the changed entries to the journal, once the journal is complete,
+    /// the changes can be replayed after a crash
+    fn write_journal(&self, positions: &[usize]) -> Result<(), IndexError> {
+        let mut journal = try!(JournalWriter::create(&self.journal_path));
+        try!(journal.write(&JOURNAL_MAGIC));
+        try!(journal.write(self.header_bytes()));
+        try!(journal.write(&u64_to_bytes(positions.len() as u64)));
+        for &pos in positions {
+            try!(journal.write(&u64_to_bytes(pos as u64)));
+            try!(journal.write(self.entry_bytes(pos)));
+        }
+        try!(journal.finish());
+        try!(sync_dir(&self.journal_path));
+        Ok(())
+    }
+
+    /// Writes all changes since the last flush to the index file
+    pub fn flush(&mut self) -> Result<(), IndexError> {
+        if !self.modified {
+            return Ok(())
+        }
+        try!(self.check_writable());
+        // The filter file is only valid for the flushed state, it is removed until the new
+        // state has been written
+        if self.filter_path.exists() {
+            try!(fs::remove_file(&self.filter_path));
+        }
+        let positions = self.changed_positions();
+        try!(self.write_journal(&positions));
+        let entries = positions.iter().map(|&pos| (pos, self.entry_bytes(pos)));
+        try!(Self::write_entries(&self.fd, self.header_bytes(), self.capacity, entries));
+        try!(fs::remove_file(&self.journal_path));
+        try!(sync_dir(&self.journal_path));
+        if let Some(ref work) = self.work {
+            try!(work.set_len(Self::file_size(self.capacity)));
+        }
+        for bits in &mut self.changed {
+            *bits = 0;
+        }
+        self.changes = 0;
+        self.modified = false;
+        // The history is only used for statistics, so failing to write it is not an error
+        self.append_history().ok();
+        // A missing filter file is rebuilt from the index
+        if let Some(ref filter) = self.filter {
+            filter.save(&self.filter_path, self.entries, self.capacity).ok();
+        }
+        Ok(())
+    }
+
+    /// Puts a bloom filter in front of the index that answers most lookups of missing keys
+    /// without reading the index. The filter is loaded from its file if it belongs to the
+    /// current index, otherwise it is rebuilt from the index.
+    pub fn enable_filter(&mut self, fp_rate: f64, max_memory: usize) {
+        if let Some(ref filter) = self.filter {
+            if filter.matches_config(fp_rate, max_memory) {
+                return
+            }
+        }
+        let filter = if self.modified {
+            None
+        } else {
+            Filter::load(&self.filter_path, fp_rate, max_memory, self.entries, self.capacity)
+        };
+        if filter.is_some() {
+            self.filter = filter;
+        } else {
+            self.rebuild_filter(fp_rate, max_memory);
+        }
+    }
+
+    /// Removes the filter, its file is removed when the index is writable
+    pub fn disable_filter(&mut self) -> Result<(), IndexError> {
+        self.filter = None;
+        if self.writable && self.filter_path.exists() {
+            try!(fs::remove_file(&self.filter_path));
+        }
+        Ok(())
+    }
+
+    fn rebuild_filter(&mut self, fp_rate: f64, max_memory: usize) {
+        let mut filter = Filter::new(max(2 * self.entries, INITIAL_SIZE), fp_rate, max_memory);
+        for (key, _) in self.iter() {
+            filter.insert(key.hash());
+        }
+        if self.writable && !self.modified {
+            filter.save(&self.filter_path, self.entries, self.capacity).ok();
+        }
+        self.filter = Some(filter);
+    }
+
+    #[inline]
+    fn may_contain(&self, key: &K) -> bool {
+        self.filter.as_ref().map(|filter| filter.may_contain(key.hash())).unwrap_or(true)
+    }
+
+    fn append_history(&self) -> Result<(), io::Error> {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let mut data = Vec::with_capacity(HISTORY_ENTRY_SIZE);
+        data.extend_from_slice(&u64_to_bytes(timestamp));
+        data.extend_from_slice(&u64_to_bytes(self.entries as u64));
+        data.extend_from_slice(&u64_to_bytes(self.capacity as u64));
+        let size = fs::metadata(&self.history_path).map(|m| m.len() as usize).unwrap_or(0);
+        if size + HISTORY_ENTRY_SIZE > MAX_HISTORY_ENTRIES * HISTORY_ENTRY_SIZE {
+            return self.rotate_history(&data)
+        }
+        let mut file = try!(OpenOptions::new().append(true).create(true).open(&self.history_path));
+        file.write_all(&data)
+    }
+
+    /// Rewrites the history file with the newest half of the entries and the given entry
+    fn rotate_history(&self, entry: &[u8]) -> Result<(), io::Error> {
+        let mut data = Vec::new();
+        try!(File::open(&self.history_path).and_then(|mut f| f.read_to_end(&mut data)));
+        let complete = data.len() - data.len() % HISTORY_ENTRY_SIZE;
+        data.truncate(complete);
+        data.extend_from_slice(entry);
+        let start = data.len().saturating_sub(MAX_HISTORY_ENTRIES / 2 * HISTORY_ENTRY_SIZE);
+        let temp_path = self.history_path.with_extension("history-new");
+        try!(File::create(&temp_path).and_then(|mut f| f.write_all(&data[start..])));
+        fs::rename(&temp_path, &self.history_path)
+    }
+
+    /// Returns the number of entries and the capacity at every flush, oldest first
+    pub fn history(&self) -> Result<Vec<IndexHistoryEntry>, IndexError> {
+        let mut history = Vec::new();
+        if !self.history_path.exists() {
+            return Ok(history)
+        }
+        let mut data = Vec::new();
+        try!(File::open(&self.history_path).and_then(|mut f| f.read_to_end(&mut data)));
+        for record in data.chunks(HISTORY_ENTRY_SIZE) {
+            if record.len() < HISTORY_ENTRY_SIZE {
+                break
+            }
+            history.push(IndexHistoryEntry {
+                timestamp: bytes_to_u64(record) as i64,
+                entries: bytes_to_u64(&record[8..]) as usize,
+                capacity: bytes_to_u64(&record[16..]) as usize
+            });
+        }
+        Ok(history)
+    }
+
+    /// Returns the number of bytes of the index that are currently held in memory
+    pub fn resident_size(&self) -> Option<usize> {
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+        if page_size <= 0 {
+            return None
+        }
+        let page_size = page_size as usize;
+        let size = self.size();
+        let mut pages = vec![0u8; (size + page_size - 1) / page_size];
+        if unsafe { libc::mincore(self.mmap.data() as *mut libc::c_void, size, pages.as_mut_ptr()) } != 0 {
+            return None
+        }
+        Some(min(pages.iter().filter(|&&page| page & 1 != 0).count() * page_size, size))
+    }
+
+    pub fn stats(&self) -> IndexStats {
+        let mut stats = self.reader().stats();
+        stats.resident_size = self.resident_size();
+        stats.filter_size = self.filter.as_ref().map(|filter| filter.size());
+        stats.filter_fp_rate = self.filter.as_ref().map(|filter| filter.current_fp_rate());
+        stats
+    }
+
+    /// Maps the working copy again with room for the given capacity. As the mapping is shared,
+    /// the new mapping already contains all changes.
+    fn remap(&mut self, capacity: usize) -> Result<(), IndexError> {
+        let mmap = {
+            let work = try!(self.work.as_ref().ok_or(IndexError::ReadOnly));
+            if try!(work.metadata()).len() < Self::file_size(capacity) {
+                try!(work.set_len(Self::file_size(capacity)));
+            }
+            try!(Self::map_fd(work, self.writable))
+        };
+        let (header, data) = unsafe { mmap_as_ref(&mmap, capacity) };
+        self.mmap = mmap;
+        self.header = header;
+        self.data = data;
+        self.changed.resize((capacity + 63) / 64, 0);
+        Ok(())
     }
 
     #[inline]
@@ -251,6 +1067,7 @@ impl<K: Key, V: Value> Index<K, V> {
                 data = entry.data;
                 entry.clear();
             }
+            self.mark_changed(pos);
             self.entries -= 1;
             try!(self.set(&key, &data));
         }
@@ -265,11 +1082,9 @@ impl<K: Key, V: Value> Index<K, V> {
         let new_capacity = self.capacity / 2;
         self.set_capacity(new_capacity);
         try!(self.reinsert(new_capacity, old_capacity));
-        try!(Self::resize_fd(&self.fd, new_capacity));
-        self.mmap = try!(Self::map_fd(&self.fd));
-        let (header, data) = unsafe { mmap_as_ref(&self.mmap, new_capacity) };
-        self.header = header;
-        self.data = data;
+        // The file is truncated on the next flush
+        let data = mem::replace(&mut self.data, &mut []);
+        self.data = &mut data[..new_capacity];
         assert_eq!(self.data.len(), self.capacity);
         Ok(true)
     }
@@ -278,16 +1093,16 @@ impl<K: Key, V: Value> Index<K, V> {
         if self.entries <= self.max_entries {
             return Ok(false)
         }
+        let old_capacity = self.capacity;
         let new_capacity = 2 * self.capacity;
-        try!(Self::resize_fd(&self.fd, new_capacity));
-        self.mmap = try!(Self::map_fd(&self.fd));
-        let (header, data) = unsafe { mmap_as_ref(&self.mmap, new_capacity) };
+        try!(self.remap(new_capacity));
         // Initialize upper half of data without dropping the uninitialized data in it
-        for d in &mut data[self.capacity..] {
+        for d in &mut self.data[old_capacity..] {
             unsafe { ptr::write(d, Entry::default()) }
         }
-        self.header = header;
-        self.data = data;
+        for pos in old_capacity..new_capacity {
+            self.mark_changed(pos);
+        }
         self.set_capacity(new_capacity);
         assert_eq!(self.data.len(), self.capacity);
         try!(self.reinsert(0, new_capacity));
@@ -295,18 +1110,7 @@ impl<K: Key, V: Value> Index<K, V> {
     }
 
     pub fn check(&self) -> Result<(), IndexError> {
-        let mut entries = 0;
-        for pos in 0..self.capacity {
-            let entry = &self.data[pos];
-            if !entry.is_used() {
-                continue;
-            }
-            entries += 1;
-            match self.locate(&entry.key) {
-                LocateResult::Found(p) if p == pos => true,
-                found => return Err(IndexError::WrongPosition(pos, found))
-            };
-        }
+        let entries = try!(self.reader().check_range(0, self.capacity));
         if entries != self.entries {
             return Err(IndexError::WrongEntryCount(self.entries, entries));
         }
@@ -333,29 +1137,15 @@ impl<K: Key, V: Value> Index<K, V> {
     fn write_header(&mut self) {
         self.header.entries = self.entries as u64;
         self.header.capacity = self.capacity as u64;
+        self.modified = true;
     }
 
     /// Finds the position for this key
     /// If the key is in the table, it will be the position of the key,
     /// otherwise it will be the position where this key should be inserted
+    #[inline]
     fn locate(&self, key: &K) -> LocateResult {
-        let mut pos = key.hash() as usize & self.mask;
-        let mut dist = 0;
-        loop {
-            let entry = &self.data[pos];
-            if !entry.is_used() {
-                return LocateResult::Hole(pos);
-            }
-            if entry.key == *key {
-                return LocateResult::Found(pos);
-            }
-            let odist = (pos + self.capacity - (entry.key.hash() as usize & self.mask)) & self.mask;
-            if dist > odist {
-                return LocateResult::Steal(pos);
-            }
-            pos = (pos + 1) & self.mask;
-            dist += 1;
-        }
+        self.reader().locate(key)
     }
 
     /// Shifts all following entries towards the left if they can get closer to their ideal position.
@@ -378,17 +1168,32 @@ impl<K: Key, V: Value> Index<K, V> {
                 }
             }
             self.data[last_pos] = self.data[pos].clone();
+            self.mark_changed(last_pos);
         }
         self.data[last_pos].clear();
+        self.mark_changed(last_pos);
     }
 
     /// Adds the key, data pair into the table.
     /// If the key existed the old data is returned.
     pub fn set(&mut self, key: &K, data: &V) -> Result<Option<V>, IndexError> {
+        try!(self.check_writable());
+        let rebuild_filter = match self.filter {
+            Some(ref filter) if self.entries >= filter.capacity() => Some((filter.fp_rate(), filter.max_memory())),
+            _ => None
+        };
+        if let Some((fp_rate, max_memory)) = rebuild_filter {
+            // The filter has become too small, so it is rebuilt with twice the size
+            self.rebuild_filter(fp_rate, max_memory);
+        }
+        if let Some(ref mut filter) = self.filter {
+            filter.insert(key.hash());
+        }
         match self.locate(key) {
             LocateResult::Found(pos) => {
                 let mut old = *data;
                 mem::swap(&mut old, &mut self.data[pos].data);
+                self.mark_changed(pos);
                 Ok(Some(old))
             },
             LocateResult::Hole(pos) => {
@@ -397,6 +1202,7 @@ impl<K: Key, V: Value> Index<K, V> {
                     entry.key = *key;
                     entry.data = *data;
                 }
+                self.mark_changed(pos);
                 try!(self.increase_count());
                 Ok(None)
             },
@@ -411,8 +1217,10 @@ impl<K: Key, V: Value> Index<K, V> {
                     entry.key = *key;
                     entry.data = *data;
                 }
+                self.mark_changed(pos);
                 loop {
                     cur_pos = (cur_pos + 1) & self.mask;
+                    self.mark_changed(cur_pos);
                     let entry = &mut self.data[cur_pos];
                     if entry.is_used() {
                         mem::swap(&mut stolen_key, &mut entry.key);
@@ -432,6 +1240,9 @@ impl<K: Key, V: Value> Index<K, V> {
     #[inline]
     pub fn contains(&self, key: &K) -> bool {
         debug_assert!(self.check().is_ok(), "Inconsistent before get");
+        if !self.may_contain(key) {
+            return false
+        }
         match self.locate(key) {
             LocateResult::Found(_) => true,
             _ => false
@@ -441,6 +1252,9 @@ impl<K: Key, V: Value> Index<K, V> {
     #[inline]
     pub fn pos(&self, key: &K) -> Option<usize> {
         debug_assert!(self.check().is_ok(), "Inconsistent before get");
+        if !self.may_contain(key) {
+            return None
+        }
         match self.locate(key) {
             LocateResult::Found(pos) => Some(pos),
             _ => None
@@ -450,6 +1264,9 @@ impl<K: Key, V: Value> Index<K, V> {
     #[inline]
     pub fn get(&self, key: &K) -> Option<V> {
         debug_assert!(self.check().is_ok(), "Inconsistent before get");
+        if !self.may_contain(key) {
+            return None
+        }
         match self.locate(key) {
             LocateResult::Found(pos) => Some(self.data[pos].data),
             _ => None
@@ -457,22 +1274,25 @@ impl<K: Key, V: Value> Index<K, V> {
     }
 
     #[inline]
-    pub fn modify<F>(&mut self, key: &K, mut f: F) -> bool where F: FnMut(&mut V) {
+    pub fn modify<F>(&mut self, key: &K, mut f: F) -> Result<bool, IndexError> where F: FnMut(&mut V) {
+        try!(self.check_writable());
         debug_assert!(self.check().is_ok(), "Inconsistent before get");
         match self.locate(key) {
             LocateResult::Found(pos) => {
-                f(&mut self.data[pos].data);
-                true
+                        f(&mut self.data[pos].data);
+                self.mark_changed(pos);
+                Ok(true)
             },
-            _ => false
+            _ => Ok(false)
         }
     }
 
     #[inline]
     pub fn delete(&mut self, key: &K) -> Result<bool, IndexError> {
+        try!(self.check_writable());
         match self.locate(key) {
             LocateResult::Found(pos) => {
-                self.backshift(pos);
+                        self.backshift(pos);
                 try!(self.decrease_count());
                 Ok(true)
             },
@@ -482,6 +1302,7 @@ impl<K: Key, V: Value> Index<K, V> {
 
     pub fn filter<F>(&mut self, mut f: F) -> Result<usize, IndexError> where F: FnMut(&K, &V) -> bool {
         //TODO: is it faster to walk in reverse direction?
+        try!(self.check_writable());
         let mut deleted = 0;
         let mut pos = 0;
         while pos < self.capacity {
@@ -507,8 +1328,15 @@ impl<K: Key, V: Value> Index<K, V> {
     }
 
     #[inline]
-    pub fn iter_mut(&mut self) -> IterMut<K, V> {
-        IterMut(self.data)
+    pub fn iter_mut(&mut self) -> Result<IterMut<K, V>, IndexError> {
+        try!(self.check_writable());
+        // The iterator only marks the positions it returns, the counter is not updated by it
+        self.modified = true;
+        Ok(IterMut {
+            data: &mut self.data[..],
+            changed: &mut self.changed[..],
+            pos: 0
+        })
     }
 
     #[inline]
@@ -518,7 +1346,7 @@ impl<K: Key, V: Value> Index<K, V> {
 
     #[inline]
     pub fn size(&self) -> usize {
-        self.mmap.len()
+        Self::file_size(self.capacity) as usize
     }
 
     #[inline]
@@ -531,11 +1359,231 @@ impl<K: Key, V: Value> Index<K, V> {
         self.capacity
     }
 
-    #[inline]
-    pub fn clear(&mut self) {
-        for entry in &mut self.data[..] {
-            entry.clear();
+    pub fn clear(&mut self) -> Result<(), IndexError> {
+        try!(self.check_writable());
+        for pos in 0..self.capacity {
+            if self.data[pos].is_used() {
+                self.data[pos].clear();
+                self.mark_changed(pos);
+            }
         }
         self.entries = 0;
+        self.write_header();
+        if let Some(ref mut filter) = self.filter {
+            filter.clear();
+        }
+        Ok(())
+    }
+}
+
+impl<K: 'static, V: 'static> Drop for Index<K, V> {
+    fn drop(&mut self) {
+        // Unflushed changes are lost anyway, the working copy is created again on opening
+        if self.work.is_some() {
+            fs::remove_file(&self.work_path).ok();
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_util::temp_dir;
+
+    const MAGIC: [u8; 7] = *b"zvtest\x01";
+
+    #[repr(packed)]
+    #[derive(Clone, Copy, PartialEq, Eq, Default)]
+    struct TestKey(u64);
+
+    impl Key for TestKey {
+        fn hash(&self) -> u64 {
+            self.0.wrapping_mul(0x9e37_79b9_7f4a_7c15)
+        }
+
+        fn is_used(&self) -> bool {
+            self.0 != 0
+        }
+
+        fn clear(&mut self) {
+            self.0 = 0;
+        }
+    }
+
+    impl Value for u64 {}
+
+    fn temp_path(name: &str) -> PathBuf {
+        temp_dir(&format!("index-{}", name)).join("index")
+    }
+
+    fn fill(index: &mut Index<TestKey, u64>, keys: ::std::ops::Range<u64>) {
+        for i in keys {
+            index.set(&TestKey(i), &(i * 3)).unwrap();
+        }
+    }
+
+    fn open(path: &Path) -> Result<Index<TestKey, u64>, IndexError> {
+        unsafe { Index::open(path, &MAGIC, 1) }
+    }
+
+    fn assert_contents(index: &Index<TestKey, u64>, keys: ::std::ops::Range<u64>) {
+        assert!(index.check().is_ok());
+        assert_eq!(index.len(), (keys.end - keys.start) as usize);
+        for i in keys {
+            assert_eq!(index.get(&TestKey(i)), Some(i * 3));
+        }
+    }
+
+    #[test]
+    fn test_flushed_changes_survive() {
+        let path = temp_path("flushed");
+        {
+            let mut index = Index::create(&path, &MAGIC, 1).unwrap();
+            fill(&mut index, 1..5000);
+            index.flush().unwrap();
+        }
+        assert!(!path.with_extension("journal").exists());
+        let index = open(&path).unwrap();
+        assert_contents(&index, 1..5000);
+    }
+
+    #[test]
+    fn test_unflushed_changes_are_rolled_back() {
+        let path = temp_path("unflushed");
+        {
+            let mut index = Index::create(&path, &MAGIC, 1).unwrap();
+            fill(&mut index, 1..100);
+            index.flush().unwrap();
+            // The process crashes without flushing, some pages of the working copy might have
+            // been written back and the index has grown in the meantime
+            fill(&mut index, 100..5000);
+            for i in 1..50 {
+                index.delete(&TestKey(i)).unwrap();
+            }
+            mem::forget(index);
+        }
+        assert!(path.with_extension("work").exists());
+        assert!(!path.with_extension("journal").exists());
+        assert_contents(&unsafe { Index::<TestKey, u64>::open_readonly(&path, &MAGIC, 1) }.unwrap(), 1..100);
+        let mut index = open(&path).unwrap();
+        assert_contents(&index, 1..100);
+        assert_eq!(index.capacity(), INITIAL_SIZE);
+        fill(&mut index, 100..200);
+        index.flush().unwrap();
+        drop(index);
+        assert!(!path.with_extension("work").exists());
+        assert_contents(&open(&path).unwrap(), 1..200);
+    }
+
+    #[test]
+    fn test_complete_journal_is_replayed() {
+        let path = temp_path("replay");
+        let pos;
+        {
+            let mut index = Index::create(&path, &MAGIC, 1).unwrap();
+            fill(&mut index, 1..1000);
+            index.flush().unwrap();
+            fill(&mut index, 1000..5000);
+            for i in 1..500 {
+                index.delete(&TestKey(i)).unwrap();
+            }
+            // The process ends after writing the journal but before the index file is written
+            index.write_journal(&index.changed_positions()).unwrap();
+            pos = index.pos(&TestKey(4000)).unwrap();
+        }
+        {
+            // Damage the changed part of the index file, the journal has to overwrite it
+            let fd = OpenOptions::new().write(true).open(&path).unwrap();
+            write_all_at(&fd, &[0xff; 16], Index::<TestKey, u64>::entry_offset(pos)).unwrap();
+            write_all_at(&fd, &[0; 16], 8).unwrap();
+        }
+        let index = open(&path).unwrap();
+        assert!(!path.with_extension("journal").exists());
+        assert_contents(&index, 500..5000);
+    }
+
+    #[test]
+    fn test_truncated_journal_is_discarded() {
+        let path = temp_path("truncated");
+        {
+            let mut index = Index::create(&path, &MAGIC, 1).unwrap();
+            fill(&mut index, 1..1000);
+            index.flush().unwrap();
+            fill(&mut index, 1000..2000);
+            index.write_journal(&index.changed_positions()).unwrap();
+        }
+        let journal = path.with_extension("journal");
+        let len = fs::metadata(&journal).unwrap().len();
+        OpenOptions::new().write(true).open(&journal).unwrap().set_len(len - 1).unwrap();
+        // The index file has not been changed yet, so it is still in the flushed state
+        let index = open(&path).unwrap();
+        assert!(!journal.exists());
+        assert_contents(&index, 1..1000);
+    }
+
+    #[test]
+    fn test_readonly_index_is_not_changed() {
+        let path = temp_path("readonly");
+        {
+            let mut index = Index::create(&path, &MAGIC, 1).unwrap();
+            fill(&mut index, 1..100);
+            index.flush().unwrap();
+        }
+        let mut index = unsafe { Index::<TestKey, u64>::open_readonly(&path, &MAGIC, 1) }.unwrap();
+        assert!(index.set(&TestKey(200), &1).is_err());
+        assert!(index.delete(&TestKey(1)).is_err());
+        assert!(index.modify(&TestKey(1), |value| *value = 0).is_err());
+        assert!(index.iter_mut().is_err());
+        assert!(index.clear().is_err());
+        assert!(!path.with_extension("journal").exists());
+        assert_eq!(index.reader().get(&TestKey(1)), Some(3));
+        assert_contents(&index, 1..100);
+    }
+
+    #[test]
+    fn test_history_is_capped() {
+        let path = temp_path("history");
+        let mut index = Index::<TestKey, u64>::create(&path, &MAGIC, 1).unwrap();
+        let mut data = Vec::new();
+        for i in 0..MAX_HISTORY_ENTRIES as u64 {
+            data.extend_from_slice(&u64_to_bytes(i));
+            data.extend_from_slice(&u64_to_bytes(i));
+            data.extend_from_slice(&u64_to_bytes(INITIAL_SIZE as u64));
+        }
+        File::create(path.with_extension("history")).unwrap().write_all(&data).unwrap();
+        fill(&mut index, 1..10);
+        index.flush().unwrap();
+        let history = index.history().unwrap();
+        assert_eq!(history.len(), MAX_HISTORY_ENTRIES / 2);
+        assert_eq!(history[0].entries, MAX_HISTORY_ENTRIES / 2 + 1);
+        assert_eq!(history.last().unwrap().entries, 9);
+        fill(&mut index, 10..20);
+        index.flush().unwrap();
+        assert_eq!(index.history().unwrap().len(), MAX_HISTORY_ENTRIES / 2 + 1);
+    }
+
+    #[test]
+    fn test_iter_mut_and_clear_are_flushed() {
+        let path = temp_path("iter-mut");
+        {
+            let mut index = Index::create(&path, &MAGIC, 1).unwrap();
+            fill(&mut index, 1..3000);
+            index.flush().unwrap();
+            for (_key, value) in index.iter_mut().unwrap() {
+                *value /= 3;
+            }
+            index.flush().unwrap();
+        }
+        {
+            let mut index = open(&path).unwrap();
+            assert!(index.check().is_ok());
+            for i in 1..3000 {
+                assert_eq!(index.get(&TestKey(i)), Some(i));
+            }
+            index.clear().unwrap();
+            index.flush().unwrap();
+        }
+        assert_contents(&open(&path).unwrap(), 1..1);
     }
 }
@@ -0,0 +1,13 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+
+
+/// Creates an empty directory for a test, the process id keeps parallel test runs apart
+pub fn temp_dir(name: &str) -> PathBuf {
+    let path = env::temp_dir().join(format!("zvault-test-{}-{}", name, process::id()));
+    let _ = fs::remove_dir_all(&path);
+    fs::create_dir_all(&path).unwrap();
+    path
+}
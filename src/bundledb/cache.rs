@@ -82,8 +82,17 @@ impl StoredBundle {
         Ok(())
     }
 
-    pub fn read_list_from<P: AsRef<Path>>(path: P) -> Result<Vec<Self>, BundleCacheError> {
-        let path = path.as_ref();
+    /// Decodes the bundle list of the given version, older versions are converted to the
+    /// current one here
+    fn decode_list_version(version: u8, file: &mut Read) -> Result<Vec<Self>, BundleCacheError> {
+        if version == CACHE_FILE_VERSION {
+            Ok(try!(msgpack::decode_from_stream(file)))
+        } else {
+            Err(BundleCacheError::UnsupportedVersion(version))
+        }
+    }
+
+    fn read_list_version_from(path: &Path) -> Result<(Vec<Self>, u8), BundleCacheError> {
         let mut file = BufReader::new(try!(File::open(path).map_err(BundleCacheError::Read)));
         let mut header = [0u8; 8];
         try!(file.read_exact(&mut header).map_err(BundleCacheError::Read));
@@ -91,10 +100,26 @@ impl StoredBundle {
             return Err(BundleCacheError::WrongHeader)
         }
         let version = header[CACHE_FILE_STRING.len()];
-        if version != CACHE_FILE_VERSION {
-            return Err(BundleCacheError::UnsupportedVersion(version))
+        Ok((try!(Self::decode_list_version(version, &mut file)), version))
+    }
+
+    /// Reads the bundle list, lists of older versions are migrated in memory and stored in the
+    /// current version when the cache is saved
+    #[inline]
+    pub fn read_list_from<P: AsRef<Path>>(path: P) -> Result<Vec<Self>, BundleCacheError> {
+        Self::read_list_version_from(path.as_ref()).map(|(list, _)| list)
+    }
+
+    /// Upgrades the cache file to the current version, returns the old version if it has been
+    /// migrated
+    pub fn migrate_list<P: AsRef<Path>>(path: P) -> Result<Option<u8>, BundleCacheError> {
+        let path = path.as_ref();
+        let (list, version) = try!(Self::read_list_version_from(path));
+        if version == CACHE_FILE_VERSION {
+            return Ok(None)
         }
-        Ok(try!(msgpack::decode_from_stream(&mut file)))
+        try!(Self::save_list_to(&list, path));
+        Ok(Some(version))
     }
 
     pub fn save_list_to<P: AsRef<Path>>(list: &[Self], path: P) -> Result<(), BundleCacheError> {
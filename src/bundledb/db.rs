@@ -1,5 +1,6 @@
 use ::prelude::*;
 use super::*;
+use super::parity::{ParityGroup, ParityWriter, ParityError};
 
 use std::path::{Path, PathBuf};
 use std::collections::{HashMap, HashSet};
@@ -53,6 +54,16 @@ quick_error!{
             description("Failed to remove bundle")
             display("Bundle db error: failed to remove bundle {}\n\tcaused by: {}", bundle, err)
         }
+        Parity(err: ParityError) {
+            from()
+            cause(err)
+            description("Failed to read/write parity data")
+            display("Bundle db error: failed to read/write parity data\n\tcaused by: {}", err)
+        }
+        DamagedParity(path: PathBuf) {
+            description("Damaged parity data")
+            display("Bundle db error: parity data in {:?} is damaged", path)
+        }
     }
 }
 
@@ -105,6 +116,19 @@ fn load_bundles(path: &Path, base: &Path, bundles: &mut HashMap<BundleId, Stored
     Ok((new, gone))
 }
 
+fn list_parity_files(path: &Path) -> Result<Vec<PathBuf>, BundleDbError> {
+    let mut files = vec![];
+    if !path.exists() {
+        return Ok(files)
+    }
+    for entry in try!(fs::read_dir(path).map_err(BundleDbError::ListBundles)) {
+        let path = try!(entry.map_err(BundleDbError::ListBundles)).path();
+        if path.extension() == Some("parity".as_ref()) {
+            files.push(path);
+        }
+    }
+    Ok(files)
+}
 
 
 pub struct BundleDb {
@@ -113,16 +137,23 @@ pub struct BundleDb {
     crypto: Arc<Mutex<Crypto>>,
     local_bundles: HashMap<BundleId, StoredBundle>,
     remote_bundles: HashMap<BundleId, StoredBundle>,
-    bundle_cache: LruCache<BundleId, (BundleReader, Vec<u8>)>
+    bundle_cache: LruCache<BundleId, (BundleReader, Option<Vec<u8>>)>,
+    redundancy: Option<Redundancy>,
+    parity: Option<ParityWriter>,
+    // Parity file of every bundle that is member of a parity group, loaded on first use
+    parity_groups: Option<HashMap<PathBuf, PathBuf>>
 }
 
 
 impl BundleDb {
-    fn new(layout: RepositoryLayout, crypto: Arc<Mutex<Crypto>>) -> Self {
+    fn new(layout: RepositoryLayout, crypto: Arc<Mutex<Crypto>>, redundancy: Option<Redundancy>) -> Self {
         BundleDb {
             layout: layout,
             crypto: crypto,
             uploader: None,
+            redundancy: redundancy,
+            parity: None,
+            parity_groups: None,
             local_bundles: HashMap::new(),
             remote_bundles: HashMap::new(),
             bundle_cache: LruCache::new(5, 10)
@@ -159,9 +190,19 @@ impl BundleDb {
     }
 
     pub fn flush(&mut self) -> Result<(), BundleDbError> {
+        try!(self.finish_parity());
         self.finish_uploads().and_then(|()| self.save_cache())
     }
 
+    #[inline]
+    pub fn set_redundancy(&mut self, redundancy: Option<Redundancy>) -> Result<(), BundleDbError> {
+        if self.redundancy != redundancy {
+            try!(self.finish_parity());
+            self.redundancy = redundancy;
+        }
+        Ok(())
+    }
+
     fn save_cache(&self) -> Result<(), BundleDbError> {
         let bundles: Vec<_> = self.local_bundles.values().cloned().collect();
         try!(StoredBundle::save_list_to(&bundles, &self.layout.local_bundle_cache_path()));
@@ -198,8 +239,8 @@ impl BundleDb {
         Ok(())
     }
 
-    pub fn open(layout: RepositoryLayout, crypto: Arc<Mutex<Crypto>>) -> Result<(Self, Vec<BundleInfo>, Vec<BundleInfo>), BundleDbError> {
-        let mut self_ = Self::new(layout, crypto);
+    pub fn open(layout: RepositoryLayout, crypto: Arc<Mutex<Crypto>>, redundancy: Option<Redundancy>) -> Result<(Self, Vec<BundleInfo>, Vec<BundleInfo>), BundleDbError> {
+        let mut self_ = Self::new(layout, crypto, redundancy);
         let (new, gone) = try!(self_.load_bundle_list());
         try!(self_.update_cache());
         let new = new.into_iter().map(|s| s.info).collect();
@@ -211,6 +252,7 @@ impl BundleDb {
         try!(fs::create_dir_all(layout.remote_bundles_path()).context(&layout.remote_bundles_path() as &Path));
         try!(fs::create_dir_all(layout.local_bundles_path()).context(&layout.local_bundles_path() as &Path));
         try!(fs::create_dir_all(layout.temp_bundles_path()).context(&layout.temp_bundles_path() as &Path));
+        try!(fs::create_dir_all(layout.remote_parity_path()).context(&layout.remote_parity_path() as &Path));
         try!(StoredBundle::save_list_to(&[], layout.local_bundle_cache_path()));
         try!(StoredBundle::save_list_to(&[], layout.remote_bundle_cache_path()));
         Ok(())
@@ -235,19 +277,30 @@ impl BundleDb {
         Ok(try!(BundleReader::load(base_path.join(&stored.path), self.crypto.clone())))
     }
 
+    /// Bundles that can be decrypted partially are cached without their contents, only the
+    /// blocks of the requested chunks are decrypted then
     pub fn get_chunk(&mut self, bundle_id: &BundleId, id: usize) -> Result<Vec<u8>, BundleDbError> {
         if let Some(&mut (ref mut bundle, ref data)) = self.bundle_cache.get_mut(bundle_id) {
             let (pos, len) = try!(bundle.get_chunk_position(id));
-            let mut chunk = Vec::with_capacity(len);
-            chunk.extend_from_slice(&data[pos..pos+len]);
-            return Ok(chunk);
+            if let Some(ref data) = *data {
+                let mut chunk = Vec::with_capacity(len);
+                chunk.extend_from_slice(&data[pos..pos+len]);
+                return Ok(chunk);
+            }
+            if let Some(chunk) = try!(bundle.load_range(pos, len)) {
+                return Ok(chunk);
+            }
         }
         let mut bundle = try!(self.get_stored_bundle(bundle_id).and_then(|s| self.get_bundle(s)));
         let (pos, len) = try!(bundle.get_chunk_position(id));
+        if let Some(chunk) = try!(bundle.load_range(pos, len)) {
+            self.bundle_cache.put(bundle_id.clone(), (bundle, None));
+            return Ok(chunk);
+        }
         let mut chunk = Vec::with_capacity(len);
         let data = try!(bundle.load_contents());
         chunk.extend_from_slice(&data[pos..pos+len]);
-        self.bundle_cache.put(bundle_id.clone(), (bundle, data));
+        self.bundle_cache.put(bundle_id.clone(), (bundle, Some(data)));
         Ok(chunk)
     }
 
@@ -269,14 +322,47 @@ impl BundleDb {
         let dst_path = folder.join(filename);
         let src_path = self.layout.base_path().join(bundle.path);
         bundle.path = dst_path.strip_prefix(self.layout.base_path()).unwrap().to_path_buf();
-        if self.uploader.is_none() {
-            self.uploader = Some(BundleUploader::new(5));
+        if let Some(redundancy) = self.redundancy {
+            if self.parity.is_none() {
+                let scratch_path = self.layout.temp_parity_path().with_extension("shards");
+                self.parity = Some(try!(ParityWriter::new(redundancy, scratch_path)));
+            }
+            try!(self.parity.as_mut().unwrap().add(bundle.path.clone(), &src_path));
         }
-        try!(self.uploader.as_ref().unwrap().queue(src_path, dst_path));
+        try!(self.queue_upload(src_path, dst_path));
         self.remote_bundles.insert(bundle.id(), bundle.clone());
+        if self.parity.as_ref().map_or(false, |p| p.is_full()) {
+            try!(self.finish_parity());
+        }
         Ok(bundle.info)
     }
 
+    fn queue_upload(&mut self, src_path: PathBuf, dst_path: PathBuf) -> Result<(), BundleDbError> {
+        if self.uploader.is_none() {
+            self.uploader = Some(BundleUploader::new(5));
+        }
+        self.uploader.as_ref().unwrap().queue(src_path, dst_path)
+    }
+
+    fn finish_parity(&mut self) -> Result<(), BundleDbError> {
+        let mut parity = None;
+        mem::swap(&mut self.parity, &mut parity);
+        if let Some(parity) = parity {
+            if !parity.is_empty() {
+                let src_path = self.layout.temp_parity_path();
+                let dst_path = self.layout.parity_path();
+                if let Some(ref mut groups) = self.parity_groups {
+                    for member in parity.members() {
+                        groups.insert(member.path.clone(), dst_path.clone());
+                    }
+                }
+                try!(parity.finish(&src_path));
+                try!(self.queue_upload(src_path, dst_path));
+            }
+        }
+        Ok(())
+    }
+
     fn finish_uploads(&mut self) -> Result<(), BundleDbError> {
         let mut uploader = None;
         mem::swap(&mut self.uploader, &mut uploader);
@@ -312,15 +398,149 @@ impl BundleDb {
 
     pub fn delete_bundle(&mut self, bundle: &BundleId) -> Result<(), BundleDbError> {
         try!(self.delete_local_bundle(bundle));
-        if let Some(bundle) = self.remote_bundles.remove(bundle) {
-            let path = self.layout.base_path().join(&bundle.path);
-            fs::remove_file(path).map_err(|e| BundleDbError::Remove(e, bundle.id()))
-        } else {
-            Err(BundleDbError::NoSuchBundle(bundle.clone()))
+        let path = match self.remote_bundles.get(bundle) {
+            Some(bundle) => bundle.path.clone(),
+            None => return Err(BundleDbError::NoSuchBundle(bundle.clone()))
+        };
+        // The bundle is kept if it can not be removed from its parity group
+        try!(self.remove_from_parity(&path));
+        self.remote_bundles.remove(bundle);
+        fs::remove_file(self.layout.base_path().join(path)).map_err(|e| BundleDbError::Remove(e, bundle.clone()))
+    }
+
+    fn load_parity_groups(&mut self) -> Result<&mut HashMap<PathBuf, PathBuf>, BundleDbError> {
+        if self.parity_groups.is_none() {
+            let mut groups = HashMap::new();
+            for path in try!(list_parity_files(&self.layout.remote_parity_path())) {
+                let header = try!(ParityGroup::load_header(&path));
+                for member in header.members {
+                    if !member.removed {
+                        groups.insert(member.path, path.clone());
+                    }
+                }
+            }
+            self.parity_groups = Some(groups);
+        }
+        Ok(self.parity_groups.as_mut().unwrap())
+    }
+
+    fn remove_from_parity(&mut self, bundle: &Path) -> Result<(), BundleDbError> {
+        try!(self.load_parity_groups());
+        if self.parity.as_ref().map_or(false, |p| p.members().iter().any(|m| m.path == bundle)) {
+            // The bundle is in the group that is currently written
+            try!(self.finish_parity());
         }
+        let path = match try!(self.load_parity_groups()).get(bundle) {
+            Some(path) => path.clone(),
+            None => return Ok(())
+        };
+        if !path.exists() {
+            // The parity file has not been uploaded yet
+            try!(self.finish_uploads());
+        }
+        try!(ParityGroup::remove_member(&path, bundle, self.layout.base_path()));
+        try!(self.load_parity_groups()).remove(bundle);
+        Ok(())
+    }
+
+    /// Replaces the bundle at `path` with the restored bundle in the file `src`
+    fn restore_member(&mut self, path: &Path, src: &Path) -> Result<(), BundleDbError> {
+        let base_path = self.layout.base_path().to_path_buf();
+        let full_path = base_path.join(path);
+        if full_path.exists() {
+            let id = self.remote_bundles.iter().find(|&(_, b)| b.path == path).map(|(id, _)| id.clone());
+            if let Some(id) = id {
+                let bundle = self.remote_bundles[&id].clone();
+                try!(self.evacuate_broken_bundle(bundle));
+            } else {
+                try!(fs::remove_file(&full_path).context(&full_path as &Path));
+            }
+        }
+        let folder = full_path.parent().unwrap();
+        try!(fs::create_dir_all(folder).context(folder));
+        try!(fs::copy(src, &full_path).context(&full_path as &Path));
+        try!(fs::remove_file(src).context(src));
+        let info = try!(BundleReader::load_info(&full_path, self.crypto.clone()));
+        info!("Restored bundle {} from parity data", info.id);
+        self.remote_bundles.insert(info.id.clone(), StoredBundle { info: info, path: path.to_path_buf() });
+        Ok(())
+    }
+
+    fn evacuate_broken_parity(&mut self, path: &Path) -> Result<(), BundleDbError> {
+        let mut dst = path.with_extension("parity.broken");
+        let mut num = 1;
+        while dst.exists() {
+            dst = path.with_extension(&format!("parity.{}.broken", num));
+            num += 1;
+        }
+        warn!("Moving parity file to {:?}", dst);
+        try!(fs::rename(path, &dst).context(path));
+        self.parity_groups = None;
+        Ok(())
+    }
+
+    fn check_parity(&mut self, repair: bool) -> Result<bool, BundleDbError> {
+        let mut restored = false;
+        let files = try!(list_parity_files(&self.layout.remote_parity_path()));
+        for path in ProgressIter::new("checking parity", files.len(), files.into_iter()) {
+            let mut group = match ParityGroup::open(&path) {
+                Ok(group) => group,
+                Err(err) => if repair {
+                    warn!("Problem detected: failed to read parity file {:?}\n\tcaused by: {}", path, err);
+                    continue
+                } else {
+                    return Err(err.into())
+                }
+            };
+            let base_path = self.layout.base_path().to_path_buf();
+            let damaged: Vec<usize> = group.header.members.iter().enumerate()
+                .filter(|&(_, m)| !m.removed && !m.matches(&base_path)).map(|(i, _)| i).collect();
+            let shards = group.check_shards();
+            let parity_damaged = shards.iter().any(|s| !s);
+            if damaged.is_empty() && !parity_damaged {
+                continue
+            }
+            if !repair {
+                for &i in &damaged {
+                    warn!("Problem detected: bundle {:?} does not match its parity data", group.header.members[i].path);
+                }
+                return Err(BundleDbError::DamagedParity(path))
+            }
+            if !damaged.is_empty() {
+                let missing: Vec<_> = damaged.iter().map(|&i| (i, self.layout.temp_bundle_path())).collect();
+                if let Err(err) = group.reconstruct(&base_path, &shards, &missing) {
+                    warn!("Problem detected: failed to restore bundles from parity {:?}\n\tcaused by: {}", path, err);
+                    for &(_, ref tmp_path) in &missing {
+                        let _ = fs::remove_file(tmp_path);
+                    }
+                    drop(group);
+                    try!(self.evacuate_broken_parity(&path));
+                    continue
+                }
+                for (i, tmp_path) in missing {
+                    let member = &group.header.members[i];
+                    warn!("Problem detected: bundle {:?} is damaged, restoring it from parity", member.path);
+                    try!(self.restore_member(&member.path, &tmp_path));
+                    restored = true;
+                }
+            }
+            if parity_damaged {
+                warn!("Problem detected: parity data in {:?} is damaged, recreating it", path);
+                let mut header = group.header.clone();
+                let tmp_path = self.layout.temp_parity_path();
+                try!(ParityGroup::encode(&tmp_path, &mut header, &base_path));
+                try!(self.queue_upload(tmp_path, path));
+            }
+        }
+        Ok(restored)
     }
 
     pub fn check(&mut self, full: bool, repair: bool) -> Result<bool, BundleDbError> {
+        let restored = if repair || full {
+            try!(self.check_parity(repair))
+        } else {
+            false
+        };
         let mut to_repair = vec![];
         for (id, stored) in ProgressIter::new("checking bundles", self.remote_bundles.len(), self.remote_bundles.iter()) {
             let mut bundle = match self.get_bundle(stored) {
@@ -347,9 +567,11 @@ impl BundleDb {
             for id in ProgressIter::new("repairing bundles", to_repair.len(), to_repair.iter()) {
                 try!(self.repair_bundle(id.clone()));
             }
+        }
+        if restored || !to_repair.is_empty() {
             try!(self.flush());
         }
-        Ok(!to_repair.is_empty())
+        Ok(restored || !to_repair.is_empty())
     }
 
     fn evacuate_broken_bundle(&mut self, mut bundle: StoredBundle) -> Result<(), BundleDbError> {
@@ -413,3 +635,45 @@ impl BundleDb {
     }
 
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_util::temp_dir;
+
+    fn add_bundle(db: &mut BundleDb, seed: u32) -> BundleId {
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i.wrapping_mul(seed) ^ (i >> 5)) as u8).collect();
+        let mut writer = BundleWriter::new(BundleMode::Data, HashMethod::Blake2, None, None, db.crypto.clone()).unwrap();
+        writer.add(&data, HashMethod::Blake2.hash(&data)).unwrap();
+        db.add_bundle(writer).unwrap().id
+    }
+
+    #[test]
+    fn test_delete_damaged_bundle() {
+        let dir = temp_dir("bundledb-parity");
+        let layout = RepositoryLayout::new(&dir);
+        BundleDb::create(layout.clone()).unwrap();
+        let crypto = Arc::new(Mutex::new(Crypto::dummy()));
+        let (mut db, _, _) = BundleDb::open(layout, crypto, Some(Redundancy::new(3, 1).unwrap())).unwrap();
+        let ids: Vec<_> = (1..4).map(|seed| add_bundle(&mut db, seed)).collect();
+        db.flush().unwrap();
+        let path = |db: &BundleDb, id: &BundleId| dir.join(&db.get_bundle_info(id).unwrap().path);
+        // The contents of the bundle do not match the parity data anymore
+        let damaged = path(&db, &ids[1]);
+        fs::copy(path(&db, &ids[0]), &damaged).unwrap();
+        db.delete_bundle(&ids[1]).unwrap();
+        assert!(!damaged.exists());
+        assert!(!db.check_parity(true).unwrap());
+        assert!(!damaged.exists());
+        db.check_parity(false).unwrap();
+        // The parity data still protects the remaining bundles
+        let missing = path(&db, &ids[2]);
+        fs::remove_file(&missing).unwrap();
+        assert!(db.check_parity(true).unwrap());
+        assert!(missing.exists());
+        db.check_parity(false).unwrap();
+        drop(db);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
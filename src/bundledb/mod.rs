@@ -3,8 +3,9 @@ mod reader;
 mod db;
 mod cache;
 mod uploader;
+mod parity;
 
-pub use self::cache::{StoredBundle, BundleCacheError};
+pub use self::cache::{StoredBundle, BundleCacheError, CACHE_FILE_VERSION};
 pub use self::writer::{BundleWriter, BundleWriterError};
 pub use self::reader::{BundleReader, BundleReaderError};
 pub use self::db::*;
@@ -14,6 +15,7 @@ use ::prelude::*;
 
 use std::fmt;
 use serde;
+use serde_bytes::ByteBuf;
 use rand;
 
 
@@ -47,7 +49,7 @@ impl BundleId {
 
     #[inline]
     pub fn random() -> Self {
-        BundleId(Hash{high: rand::random(), low: rand::random()})
+        BundleId(Hash::new(rand::random(), rand::random()))
     }
 }
 
@@ -79,11 +81,13 @@ serde_impl!(BundleMode(u8) {
 #[derive(Default, Debug, Clone)]
 pub struct BundleHeader {
     pub encryption: Option<Encryption>,
-    pub info_size: usize
+    pub info_size: usize,
+    pub data_key: Option<ByteBuf>
 }
-serde_impl!(BundleHeader(u8) {
+serde_impl!(BundleHeader(u8?) {
     encryption: Option<Encryption> => 0,
-    info_size: usize => 1
+    info_size: usize => 1,
+    data_key: Option<ByteBuf> => 2
 });
 
 
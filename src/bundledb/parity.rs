@@ -0,0 +1,683 @@
+use ::prelude::*;
+
+use std::path::{Path, PathBuf};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Read, Write, Seek, SeekFrom};
+use std::cmp::{min, max};
+
+use blake2::blake2b::Blake2b;
+
+
+pub static PARITY_HEADER_STRING: [u8; 7] = *b"zvault\x05";
+pub static PARITY_HEADER_VERSION: u8 = 1;
+
+// Bundles and parity shards are processed in blocks of this size and never loaded as a whole
+const BLOCK_SIZE: usize = 64 * 1024;
+
+
+quick_error!{
+    #[derive(Debug)]
+    pub enum ParityError {
+        Read(err: io::Error, path: PathBuf) {
+            cause(err)
+            context(path: &'a Path, err: io::Error) -> (err, path.to_path_buf())
+            description("Failed to read parity file")
+            display("Parity error: failed to read parity file {:?}\n\tcaused by: {}", path, err)
+        }
+        Write(err: io::Error, path: PathBuf) {
+            cause(err)
+            description("Failed to write parity file")
+            display("Parity error: failed to write parity file {:?}\n\tcaused by: {}", path, err)
+        }
+        WrongHeader(path: PathBuf) {
+            description("Wrong header")
+            display("Parity error: wrong header on parity file {:?}", path)
+        }
+        UnsupportedVersion(path: PathBuf, version: u8) {
+            description("Wrong version")
+            display("Parity error: unsupported version on parity file {:?}: {}", path, version)
+        }
+        Decode(err: msgpack::DecodeError, path: PathBuf) {
+            cause(err)
+            context(path: &'a Path, err: msgpack::DecodeError) -> (err, path.to_path_buf())
+            description("Failed to decode parity header")
+            display("Parity error: failed to decode parity header of {:?}\n\tcaused by: {}", path, err)
+        }
+        Encode(err: msgpack::EncodeError, path: PathBuf) {
+            cause(err)
+            context(path: &'a Path, err: msgpack::EncodeError) -> (err, path.to_path_buf())
+            description("Failed to encode parity header")
+            display("Parity error: failed to encode parity header of {:?}\n\tcaused by: {}", path, err)
+        }
+        Erasure(err: ErasureError) {
+            from()
+            cause(err)
+            description("Failed to reconstruct data")
+            display("Parity error: failed to reconstruct data\n\tcaused by: {}", err)
+        }
+        Mismatch(member: PathBuf) {
+            description("Reconstructed data does not match its checksum")
+            display("Parity error: reconstructed data of {:?} does not match its checksum", member)
+        }
+        Damaged(member: PathBuf) {
+            description("Bundle does not match its parity data")
+            display("Parity error: bundle {:?} does not match its parity data", member)
+        }
+    }
+}
+
+
+/// Reads the block at the given position, the rest of the buffer after the end of the file is
+/// filled with zeros. Returns the number of bytes that have been read.
+fn read_block(file: &mut File, pos: u64, buf: &mut [u8]) -> Result<usize, io::Error> {
+    try!(file.seek(SeekFrom::Start(pos)));
+    let mut len = 0;
+    while len < buf.len() {
+        match file.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err)
+        }
+    }
+    for byte in &mut buf[len..] {
+        *byte = 0;
+    }
+    Ok(len)
+}
+
+fn write_block(file: &mut File, pos: u64, data: &[u8]) -> Result<(), io::Error> {
+    try!(file.seek(SeekFrom::Start(pos)));
+    file.write_all(data)
+}
+
+
+/// Incremental version of `ParityGroup::checksum`
+struct Checksummer(Blake2b);
+
+impl Checksummer {
+    #[inline]
+    fn new() -> Self {
+        Checksummer(Blake2b::new(SHORT_HASH_SIZE))
+    }
+
+    #[inline]
+    fn update(&mut self, data: &[u8]) {
+        self.0.update(data)
+    }
+
+    #[inline]
+    fn finish(self) -> Hash {
+        Hash::from_digest(self.0.finalize().as_bytes())
+    }
+}
+
+
+#[derive(Clone, Default, Debug)]
+pub struct ParityMember {
+    pub path: PathBuf,
+    pub size: usize,
+    pub checksum: Hash,
+    pub removed: bool
+}
+serde_impl!(ParityMember(u8) {
+    path: PathBuf => 0,
+    size: usize => 1,
+    checksum: Hash => 2,
+    removed: bool => 3
+});
+
+impl ParityMember {
+    /// Whether the bundle file below the base path still has the contents of the parity data
+    pub fn matches(&self, base_path: &Path) -> bool {
+        match ParityGroup::checksum_file(base_path.join(&self.path)) {
+            Ok((checksum, size)) => checksum == self.checksum && size == self.size,
+            Err(_) => false
+        }
+    }
+}
+
+
+#[derive(Clone, Default, Debug)]
+pub struct ParityHeader {
+    pub members: Vec<ParityMember>,
+    pub shard_size: usize,
+    pub shard_checksums: Vec<Hash>
+}
+serde_impl!(ParityHeader(u8) {
+    members: Vec<ParityMember> => 0,
+    shard_size: usize => 1,
+    shard_checksums: Vec<Hash> => 2
+});
+
+impl ParityHeader {
+    #[inline]
+    pub fn parity(&self) -> usize {
+        self.shard_checksums.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.members.iter().all(|m| m.removed)
+    }
+}
+
+
+/// An opened parity file, the shards are read block by block
+pub struct ParityGroup {
+    pub header: ParityHeader,
+    path: PathBuf,
+    file: File,
+    offset: u64
+}
+
+impl ParityGroup {
+    #[inline]
+    pub fn checksum(data: &[u8]) -> Hash {
+        HashMethod::Blake2.hash(data)
+    }
+
+    /// Checksum and size of the file, the file is read block by block
+    pub fn checksum_file<P: AsRef<Path>>(path: P) -> Result<(Hash, usize), io::Error> {
+        let mut file = try!(File::open(path));
+        let mut checksum = Checksummer::new();
+        let mut buf = vec![0u8; BLOCK_SIZE];
+        let mut size = 0;
+        loop {
+            let len = try!(read_block(&mut file, size as u64, &mut buf));
+            checksum.update(&buf[..len]);
+            size += len;
+            if len < BLOCK_SIZE {
+                return Ok((checksum.finish(), size))
+            }
+        }
+    }
+
+    fn read_header(file: &mut Read, path: &Path) -> Result<ParityHeader, ParityError> {
+        let mut header = [0u8; 8];
+        try!(file.read_exact(&mut header).context(path));
+        if header[..PARITY_HEADER_STRING.len()] != PARITY_HEADER_STRING {
+            return Err(ParityError::WrongHeader(path.to_path_buf()))
+        }
+        let version = header[PARITY_HEADER_STRING.len()];
+        if version != PARITY_HEADER_VERSION {
+            return Err(ParityError::UnsupportedVersion(path.to_path_buf(), version))
+        }
+        Ok(try!(msgpack::decode_from_stream(file).context(path)))
+    }
+
+    pub fn load_header<P: AsRef<Path>>(path: P) -> Result<ParityHeader, ParityError> {
+        let path = path.as_ref();
+        let mut file = BufReader::new(try!(File::open(path).context(path)));
+        Self::read_header(&mut file, path)
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, ParityError> {
+        let path = path.as_ref();
+        let mut file = BufReader::new(try!(File::open(path).context(path)));
+        let header = try!(Self::read_header(&mut file, path));
+        let offset = try!(file.stream_position().context(path));
+        Ok(ParityGroup {
+            header: header,
+            path: path.to_path_buf(),
+            file: file.into_inner(),
+            offset: offset
+        })
+    }
+
+    /// Reads a block of a shard, a truncated parity file is an error
+    fn read_shard_block(&mut self, shard: usize, pos: usize, buf: &mut [u8]) -> Result<(), ParityError> {
+        let start = self.offset + (shard * self.header.shard_size + pos) as u64;
+        if try!(read_block(&mut self.file, start, buf).context(&self.path as &Path)) < buf.len() {
+            let err = io::Error::new(io::ErrorKind::UnexpectedEof, "parity file is truncated");
+            return Err(ParityError::Read(err, self.path.clone()))
+        }
+        Ok(())
+    }
+
+    /// Checks all shards against their checksums, damaged shards are `false`
+    pub fn check_shards(&mut self) -> Vec<bool> {
+        let mut intact = Vec::with_capacity(self.header.parity());
+        let mut buf = vec![0u8; BLOCK_SIZE];
+        for shard in 0..self.header.parity() {
+            let mut checksum = Checksummer::new();
+            let mut pos = 0;
+            let mut ok = true;
+            while ok && pos < self.header.shard_size {
+                let len = min(BLOCK_SIZE, self.header.shard_size - pos);
+                ok = self.read_shard_block(shard, pos, &mut buf[..len]).is_ok();
+                checksum.update(&buf[..len]);
+                pos += len;
+            }
+            intact.push(ok && checksum.finish() == self.header.shard_checksums[shard]);
+        }
+        intact
+    }
+
+    /// Reconstructs the missing members from the other members below the base path and the
+    /// intact shards (`shards` as returned by `check_shards`). The members are written to the
+    /// given paths and checked against their checksums.
+    pub fn reconstruct(&mut self, base_path: &Path, shards: &[bool], missing: &[(usize, PathBuf)]) -> Result<(), ParityError> {
+        let code = ErasureCode::new(self.header.parity());
+        let members = self.header.members.clone();
+        let mut inputs = Vec::with_capacity(members.len());
+        for (i, member) in members.iter().enumerate() {
+            if member.removed || missing.iter().any(|&(m, _)| m == i) {
+                inputs.push(None);
+            } else {
+                let path = base_path.join(&member.path);
+                inputs.push(Some((try!(File::open(&path).context(&path as &Path)), path)));
+            }
+        }
+        let mut outputs = Vec::with_capacity(missing.len());
+        for &(i, ref path) in missing {
+            let file = try!(File::create(path).map_err(|err| ParityError::Write(err, path.clone())));
+            outputs.push((i, file, path, Checksummer::new()));
+        }
+        let mut pos = 0;
+        while pos < self.header.shard_size {
+            let len = min(BLOCK_SIZE, self.header.shard_size - pos);
+            let mut data = Vec::with_capacity(members.len());
+            for (member, input) in members.iter().zip(&mut inputs) {
+                if let Some((ref mut file, ref path)) = *input {
+                    let mut block = vec![0u8; len];
+                    try!(read_block(file, pos as u64, &mut block).context(path as &Path));
+                    data.push(Some(block));
+                } else if member.removed {
+                    data.push(Some(vec![]));
+                } else {
+                    data.push(None);
+                }
+            }
+            let mut parity = Vec::with_capacity(shards.len());
+            for (shard, &intact) in shards.iter().enumerate() {
+                if intact {
+                    let mut block = vec![0u8; len];
+                    try!(self.read_shard_block(shard, pos, &mut block));
+                    parity.push(Some(block));
+                } else {
+                    parity.push(None);
+                }
+            }
+            try!(code.reconstruct(&mut data, &parity));
+            for &mut (i, ref mut file, path, ref mut checksum) in &mut outputs {
+                let size = members[i].size;
+                if pos < size {
+                    let block = &data[i].as_ref().unwrap()[..min(len, size - pos)];
+                    checksum.update(block);
+                    try!(file.write_all(block).map_err(|err| ParityError::Write(err, path.clone())));
+                }
+            }
+            pos += len;
+        }
+        for (i, _, _, checksum) in outputs {
+            if checksum.finish() != members[i].checksum {
+                return Err(ParityError::Mismatch(members[i].path.clone()))
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes a parity file with the given header, `fill` computes the shards block by block.
+    /// It gets the position in the shards and one zeroed block per shard. The shard checksums
+    /// of the header are updated.
+    fn save<P, F>(path: P, header: &mut ParityHeader, mut fill: F) -> Result<(), ParityError>
+        where P: AsRef<Path>, F: FnMut(usize, &mut [Vec<u8>]) -> Result<(), ParityError>
+    {
+        let path = path.as_ref();
+        let parity = header.parity();
+        // The checksums are written last, placeholders of the same size keep the offsets
+        header.shard_checksums = vec![Hash::empty(); parity];
+        let mut head = Vec::new();
+        head.extend_from_slice(&PARITY_HEADER_STRING);
+        head.push(PARITY_HEADER_VERSION);
+        try!(msgpack::encode_to_stream(&*header, &mut head).context(path));
+        let offset = head.len();
+        let mut file = try!(File::create(path).map_err(|err| ParityError::Write(err, path.to_path_buf())));
+        try!(file.write_all(&head).map_err(|err| ParityError::Write(err, path.to_path_buf())));
+        let mut checksums: Vec<_> = (0..parity).map(|_| Checksummer::new()).collect();
+        let mut blocks = vec![vec![]; parity];
+        let mut pos = 0;
+        while pos < header.shard_size {
+            let len = min(BLOCK_SIZE, header.shard_size - pos);
+            for block in &mut blocks {
+                block.clear();
+                block.resize(len, 0);
+            }
+            try!(fill(pos, &mut blocks));
+            for (shard, block) in blocks.iter().enumerate() {
+                checksums[shard].update(block);
+                let start = (offset + shard * header.shard_size + pos) as u64;
+                try!(write_block(&mut file, start, block).map_err(|err| ParityError::Write(err, path.to_path_buf())));
+            }
+            pos += len;
+        }
+        header.shard_checksums = checksums.into_iter().map(|c| c.finish()).collect();
+        head.truncate(PARITY_HEADER_STRING.len() + 1);
+        try!(msgpack::encode_to_stream(&*header, &mut head).context(path));
+        debug_assert_eq!(head.len(), offset);
+        write_block(&mut file, 0, &head).map_err(|err| ParityError::Write(err, path.to_path_buf()))
+    }
+
+    /// Computes the parity data of all members that are not removed from the bundle files below
+    /// the base path and writes the parity file. The bundles must match their checksums.
+    pub fn encode<P: AsRef<Path>>(path: P, header: &mut ParityHeader, base_path: &Path) -> Result<(), ParityError> {
+        let code = ErasureCode::new(header.parity());
+        let mut inputs = Vec::with_capacity(header.members.len());
+        for (i, member) in header.members.iter().enumerate() {
+            if !member.removed {
+                let path = base_path.join(&member.path);
+                inputs.push((i, try!(File::open(&path).context(&path as &Path)), path));
+            }
+        }
+        let mut buf = vec![0u8; BLOCK_SIZE];
+        Self::save(path, header, |pos, blocks| {
+            let len = blocks[0].len();
+            for &mut (i, ref mut file, ref path) in &mut inputs {
+                let read = try!(read_block(file, pos as u64, &mut buf[..len]).context(path as &Path));
+                code.update(i, &buf[..read], blocks);
+            }
+            Ok(())
+        })
+    }
+
+    /// Removes a member from the parity group, a group without members is removed. The
+    /// contribution of the member is subtracted from the parity data. If the bundle or the
+    /// parity data is damaged, the parity data is computed again from the remaining members
+    /// instead. This fails if one of them is damaged as well, so that it can still be restored.
+    pub fn remove_member<P: AsRef<Path>>(path: P, member: &Path, base_path: &Path) -> Result<bool, ParityError> {
+        let path = path.as_ref();
+        let mut group = try!(Self::open(path));
+        let index = if let Some(index) = group.header.members.iter().position(|m| !m.removed && m.path == member) {
+            index
+        } else {
+            return Ok(false)
+        };
+        let mut header = group.header.clone();
+        header.members[index].removed = true;
+        if header.is_empty() {
+            try!(fs::remove_file(path).map_err(|err| ParityError::Write(err, path.to_path_buf())));
+            return Ok(true)
+        }
+        let tmp_path = path.with_extension("parity.tmp");
+        if header.members[index].matches(base_path) && group.check_shards().iter().all(|s| *s) {
+            let code = ErasureCode::new(header.parity());
+            let bundle_path = base_path.join(member);
+            let mut bundle = try!(File::open(&bundle_path).context(&bundle_path as &Path));
+            let mut buf = vec![0u8; BLOCK_SIZE];
+            try!(Self::save(&tmp_path, &mut header, |pos, blocks| {
+                for (shard, block) in blocks.iter_mut().enumerate() {
+                    try!(group.read_shard_block(shard, pos, block));
+                }
+                let len = blocks[0].len();
+                let read = try!(read_block(&mut bundle, pos as u64, &mut buf[..len]).context(&bundle_path as &Path));
+                // As addition is XOR, this removes the contribution of the bundle
+                code.update(index, &buf[..read], blocks);
+                Ok(())
+            }));
+        } else {
+            warn!("Bundle {:?} or its parity data is damaged, recomputing the parity data of the remaining bundles", member);
+            if let Some(damaged) = header.members.iter().find(|m| !m.removed && !m.matches(base_path)) {
+                return Err(ParityError::Damaged(damaged.path.clone()))
+            }
+            try!(Self::encode(&tmp_path, &mut header, base_path));
+        }
+        try!(fs::rename(&tmp_path, path).map_err(|err| ParityError::Write(err, path.to_path_buf())));
+        Ok(true)
+    }
+}
+
+
+pub struct ParityWriter {
+    redundancy: Redundancy,
+    code: ErasureCode,
+    members: Vec<ParityMember>,
+    // The shards grow with every member, so their blocks are stored interleaved until the end
+    scratch: File,
+    scratch_path: PathBuf,
+    shard_size: usize
+}
+
+impl ParityWriter {
+    pub fn new(redundancy: Redundancy, scratch_path: PathBuf) -> Result<Self, ParityError> {
+        let scratch = try!(OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&scratch_path)
+            .map_err(|err| ParityError::Write(err, scratch_path.clone())));
+        Ok(ParityWriter {
+            redundancy: redundancy,
+            code: ErasureCode::new(redundancy.parity),
+            members: Vec::with_capacity(redundancy.bundles),
+            scratch: scratch,
+            scratch_path: scratch_path,
+            shard_size: 0
+        })
+    }
+
+    #[inline]
+    fn scratch_pos(parity: usize, pos: usize, shard: usize) -> u64 {
+        ((pos / BLOCK_SIZE * parity + shard) * BLOCK_SIZE) as u64
+    }
+
+    /// Adds the bundle file at `src` that will be stored at `path`
+    pub fn add(&mut self, path: PathBuf, src: &Path) -> Result<(), ParityError> {
+        let index = self.members.len();
+        let parity = self.redundancy.parity;
+        let mut file = try!(File::open(src).context(src));
+        let mut checksum = Checksummer::new();
+        let mut buf = vec![0u8; BLOCK_SIZE];
+        let mut blocks = vec![vec![0u8; BLOCK_SIZE]; parity];
+        let mut size = 0;
+        loop {
+            let len = try!(read_block(&mut file, size as u64, &mut buf).context(src));
+            if len == 0 {
+                break
+            }
+            checksum.update(&buf[..len]);
+            for (shard, block) in blocks.iter_mut().enumerate() {
+                let pos = Self::scratch_pos(parity, size, shard);
+                try!(read_block(&mut self.scratch, pos, block).context(&self.scratch_path as &Path));
+            }
+            self.code.update(index, &buf[..len], &mut blocks);
+            for (shard, block) in blocks.iter().enumerate() {
+                let pos = Self::scratch_pos(parity, size, shard);
+                try!(write_block(&mut self.scratch, pos, block).map_err(|err| ParityError::Write(err, self.scratch_path.clone())));
+            }
+            size += len;
+            if len < BLOCK_SIZE {
+                break
+            }
+        }
+        self.shard_size = max(self.shard_size, size);
+        self.members.push(ParityMember {
+            path: path,
+            size: size,
+            checksum: checksum.finish(),
+            removed: false
+        });
+        Ok(())
+    }
+
+    #[inline]
+    pub fn members(&self) -> &[ParityMember] {
+        &self.members
+    }
+
+    #[inline]
+    pub fn is_full(&self) -> bool {
+        self.members.len() >= self.redundancy.bundles
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+
+    pub fn finish<P: AsRef<Path>>(self, path: P) -> Result<(), ParityError> {
+        let ParityWriter { redundancy, members, mut scratch, scratch_path, shard_size, .. } = self;
+        let parity = redundancy.parity;
+        let mut header = ParityHeader {
+            members: members,
+            shard_size: shard_size,
+            shard_checksums: vec![Hash::empty(); parity]
+        };
+        try!(ParityGroup::save(path, &mut header, |pos, blocks| {
+            for (shard, block) in blocks.iter_mut().enumerate() {
+                let pos = Self::scratch_pos(parity, pos, shard);
+                try!(read_block(&mut scratch, pos, block).context(&scratch_path as &Path));
+            }
+            Ok(())
+        }));
+        fs::remove_file(&scratch_path).map_err(|err| ParityError::Write(err, scratch_path.clone()))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_util::temp_dir;
+
+    // The bundles span several blocks and have different sizes
+    fn bundles() -> Vec<Vec<u8>> {
+        (0..4).map(|i| (0..2 * BLOCK_SIZE + 1000 + 337 * i).map(|j| (j * 7 + i * 13) as u8).collect()).collect()
+    }
+
+    fn write_file(path: &Path, data: &[u8]) {
+        File::create(path).and_then(|mut f| f.write_all(data)).unwrap();
+    }
+
+    fn read_file(path: &Path) -> Vec<u8> {
+        let mut data = Vec::new();
+        File::open(path).and_then(|mut f| f.read_to_end(&mut data)).unwrap();
+        data
+    }
+
+    fn write_group(dir: &Path, bundles: &[Vec<u8>], parity: usize) -> PathBuf {
+        let mut writer = ParityWriter::new(Redundancy::new(bundles.len(), parity).unwrap(), dir.join("scratch")).unwrap();
+        for (i, data) in bundles.iter().enumerate() {
+            let name = format!("bundle{}", i);
+            write_file(&dir.join(&name), data);
+            writer.add(PathBuf::from(name), &dir.join(format!("bundle{}", i))).unwrap();
+        }
+        assert!(writer.is_full());
+        let path = dir.join("group.parity");
+        writer.finish(&path).unwrap();
+        assert!(!dir.join("scratch").exists());
+        path
+    }
+
+    fn restore(dir: &Path, path: &Path, missing: &[usize]) -> Result<Vec<Vec<u8>>, ParityError> {
+        let mut group = ParityGroup::open(path).unwrap();
+        let shards = group.check_shards();
+        let missing: Vec<_> = missing.iter().map(|&i| (i, dir.join(format!("restored{}", i)))).collect();
+        try!(group.reconstruct(dir, &shards, &missing));
+        Ok(missing.iter().map(|m| read_file(&m.1)).collect())
+    }
+
+    #[test]
+    fn test_matches_checksum() {
+        let dir = temp_dir("parity-checksum");
+        let bundles = bundles();
+        let path = write_group(&dir, &bundles, 2);
+        let header = ParityGroup::load_header(&path).unwrap();
+        for (member, data) in header.members.iter().zip(&bundles) {
+            assert_eq!(member.size, data.len());
+            assert_eq!(member.checksum, ParityGroup::checksum(data));
+            assert!(member.matches(&dir));
+        }
+        assert_eq!(ParityGroup::open(&path).unwrap().check_shards(), vec![true, true]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_reconstruct_missing() {
+        let dir = temp_dir("parity-missing");
+        let bundles = bundles();
+        let path = write_group(&dir, &bundles, 2);
+        fs::remove_file(dir.join("bundle0")).unwrap();
+        fs::remove_file(dir.join("bundle3")).unwrap();
+        let restored = restore(&dir, &path, &[0, 3]).unwrap();
+        assert!(restored[0] == bundles[0]);
+        assert!(restored[1] == bundles[3]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_reconstruct_damaged() {
+        let dir = temp_dir("parity-damaged");
+        let bundles = bundles();
+        let path = write_group(&dir, &bundles, 1);
+        // A damaged bundle is detected by its checksum and treated like a missing one
+        let mut damaged = bundles[2].clone();
+        damaged[BLOCK_SIZE + 17] ^= 0xff;
+        write_file(&dir.join("bundle2"), &damaged);
+        let header = ParityGroup::load_header(&path).unwrap();
+        assert!(!header.members[2].matches(&dir));
+        let restored = restore(&dir, &path, &[2]).unwrap();
+        assert!(restored[0] == bundles[2]);
+        // Too many missing bundles can not be restored
+        assert!(restore(&dir, &path, &[1, 2]).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_reconstruct_damaged_parity() {
+        let dir = temp_dir("parity-damaged-parity");
+        let bundles = bundles();
+        let path = write_group(&dir, &bundles, 2);
+        let mut file = read_file(&path);
+        let len = file.len();
+        file[len - 1] ^= 0xff;
+        write_file(&path, &file);
+        assert_eq!(ParityGroup::open(&path).unwrap().check_shards(), vec![true, false]);
+        fs::remove_file(dir.join("bundle1")).unwrap();
+        let restored = restore(&dir, &path, &[1]).unwrap();
+        assert!(restored[0] == bundles[1]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_remove_member() {
+        let dir = temp_dir("parity-remove");
+        let bundles = bundles();
+        let path = write_group(&dir, &bundles, 1);
+        assert!(ParityGroup::remove_member(&path, Path::new("bundle1"), &dir).unwrap());
+        assert!(!ParityGroup::remove_member(&path, Path::new("bundle1"), &dir).unwrap());
+        fs::remove_file(dir.join("bundle1")).unwrap();
+        let header = ParityGroup::load_header(&path).unwrap();
+        assert!(header.members[1].removed);
+        fs::remove_file(dir.join("bundle3")).unwrap();
+        let restored = restore(&dir, &path, &[3]).unwrap();
+        assert!(restored[0] == bundles[3]);
+        fs::rename(dir.join("restored3"), dir.join("bundle3")).unwrap();
+        for i in 0..bundles.len() {
+            ParityGroup::remove_member(&path, Path::new(&format!("bundle{}", i)), &dir).unwrap();
+        }
+        assert!(!path.exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_remove_damaged_member() {
+        let dir = temp_dir("parity-remove-damaged");
+        let bundles = bundles();
+        let path = write_group(&dir, &bundles, 1);
+        // The parity data is computed again from the remaining bundles
+        write_file(&dir.join("bundle1"), &bundles[0]);
+        assert!(ParityGroup::remove_member(&path, Path::new("bundle1"), &dir).unwrap());
+        assert_eq!(ParityGroup::open(&path).unwrap().check_shards(), vec![true]);
+        fs::remove_file(dir.join("bundle1")).unwrap();
+        fs::remove_file(dir.join("bundle2")).unwrap();
+        let restored = restore(&dir, &path, &[2]).unwrap();
+        assert!(restored[0] == bundles[2]);
+        fs::rename(dir.join("restored2"), dir.join("bundle2")).unwrap();
+        // That fails if one of the remaining bundles is damaged too
+        fs::remove_file(dir.join("bundle0")).unwrap();
+        write_file(&dir.join("bundle3"), &bundles[2]);
+        match ParityGroup::remove_member(&path, Path::new("bundle0"), &dir) {
+            Err(ParityError::Damaged(member)) => assert_eq!(member, Path::new("bundle3")),
+            _ => panic!("Removing a member of a damaged group must fail")
+        }
+        assert!(!ParityGroup::load_header(&path).unwrap().members[0].removed);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
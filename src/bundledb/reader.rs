@@ -8,6 +8,8 @@ use std::cmp::max;
 use std::fmt::{self, Debug};
 use std::sync::{Arc, Mutex};
 
+use serde_bytes::ByteBuf;
+
 
 quick_error!{
     #[derive(Debug)]
@@ -61,19 +63,21 @@ pub struct BundleReader {
     pub version: u8,
     pub path: PathBuf,
     crypto: Arc<Mutex<Crypto>>,
+    data_key: Option<ByteBuf>,
     pub content_start: usize,
     pub chunks: Option<ChunkList>,
     pub chunk_positions: Option<Vec<usize>>
 }
 
 impl BundleReader {
-    pub fn new(path: PathBuf, version: u8, content_start: usize, crypto: Arc<Mutex<Crypto>>, info: BundleInfo) -> Self {
+    pub fn new(path: PathBuf, version: u8, content_start: usize, crypto: Arc<Mutex<Crypto>>, data_key: Option<ByteBuf>, info: BundleInfo) -> Self {
         BundleReader {
             info: info,
             chunks: None,
             version: version,
             path: path,
             crypto: crypto,
+            data_key: data_key,
             content_start: content_start,
             chunk_positions: None
         }
@@ -84,7 +88,7 @@ impl BundleReader {
         self.info.id.clone()
     }
 
-    fn load_header<P: AsRef<Path>>(path: P, crypto: Arc<Mutex<Crypto>>) -> Result<(BundleInfo, u8, usize), BundleReaderError> {
+    fn load_header<P: AsRef<Path>>(path: P, crypto: Arc<Mutex<Crypto>>) -> Result<(BundleInfo, u8, usize, Option<ByteBuf>), BundleReaderError> {
         let path = path.as_ref();
         let mut file = BufReader::new(try!(File::open(path).context(path)));
         let mut header = [0u8; 8];
@@ -101,13 +105,13 @@ impl BundleReader {
         info_data.resize(header.info_size, 0);
         try!(file.read_exact(&mut info_data).context(path));
         if let Some(ref encryption) = header.encryption {
-            info_data = try!(crypto.lock().unwrap().decrypt(encryption, &info_data).context(path));
+            info_data = try!(crypto.lock().unwrap().decrypt_with(encryption, header.data_key.as_ref().map(|k| &k[..]), &info_data).context(path));
         }
         let mut info: BundleInfo = try!(msgpack::decode(&info_data).context(path));
         info.encryption = header.encryption;
         debug!("Load bundle {}", info.id);
         let content_start = file.seek(SeekFrom::Current(0)).unwrap() as usize + info.chunk_list_size;
-        Ok((info, version, content_start))
+        Ok((info, version, content_start, header.data_key))
     }
 
     #[inline]
@@ -117,8 +121,8 @@ impl BundleReader {
 
     #[inline]
     pub fn load(path: PathBuf, crypto: Arc<Mutex<Crypto>>) -> Result<Self, BundleReaderError> {
-        let (header, version, content_start) = try!(Self::load_header(&path, crypto.clone()));
-        Ok(BundleReader::new(path, version, content_start, crypto, header))
+        let (header, version, content_start, data_key) = try!(Self::load_header(&path, crypto.clone()));
+        Ok(BundleReader::new(path, version, content_start, crypto, data_key, header))
     }
 
     fn load_chunklist(&mut self) -> Result<(), BundleReaderError> {
@@ -131,9 +135,17 @@ impl BundleReader {
         chunk_data.resize(self.info.chunk_list_size, 0);
         try!(file.read_exact(&mut chunk_data).context(&self.path as &Path));
         if let Some(ref encryption) = self.info.encryption {
-            chunk_data = try!(self.crypto.lock().unwrap().decrypt(encryption, &chunk_data).context(&self.path as &Path));
+            chunk_data = try!(self.crypto.lock().unwrap().decrypt_with(encryption, self.data_key.as_ref().map(|k| &k[..]), &chunk_data).context(&self.path as &Path));
         }
-        let chunks = ChunkList::read_from(&chunk_data);
+        // Bundles can also contain chunks of older hash methods, so the hash size follows from the
+        // number of chunks
+        let hash_size = match self.info.chunk_count {
+            0 => self.info.hash_method.hash_size(),
+            count if chunk_data.len() == count * (SHORT_HASH_SIZE + 4) => SHORT_HASH_SIZE,
+            count if chunk_data.len() == count * (LONG_HASH_SIZE + 4) => LONG_HASH_SIZE,
+            _ => return Err(BundleReaderError::Integrity(self.id(), "Chunk list size does not match chunk count"))
+        };
+        let chunks = ChunkList::read_from(&chunk_data, hash_size);
         let mut chunk_positions = Vec::with_capacity(chunks.len());
         let mut pos = 0;
         for &(_, len) in (&chunks).iter() {
@@ -164,7 +176,7 @@ impl BundleReader {
 
     fn decode_contents(&self, mut data: Vec<u8>) -> Result<Vec<u8>, BundleReaderError> {
         if let Some(ref encryption) = self.info.encryption {
-            data = try!(self.crypto.lock().unwrap().decrypt(encryption, &data).context(&self.path as &Path));
+            data = try!(self.crypto.lock().unwrap().decrypt_with(encryption, self.data_key.as_ref().map(|k| &k[..]), &data).context(&self.path as &Path));
         }
         if let Some(ref compression) = self.info.compression {
             let mut stream = try!(compression.decompress_stream().context(&self.path as &Path));
@@ -176,11 +188,95 @@ impl BundleReader {
         Ok(data)
     }
 
-    #[inline]
+    fn decompress_part(&self, stream: Option<&mut CompressionStream>, part: &mut Vec<u8>, data: &mut Vec<u8>) -> Result<(), BundleReaderError> {
+        if let Some(stream) = stream {
+            try!(stream.process(part, data).context(&self.path as &Path));
+            part.clear();
+        } else {
+            data.append(part);
+        }
+        Ok(())
+    }
+
+    /// Decrypts and decompresses the contents while reading them, so the encoded contents are
+    /// never loaded completely
+    fn load_contents_incrementally(&self, mut decrypt: CipherStream) -> Result<Vec<u8>, BundleReaderError> {
+        let mut file = BufReader::new(try!(File::open(&self.path).context(&self.path as &Path)));
+        try!(file.seek(SeekFrom::Start(self.content_start as u64)).context(&self.path as &Path));
+        let mut decompress = match self.info.compression {
+            Some(ref compression) => Some(try!(compression.decompress_stream().context(&self.path as &Path))),
+            None => None
+        };
+        let mut data = Vec::with_capacity(self.info.raw_size);
+        let mut buffer = vec![0u8; STREAM_BLOCK_SIZE];
+        let mut part = Vec::with_capacity(STREAM_BLOCK_SIZE);
+        loop {
+            let len = try!(file.read(&mut buffer).context(&self.path as &Path));
+            if len == 0 {
+                break
+            }
+            try!(decrypt.process(&buffer[..len], &mut part).context(&self.path as &Path));
+            try!(self.decompress_part(decompress.as_mut(), &mut part, &mut data));
+        }
+        try!(decrypt.finish(&mut part).context(&self.path as &Path));
+        try!(self.decompress_part(decompress.as_mut(), &mut part, &mut data));
+        if let Some(stream) = decompress {
+            try!(stream.finish(&mut data).context(&self.path as &Path));
+        }
+        Ok(data)
+    }
+
     pub fn load_contents(&self) -> Result<Vec<u8>, BundleReaderError> {
+        if let Some(ref encryption) = self.info.encryption {
+            let stream = try!(self.crypto.lock().unwrap().decrypt_stream(encryption, self.data_key.as_ref().map(|k| &k[..])).context(&self.path as &Path));
+            if let Some(stream) = stream {
+                return self.load_contents_incrementally(stream)
+            }
+        }
         self.load_encoded_contents().and_then(|data| self.decode_contents(data))
     }
 
+    /// Loads only the given range of the contents. Only the blocks that cover the range are
+    /// decrypted, this is possible for uncompressed bundles of the stream method. Other bundles
+    /// return `None` and have to be loaded completely.
+    pub fn load_range(&self, pos: usize, len: usize) -> Result<Option<Vec<u8>>, BundleReaderError> {
+        if self.info.compression.is_some() {
+            return Ok(None)
+        }
+        let decrypt = match self.info.encryption {
+            Some(ref encryption) => try!(self.crypto.lock().unwrap().decrypt_stream(encryption, self.data_key.as_ref().map(|k| &k[..])).context(&self.path as &Path)),
+            None => None
+        };
+        let decrypt = match decrypt {
+            Some(decrypt) => decrypt,
+            None => return Ok(None)
+        };
+        if pos + len > self.info.raw_size || CipherStream::encrypted_size(self.info.raw_size) != self.info.encoded_size {
+            return Err(BundleReaderError::Integrity(self.id(), "Encrypted size does not match the contents"))
+        }
+        if len == 0 {
+            return Ok(Some(vec![]))
+        }
+        let blocks = CipherStream::block_count(self.info.raw_size);
+        let (first, last) = (pos / STREAM_BLOCK_SIZE, (pos + len - 1) / STREAM_BLOCK_SIZE);
+        let start = STREAM_PREFIX_SIZE + first * STREAM_ENCRYPTED_BLOCK_SIZE;
+        let end = if last == blocks - 1 {
+            self.info.encoded_size
+        } else {
+            STREAM_PREFIX_SIZE + (last + 1) * STREAM_ENCRYPTED_BLOCK_SIZE
+        };
+        let mut file = try!(File::open(&self.path).context(&self.path as &Path));
+        try!(file.seek(SeekFrom::Start(self.content_start as u64)).context(&self.path as &Path));
+        let mut prefix = vec![0u8; STREAM_PREFIX_SIZE];
+        try!(file.read_exact(&mut prefix).context(&self.path as &Path));
+        try!(file.seek(SeekFrom::Start((self.content_start + start) as u64)).context(&self.path as &Path));
+        let mut data = vec![0u8; end - start];
+        try!(file.read_exact(&mut data).context(&self.path as &Path));
+        let data = try!(decrypt.decrypt_blocks(&prefix, first, &data, last == blocks - 1).context(&self.path as &Path));
+        let offset = pos - first * STREAM_BLOCK_SIZE;
+        Ok(Some(data[offset..offset+len].to_vec()))
+    }
+
     pub fn get_chunk_position(&mut self, id: usize) -> Result<(usize, usize), BundleReaderError> {
         if id >= self.info.chunk_count {
             return Err(BundleReaderError::NoSuchChunk(self.id(), id))
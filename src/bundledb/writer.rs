@@ -40,6 +40,10 @@ quick_error!{
             description("Failed to write data to file")
             display("Bundle writer error: failed to write data to file {:?}\n\tcaused by: {}", path, err)
         }
+        MissingHashKey(method: HashMethod) {
+            description("Missing hash key")
+            display("Bundle writer error: the hash method {} needs a hash key but none is loaded", method.name())
+        }
     }
 }
 
@@ -59,6 +63,10 @@ pub struct BundleWriter {
 
 impl BundleWriter {
     pub fn new(mode: BundleMode, hash_method: HashMethod, compression: Option<Compression>, encryption: Option<Encryption>, crypto: Arc<Mutex<Crypto>>) -> Result<Self, BundleWriterError> {
+        // Bundle ids must never fall back to unkeyed hashes
+        if hash_method.is_keyed() && !crypto.lock().unwrap().has_hash_key() {
+            return Err(BundleWriterError::MissingHashKey(hash_method))
+        }
         let compression_stream = match compression {
             Some(ref compression) => Some(try!(compression.compress_stream().map_err(BundleWriterError::CompressionSetup))),
             None => None
@@ -93,15 +101,19 @@ impl BundleWriter {
         if let Some(stream) = self.compression_stream {
             try!(stream.finish(&mut self.data).map_err(BundleWriterError::Compression))
         }
+        let data_key = match self.encryption {
+            Some(ref encryption) => try!(self.crypto.lock().unwrap().gen_data_key(encryption)),
+            None => None
+        };
         if let Some(ref encryption) = self.encryption {
-            self.data = try!(self.crypto.lock().unwrap().encrypt(encryption, &self.data));
+            self.data = try!(self.crypto.lock().unwrap().encrypt_with(encryption, data_key.as_ref().map(|k| &k[..]), &self.data));
         }
         let encoded_size = self.data.len();
         let mut chunk_data = Vec::with_capacity(self.chunks.encoded_size());
         self.chunks.write_to(&mut chunk_data).unwrap();
-        let id = BundleId(self.hash_method.hash(&chunk_data));
+        let id = BundleId(self.hash_method.hash_keyed(self.crypto.lock().unwrap().hash_key(), &chunk_data));
         if let Some(ref encryption) = self.encryption {
-            chunk_data = try!(self.crypto.lock().unwrap().encrypt(encryption, &chunk_data));
+            chunk_data = try!(self.crypto.lock().unwrap().encrypt_with(encryption, data_key.as_ref().map(|k| &k[..]), &chunk_data));
         }
         let mut path = db.layout.temp_bundle_path();
         let mut file = BufWriter::new(try!(File::create(&path).context(&path as &Path)));
@@ -121,11 +133,12 @@ impl BundleWriter {
         };
         let mut info_data = try!(msgpack::encode(&info).context(&path as &Path));
         if let Some(ref encryption) = self.encryption {
-            info_data = try!(self.crypto.lock().unwrap().encrypt(encryption, &info_data));
+            info_data = try!(self.crypto.lock().unwrap().encrypt_with(encryption, data_key.as_ref().map(|k| &k[..]), &info_data));
         }
         let header = BundleHeader {
             encryption: self.encryption,
-            info_size: info_data.len()
+            info_size: info_data.len(),
+            data_key: data_key
         };
         try!(msgpack::encode_to_stream(&header, &mut file).context(&path as &Path));
         try!(file.write_all(&info_data).context(&path as &Path));
@@ -142,6 +155,6 @@ impl BundleWriter {
 
     #[inline]
     pub fn estimate_final_size(&self) -> usize {
-        self.data.len() + self.chunk_count * 20 + 500
+        self.data.len() + self.chunk_count * (self.hash_method.hash_size() + 4) + 500
     }
 }
@@ -1,6 +1,14 @@
 pub use chunking::*;
 
 use std::str::FromStr;
+use std::cmp::min;
+
+use crossbeam;
+
+
+/// Chunks are held in memory while they are processed and have to fit into bundles, so larger
+/// chunks are not allowed
+pub const MAX_CHUNK_SIZE: usize = 16 * 1024 * 1024;
 
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
@@ -8,23 +16,71 @@ pub enum ChunkerType {
     Ae(usize),
     Rabin((usize, u32)),
     FastCdc((usize, u64)),
-    Fixed(usize)
+    Fixed(usize),
+    Buzhash((usize, u64)),
+    Gear((usize, u64)),
+    Tttd((usize, u64))
 }
 serde_impl!(ChunkerType(u64) {
     Ae(usize) => 1,
     Rabin((usize, u32)) => 2,
     FastCdc((usize, u64)) => 3,
-    Fixed(usize) => 4
+    Fixed(usize) => 4,
+    Buzhash((usize, u64)) => 5,
+    Gear((usize, u64)) => 6,
+    Tttd((usize, u64)) => 7
 });
 
 
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Default)]
+pub struct ChunkerLimits {
+    pub min_size: usize,
+    pub max_size: usize,
+    pub normalization: usize
+}
+serde_impl!(ChunkerLimits(u8) {
+    min_size: usize => 0,
+    max_size: usize => 1,
+    normalization: usize => 2
+});
+
+impl ChunkerLimits {
+    pub fn from_string(val: &str) -> Result<Self, &'static str> {
+        let (sizes, normalization) = if let Some(pos) = val.find('/') {
+            let normalization = try!(usize::from_str(&val[pos+1..]).map_err(|_| "Normalization level must be a number"));
+            (&val[..pos], normalization)
+        } else {
+            (val, 0)
+        };
+        let pos = try!(sizes.find('-').ok_or("Sizes must be given as MIN-MAX"));
+        let min_size = try!(usize::from_str(&sizes[..pos]).map_err(|_| "Minimum size must be a number"));
+        let max_size = try!(usize::from_str(&sizes[pos+1..]).map_err(|_| "Maximum size must be a number"));
+        Ok(ChunkerLimits {
+            min_size: try!(min_size.checked_mul(1024).ok_or("Minimum size is too large")),
+            max_size: try!(max_size.checked_mul(1024).ok_or("Maximum size is too large")),
+            normalization: normalization
+        })
+    }
+
+    pub fn to_string(&self) -> String {
+        format!("{}-{}/{}", self.min_size/1024, self.max_size/1024, self.normalization)
+    }
+}
+
+
 impl ChunkerType {
     pub fn from(name: &str, avg_size: usize, seed: u64) -> Result<Self, &'static str> {
+        if avg_size == 0 || avg_size > MAX_CHUNK_SIZE {
+            return Err("Chunk size must be between 1 byte and 16 MiB")
+        }
         match name {
             "ae" => Ok(ChunkerType::Ae(avg_size)),
             "rabin" => Ok(ChunkerType::Rabin((avg_size, seed as u32))),
             "fastcdc" => Ok(ChunkerType::FastCdc((avg_size, seed))),
             "fixed" => Ok(ChunkerType::Fixed(avg_size)),
+            "buzhash" => Ok(ChunkerType::Buzhash((avg_size, seed))),
+            "gear" => Ok(ChunkerType::Gear((avg_size, seed))),
+            "tttd" => Ok(ChunkerType::Tttd((avg_size, seed))),
             _ => Err("Unsupported chunker type")
         }
     }
@@ -37,26 +93,95 @@ impl ChunkerType {
         } else {
             (name, 8)
         };
-        Self::from(name, size * 1024, 0)
+        Self::from(name, try!(size.checked_mul(1024).ok_or("Chunk size is too large")), 0)
+    }
+
+
+    pub fn default_limits(&self) -> ChunkerLimits {
+        let (min_size, max_size, normalization) = match *self {
+            ChunkerType::Ae(size) => (0, size*256, 0),
+            ChunkerType::Rabin((size, _seed)) => (size/4, size*4, 0),
+            ChunkerType::FastCdc((size, _seed)) => (size/4, size*8, 2),
+            ChunkerType::Fixed(size) => (size, size, 0),
+            ChunkerType::Buzhash((size, _seed)) | ChunkerType::Tttd((size, _seed)) => (size/4, size*4, 0),
+            ChunkerType::Gear((size, _seed)) => (size/4, size*8, 0)
+        };
+        ChunkerLimits {
+            min_size: min_size,
+            max_size: min(max_size, MAX_CHUNK_SIZE),
+            normalization: normalization
+        }
     }
 
+    #[inline]
+    pub fn limits(&self, limits: Option<ChunkerLimits>) -> ChunkerLimits {
+        limits.unwrap_or_else(|| self.default_limits())
+    }
+
+    pub fn check_limits(&self, limits: &ChunkerLimits) -> Result<(), &'static str> {
+        let avg_size = self.avg_size();
+        if limits.min_size > avg_size || limits.max_size < avg_size {
+            return Err("Average chunk size must be between the minimum and maximum size")
+        }
+        if limits.max_size > MAX_CHUNK_SIZE {
+            return Err("Maximum chunk size must not be larger than 16 MiB")
+        }
+        match *self {
+            ChunkerType::Fixed(size) => if limits.min_size != size || limits.max_size != size {
+                return Err("Fixed chunker does not support minimum and maximum sizes")
+            },
+            ChunkerType::FastCdc(_) => {
+                let bits = (avg_size.next_power_of_two() - 1).count_ones() as usize;
+                if limits.normalization > 3 || limits.normalization >= bits {
+                    return Err("Normalization level must be between 0 and 3")
+                }
+                return Ok(())
+            },
+            _ => ()
+        }
+        if limits.normalization != 0 {
+            return Err("Only the fastcdc chunker supports normalization")
+        }
+        Ok(())
+    }
 
     #[inline]
-    pub fn create(&self) -> Box<Chunker> {
+    pub fn create(&self, limits: Option<ChunkerLimits>) -> Box<Chunker> {
+        let limits = self.limits(limits);
         match *self {
-            ChunkerType::Ae(size) => Box::new(AeChunker::new(size)),
-            ChunkerType::Rabin((size, seed)) => Box::new(RabinChunker::new(size, seed)),
-            ChunkerType::FastCdc((size, seed)) => Box::new(FastCdcChunker::new(size, seed)),
+            ChunkerType::Ae(size) => Box::new(AeChunker::with_limits(limits.min_size, size, limits.max_size)),
+            ChunkerType::Rabin((size, seed)) => Box::new(RabinChunker::with_limits(limits.min_size, size, limits.max_size, seed)),
+            ChunkerType::FastCdc((size, seed)) => Box::new(FastCdcChunker::with_limits(limits.min_size, size, limits.max_size, limits.normalization, seed)),
             ChunkerType::Fixed(size) => Box::new(FixedChunker::new(size)),
+            ChunkerType::Buzhash((size, seed)) => Box::new(BuzhashChunker::with_limits(limits.min_size, size, limits.max_size, seed)),
+            ChunkerType::Gear((size, seed)) => Box::new(GearChunker::with_limits(limits.min_size, size, limits.max_size, seed)),
+            ChunkerType::Tttd((size, seed)) => Box::new(TttdChunker::with_limits(limits.min_size, size, limits.max_size, seed)),
         }
     }
 
+    /// Returns the cut points of `data` like `find_cuts`, the data is split into regions that
+    /// are chunked on separate threads
+    pub fn find_cuts_parallel(&self, limits: Option<ChunkerLimits>, data: &[u8], threads: usize) -> Vec<usize> {
+        let step = (data.len() + threads - 1) / threads;
+        let regions: Vec<RegionCuts> = crossbeam::scope(|scope| {
+            let handles: Vec<_> = (0..threads).map(|i| {
+                let (start, end) = (min(i * step, data.len()), min((i + 1) * step, data.len()));
+                scope.spawn(move || find_region_cuts(&*self.create(limits), data, start, end))
+            }).collect();
+            handles.into_iter().map(|handle| handle.join()).collect()
+        });
+        merge_region_cuts(&*self.create(limits), data, &regions)
+    }
+
     pub fn name(&self) -> &'static str {
         match *self {
             ChunkerType::Ae(_size) => "ae",
             ChunkerType::Rabin((_size, _seed)) => "rabin",
             ChunkerType::FastCdc((_size, _seed)) => "fastcdc",
             ChunkerType::Fixed(_size) => "fixed",
+            ChunkerType::Buzhash((_size, _seed)) => "buzhash",
+            ChunkerType::Gear((_size, _seed)) => "gear",
+            ChunkerType::Tttd((_size, _seed)) => "tttd",
         }
     }
 
@@ -64,7 +189,8 @@ impl ChunkerType {
         match *self {
             ChunkerType::Ae(size) | ChunkerType::Fixed(size) => size,
             ChunkerType::Rabin((size, _seed)) => size,
-            ChunkerType::FastCdc((size, _seed)) => size
+            ChunkerType::FastCdc((size, _seed)) | ChunkerType::Buzhash((size, _seed)) => size,
+            ChunkerType::Gear((size, _seed)) | ChunkerType::Tttd((size, _seed)) => size
         }
     }
 
@@ -72,11 +198,24 @@ impl ChunkerType {
         format!("{}/{}", self.name(), self.avg_size()/1024)
     }
 
+    /// Returns the same chunker with another seed, chunkers without a seed are unchanged
+    pub fn with_seed(&self, seed: u64) -> Self {
+        match *self {
+            ChunkerType::Ae(_) | ChunkerType::Fixed(_) => *self,
+            ChunkerType::Rabin((size, _seed)) => ChunkerType::Rabin((size, seed as u32)),
+            ChunkerType::FastCdc((size, _seed)) => ChunkerType::FastCdc((size, seed)),
+            ChunkerType::Buzhash((size, _seed)) => ChunkerType::Buzhash((size, seed)),
+            ChunkerType::Gear((size, _seed)) => ChunkerType::Gear((size, seed)),
+            ChunkerType::Tttd((size, _seed)) => ChunkerType::Tttd((size, seed))
+        }
+    }
+
     pub fn seed(&self) -> u64 {
         match *self {
             ChunkerType::Ae(_size) | ChunkerType::Fixed(_size) => 0,
             ChunkerType::Rabin((_size, seed)) => seed as u64,
-            ChunkerType::FastCdc((_size, seed)) => seed
+            ChunkerType::FastCdc((_size, seed)) | ChunkerType::Buzhash((_size, seed)) => seed,
+            ChunkerType::Gear((_size, seed)) | ChunkerType::Tttd((_size, seed)) => seed
         }
     }
 }
@@ -41,7 +41,7 @@ fn chunk(data: &[u8], mut chunker: Box<Chunker>, sink: &mut ChunkSink) {
 }
 
 #[allow(dead_code)]
-pub fn run(path: &str, bundle_size: usize, chunker: ChunkerType, compression: Option<Compression>, encrypt: bool,hash: HashMethod) {
+pub fn run(path: &str, bundle_size: usize, chunker: ChunkerType, chunker_limits: Option<ChunkerLimits>, format_aware_chunking: bool, compression: Option<Compression>, encrypt: bool,hash: HashMethod) {
     let mut total_write_time = 0.0;
     let mut total_read_time = 0.0;
 
@@ -57,13 +57,23 @@ pub fn run(path: &str, bundle_size: usize, chunker: ChunkerType, compression: Op
 
     println!();
 
-    println!("Chunking data with {}, avg chunk size {} ...", chunker.name(), to_file_size(chunker.avg_size() as u64));
+    let limits = chunker.limits(chunker_limits);
+    println!("Chunking data with {}, avg chunk size {}, limits {} - {}, normalization {} ...", chunker.name(), to_file_size(chunker.avg_size() as u64), to_file_size(limits.min_size as u64), to_file_size(limits.max_size as u64), limits.normalization);
     let mut chunk_sink = ChunkSink {
         chunks: Vec::with_capacity(2*size as usize/chunker.avg_size()),
         written: 0,
         pos: 0
     };
-    let chunker = chunker.create();
+    let plain_chunker = if format_aware_chunking {
+        println!("- format-aware, detected format: {}", detect_format(&data).map(|f| f.name()).unwrap_or("none"));
+        Some(chunker.create(chunker_limits))
+    } else {
+        None
+    };
+    let mut chunker = chunker.create(chunker_limits);
+    if format_aware_chunking {
+        chunker = Box::new(FormatChunker::new(chunker));
+    }
     let chunk_time = Duration::span(|| {
         chunk(&data, chunker, &mut chunk_sink)
     }).num_milliseconds() as f32 / 1_000.0;
@@ -74,14 +84,18 @@ pub fn run(path: &str, bundle_size: usize, chunker: ChunkerType, compression: Op
     let chunk_size_avg = size as f32 / chunks.len() as f32;
     let chunk_size_stddev = (chunks.iter().map(|c| (c.1 as f32 - chunk_size_avg).powi(2)).sum::<f32>() / (chunks.len() as f32 - 1.0)).sqrt();
     println!("- {} chunks, avg size: {} ±{}", chunks.len(), to_file_size(chunk_size_avg as u64), to_file_size(chunk_size_stddev as u64));
+    let chunk_size_min = chunks.iter().map(|c| c.1).min().unwrap_or(0);
+    let chunk_size_max = chunks.iter().map(|c| c.1).max().unwrap_or(0);
+    println!("- smallest chunk: {}, largest chunk: {}", to_file_size(chunk_size_min as u64), to_file_size(chunk_size_max as u64));
 
     println!();
 
     println!("Hashing chunks with {} ...", hash.name());
     let mut hashes = Vec::with_capacity(chunks.len());
+    let hash_key = [0u8; HASH_KEY_SIZE];
     let hash_time = Duration::span(|| {
         for &(pos, len) in &chunks {
-            hashes.push(hash.hash(&data[pos..pos+len]))
+            hashes.push(hash.hash_keyed(&hash_key, &data[pos..pos+len]))
         }
     }).num_milliseconds() as f32 / 1_000.0;
     total_write_time += hash_time;
@@ -100,6 +114,19 @@ pub fn run(path: &str, bundle_size: usize, chunker: ChunkerType, compression: Op
         dup_size += len;
     }
     println!("- {} duplicate chunks, {}, {:.1}% saved", dups.len(), to_file_size(dup_size as u64), dup_size as f32 / size as f32*100.0);
+    if let Some(plain_chunker) = plain_chunker {
+        let mut plain_sink = ChunkSink {
+            chunks: Vec::with_capacity(chunks.len() + dups.len()),
+            written: 0,
+            pos: 0
+        };
+        chunk(&data, plain_chunker, &mut plain_sink);
+        let mut seen_hashes = HashSet::with_capacity(plain_sink.chunks.len());
+        let plain_dup_size = plain_sink.chunks.iter().filter(|&&(pos, len)| {
+            !seen_hashes.insert(hash.hash_keyed(&hash_key, &data[pos..pos+len]))
+        }).map(|&(_, len)| len).sum::<usize>();
+        println!("- without format-aware chunking: {} chunks, {} duplicate, {:.1}% saved", plain_sink.chunks.len(), to_file_size(plain_dup_size as u64), plain_dup_size as f32 / size as f32*100.0);
+    }
     size -= dup_size as u64;
 
     let mut bundles = Vec::new();
@@ -146,7 +173,7 @@ pub fn run(path: &str, bundle_size: usize, chunker: ChunkerType, compression: Op
         let (public, secret) = Crypto::gen_keypair();
         let mut crypto = Crypto::dummy();
         crypto.add_secret_key(public, secret);
-        let encryption = (EncryptionMethod::Sodium, public[..].to_vec().into());
+        let encryption = Crypto::encryption_for(&[public]).unwrap();
 
         println!("Encrypting bundles...");
         let mut encrypted_bundles = Vec::with_capacity(bundles.len());
@@ -10,9 +10,15 @@ pub enum Arguments {
         repo_path: PathBuf,
         bundle_size: usize,
         chunker: ChunkerType,
+        chunker_limits: Option<ChunkerLimits>,
+        format_aware_chunking: bool,
         compression: Option<Compression>,
+        adaptive_compression: bool,
         encryption: bool,
+        passphrase: bool,
         hash: HashMethod,
+        redundancy: Option<Redundancy>,
+        hide_backup_names: bool,
         remote_path: String
     },
     Backup {
@@ -62,6 +68,7 @@ pub enum Arguments {
         bundles: bool,
         bundle_data: bool,
         index: bool,
+        chunk_refs: bool,
         repair: bool
     },
     List {
@@ -108,33 +115,117 @@ pub enum Arguments {
         repo_path: PathBuf,
         bundle_id: BundleId
     },
+    IndexInfo {
+        repo_path: PathBuf,
+        bundles: bool,
+        history: bool
+    },
+    Upgrade {
+        repo_path: PathBuf
+    },
     Import {
         repo_path: PathBuf,
         remote_path: String,
-        key_files: Vec<String>
+        key_files: Vec<String>,
+        signing_key: Option<String>
     },
     Config {
         repo_path: PathBuf,
         bundle_size: Option<usize>,
         chunker: Option<ChunkerType>,
+        chunker_limits: Option<Option<ChunkerLimits>>,
+        format_aware_chunking: Option<bool>,
         compression: Option<Option<Compression>>,
-        encryption: Option<Option<PublicKey>>,
-        hash: Option<HashMethod>
+        adaptive_compression: Option<bool>,
+        encryption: Option<Vec<PublicKey>>,
+        hash: Option<HashMethod>,
+        redundancy: Option<Option<Redundancy>>,
+        kdf_cost: Option<String>,
+        hide_backup_names: Option<bool>,
+        index_filter: Option<Option<IndexFilter>>
+    },
+    TrainDict {
+        repo_path: PathBuf,
+        size: usize
     },
     GenKey {
         file: Option<String>,
-        password: Option<String>
+        password: Option<String>,
+        salt: Option<Vec<u8>>,
+        kdf_cost: String,
+        legacy: bool,
+        passphrase: bool
     },
     AddKey {
         repo_path: PathBuf,
         file: Option<String>,
         password: Option<String>,
+        legacy: bool,
+        passphrase: bool,
         set_default: bool
     },
+    Passphrase {
+        repo_path: PathBuf,
+        remove: bool
+    },
+    Rekey {
+        repo_path: PathBuf,
+        force: bool,
+        remove_keys: bool
+    },
+    SplitKey {
+        file: String,
+        threshold: u8,
+        shares: u8
+    },
+    JoinKey {
+        file: Option<String>,
+        passphrase: bool
+    },
+    KeyList {
+        repo_path: PathBuf
+    },
+    SignKey {
+        repo_path: PathBuf,
+        generate: bool,
+        import: Option<String>,
+        export: Option<String>,
+        public: bool,
+        passphrase: bool
+    },
+    AppendOnly {
+        repo_path: PathBuf,
+        enable: Option<PublicKey>,
+        disable: bool,
+        admin_key: Option<String>
+    },
+    Deletions {
+        repo_path: PathBuf,
+        admin_key: Option<String>,
+        execute: bool,
+        discard: bool
+    },
+    KeyShow {
+        repo_path: PathBuf,
+        key: String
+    },
+    KeyRemove {
+        repo_path: PathBuf,
+        key: String,
+        force: bool
+    },
+    KeyExport {
+        repo_path: PathBuf,
+        key: String,
+        passphrase: bool,
+        file: Option<String>
+    },
     AlgoTest {
         file: String,
         bundle_size: usize,
         chunker: ChunkerType,
+        chunker_limits: Option<ChunkerLimits>,
+        format_aware_chunking: bool,
         compression: Option<Compression>,
         encrypt: bool,
         hash: HashMethod
@@ -225,6 +316,18 @@ fn validate_chunker(val: String) -> Result<(), String> {
     parse_chunker(&val).map(|_| ())
 }
 
+fn parse_chunker_limits(val: &str) -> Result<Option<ChunkerLimits>, String> {
+    if val == "default" {
+        return Ok(None)
+    }
+    ChunkerLimits::from_string(val).map(Some).map_err(|err| err.to_string())
+}
+
+#[allow(unknown_lints,needless_pass_by_value)]
+fn validate_chunker_limits(val: String) -> Result<(), String> {
+    parse_chunker_limits(&val).map(|_| ())
+}
+
 fn parse_compression(val: &str) -> Result<Option<Compression>, String> {
     if val == "none" {
         return Ok(None)
@@ -241,21 +344,75 @@ fn validate_compression(val: String) -> Result<(), String> {
     parse_compression(&val).map(|_| ())
 }
 
-fn parse_public_key(val: &str) -> Result<Option<PublicKey>, String> {
+fn parse_bool(val: &str) -> Result<bool, String> {
+    match &val.to_lowercase() as &str {
+        "yes" | "true" | "on" => Ok(true),
+        "no" | "false" | "off" => Ok(false),
+        _ => Err("Invalid value, must be yes or no".to_string())
+    }
+}
+
+#[allow(unknown_lints,needless_pass_by_value)]
+fn validate_bool(val: String) -> Result<(), String> {
+    parse_bool(&val).map(|_| ())
+}
+
+fn parse_redundancy(val: &str) -> Result<Option<Redundancy>, String> {
+    if val == "none" {
+        return Ok(None)
+    }
+    Redundancy::from_string(val).map(Some).map_err(|err| err.to_string())
+}
+
+#[allow(unknown_lints,needless_pass_by_value)]
+fn validate_redundancy(val: String) -> Result<(), String> {
+    parse_redundancy(&val).map(|_| ())
+}
+
+fn parse_index_filter(val: &str) -> Result<Option<IndexFilter>, String> {
+    if val == "none" {
+        return Ok(None)
+    }
+    IndexFilter::from_string(val).map(Some).map_err(|err| err.to_string())
+}
+
+#[allow(unknown_lints,needless_pass_by_value)]
+fn validate_index_filter(val: String) -> Result<(), String> {
+    parse_index_filter(&val).map(|_| ())
+}
+
+fn parse_public_keys(val: &str) -> Result<Vec<PublicKey>, String> {
     if val.to_lowercase() == "none" {
-        return Ok(None);
+        return Ok(vec![]);
     }
-    let bytes = match parse_hex(val) {
-        Ok(bytes) => bytes,
-        Err(_) => {
-            return Err("Invalid hexadecimal".to_string());
+    let mut keys = vec![];
+    for val in val.split(',') {
+        let bytes = match parse_hex(val.trim()) {
+            Ok(bytes) => bytes,
+            Err(_) => {
+                return Err("Invalid hexadecimal".to_string());
+            }
+        };
+        if let Some(key) = PublicKey::from_slice(&bytes) {
+            keys.push(key)
+        } else {
+            return Err("Invalid key".to_string())
         }
-    };
-    if let Some(key) = PublicKey::from_slice(&bytes) {
-        Ok(Some(key))
-    } else {
-        return Err("Invalid key".to_string())
     }
+    Ok(keys)
+}
+
+#[allow(unknown_lints,needless_pass_by_value)]
+fn validate_public_keys(val: String) -> Result<(), String> {
+    parse_public_keys(&val).map(|_| ())
+}
+
+fn parse_public_key(val: &str) -> Result<PublicKey, String> {
+    let mut keys = try!(parse_public_keys(val));
+    if keys.len() != 1 {
+        return Err("Expected exactly one key".to_string())
+    }
+    Ok(keys.remove(0))
 }
 
 #[allow(unknown_lints,needless_pass_by_value)]
@@ -263,6 +420,36 @@ fn validate_public_key(val: String) -> Result<(), String> {
     parse_public_key(&val).map(|_| ())
 }
 
+fn parse_share_count(val: &str) -> Result<u8, String> {
+    match val.parse::<u8>() {
+        Ok(num) if num >= 2 => Ok(num),
+        _ => Err("Must be a number between 2 and 255".to_string())
+    }
+}
+
+#[allow(unknown_lints,needless_pass_by_value)]
+fn validate_share_count(val: String) -> Result<(), String> {
+    parse_share_count(&val).map(|_| ())
+}
+
+fn parse_salt(val: &str) -> Result<Vec<u8>, String> {
+    let bytes = try!(parse_hex(val).map_err(|_| "Invalid hexadecimal".to_string()));
+    if bytes.len() != 32 {
+        return Err("Salt must be 32 bytes long".to_string())
+    }
+    Ok(bytes)
+}
+
+#[allow(unknown_lints,needless_pass_by_value)]
+fn validate_salt(val: String) -> Result<(), String> {
+    parse_salt(&val).map(|_| ())
+}
+
+#[allow(unknown_lints,needless_pass_by_value)]
+fn validate_kdf_cost(val: String) -> Result<(), String> {
+    KeyDerivation::parse_cost(&val).map(|_| ()).map_err(|err| err.to_string())
+}
+
 fn parse_hash(val: &str) -> Result<HashMethod, String> {
     if let Ok(hash) = HashMethod::from(val) {
         Ok(hash)
@@ -316,11 +503,21 @@ pub fn parse() -> Result<(LogLevel, Arguments), ErrorCode> {
                 .default_value(DEFAULT_BUNDLE_SIZE_STR).validator(validate_num))
             .arg(Arg::from_usage("--chunker [CHUNKER] 'Set the chunker algorithm and target chunk size'")
                 .default_value(DEFAULT_CHUNKER).validator(validate_chunker))
-            .arg(Arg::from_usage("-c --compression [COMPRESSION] 'Set the compression method and level'")
+            .arg(Arg::from_usage("[chunker_limits] --chunker-limits [LIMITS] 'Set the minimum and maximum chunk size in KiB and the normalization level (min-max/level or default)'")
+                .default_value("default").validator(validate_chunker_limits))
+            .arg(Arg::from_usage("--format-aware 'Cut chunks at member and block boundaries of tar, zip, qcow2 and disk image files'"))
+            .arg(Arg::from_usage("-c --compression [COMPRESSION] 'Set the compression method and level (zstd also supports /long)'")
                 .default_value(DEFAULT_COMPRESSION).validator(validate_compression))
+            .arg(Arg::from_usage("--adaptive-compression 'Store incompressible data uncompressed'"))
             .arg(Arg::from_usage("-e --encrypt 'Generate a keypair and enable encryption'"))
+            .arg(Arg::from_usage("--passphrase 'Protect the generated key file with a passphrase'")
+                .requires("encrypt"))
             .arg(Arg::from_usage("--hash [HASH] 'Set the hash method'")
                 .default_value(DEFAULT_HASH).validator(validate_hash))
+            .arg(Arg::from_usage("--redundancy [REDUNDANCY] 'Write parity data for groups of bundles (bundles/parity)'")
+                .default_value("none").validator(validate_redundancy))
+            .arg(Arg::from_usage("--hide-backup-names 'Store backups under opaque ids with encrypted names'")
+                .requires("encrypt"))
             .arg(Arg::from_usage("-r --remote <REMOTE> 'Set the path to the mounted remote storage'")
                 .validator(validate_existing_path))
             .arg(Arg::from_usage("<REPO> 'The path for the new repository'")
@@ -372,6 +569,7 @@ pub fn parse() -> Result<(LogLevel, Arguments), ErrorCode> {
             .arg(Arg::from_usage("-b --bundles 'Check the bundles'"))
             .arg(Arg::from_usage("[bundle_data] --bundle-data 'Check bundle contents (slow)'").requires("bundles").alias("data"))
             .arg(Arg::from_usage("-i --index 'Check the chunk index'"))
+            .arg(Arg::from_usage("[chunk_refs] --chunk-refs 'Verify the chunk reference counts by scanning all backups'"))
             .arg(Arg::from_usage("-r --repair 'Try to repair errors'"))
             .arg(Arg::from_usage("<PATH> 'Path of the repository/backup/subtree, [repository][::backup[::subtree]]'")
                 .validator(|val| validate_repo_path(val, true, None, None))))
@@ -390,8 +588,18 @@ pub fn parse() -> Result<(LogLevel, Arguments), ErrorCode> {
             .arg(Arg::from_usage("<REPO> 'Path of the repository'")
                 .validator(|val| validate_repo_path(val, true, Some(false), Some(false))))
             .arg(Arg::from_usage("<BUNDLE> 'Id of the bundle'")))
+        .subcommand(SubCommand::with_name("indexinfo").about("Display statistics on the index")
+            .arg(Arg::from_usage("--bundles 'List the number of index entries of every bundle'"))
+            .arg(Arg::from_usage("--history 'List the size of the index at every time it has been saved'"))
+            .arg(Arg::from_usage("<REPO> 'Path of the repository'")
+                .validator(|val| validate_repo_path(val, true, Some(false), Some(false)))))
+        .subcommand(SubCommand::with_name("upgrade").about("Upgrade the local files of a repository to the current format versions")
+            .arg(Arg::from_usage("<REPO> 'Path of the repository'")
+                .validator(|val| validate_repo_path(val, true, Some(false), Some(false)))))
         .subcommand(SubCommand::with_name("import").about("Reconstruct a repository from the remote storage")
             .arg(Arg::from_usage("-k --key [FILE]... 'Key file needed to read the bundles'"))
+            .arg(Arg::from_usage("[signing_key] --signing-key [FILE] 'Signing key file to verify the backups'")
+                .validator(validate_existing_path))
             .arg(Arg::from_usage("<REMOTE> 'Remote repository path'")
                 .validator(validate_existing_path))
             .arg(Arg::from_usage("<REPO> 'The path for the new repository'")
@@ -421,16 +629,53 @@ pub fn parse() -> Result<(LogLevel, Arguments), ErrorCode> {
                 .validator(validate_num))
             .arg(Arg::from_usage("--chunker [CHUNKER] 'Set the chunker algorithm and target chunk size'")
                 .validator(validate_chunker))
-            .arg(Arg::from_usage("-c --compression [COMPRESSION] 'Set the compression method and level'")
+            .arg(Arg::from_usage("[chunker_limits] --chunker-limits [LIMITS] 'Set the minimum and maximum chunk size in KiB and the normalization level (min-max/level or default)'")
+                .validator(validate_chunker_limits))
+            .arg(Arg::from_usage("[format_aware] --format-aware [ENABLED] 'Cut chunks at member and block boundaries of tar, zip, qcow2 and disk image files (yes/no)'")
+                .validator(validate_bool))
+            .arg(Arg::from_usage("-c --compression [COMPRESSION] 'Set the compression method and level (zstd also supports /long)'")
                 .validator(validate_compression))
-            .arg(Arg::from_usage("-e --encryption [PUBLIC_KEY] 'The public key to use for encryption'")
-                .validator(validate_public_key))
+            .arg(Arg::from_usage("[adaptive_compression] --adaptive-compression [ENABLED] 'Store incompressible data uncompressed (yes/no)'")
+                .validator(validate_bool))
+            .arg(Arg::from_usage("-e --encryption [PUBLIC_KEYS] 'The public keys to use for encryption, separated by commas'")
+                .validator(validate_public_keys))
             .arg(Arg::from_usage("--hash [HASH] 'Set the hash method'")
                 .validator(validate_hash))
+            .arg(Arg::from_usage("--redundancy [REDUNDANCY] 'Write parity data for groups of bundles (bundles/parity)'")
+                .validator(validate_redundancy))
+            .arg(Arg::from_usage("[kdf_cost] --kdf-cost [COST] 'Set the cost of deriving key pairs from passwords'")
+                .validator(validate_kdf_cost))
+            .arg(Arg::from_usage("[hide_backup_names] --hide-backup-names [ENABLED] 'Store backups under opaque ids with encrypted names (yes/no)'")
+                .validator(validate_bool))
+            .arg(Arg::from_usage("[index_filter] --index-filter [FILTER] 'Keep a bloom filter in front of the index (rate%/memory in MiB or none)'")
+                .validator(validate_index_filter))
+            .arg(Arg::from_usage("<REPO> 'Path of the repository'")
+                .validator(|val| validate_repo_path(val, true, Some(false), Some(false)))))
+        .subcommand(SubCommand::with_name("traindict").about("Train a compression dictionary for meta bundles")
+            .arg(Arg::from_usage("-s --size [SIZE] 'Set the maximal dictionary size in KiB'")
+                .default_value(DEFAULT_DICTIONARY_SIZE_STR).validator(validate_num))
             .arg(Arg::from_usage("<REPO> 'Path of the repository'")
                 .validator(|val| validate_repo_path(val, true, Some(false), Some(false)))))
         .subcommand(SubCommand::with_name("genkey").about("Generate a new key pair")
             .arg(Arg::from_usage("-p --password [PASSWORD] 'Derive the key pair from the given password'"))
+            .arg(Arg::from_usage("--salt [SALT] 'Use this salt to derive the key pair (hex)'")
+                .requires("password").validator(validate_salt))
+            .arg(Arg::from_usage("[kdf_cost] --kdf-cost [COST] 'Set the cost of deriving the key pair'")
+                .default_value(DEFAULT_KDF_COST).validator(validate_kdf_cost))
+            .arg(Arg::from_usage("--legacy 'Derive the key pair with the old scheme (fixed salt)'")
+                .requires("password").conflicts_with("salt"))
+            .arg(Arg::from_usage("--passphrase 'Protect the key file with a passphrase'")
+                .requires("FILE"))
+            .arg(Arg::from_usage("[FILE] 'Destination file for the keypair'")))
+        .subcommand(SubCommand::with_name("splitkey").about("Split a key pair into shares")
+            .arg(Arg::from_usage("-t --threshold <NUM> 'Number of shares needed to restore the key pair'")
+                .validator(validate_share_count))
+            .arg(Arg::from_usage("-n --shares <NUM> 'Number of shares to create'")
+                .validator(validate_share_count))
+            .arg(Arg::from_usage("<FILE> 'The key file to split'")))
+        .subcommand(SubCommand::with_name("joinkey").about("Restore a key pair from shares")
+            .arg(Arg::from_usage("--passphrase 'Protect the key file with a passphrase'")
+                .requires("FILE"))
             .arg(Arg::from_usage("[FILE] 'Destination file for the keypair'")))
         .subcommand(SubCommand::with_name("addkey").about("Add a key pair to the repository")
             .arg(Arg::from_usage("-g --generate 'Generate a new key pair'")
@@ -438,16 +683,82 @@ pub fn parse() -> Result<(LogLevel, Arguments), ErrorCode> {
             .arg(Arg::from_usage("[set_default] --default -d 'Set the key pair as default'"))
             .arg(Arg::from_usage("-p --password [PASSWORD] 'Derive the key pair from the given password'")
                 .requires("generate"))
+            .arg(Arg::from_usage("--legacy 'Derive the key pair with the old scheme (fixed salt)'")
+                .requires("password"))
+            .arg(Arg::from_usage("--passphrase 'Protect the stored key file with a passphrase'"))
             .arg(Arg::from_usage("[FILE] 'File containing the keypair'")
                 .validator(validate_existing_path))
             .arg(Arg::from_usage("<REPO> 'Path of the repository'")
                 .validator(|val| validate_repo_path(val, true, Some(false), Some(false)))))
+        .subcommand(SubCommand::with_name("passphrase").about("Change the passphrase of the key files")
+            .arg(Arg::from_usage("--remove 'Store the key files without passphrase'"))
+            .arg(Arg::from_usage("<REPO> 'Path of the repository'")
+                .validator(|val| validate_repo_path(val, true, Some(false), Some(false)))))
+        .subcommand(SubCommand::with_name("signkey").about("Manage the key that signs backups and the config")
+            .arg(Arg::from_usage("-g --generate 'Generate a new signing key and sign the config and all backups'")
+                .conflicts_with_all(&["import", "export"]))
+            .arg(Arg::from_usage("--import [FILE] 'Use the signing key from the given file'")
+                .validator(validate_existing_path).conflicts_with("export"))
+            .arg(Arg::from_usage("--export [FILE] 'Write the signing key to the given file'"))
+            .arg(Arg::from_usage("--public 'Only export the public signing key'")
+                .requires("export"))
+            .arg(Arg::from_usage("--passphrase 'Protect the generated secret signing key with a passphrase'")
+                .requires("generate"))
+            .arg(Arg::from_usage("<REPO> 'Path of the repository'")
+                .validator(|val| validate_repo_path(val, true, Some(false), Some(false)))))
+        .subcommand(SubCommand::with_name("appendonly").about("Make the repository append-only or lift the mode")
+            .arg(Arg::from_usage("--enable [PUBLIC_KEY] 'Make the repository append-only with the given administrator key'")
+                .validator(validate_public_key).conflicts_with("disable"))
+            .arg(Arg::from_usage("--disable 'Lift the append-only mode'")
+                .requires("admin_key"))
+            .arg(Arg::from_usage("[admin_key] --admin-key [FILE] 'Key file containing the administrator key pair'")
+                .validator(validate_existing_path))
+            .arg(Arg::from_usage("<REPO> 'Path of the repository'")
+                .validator(|val| validate_repo_path(val, true, Some(false), Some(false)))))
+        .subcommand(SubCommand::with_name("deletions").about("List, execute or discard queued deletion requests")
+            .arg(Arg::from_usage("[admin_key] --admin-key [FILE] 'Key file containing the administrator key pair'")
+                .validator(validate_existing_path))
+            .arg(Arg::from_usage("--execute 'Execute all queued deletion requests'")
+                .requires("admin_key").conflicts_with("discard"))
+            .arg(Arg::from_usage("--discard 'Discard all queued deletion requests'")
+                .requires("admin_key"))
+            .arg(Arg::from_usage("<REPO> 'Path of the repository'")
+                .validator(|val| validate_repo_path(val, true, Some(false), Some(false)))))
+        .subcommand(SubCommand::with_name("rekey").about("Reencrypt all bundles and backups with the current key")
+            .arg(Arg::from_usage("-f --force 'Actually run the rekey instead of simulating it'"))
+            .arg(Arg::from_usage("[remove_keys] --remove-keys 'Remove the key pairs that are not used anymore'").requires("force"))
+            .arg(Arg::from_usage("<REPO> 'Path of the repository'")
+                .validator(|val| validate_repo_path(val, true, Some(false), Some(false)))))
+        .subcommand(SubCommand::with_name("key").about("List, inspect, remove and export key pairs")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(SubCommand::with_name("list").alias("ls").about("List all key pairs with their fingerprints and usage")
+                .arg(Arg::from_usage("<REPO> 'Path of the repository'")
+                    .validator(|val| validate_repo_path(val, true, Some(false), Some(false)))))
+            .subcommand(SubCommand::with_name("show").about("Display information on a key pair")
+                .arg(Arg::from_usage("<REPO> 'Path of the repository'")
+                    .validator(|val| validate_repo_path(val, true, Some(false), Some(false))))
+                .arg(Arg::from_usage("<KEY> 'Public key or fingerprint of the key pair'")))
+            .subcommand(SubCommand::with_name("remove").aliases(&["rm", "delete", "del"]).about("Remove a key pair from the repository")
+                .arg(Arg::from_usage("-f --force 'Remove the key pair even if it is still used'"))
+                .arg(Arg::from_usage("<REPO> 'Path of the repository'")
+                    .validator(|val| validate_repo_path(val, true, Some(false), Some(false))))
+                .arg(Arg::from_usage("<KEY> 'Public key or fingerprint of the key pair'")))
+            .subcommand(SubCommand::with_name("export").about("Export a key pair to a key file")
+                .arg(Arg::from_usage("--passphrase 'Protect the exported key file with a passphrase'")
+                    .requires("FILE"))
+                .arg(Arg::from_usage("<REPO> 'Path of the repository'")
+                    .validator(|val| validate_repo_path(val, true, Some(false), Some(false))))
+                .arg(Arg::from_usage("<KEY> 'Public key or fingerprint of the key pair'"))
+                .arg(Arg::from_usage("[FILE] 'Destination file for the key pair'"))))
         .subcommand(SubCommand::with_name("algotest").about("Test a specific algorithm combination")
             .arg(Arg::from_usage("[bundle_size] --bundle-size [SIZE] 'Set the target bundle size in MiB'")
                 .default_value(DEFAULT_BUNDLE_SIZE_STR).validator(validate_num))
             .arg(Arg::from_usage("--chunker [CHUNKER] 'Set the chunker algorithm and target chunk size'")
                 .default_value(DEFAULT_CHUNKER).validator(validate_chunker))
-            .arg(Arg::from_usage("-c --compression [COMPRESSION] 'Set the compression method and level'")
+            .arg(Arg::from_usage("[chunker_limits] --chunker-limits [LIMITS] 'Set the minimum and maximum chunk size in KiB and the normalization level (min-max/level or default)'")
+                .default_value("default").validator(validate_chunker_limits))
+            .arg(Arg::from_usage("--format-aware 'Cut chunks at member and block boundaries of tar, zip, qcow2 and disk image files'"))
+            .arg(Arg::from_usage("-c --compression [COMPRESSION] 'Set the compression method and level (zstd also supports /long)'")
                 .default_value(DEFAULT_COMPRESSION).validator(validate_compression))
             .arg(Arg::from_usage("-e --encrypt 'Generate a keypair and enable encryption'"))
             .arg(Arg::from_usage("--hash [HASH] 'Set the hash method'")
@@ -468,9 +779,15 @@ pub fn parse() -> Result<(LogLevel, Arguments), ErrorCode> {
             Arguments::Init {
                 bundle_size: (parse_num(args.value_of("bundle_size").unwrap()).unwrap() * 1024 * 1024) as usize,
                 chunker: parse_chunker(args.value_of("chunker").unwrap()).unwrap(),
+                chunker_limits: parse_chunker_limits(args.value_of("chunker_limits").unwrap()).unwrap(),
+                format_aware_chunking: args.is_present("format-aware"),
                 compression: parse_compression(args.value_of("compression").unwrap()).unwrap(),
+                adaptive_compression: args.is_present("adaptive-compression"),
                 encryption: args.is_present("encrypt"),
+                passphrase: args.is_present("passphrase"),
                 hash: parse_hash(args.value_of("hash").unwrap()).unwrap(),
+                redundancy: parse_redundancy(args.value_of("redundancy").unwrap()).unwrap(),
+                hide_backup_names: args.is_present("hide-backup-names"),
                 repo_path: repository,
                 remote_path: args.value_of("remote").unwrap().to_string()
             }
@@ -539,6 +856,7 @@ pub fn parse() -> Result<(LogLevel, Arguments), ErrorCode> {
                 bundles: args.is_present("bundles"),
                 bundle_data: args.is_present("bundle_data"),
                 index: args.is_present("index"),
+                chunk_refs: args.is_present("chunk_refs"),
                 repair: args.is_present("repair")
             }
         },
@@ -563,6 +881,20 @@ pub fn parse() -> Result<(LogLevel, Arguments), ErrorCode> {
                 bundle_id: try!(parse_bundle_id(args.value_of("BUNDLE").unwrap()))
             }
         },
+        ("indexinfo", Some(args)) => {
+            let (repository, _backup, _inode) = parse_repo_path(args.value_of("REPO").unwrap(), true, Some(false), Some(false)).unwrap();
+            Arguments::IndexInfo {
+                repo_path: repository,
+                bundles: args.is_present("bundles"),
+                history: args.is_present("history")
+            }
+        },
+        ("upgrade", Some(args)) => {
+            let (repository, _backup, _inode) = parse_repo_path(args.value_of("REPO").unwrap(), true, Some(false), Some(false)).unwrap();
+            Arguments::Upgrade {
+                repo_path: repository
+            }
+        },
         ("info", Some(args)) => {
             let (repository, backup, inode) = parse_repo_path(args.value_of("PATH").unwrap(), true, None, None).unwrap();
             Arguments::Info {
@@ -620,7 +952,8 @@ pub fn parse() -> Result<(LogLevel, Arguments), ErrorCode> {
             Arguments::Import {
                 repo_path: repository,
                 remote_path: args.value_of("REMOTE").unwrap().to_string(),
-                key_files: args.values_of("key").map(|v| v.map(|k| k.to_string()).collect()).unwrap_or_else(|| vec![])
+                key_files: args.values_of("key").map(|v| v.map(|k| k.to_string()).collect()).unwrap_or_else(|| vec![]),
+                signing_key: args.value_of("signing_key").map(|v| v.to_string())
             }
         },
         ("config", Some(args)) => {
@@ -628,16 +961,47 @@ pub fn parse() -> Result<(LogLevel, Arguments), ErrorCode> {
             Arguments::Config {
                 bundle_size: args.value_of("bundle_size").map(|v| parse_num(v).unwrap() as usize * 1024 * 1024),
                 chunker: args.value_of("chunker").map(|v| parse_chunker(v).unwrap()),
+                chunker_limits: args.value_of("chunker_limits").map(|v| parse_chunker_limits(v).unwrap()),
+                format_aware_chunking: args.value_of("format_aware").map(|v| parse_bool(v).unwrap()),
                 compression: args.value_of("compression").map(|v| parse_compression(v).unwrap()),
-                encryption: args.value_of("encryption").map(|v| parse_public_key(v).unwrap()),
+                adaptive_compression: args.value_of("adaptive_compression").map(|v| parse_bool(v).unwrap()),
+                encryption: args.value_of("encryption").map(|v| parse_public_keys(v).unwrap()),
                 hash: args.value_of("hash").map(|v| parse_hash(v).unwrap()),
+                redundancy: args.value_of("redundancy").map(|v| parse_redundancy(v).unwrap()),
+                kdf_cost: args.value_of("kdf_cost").map(|v| v.to_string()),
+                hide_backup_names: args.value_of("hide_backup_names").map(|v| parse_bool(v).unwrap()),
+                index_filter: args.value_of("index_filter").map(|v| parse_index_filter(v).unwrap()),
+                repo_path: repository,
+            }
+        },
+        ("traindict", Some(args)) => {
+            let (repository, _backup, _inode) = parse_repo_path(args.value_of("REPO").unwrap(), true, Some(false), Some(false)).unwrap();
+            Arguments::TrainDict {
                 repo_path: repository,
+                size: parse_num(args.value_of("size").unwrap()).unwrap() as usize * 1024
             }
         },
         ("genkey", Some(args)) => {
             Arguments::GenKey {
                 file: args.value_of("FILE").map(|v| v.to_string()),
-                password: args.value_of("password").map(|v| v.to_string())
+                password: args.value_of("password").map(|v| v.to_string()),
+                salt: args.value_of("salt").map(|v| parse_salt(v).unwrap()),
+                kdf_cost: args.value_of("kdf_cost").unwrap().to_string(),
+                legacy: args.is_present("legacy"),
+                passphrase: args.is_present("passphrase")
+            }
+        },
+        ("splitkey", Some(args)) => {
+            Arguments::SplitKey {
+                file: args.value_of("FILE").unwrap().to_string(),
+                threshold: parse_share_count(args.value_of("threshold").unwrap()).unwrap(),
+                shares: parse_share_count(args.value_of("shares").unwrap()).unwrap()
+            }
+        },
+        ("joinkey", Some(args)) => {
+            Arguments::JoinKey {
+                file: args.value_of("FILE").map(|v| v.to_string()),
+                passphrase: args.is_present("passphrase")
             }
         },
         ("addkey", Some(args)) => {
@@ -646,13 +1010,90 @@ pub fn parse() -> Result<(LogLevel, Arguments), ErrorCode> {
                 repo_path: repository,
                 set_default: args.is_present("set_default"),
                 password: args.value_of("password").map(|v| v.to_string()),
+                legacy: args.is_present("legacy"),
+                passphrase: args.is_present("passphrase"),
                 file: args.value_of("FILE").map(|v| v.to_string())
             }
         },
+        ("passphrase", Some(args)) => {
+            let (repository, _backup, _inode) = parse_repo_path(args.value_of("REPO").unwrap(), true, Some(false), Some(false)).unwrap();
+            Arguments::Passphrase {
+                repo_path: repository,
+                remove: args.is_present("remove")
+            }
+        },
+        ("signkey", Some(args)) => {
+            let (repository, _backup, _inode) = parse_repo_path(args.value_of("REPO").unwrap(), true, Some(false), Some(false)).unwrap();
+            Arguments::SignKey {
+                repo_path: repository,
+                generate: args.is_present("generate"),
+                import: args.value_of("import").map(|v| v.to_string()),
+                export: args.value_of("export").map(|v| v.to_string()),
+                public: args.is_present("public"),
+                passphrase: args.is_present("passphrase")
+            }
+        },
+        ("appendonly", Some(args)) => {
+            let (repository, _backup, _inode) = parse_repo_path(args.value_of("REPO").unwrap(), true, Some(false), Some(false)).unwrap();
+            Arguments::AppendOnly {
+                repo_path: repository,
+                enable: args.value_of("enable").map(|v| parse_public_key(v).unwrap()),
+                disable: args.is_present("disable"),
+                admin_key: args.value_of("admin_key").map(|v| v.to_string())
+            }
+        },
+        ("deletions", Some(args)) => {
+            let (repository, _backup, _inode) = parse_repo_path(args.value_of("REPO").unwrap(), true, Some(false), Some(false)).unwrap();
+            Arguments::Deletions {
+                repo_path: repository,
+                admin_key: args.value_of("admin_key").map(|v| v.to_string()),
+                execute: args.is_present("execute"),
+                discard: args.is_present("discard")
+            }
+        },
+        ("rekey", Some(args)) => {
+            let (repository, _backup, _inode) = parse_repo_path(args.value_of("REPO").unwrap(), true, Some(false), Some(false)).unwrap();
+            Arguments::Rekey {
+                repo_path: repository,
+                force: args.is_present("force"),
+                remove_keys: args.is_present("remove_keys")
+            }
+        },
+        ("key", Some(args)) => {
+            let (command, args) = args.subcommand();
+            let args = args.unwrap();
+            let (repository, _backup, _inode) = parse_repo_path(args.value_of("REPO").unwrap(), true, Some(false), Some(false)).unwrap();
+            match command {
+                "list" => Arguments::KeyList {
+                    repo_path: repository
+                },
+                "show" => Arguments::KeyShow {
+                    repo_path: repository,
+                    key: args.value_of("KEY").unwrap().to_string()
+                },
+                "remove" => Arguments::KeyRemove {
+                    repo_path: repository,
+                    key: args.value_of("KEY").unwrap().to_string(),
+                    force: args.is_present("force")
+                },
+                "export" => Arguments::KeyExport {
+                    repo_path: repository,
+                    key: args.value_of("KEY").unwrap().to_string(),
+                    passphrase: args.is_present("passphrase"),
+                    file: args.value_of("FILE").map(|v| v.to_string())
+                },
+                _ => {
+                    error!("No key subcommand given");
+                    return Err(ErrorCode::InvalidArgs)
+                }
+            }
+        },
         ("algotest", Some(args)) => {
             Arguments::AlgoTest {
                 bundle_size: (parse_num(args.value_of("bundle_size").unwrap()).unwrap() * 1024 * 1024) as usize,
                 chunker: parse_chunker(args.value_of("chunker").unwrap()).unwrap(),
+                chunker_limits: parse_chunker_limits(args.value_of("chunker_limits").unwrap()).unwrap(),
+                format_aware_chunking: args.is_present("format-aware"),
                 compression: parse_compression(args.value_of("compression").unwrap()).unwrap(),
                 encrypt: args.is_present("encrypt"),
                 hash: parse_hash(args.value_of("hash").unwrap()).unwrap(),
@@ -8,10 +8,11 @@ use chrono::prelude::*;
 use regex::{self, RegexSet};
 
 use std::collections::HashMap;
-use std::io::{BufReader, BufRead};
+use std::io::{self, BufReader, BufRead};
 use std::fs::File;
 use std::env;
 use std::str;
+use std::cmp::max;
 use std::path::{Path, PathBuf};
 
 use self::args::Arguments;
@@ -27,7 +28,7 @@ pub enum ErrorCode {
     SaveConfig,
     LoadExcludes, InvalidExcludes,
     BackupRun, RestoreRun, RemoveRun, PruneRun, VacuumRun, CheckRun, AnalyzeRun, DiffRun,
-    VersionsRun, ImportRun, FuseMount
+    VersionsRun, ImportRun, FuseMount, TrainDictRun, ChangePassphrase, RekeyRun, UpgradeRun
 }
 impl ErrorCode {
     pub fn code(&self) -> i32 {
@@ -64,7 +65,12 @@ impl ErrorCode {
             ErrorCode::FuseMount => 24,
             //
             ErrorCode::NoSuchBackup => 25,
-            ErrorCode::BackupAlreadyExists => 26
+            ErrorCode::BackupAlreadyExists => 26,
+            //
+            ErrorCode::TrainDictRun => 27,
+            ErrorCode::ChangePassphrase => 28,
+            ErrorCode::RekeyRun => 29,
+            ErrorCode::UpgradeRun => 30
         }
     }
 }
@@ -75,6 +81,7 @@ pub const DEFAULT_HASH: &'static str = "blake2";
 pub const DEFAULT_COMPRESSION: &'static str = "brotli/3";
 pub const DEFAULT_BUNDLE_SIZE_STR: &'static str = "25";
 pub const DEFAULT_VACUUM_RATIO_STR: &'static str = "0";
+pub const DEFAULT_DICTIONARY_SIZE_STR: &'static str = "112";
 lazy_static! {
     pub static ref ZVAULT_FOLDER: PathBuf = {
         env::home_dir().unwrap().join(".zvault")
@@ -97,8 +104,54 @@ fn open_repository(path: &Path) -> Result<Repository, ErrorCode> {
     Ok(checked!(Repository::open(path), "load repository", ErrorCode::LoadRepository))
 }
 
+fn get_new_passphrase(enabled: bool) -> Result<Option<String>, ErrorCode> {
+    if !enabled {
+        return Ok(None)
+    }
+    Ok(Some(checked!(read_new_passphrase(PASSPHRASE_ENV), "read passphrase", ErrorCode::InvalidArgs)))
+}
+
+fn unlock_admin(repo: &mut Repository, admin_key: Option<String>) -> Result<(), ErrorCode> {
+    if let Some(file) = admin_key {
+        let (public, secret) = checked!(Crypto::load_keypair_from_file(file), "load administrator key", ErrorCode::LoadKey);
+        checked!(repo.unlock_admin(&public, &secret), "unlock administrator mode", ErrorCode::LoadKey);
+    }
+    Ok(())
+}
+
+fn print_deletion_request(request: &DeletionRequest) {
+    let date = Local.timestamp(request.timestamp, 0).to_rfc2822();
+    for name in &request.backups {
+        println!("{} from {}: remove backup {}", date, request.host, name);
+    }
+    if request.vacuum {
+        println!("{} from {}: vacuum with ratio {}%{}", date, request.host, (request.ratio * 100.0) as usize,
+            if request.combine { ", combining small bundles" } else { "" });
+    }
+}
+
+fn print_keypair(public: &PublicKey, secret: &SecretKey, protected: bool) {
+    info!("Created the following key pair");
+    println!("public: {}", to_hex(&public[..]));
+    if !protected {
+        println!("secret: {}", to_hex(&secret[..]));
+    }
+}
+
+fn find_key(repo: &Repository, key: &str) -> Result<(PublicKey, KeyUsage), ErrorCode> {
+    let key = key.replace(':', "").to_lowercase();
+    let usage = checked!(repo.key_usage(), "list keys", ErrorCode::LoadKey);
+    for (public, usage) in usage {
+        if to_hex(&public[..]) == key || Crypto::fingerprint(&public).replace(':', "").to_lowercase() == key {
+            return Ok((public, usage))
+        }
+    }
+    error!("A key pair with that public key or fingerprint does not exist");
+    Err(ErrorCode::LoadKey)
+}
+
 fn get_backup(repo: &Repository, backup_name: &str) -> Result<Backup, ErrorCode> {
-    if !repo.has_backup(backup_name) {
+    if !checked!(repo.has_backup(backup_name), "read backup names", ErrorCode::LoadBackup) {
         error!("A backup with that name does not exist");
         return Err(ErrorCode::NoSuchBackup)
     }
@@ -209,14 +262,110 @@ fn print_repoinfo(info: &RepositoryInfo) {
     println!("Index: {}, {:.0}% full", to_file_size(info.index_size as u64), index_usage * 100.0);
 }
 
+fn print_index_stats(stats: &IndexStats) {
+    println!("Entries: {}", stats.entries);
+    println!("Capacity: {}", stats.capacity);
+    println!("Load factor: {:.1}%", stats.load_factor * 100.0);
+    println!("Size: {}", to_file_size(stats.size as u64));
+    if let Some(resident) = stats.resident_size {
+        println!("Resident in memory: {} ({:.1}%)", to_file_size(resident as u64), resident as f64 / stats.size as f64 * 100.0);
+    }
+    println!("Displacement: {:.2} on average, {} at most", stats.avg_displacement, stats.max_displacement);
+    println!("Probes per lookup: {:.2} for existing keys, {:.2} for missing keys", stats.avg_hit_probes, stats.avg_miss_probes);
+    println!("Longest cluster: {} entries", stats.max_cluster);
+    if let (Some(size), Some(fp_rate)) = (stats.filter_size, stats.filter_fp_rate) {
+        println!("Filter: {}, {:.3}% false positives", to_file_size(size as u64), fp_rate * 100.0);
+    } else {
+        println!("Filter: none");
+    }
+    println!("Displacement histogram:");
+    let max_count = max(1, stats.displacement.iter().cloned().max().unwrap_or(0));
+    for (dist, &count) in stats.displacement.iter().enumerate() {
+        let label = if dist == stats.displacement.len() - 1 { format!("{}+", dist) } else { dist.to_string() };
+        let bar = "#".repeat(count * 50 / max_count);
+        println!("  - {:>3}: {:>10} {}", label, count, bar);
+    }
+}
+
+fn print_unused_chunks(repo: &mut Repository) {
+    match repo.unused_chunks() {
+        Some((0, _)) | None => info!("Run vacuum to reclaim space"),
+        Some((count, size)) => info!("{} chunks with {} are no longer used, run vacuum to reclaim space", count, to_file_size(size))
+    }
+}
+
+fn print_index_bundle_usage(usage: &[(BundleId, usize, usize)]) {
+    if usage.is_empty() {
+        return
+    }
+    let mut counts: Vec<usize> = usage.iter().map(|&(_, indexed, _)| indexed).collect();
+    counts.sort();
+    let total: usize = counts.iter().sum();
+    println!("Index entries per bundle: {} on average, {} at least, {} at most",
+        total / counts.len(), counts[0], counts[counts.len() - 1]);
+}
+
+fn format_encryption(encryption: &Encryption) -> String {
+    let keys: Vec<String> = encryption.1.chunks(32).map(to_hex).collect();
+    keys.join(",")
+}
+
+fn format_key_usage(usage: &KeyUsage) -> String {
+    let mut used = vec![];
+    if usage.config {
+        used.push("config".to_string());
+    }
+    if usage.bundles > 0 {
+        used.push(format!("{} bundles", usage.bundles));
+    }
+    if !usage.backups.is_empty() {
+        used.push(format!("{} backups", usage.backups.len()));
+    }
+    if used.is_empty() {
+        "unused".to_string()
+    } else {
+        used.join(", ")
+    }
+}
+
+fn print_key_one_line(public: &PublicKey, usage: &KeyUsage) {
+    println!("{} {} {:7} {}",
+        Crypto::fingerprint(public),
+        to_hex(&public[..]),
+        if !usage.secret_key { "missing" } else if usage.protected { "locked" } else { "secret" },
+        format_key_usage(usage)
+    );
+}
+
+fn print_key(public: &PublicKey, usage: &KeyUsage) {
+    println!("Key pair {}", Crypto::fingerprint(public));
+    println!("  - Public key: {}", to_hex(&public[..]));
+    let secret = if !usage.secret_key {
+        "missing"
+    } else if usage.protected {
+        "protected with passphrase"
+    } else {
+        "available"
+    };
+    println!("  - Secret key: {}", secret);
+    println!("  - Used for new data: {}", if usage.config { "yes" } else { "no" });
+    println!("  - Bundles: {}", usage.bundles);
+    println!("  - Backups: {}", usage.backups.len());
+    let mut backups = usage.backups.clone();
+    backups.sort();
+    for name in backups {
+        println!("    - {}", name);
+    }
+}
+
 fn print_bundle(bundle: &StoredBundle) {
     println!("Bundle {}", bundle.info.id);
     println!("  - Mode: {:?}", bundle.info.mode);
     println!("  - Path: {:?}", bundle.path);
     println!("  - Date: {}", Local.timestamp(bundle.info.timestamp, 0).to_rfc2822());
     println!("  - Hash method: {:?}", bundle.info.hash_method);
-    let encryption = if let Some((_, ref key)) = bundle.info.encryption {
-        to_hex(key)
+    let encryption = if let Some(ref encryption) = bundle.info.encryption {
+        format_encryption(encryption)
     } else {
         "none".to_string()
     };
@@ -240,17 +389,42 @@ fn print_bundle_one_line(bundle: &BundleInfo) {
 fn print_config(config: &Config) {
     println!("Bundle size: {}", to_file_size(config.bundle_size as u64));
     println!("Chunker: {}", config.chunker.to_string());
+    let limits = config.chunker.limits(config.chunker_limits);
+    println!("Chunker limits: {} - {}, normalization {}{}", to_file_size(limits.min_size as u64), to_file_size(limits.max_size as u64), limits.normalization, if config.chunker_limits.is_none() { " (default)" } else { "" });
     if let Some(ref compression) = config.compression {
         println!("Compression: {}", compression.to_string());
     } else {
         println!("Compression: none");
     }
+    println!("Format-aware chunking: {}", if config.format_aware_chunking { "yes" } else { "no" });
+    println!("Adaptive compression: {}", if config.adaptive_compression { "yes" } else { "no" });
     if let Some(ref encryption) = config.encryption {
-        println!("Encryption: {}", to_hex(&encryption.1[..]));
+        println!("Encryption: {}", format_encryption(encryption));
     } else {
         println!("Encryption: none");
     }
     println!("Hash method: {}", config.hash.name());
+    if let Some(ref dictionary) = config.dictionary {
+        println!("Meta dictionary: {}", dictionary);
+    } else {
+        println!("Meta dictionary: none");
+    }
+    if let Some(ref redundancy) = config.redundancy {
+        println!("Redundancy: {}", redundancy.to_string());
+    } else {
+        println!("Redundancy: none");
+    }
+    if let Some(ref kdf) = config.key_derivation {
+        println!("Key derivation cost: {}", kdf.cost_string());
+    } else {
+        println!("Key derivation cost: none");
+    }
+    println!("Hide backup names: {}", if config.hide_backup_names { "yes" } else { "no" });
+    if let Some(ref filter) = config.index_filter {
+        println!("Index filter: {}", filter.to_string());
+    } else {
+        println!("Index filter: none");
+    }
 }
 
 fn print_analysis(analysis: &HashMap<u32, BundleAnalysis>) {
@@ -286,30 +460,55 @@ pub fn run() -> Result<(), ErrorCode> {
         return Err(ErrorCode::InitializeLogger)
     }
     match args {
-        Arguments::Init{repo_path, bundle_size, chunker, compression, encryption, hash, remote_path} => {
+        Arguments::Init{repo_path, bundle_size, chunker, chunker_limits, format_aware_chunking, compression, adaptive_compression, encryption, passphrase, hash, redundancy, hide_backup_names, remote_path} => {
+            if hash.is_keyed() && !encryption {
+                error!("Keyed hash methods require encryption");
+                return Err(ErrorCode::InvalidArgs)
+            }
+            if let Some(ref limits) = chunker_limits {
+                if let Err(err) = chunker.check_limits(limits) {
+                    error!("Invalid chunker limits: {}", err);
+                    return Err(ErrorCode::InvalidArgs)
+                }
+            }
+            let passphrase = try!(get_new_passphrase(passphrase));
             let mut repo = checked!(Repository::create(repo_path, Config {
                 bundle_size: bundle_size,
                 chunker: chunker,
                 compression: compression,
                 encryption: None,
-                hash: hash
+                hash: hash,
+                redundancy: redundancy,
+                dictionary: None,
+                adaptive_compression: adaptive_compression,
+                hash_key: None,
+                keyed_chunker_seed: false,
+                key_derivation: None,
+                hide_backup_names: hide_backup_names,
+                index_filter: None,
+                chunker_limits: chunker_limits,
+                format_aware_chunking: format_aware_chunking,
+                admin_key: None
             }, remote_path), "create repository", ErrorCode::CreateRepository);
             if encryption {
                 let (public, secret) = Crypto::gen_keypair();
-                info!("Created the following key pair");
-                println!("public: {}", to_hex(&public[..]));
-                println!("secret: {}", to_hex(&secret[..]));
-                repo.set_encryption(Some(&public));
-                checked!(repo.register_key(public, secret), "add key", ErrorCode::AddKey);
+                print_keypair(&public, &secret, passphrase.is_some());
+                checked!(repo.set_encryption(&[public]), "set encryption", ErrorCode::SaveConfig);
+                checked!(repo.register_key(public, secret, passphrase.as_ref().map(|p| p.as_str())), "add key", ErrorCode::AddKey);
+                checked!(repo.set_hash(hash), "set hash method", ErrorCode::SaveConfig);
                 checked!(repo.save_config(), "save config", ErrorCode::SaveConfig);
-                warn!("Please store this key pair in a secure location before using the repository");
+                if passphrase.is_some() {
+                    warn!("Please store a copy of the key file in {:?} and the passphrase in a secure location before using the repository", repo.layout.keys_path());
+                } else {
+                    warn!("Please store this key pair in a secure location before using the repository");
+                }
                 println!();
             }
             print_config(&repo.config);
         },
         Arguments::Backup{repo_path, backup_name, src_path, full, reference, same_device, mut excludes, excludes_from, no_default_excludes, tar} => {
             let mut repo = try!(open_repository(&repo_path));
-            if repo.has_backup(&backup_name) {
+            if checked!(repo.has_backup(&backup_name), "read backup names", ErrorCode::LoadBackup) {
                 error!("A backup with that name already exists");
                 return Err(ErrorCode::BackupAlreadyExists)
             }
@@ -397,6 +596,14 @@ pub fn run() -> Result<(), ErrorCode> {
             } else {
                 checked!(repo.get_inode(&backup.root), "load root inode", ErrorCode::LoadInode)
             };
+            let missing = checked!(repo.missing_keys(&inode), "list keys", ErrorCode::LoadKey);
+            if !missing.is_empty() {
+                warn!("Some bundles of this backup can not be decrypted because the secret keys are missing:");
+                for key in missing {
+                    warn!("  - {} ({})", Crypto::fingerprint(&key), to_hex(&key[..]));
+                }
+                warn!("The restore will fail when it reaches data stored in those bundles");
+            }
             if tar {
                 checked!(repo.export_tarfile(&backup, inode, &dst_path), "restore backup", ErrorCode::RestoreRun);
             } else {
@@ -410,7 +617,7 @@ pub fn run() -> Result<(), ErrorCode> {
                 return Err(ErrorCode::InvalidArgs)
             }
             let mut repo = try!(open_repository(&repo_path_src));
-            if repo.has_backup(&backup_name_dst) {
+            if checked!(repo.has_backup(&backup_name_dst), "read backup names", ErrorCode::LoadBackup) {
                 error!("A backup with that name already exists");
                 return Err(ErrorCode::BackupAlreadyExists)
             }
@@ -423,13 +630,19 @@ pub fn run() -> Result<(), ErrorCode> {
                 let mut backup = try!(get_backup(&repo, &backup_name));
                 checked!(repo.remove_backup_path(&mut backup, inode), "remove backup subpath", ErrorCode::RemoveRun);
                 checked!(repo.save_backup(&backup, &backup_name), "save backup file", ErrorCode::SaveBackup);
-                info!("The backup subpath has been deleted, run vacuum to reclaim space");
-            } else if repo.layout.backups_path().join(&backup_name).is_dir() {
+                info!("The backup subpath has been deleted");
+                print_unused_chunks(&mut repo);
+            } else if checked!(repo.has_backup_folder(&backup_name), "read backup names", ErrorCode::LoadBackup) {
                 let backups = checked!(repo.get_backups(&backup_name), "retrieve backups", ErrorCode::RemoveRun);
                 if force {
                     for name in backups.keys() {
                         checked!(repo.delete_backup(&format!("{}/{}", &backup_name, name)), "delete backup", ErrorCode::RemoveRun);
                     }
+                    if repo.is_append_only() {
+                        info!("The repository is append-only, the deletion has been queued for the administrator");
+                    } else {
+                        print_unused_chunks(&mut repo);
+                    }
                 } else {
                     error!("Denying to remove multiple backups (use --force):");
                     for name in backups.keys() {
@@ -438,7 +651,12 @@ pub fn run() -> Result<(), ErrorCode> {
                 }
             } else {
                 checked!(repo.delete_backup(&backup_name), "delete backup", ErrorCode::RemoveRun);
-                info!("The backup has been deleted, run vacuum to reclaim space");
+                if repo.is_append_only() {
+                    info!("The repository is append-only, the deletion has been queued for the administrator");
+                } else {
+                    info!("The backup has been deleted");
+                    print_unused_chunks(&mut repo);
+                }
             }
         },
         Arguments::Prune{repo_path, prefix, daily, weekly, monthly, yearly, force} => {
@@ -450,6 +668,10 @@ pub fn run() -> Result<(), ErrorCode> {
             checked!(repo.prune_backups(&prefix, daily, weekly, monthly, yearly, force), "prune backups", ErrorCode::PruneRun);
             if !force {
                 info!("Run with --force to actually execute this command");
+            } else if repo.is_append_only() {
+                info!("The repository is append-only, the deletions have been queued for the administrator");
+            } else {
+                print_unused_chunks(&mut repo);
             }
         },
         Arguments::Vacuum{repo_path, ratio, force, combine} => {
@@ -458,12 +680,12 @@ pub fn run() -> Result<(), ErrorCode> {
             checked!(repo.vacuum(ratio, combine, force), "vacuum", ErrorCode::VacuumRun);
             if !force {
                 info!("Run with --force to actually execute this command");
-            } else {
+            } else if !repo.is_append_only() {
                 let info_after = repo.info();
                 info!("Reclaimed {}", to_file_size(info_before.encoded_data_size - info_after.encoded_data_size));
             }
         },
-        Arguments::Check{repo_path, backup_name, inode, bundles, index, bundle_data, repair} => {
+        Arguments::Check{repo_path, backup_name, inode, bundles, index, chunk_refs, bundle_data, repair} => {
             let mut repo = try!(open_repository(&repo_path));
             checked!(repo.check_repository(repair), "check repository", ErrorCode::CheckRun);
             if bundles {
@@ -472,6 +694,9 @@ pub fn run() -> Result<(), ErrorCode> {
             if index {
                 checked!(repo.check_index(repair), "check index", ErrorCode::CheckRun);
             }
+            if chunk_refs {
+                checked!(repo.check_chunk_refs(repair), "check chunk references", ErrorCode::CheckRun);
+            }
             if let Some(backup_name) = backup_name {
                 let mut backup = try!(get_backup(&repo, &backup_name));
                 if let Some(path) = inode {
@@ -488,7 +713,7 @@ pub fn run() -> Result<(), ErrorCode> {
         Arguments::List{repo_path, backup_name, inode} => {
             let mut repo = try!(open_repository(&repo_path));
             let backup_map = if let Some(backup_name) = backup_name {
-                if repo.layout.backups_path().join(&backup_name).is_dir() {
+                if checked!(repo.has_backup_folder(&backup_name), "read backup names", ErrorCode::LoadBackup) {
                     repo.get_backups(&backup_name)
                 } else {
                     let backup = try!(get_backup(&repo, &backup_name));
@@ -535,7 +760,7 @@ pub fn run() -> Result<(), ErrorCode> {
         Arguments::Mount{repo_path, backup_name, inode, mount_point} => {
             let mut repo = try!(open_repository(&repo_path));
             let fs = if let Some(backup_name) = backup_name {
-                if repo.layout.backups_path().join(&backup_name).is_dir() {
+                if checked!(repo.has_backup_folder(&backup_name), "read backup names", ErrorCode::LoadBackup) {
                     checked!(FuseFilesystem::from_repository(&mut repo, Some(&backup_name)), "create fuse filesystem", ErrorCode::FuseMount)
                 } else {
                     let backup = try!(get_backup(&repo, &backup_name));
@@ -572,8 +797,38 @@ pub fn run() -> Result<(), ErrorCode> {
                 return Err(ErrorCode::LoadBundle)
             }
         },
-        Arguments::Import{repo_path, remote_path, key_files} => {
-            checked!(Repository::import(repo_path, remote_path, key_files), "import repository", ErrorCode::ImportRun);
+        Arguments::IndexInfo{repo_path, bundles, history} => {
+            let repo = try!(open_repository(&repo_path));
+            print_index_stats(&repo.index_stats());
+            let usage = repo.index_bundle_usage();
+            print_index_bundle_usage(&usage);
+            if bundles {
+                for (id, indexed, chunks) in usage {
+                    println!("{}: {} of {} chunks indexed", id, indexed, chunks);
+                }
+            }
+            if history {
+                for entry in checked!(repo.index_history(), "load index history", ErrorCode::LoadRepository) {
+                    println!("{}: {} entries, capacity {}, {:.1}% full", Local.timestamp(entry.timestamp, 0).to_rfc2822(),
+                        entry.entries, entry.capacity, entry.entries as f64 / entry.capacity as f64 * 100.0);
+                }
+            }
+        },
+        Arguments::Upgrade{repo_path} => {
+            let migrated = checked!(Repository::upgrade(&repo_path), "upgrade repository", ErrorCode::UpgradeRun);
+            if migrated.is_empty() {
+                info!("All local files are up to date");
+            }
+            for file in migrated {
+                info!("Migrated {} from version {} to {}", file.name, file.from, file.to);
+            }
+            try!(open_repository(&repo_path));
+        },
+        Arguments::Import{repo_path, remote_path, key_files, signing_key} => {
+            if signing_key.is_none() {
+                warn!("Without a signing key, the backups can not be verified");
+            }
+            checked!(Repository::import(repo_path, remote_path, key_files, signing_key), "import repository", ErrorCode::ImportRun);
             info!("Import finished");
         },
         Arguments::Versions{repo_path, path} => {
@@ -610,7 +865,7 @@ pub fn run() -> Result<(), ErrorCode> {
                 info!("No differences found");
             }
         },
-        Arguments::Config{repo_path, bundle_size, chunker, compression, encryption, hash} => {
+        Arguments::Config{repo_path, bundle_size, chunker, chunker_limits, format_aware_chunking, compression, adaptive_compression, encryption, hash, redundancy, kdf_cost, hide_backup_names, index_filter} => {
             let mut repo = try!(open_repository(&repo_path));
             let mut changed = false;
             if let Some(bundle_size) = bundle_size {
@@ -620,19 +875,65 @@ pub fn run() -> Result<(), ErrorCode> {
             if let Some(chunker) = chunker {
                 warn!("Changing the chunker makes it impossible to use existing data for deduplication");
                 repo.config.chunker = chunker;
+                repo.config.chunker_limits = None;
+                changed = true;
+            }
+            if let Some(chunker_limits) = chunker_limits {
+                if let Some(ref limits) = chunker_limits {
+                    if let Err(err) = repo.config.chunker.check_limits(limits) {
+                        error!("Invalid chunker limits: {}", err);
+                        return Err(ErrorCode::InvalidArgs)
+                    }
+                }
+                warn!("Changing the chunker limits makes it impossible to use existing data for deduplication");
+                repo.config.chunker_limits = chunker_limits;
+                changed = true;
+            }
+            if let Some(format_aware_chunking) = format_aware_chunking {
+                repo.config.format_aware_chunking = format_aware_chunking;
                 changed = true;
             }
             if let Some(compression) = compression {
                 repo.config.compression = compression;
                 changed = true;
             }
+            if let Some(adaptive_compression) = adaptive_compression {
+                repo.config.adaptive_compression = adaptive_compression;
+                changed = true;
+            }
             if let Some(encryption) = encryption {
-                repo.set_encryption(encryption.as_ref());
+                checked!(repo.set_encryption(&encryption), "set encryption", ErrorCode::SaveConfig);
                 changed = true;
             }
             if let Some(hash) = hash {
                 warn!("Changing the hash makes it impossible to use existing data for deduplication");
-                repo.config.hash = hash;
+                info!("Existing chunks keep their old hashes until the repository is rekeyed");
+                checked!(repo.set_hash(hash), "set hash method", ErrorCode::SaveConfig);
+                changed = true;
+            }
+            if let Some(redundancy) = redundancy {
+                info!("The redundancy setting only applies to bundles written from now on");
+                repo.config.redundancy = redundancy;
+                changed = true;
+            }
+            if let Some(kdf_cost) = kdf_cost {
+                warn!("Key pairs that have been derived from passwords before can only be derived again with the old cost");
+                let mut kdf = checked!(repo.key_derivation(), "load key derivation", ErrorCode::SaveConfig);
+                kdf.set_cost(&kdf_cost).unwrap();
+                repo.config.key_derivation = Some(kdf);
+                changed = true;
+            }
+            if let Some(hide_backup_names) = hide_backup_names {
+                if hide_backup_names && repo.config.encryption.is_none() {
+                    error!("Hiding backup names requires encryption");
+                    return Err(ErrorCode::InvalidArgs)
+                }
+                info!("Existing backup files keep their names until the repository is rekeyed");
+                repo.config.hide_backup_names = hide_backup_names;
+                changed = true;
+            }
+            if let Some(index_filter) = index_filter {
+                repo.config.index_filter = index_filter;
                 changed = true;
             }
             if changed {
@@ -642,41 +943,233 @@ pub fn run() -> Result<(), ErrorCode> {
                 print_config(&repo.config);
             }
         },
-        Arguments::GenKey{file, password} => {
+        Arguments::TrainDict{repo_path, size} => {
+            let mut repo = try!(open_repository(&repo_path));
+            let id = checked!(repo.train_dictionary(size), "train dictionary", ErrorCode::TrainDictRun);
+            info!("Meta bundles will be compressed with the new dictionary {}", id);
+        },
+        Arguments::GenKey{file, password, salt, kdf_cost, legacy, passphrase} => {
+            let passphrase = try!(get_new_passphrase(passphrase));
             let (public, secret) = match password {
                 None => Crypto::gen_keypair(),
-                Some(ref password) => Crypto::keypair_from_password(password)
+                Some(ref password) if legacy => Crypto::keypair_from_password(password),
+                Some(ref password) => {
+                    let mut kdf = KeyDerivation::new(&kdf_cost).unwrap();
+                    if let Some(salt) = salt {
+                        kdf.salt = salt.into();
+                    }
+                    info!("Please keep the salt and cost to derive the key pair again");
+                    println!("salt: {}", to_hex(&kdf.salt[..]));
+                    println!("cost: {}", kdf.cost_string());
+                    checked!(kdf.derive_keypair(password), "derive key pair", ErrorCode::SaveKey)
+                }
             };
-            info!("Created the following key pair");
-            println!("public: {}", to_hex(&public[..]));
-            println!("secret: {}", to_hex(&secret[..]));
+            print_keypair(&public, &secret, passphrase.is_some());
             if let Some(file) = file {
-                checked!(Crypto::save_keypair_to_file(&public, &secret, file), "save key pair", ErrorCode::SaveKey);
+                checked!(Crypto::save_keypair_to_file(&public, &secret, passphrase.as_ref().map(|p| p.as_str()), file), "save key pair", ErrorCode::SaveKey);
+            }
+        },
+        Arguments::SplitKey{file, threshold, shares} => {
+            if threshold > shares {
+                error!("The threshold must not be larger than the number of shares");
+                return Err(ErrorCode::InvalidArgs)
+            }
+            let (public, secret) = checked!(Crypto::load_keypair_from_file(file), "load key pair", ErrorCode::LoadKey);
+            let shares = checked!(Crypto::split_keypair(&public, &secret, threshold, shares), "split key pair", ErrorCode::SaveKey);
+            info!("Any {} of the following shares can restore the key pair, please store them separately", threshold);
+            for share in shares {
+                println!("{}", share);
             }
         },
-        Arguments::AddKey{repo_path, set_default, password, file} => {
+        Arguments::JoinKey{file, passphrase} => {
+            info!("Please enter the key shares, one per line, and finish with an empty line");
+            let stdin = io::stdin();
+            let mut lines = vec![];
+            for line in stdin.lock().lines() {
+                let line = checked!(line, "read key shares", ErrorCode::LoadKey);
+                if line.trim().is_empty() && !lines.is_empty() {
+                    break
+                }
+                lines.push(line);
+            }
+            let (public, secret) = checked!(Crypto::keypair_from_shares(&lines), "restore key pair", ErrorCode::LoadKey);
+            let passphrase = try!(get_new_passphrase(passphrase));
+            print_keypair(&public, &secret, passphrase.is_some());
+            if let Some(file) = file {
+                checked!(Crypto::save_keypair_to_file(&public, &secret, passphrase.as_ref().map(|p| p.as_str()), file), "save key pair", ErrorCode::SaveKey);
+            }
+        },
+        Arguments::AddKey{repo_path, set_default, password, legacy, passphrase, file} => {
             let mut repo = try!(open_repository(&repo_path));
+            let passphrase = try!(get_new_passphrase(passphrase));
             let (public, secret) = if let Some(file) = file {
                 checked!(Crypto::load_keypair_from_file(file), "load key pair", ErrorCode::LoadKey)
             } else {
-                info!("Created the following key pair");
                 let (public, secret) = match password {
                     None => Crypto::gen_keypair(),
-                    Some(ref password) => Crypto::keypair_from_password(password)
+                    Some(ref password) if legacy => Crypto::keypair_from_password(password),
+                    Some(ref password) => {
+                        let kdf = checked!(repo.key_derivation(), "load key derivation", ErrorCode::SaveConfig);
+                        checked!(kdf.derive_keypair(password), "derive key pair", ErrorCode::AddKey)
+                    }
                 };
-                println!("public: {}", to_hex(&public[..]));
-                println!("secret: {}", to_hex(&secret[..]));
+                print_keypair(&public, &secret, passphrase.is_some());
                 (public, secret)
             };
-            checked!(repo.register_key(public, secret), "add key pair", ErrorCode::AddKey);
+            checked!(repo.register_key(public, secret, passphrase.as_ref().map(|p| p.as_str())), "add key pair", ErrorCode::AddKey);
             if set_default {
-                repo.set_encryption(Some(&public));
+                checked!(repo.set_encryption(&[public]), "set encryption", ErrorCode::SaveConfig);
                 checked!(repo.save_config(), "save config", ErrorCode::SaveConfig);
                 warn!("Please store this key pair in a secure location before using the repository");
             }
         },
-        Arguments::AlgoTest{bundle_size, chunker, compression, encrypt, hash, file} => {
-            algotest::run(&file, bundle_size, chunker, compression, encrypt, hash);
+        Arguments::Passphrase{repo_path, remove} => {
+            let mut repo = try!(open_repository(&repo_path));
+            let passphrase = if remove {
+                None
+            } else {
+                Some(checked!(read_new_passphrase(NEW_PASSPHRASE_ENV), "read passphrase", ErrorCode::InvalidArgs))
+            };
+            checked!(repo.change_passphrase(passphrase.as_ref().map(|p| p.as_str())), "change passphrase", ErrorCode::ChangePassphrase);
+            if remove {
+                warn!("The key files are now stored without passphrase");
+            } else {
+                info!("The passphrase has been changed");
+            }
+        },
+        Arguments::Rekey{repo_path, force, remove_keys} => {
+            let mut repo = try!(open_repository(&repo_path));
+            if repo.config.encryption.is_none() {
+                warn!("Encryption is disabled, all data will be stored unencrypted");
+            }
+            checked!(repo.rekey(force), "rekey", ErrorCode::RekeyRun);
+            if !force {
+                info!("Run with --force to actually execute this command");
+                return Ok(())
+            }
+            for key in checked!(repo.unused_keys(), "list unused keys", ErrorCode::RekeyRun) {
+                if remove_keys {
+                    checked!(repo.remove_key(&key), "remove key", ErrorCode::RekeyRun);
+                    info!("Removed key pair {}", to_hex(&key[..]));
+                } else {
+                    info!("Key pair {} is not used anymore, remove it with --remove-keys", to_hex(&key[..]));
+                }
+            }
+        },
+        Arguments::SignKey{repo_path, generate, import, export, public, passphrase} => {
+            let mut repo = try!(open_repository(&repo_path));
+            if generate {
+                if repo.signing_key().is_some() {
+                    warn!("The old signing key will be replaced, please update all copies of it");
+                }
+                let passphrase = try!(get_new_passphrase(passphrase));
+                let key = checked!(repo.gen_signing_key(passphrase.as_ref().map(|p| p.as_str())), "generate signing key", ErrorCode::SaveKey);
+                info!("The config and all backups have been signed");
+                warn!("Please store a copy of the signing key file {:?} in a secure location", repo.layout.signing_key_path());
+                println!("public: {}", to_hex(&key[..]));
+            } else if let Some(file) = import {
+                checked!(repo.import_signing_key(file), "import signing key", ErrorCode::LoadKey);
+                info!("Backups and the config will be verified with this signing key");
+            } else if let Some(file) = export {
+                if repo.signing_key().is_none() {
+                    error!("The repository does not have a signing key");
+                    return Err(ErrorCode::LoadKey)
+                }
+                checked!(repo.export_signing_key(file, public), "export signing key", ErrorCode::SaveKey);
+            } else if let Some(key) = repo.signing_key() {
+                println!("public: {}", to_hex(&key[..]));
+            } else {
+                info!("The repository does not have a signing key");
+            }
+        },
+        Arguments::AppendOnly{repo_path, enable, disable, admin_key} => {
+            let mut repo = try!(open_repository(&repo_path));
+            try!(unlock_admin(&mut repo, admin_key));
+            if let Some(key) = enable {
+                checked!(repo.set_append_only(Some(&key)), "enable append-only mode", ErrorCode::SaveConfig);
+                info!("The repository is now append-only");
+                warn!("Please also deny clients to modify or remove existing files on the remote storage");
+            } else if disable {
+                checked!(repo.set_append_only(None), "disable append-only mode", ErrorCode::SaveConfig);
+                info!("The repository is not append-only anymore");
+            } else if let Some(key) = checked!(repo.admin_key(), "load administrator key", ErrorCode::LoadKey) {
+                let requests = checked!(repo.deletion_requests(), "load deletion requests", ErrorCode::LoadRepository);
+                println!("Append-only: yes");
+                println!("Administrator key: {}", Crypto::fingerprint(&key));
+                println!("Queued deletion requests: {}", requests.len());
+            } else {
+                println!("Append-only: no");
+            }
+        },
+        Arguments::Deletions{repo_path, admin_key, execute, discard} => {
+            let mut repo = try!(open_repository(&repo_path));
+            try!(unlock_admin(&mut repo, admin_key));
+            let requests = checked!(repo.deletion_requests(), "load deletion requests", ErrorCode::LoadRepository);
+            for &(_, ref request) in &requests {
+                print_deletion_request(request);
+            }
+            if requests.is_empty() {
+                info!("No deletion requests are queued");
+            } else if execute {
+                checked!(repo.execute_deletions(), "execute deletion requests", ErrorCode::RemoveRun);
+                info!("Executed {} deletion requests", requests.len());
+            } else if discard {
+                let count = requests.len();
+                checked!(repo.discard_deletions(requests), "discard deletion requests", ErrorCode::RemoveRun);
+                info!("Discarded {} deletion requests", count);
+            }
+        },
+        Arguments::KeyList{repo_path} => {
+            let repo = try!(open_repository(&repo_path));
+            let mut keys: Vec<_> = checked!(repo.key_usage(), "list keys", ErrorCode::LoadKey).into_iter().collect();
+            keys.sort_by_key(|&(ref public, _)| Crypto::fingerprint(public));
+            for (public, usage) in keys {
+                print_key_one_line(&public, &usage);
+            }
+        },
+        Arguments::KeyShow{repo_path, key} => {
+            let repo = try!(open_repository(&repo_path));
+            let (public, usage) = try!(find_key(&repo, &key));
+            print_key(&public, &usage);
+        },
+        Arguments::KeyRemove{repo_path, key, force} => {
+            let mut repo = try!(open_repository(&repo_path));
+            let (public, usage) = try!(find_key(&repo, &key));
+            if !usage.secret_key {
+                error!("The secret key of this key pair is not stored in the repository");
+                return Err(ErrorCode::LoadKey)
+            }
+            if usage.is_used() {
+                if !force {
+                    error!("The key pair is still used ({}), remove it with --force or run rekey first", format_key_usage(&usage));
+                    return Err(ErrorCode::UnsafeArgs)
+                }
+                warn!("Data that is only encrypted with this key pair can not be read anymore without a copy of it");
+            }
+            checked!(repo.remove_key(&public), "remove key", ErrorCode::SaveKey);
+            info!("Removed key pair {}", Crypto::fingerprint(&public));
+        },
+        Arguments::KeyExport{repo_path, key, passphrase, file} => {
+            let repo = try!(open_repository(&repo_path));
+            let (public, _usage) = try!(find_key(&repo, &key));
+            let secret = checked!(repo.get_secret_key(&public), "load key pair", ErrorCode::LoadKey);
+            if let Some(file) = file {
+                let passphrase = try!(get_new_passphrase(passphrase));
+                checked!(Crypto::save_keypair_to_file(&public, &secret, passphrase.as_ref().map(|p| p.as_str()), file), "save key pair", ErrorCode::SaveKey);
+                info!("Exported key pair {}", Crypto::fingerprint(&public));
+            } else {
+                println!("public: {}", to_hex(&public[..]));
+                println!("secret: {}", to_hex(&secret[..]));
+            }
+        },
+        Arguments::AlgoTest{bundle_size, chunker, chunker_limits, format_aware_chunking, compression, encrypt, hash, file} => {
+            if let Some(ref limits) = chunker_limits {
+                if let Err(err) = chunker.check_limits(limits) {
+                    error!("Invalid chunker limits: {}", err);
+                    return Err(ErrorCode::InvalidArgs)
+                }
+            }
+            algotest::run(&file, bundle_size, chunker, chunker_limits, format_aware_chunking, compression, encrypt, hash);
         }
     }
     Ok(())
@@ -5,8 +5,11 @@ extern crate serde_bytes;
 extern crate rmp_serde;
 #[macro_use] extern crate serde_utils;
 extern crate squash_sys as squash;
+extern crate zstd;
 extern crate blake2_rfc as blake2;
 extern crate murmurhash3;
+extern crate sha2;
+extern crate blake3;
 extern crate serde_yaml;
 #[macro_use] extern crate quick_error;
 extern crate chrono;
@@ -15,6 +18,7 @@ extern crate chrono;
 extern crate byteorder;
 extern crate sodiumoxide;
 extern crate libsodium_sys;
+extern crate rpassword;
 extern crate ansi_term;
 extern crate filetime;
 extern crate regex;
@@ -38,6 +42,7 @@ mod cli;
 mod prelude;
 mod mount;
 mod chunker;
+#[cfg(test)] mod test_util;
 
 use std::process::exit;
 
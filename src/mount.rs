@@ -300,7 +300,7 @@ impl<'a> FuseFilesystem<'a> {
             },
             Some(FileData::ChunkedIndirect(ref c)) => {
                 let chunk_data = try!(self.repository.get_data(c));
-                chunks = Some(ChunkList::read_from(&chunk_data));
+                chunks = Some(ChunkList::read_from(&chunk_data, c.hash_size()));
             }
         }
         inode.chunks = chunks;
@@ -1,8 +1,8 @@
 pub use ::util::*;
-pub use ::bundledb::{BundleReader, BundleMode, BundleWriter, BundleInfo, BundleId, BundleDbError, BundleDb, BundleWriterError, StoredBundle};
-pub use ::chunker::{ChunkerType, Chunker, ChunkerStatus, ChunkerError};
-pub use ::repository::{Repository, Backup, Config, RepositoryError, RepositoryInfo, Inode, FileType, IntegrityError, BackupFileError, BackupError, BackupOptions, BundleAnalysis, FileData, DiffType, InodeError, RepositoryLayout, Location};
-pub use ::index::{Index, IndexError};
+pub use ::bundledb::{BundleReader, BundleMode, BundleWriter, BundleInfo, BundleId, BundleDbError, BundleDb, BundleWriterError, StoredBundle, CACHE_FILE_VERSION};
+pub use ::chunker::{ChunkerType, ChunkerLimits, Chunker, ChunkerStatus, ChunkerError, FormatChunker, Format, detect_format, find_cuts};
+pub use ::repository::{Repository, Backup, Config, IndexFilter, RepositoryError, RepositoryInfo, Inode, FileType, IntegrityError, BackupFileError, BackupError, BackupOptions, BundleAnalysis, KeyUsage, FileData, DiffType, InodeError, RepositoryLayout, Location, AppendOnlyError, DeletionRequest, MigratedFile};
+pub use ::index::{Index, IndexError, IndexStats, IndexHistoryEntry, Migration};
 pub use ::mount::FuseFilesystem;
 
 pub use serde::{Serialize, Deserialize};
@@ -0,0 +1,285 @@
+use ::prelude::*;
+
+use serde_yaml;
+use serde_bytes::ByteBuf;
+use chrono::prelude::*;
+use sodiumoxide::randombytes::randombytes;
+
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::io::{self, Read, Write};
+
+
+quick_error!{
+    #[derive(Debug)]
+    pub enum AppendOnlyError {
+        Denied(action: &'static str) {
+            description("Denied in append-only mode")
+            display("Append-only error: the repository is append-only, {} requires the administrator key", action)
+        }
+        WrongAdminKey {
+            description("Wrong administrator key")
+            display("Append-only error: the key pair is not the administrator key")
+        }
+        InvalidAdminKey {
+            description("Invalid administrator key")
+            display("Append-only error: the administrator key in the repository is invalid")
+        }
+        FlagChanged {
+            description("Append-only flag changed")
+            display("Append-only error: the append-only flag on the remote storage is missing or does not match the config")
+        }
+        Unsigned {
+            description("Config not signed")
+            display("Append-only error: the append-only mode needs a signing key to protect the administrator key in the config")
+        }
+        Io(err: io::Error) {
+            from()
+            cause(err)
+            description("IO error")
+            display("Append-only error: failed to read or write file\n\tcaused by: {}", err)
+        }
+        Yaml(err: serde_yaml::Error) {
+            from()
+            cause(err)
+            description("Yaml format error")
+            display("Append-only error: yaml format error\n\tcaused by: {}", err)
+        }
+        Decode(err: msgpack::DecodeError, path: PathBuf) {
+            cause(err)
+            context(path: &'a Path, err: msgpack::DecodeError) -> (err, path.to_path_buf())
+            description("Failed to decode deletion request")
+            display("Append-only error: failed to decode deletion request {:?}\n\tcaused by: {}", path, err)
+        }
+        Encryption(err: EncryptionError) {
+            from()
+            cause(err)
+            description("Encryption failed")
+            display("Append-only error: failed to encrypt or decrypt deletion request\n\tcaused by: {}", err)
+        }
+        Encode(err: msgpack::EncodeError) {
+            from()
+            cause(err)
+            description("Failed to encode deletion request")
+            display("Append-only error: failed to encode deletion request\n\tcaused by: {}", err)
+        }
+    }
+}
+
+
+struct AppendOnlyYaml {
+    admin_key: String
+}
+impl Default for AppendOnlyYaml {
+    fn default() -> Self {
+        AppendOnlyYaml {
+            admin_key: "".to_string()
+        }
+    }
+}
+serde_impl!(AppendOnlyYaml(String) {
+    admin_key: String => "admin_key"
+});
+
+
+/// A deletion that a client without the administrator key has asked for
+#[derive(Default, Debug, Clone)]
+pub struct DeletionRequest {
+    pub backups: Vec<String>,
+    pub vacuum: bool,
+    pub ratio: f32,
+    pub combine: bool,
+    pub host: String,
+    pub timestamp: i64
+}
+serde_impl!(DeletionRequest(u8?) {
+    backups: Vec<String> => 0,
+    vacuum: bool => 1,
+    ratio: f32 => 2,
+    combine: bool => 3,
+    host: String => 4,
+    timestamp: i64 => 5
+});
+
+impl DeletionRequest {
+    fn new() -> Self {
+        DeletionRequest {
+            host: get_hostname().unwrap_or_else(|_| "".to_string()),
+            timestamp: Local::now().timestamp(),
+            .. Default::default()
+        }
+    }
+
+    pub fn remove_backup(name: &str) -> Self {
+        DeletionRequest { backups: vec![name.to_string()], .. Self::new() }
+    }
+
+    pub fn vacuum(ratio: f32, combine: bool) -> Self {
+        DeletionRequest { vacuum: true, ratio: ratio, combine: combine, .. Self::new() }
+    }
+}
+
+
+/// Requests are encrypted like all other data on the remote storage, as they contain backup names
+#[derive(Default)]
+struct DeletionRequestFile {
+    encryption: Option<Encryption>,
+    data: ByteBuf
+}
+serde_impl!(DeletionRequestFile(u8?) {
+    encryption: Option<Encryption> => 0,
+    data: ByteBuf => 1
+});
+
+
+impl Repository {
+    /// Whether the config or the flag file on the remote storage mark the repository as
+    /// append-only, `admin_key` checks that both agree
+    #[inline]
+    pub fn is_append_only(&self) -> bool {
+        self.config.admin_key.is_some() || self.layout.append_only_path().exists()
+    }
+
+    /// The administrator key from the config, the config signature protects it against clients
+    fn config_admin_key(&self) -> Result<Option<PublicKey>, RepositoryError> {
+        match self.config.admin_key {
+            Some(ref key) => Ok(Some(try!(PublicKey::from_slice(key).ok_or(AppendOnlyError::InvalidAdminKey)))),
+            None => Ok(None)
+        }
+    }
+
+    fn load_append_only_flag(&self) -> Result<Option<PublicKey>, RepositoryError> {
+        let path = self.layout.append_only_path();
+        if !path.exists() {
+            return Ok(None)
+        }
+        let file = try!(File::open(path).map_err(AppendOnlyError::from));
+        let yaml: AppendOnlyYaml = try!(serde_yaml::from_reader(file).map_err(AppendOnlyError::from));
+        let key = try!(parse_hex(&yaml.admin_key).map_err(|_| AppendOnlyError::InvalidAdminKey));
+        Ok(Some(try!(PublicKey::from_slice(&key).ok_or(AppendOnlyError::InvalidAdminKey))))
+    }
+
+    /// Returns the administrator key of an append-only repository. The key in the signed config
+    /// and the flag file on the remote storage must be the same, so a client can neither lift
+    /// the mode by removing the file nor replace the key with its own one.
+    pub fn admin_key(&self) -> Result<Option<PublicKey>, RepositoryError> {
+        let key = try!(self.config_admin_key());
+        if try!(self.load_append_only_flag()) != key {
+            return Err(AppendOnlyError::FlagChanged.into())
+        }
+        Ok(key)
+    }
+
+    /// Proves that the administrator key is present, deletions are executed directly afterwards.
+    /// The key is only compared to the signed config, so the administrator can repair a
+    /// modified flag file.
+    pub fn unlock_admin(&mut self, public: &PublicKey, secret: &SecretKey) -> Result<(), RepositoryError> {
+        if try!(self.config_admin_key()) != Some(*public) || !Crypto::keypair_matches(public, secret) {
+            return Err(AppendOnlyError::WrongAdminKey.into())
+        }
+        self.admin = true;
+        Ok(())
+    }
+
+    /// Makes the repository append-only with the given administrator key or lifts the mode.
+    /// Once the repository is append-only, this needs the administrator key. The key is stored
+    /// in the config, so the repository needs a signing key that protects the config.
+    pub fn set_append_only(&mut self, admin_key: Option<&PublicKey>) -> Result<(), RepositoryError> {
+        if self.config.admin_key.is_some() && !self.admin {
+            return Err(AppendOnlyError::Denied("changing the append-only mode").into())
+        }
+        if admin_key.is_some() && !self.crypto.lock().unwrap().can_sign() {
+            return Err(AppendOnlyError::Unsigned.into())
+        }
+        self.config.admin_key = admin_key.map(|key| key[..].to_vec().into());
+        try!(self.save_config());
+        let path = self.layout.append_only_path();
+        if let Some(key) = admin_key {
+            let yaml = AppendOnlyYaml { admin_key: to_hex(&key[..]) };
+            let mut file = try!(File::create(path).map_err(AppendOnlyError::from));
+            try!(serde_yaml::to_writer(&mut file, &yaml).map_err(AppendOnlyError::from));
+        } else if path.exists() {
+            try!(fs::remove_file(path).map_err(AppendOnlyError::from));
+        }
+        Ok(())
+    }
+
+    pub fn queue_deletion(&mut self, request: &DeletionRequest) -> Result<(), RepositoryError> {
+        let data = try!(msgpack::encode(request).map_err(AppendOnlyError::from));
+        let data = match self.config.encryption {
+            Some(ref encryption) => try!(self.crypto.lock().unwrap().encrypt(encryption, &data).map_err(AppendOnlyError::from)),
+            None => data
+        };
+        let file = DeletionRequestFile { encryption: self.config.encryption.clone(), data: data.into() };
+        let data = try!(msgpack::encode(&file).map_err(AppendOnlyError::from));
+        try!(fs::create_dir_all(self.layout.deletions_path()).map_err(AppendOnlyError::from));
+        // Request ids start with the timestamp, so they are executed in order
+        let id = format!("{}-{}", request.timestamp, to_hex(&randombytes(4)));
+        let path = self.layout.deletions_path().join(id + ".request");
+        try!(File::create(path).and_then(|mut f| f.write_all(&data)).map_err(AppendOnlyError::from));
+        Ok(())
+    }
+
+    fn load_deletion_request(&self, path: &Path) -> Result<DeletionRequest, RepositoryError> {
+        let mut data = Vec::new();
+        try!(File::open(path).and_then(|mut f| f.read_to_end(&mut data)).map_err(AppendOnlyError::from));
+        let file: DeletionRequestFile = try!(msgpack::decode(&data).context(path).map_err(AppendOnlyError::from));
+        let data = match file.encryption {
+            Some(ref encryption) => try!(self.crypto.lock().unwrap().decrypt(encryption, &file.data[..]).map_err(AppendOnlyError::from)),
+            None => file.data.into()
+        };
+        Ok(try!(msgpack::decode(&data).context(path).map_err(AppendOnlyError::from)))
+    }
+
+    /// All queued deletion requests, oldest first
+    pub fn deletion_requests(&self) -> Result<Vec<(PathBuf, DeletionRequest)>, RepositoryError> {
+        let mut requests = vec![];
+        if !self.layout.deletions_path().exists() {
+            return Ok(requests)
+        }
+        for entry in try!(fs::read_dir(self.layout.deletions_path())) {
+            let path = try!(entry).path();
+            if path.extension() != Some("request".as_ref()) {
+                continue
+            }
+            let request = try!(self.load_deletion_request(&path));
+            requests.push((path, request));
+        }
+        requests.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(requests)
+    }
+
+    /// Executes all queued deletion requests, backups that do not exist anymore are skipped and
+    /// all requested vacuum runs are combined into one.
+    pub fn execute_deletions(&mut self) -> Result<(), RepositoryError> {
+        try!(self.check_may_delete("executing deletion requests"));
+        let requests = try!(self.deletion_requests());
+        let mut vacuum = None;
+        for &(_, ref request) in &requests {
+            for name in &request.backups {
+                if try!(self.has_backup(name)) {
+                    info!("Removing backup {}", name);
+                    try!(self.delete_backup(name));
+                } else {
+                    warn!("Backup {} does not exist anymore", name);
+                }
+            }
+            if request.vacuum {
+                let (ratio, combine) = vacuum.unwrap_or((0.0, false));
+                vacuum = Some((request.ratio.max(ratio), request.combine || combine));
+            }
+        }
+        if let Some((ratio, combine)) = vacuum {
+            try!(self.vacuum(ratio, combine, true));
+        }
+        self.discard_deletions(requests)
+    }
+
+    pub fn discard_deletions(&mut self, requests: Vec<(PathBuf, DeletionRequest)>) -> Result<(), RepositoryError> {
+        try!(self.check_may_delete("discarding deletion requests"));
+        for (path, _) in requests {
+            try!(fs::remove_file(path));
+        }
+        Ok(())
+    }
+}
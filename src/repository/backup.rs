@@ -1,6 +1,7 @@
 use ::prelude::*;
 
 use std::fs;
+use std::cell::Ref;
 use std::path::{self, Path, PathBuf};
 use std::collections::{HashMap, BTreeMap, VecDeque};
 use std::os::linux::fs::MetadataExt;
@@ -8,6 +9,10 @@ use std::os::linux::fs::MetadataExt;
 use chrono::prelude::*;
 use regex::RegexSet;
 use users::{self, Users, Groups};
+use sodiumoxide::randombytes::randombytes;
+
+
+const HIDDEN_BACKUP_ID_SIZE: usize = 16;
 
 
 quick_error!{
@@ -43,29 +48,108 @@ impl Repository {
     }
 
     pub fn get_backups<P: AsRef<Path>>(&self, path: P) -> Result<HashMap<String, Backup>, RepositoryError> {
-        Ok(try!(Backup::get_all_from(&self.crypto.lock().unwrap(), self.layout.backups_path().join(path))))
+        // Hidden backups are stored in a flat folder, so the folder can only be selected by name
+        let prefix = format!("{}/", path.as_ref().to_string_lossy().trim_right_matches('/'));
+        let paths: Vec<(String, PathBuf)> = try!(self.backup_names()).iter()
+            .filter(|&(name, _)| name.starts_with(&prefix))
+            .map(|(name, path)| (name.clone(), path.clone()))
+            .collect();
+        let crypto = self.crypto.lock().unwrap();
+        let mut backups = HashMap::new();
+        let mut failed_paths = vec![];
+        for (name, path) in paths {
+            if let Ok(backup) = Backup::read_from(&crypto, &path, &name) {
+                backups.insert(name[prefix.len()..].to_string(), backup);
+            } else {
+                failed_paths.push(path);
+            }
+        }
+        if failed_paths.is_empty() {
+            Ok(backups)
+        } else {
+            Err(BackupFileError::PartialBackupsList(backups, failed_paths).into())
+        }
+    }
+
+    /// The logical names and paths of all backup files, the names are only read once
+    fn backup_names(&self) -> Result<Ref<HashMap<String, PathBuf>>, RepositoryError> {
+        if self.backup_names.borrow().is_none() {
+            let names = try!(Backup::list_names_from(&self.crypto.lock().unwrap(), self.layout.backups_path()));
+            *self.backup_names.borrow_mut() = Some(names);
+        }
+        Ok(Ref::map(self.backup_names.borrow(), |names| names.as_ref().unwrap()))
+    }
+
+    /// Keeps the loaded backup names in sync when a backup file is written or removed
+    pub fn update_backup_names(&self, name: Option<&str>, path: &Path) {
+        if let Some(ref mut names) = *self.backup_names.borrow_mut() {
+            let old: Vec<String> = names.iter().filter(|&(_, p)| p == path).map(|(n, _)| n.clone()).collect();
+            for n in old {
+                names.remove(&n);
+            }
+            if let Some(name) = name {
+                names.insert(name.to_string(), path.to_path_buf());
+            }
+        }
+    }
+
+    /// Finds the file of the backup, either under its own name or under a hidden id
+    #[inline]
+    pub fn find_backup(&self, name: &str) -> Result<Option<PathBuf>, RepositoryError> {
+        Ok(try!(self.backup_names()).get(name).cloned())
     }
 
     #[inline]
-    pub fn has_backup(&self, name: &str) -> bool {
-        self.layout.backup_path(name).exists()
+    pub fn has_backup(&self, name: &str) -> Result<bool, RepositoryError> {
+        Ok(try!(self.backup_names()).contains_key(name))
+    }
+
+    pub fn has_backup_folder(&self, path: &str) -> Result<bool, RepositoryError> {
+        if self.layout.backups_path().join(path).is_dir() {
+            return Ok(true)
+        }
+        let prefix = format!("{}/", path.trim_right_matches('/'));
+        Ok(try!(self.backup_names()).keys().any(|name| name.starts_with(&prefix)))
     }
 
     pub fn get_backup(&self, name: &str) -> Result<Backup, RepositoryError> {
-        Ok(try!(Backup::read_from(&self.crypto.lock().unwrap(), self.layout.backup_path(name))))
+        let path = try!(self.find_backup(name)).unwrap_or_else(|| self.layout.backup_path(name));
+        Ok(try!(Backup::read_from(&self.crypto.lock().unwrap(), path, name)))
     }
 
     pub fn save_backup(&mut self, backup: &Backup, name: &str) -> Result<(), RepositoryError> {
         try!(self.write_mode());
-        let path = self.layout.backup_path(name);
+        let old_path = try!(self.find_backup(name));
+        if old_path.is_some() {
+            try!(self.check_may_delete("modifying backups"));
+        }
+        let hidden = self.config.hide_backup_names && self.config.encryption.is_some();
+        let path = if hidden {
+            match old_path {
+                Some(ref path) if try!(Backup::has_hidden_name(path)) => path.clone(),
+                _ => self.layout.backup_path(&to_hex(&randombytes(HIDDEN_BACKUP_ID_SIZE)))
+            }
+        } else {
+            self.layout.backup_path(name)
+        };
         try!(fs::create_dir_all(path.parent().unwrap()));
-        Ok(try!(backup.save_to(&self.crypto.lock().unwrap(), self.config.encryption.clone(), path)))
+        // Write to a temporary file first, so an existing backup is never lost
+        let temp_path = path.with_extension("backup.tmp");
+        try!(backup.save_to(&self.crypto.lock().unwrap(), self.config.encryption.clone(), name, hidden, &temp_path));
+        try!(fs::rename(&temp_path, &path));
+        self.update_backup_names(Some(name), &path);
+        if let Some(old_path) = old_path {
+            if old_path != path {
+                try!(self.remove_backup_file(old_path));
+            }
+        }
+        self.update_chunk_refs(name, Some(&backup.root));
+        Ok(())
     }
 
-    pub fn delete_backup(&mut self, name: &str) -> Result<(), RepositoryError> {
-        try!(self.write_mode());
-        let mut path = self.layout.backup_path(name);
+    fn remove_backup_file(&self, mut path: PathBuf) -> Result<(), RepositoryError> {
         try!(fs::remove_file(&path));
+        self.update_backup_names(None, &path);
         loop {
             path = path.parent().unwrap().to_owned();
             if path == self.layout.backups_path() || fs::remove_dir(&path).is_err() {
@@ -75,6 +159,18 @@ impl Repository {
         Ok(())
     }
 
+    /// Deletes the backup file, in append-only mode a deletion request is queued instead
+    pub fn delete_backup(&mut self, name: &str) -> Result<(), RepositoryError> {
+        try!(self.write_mode());
+        if !try!(self.may_delete()) && try!(self.has_backup(name)) {
+            return self.queue_deletion(&DeletionRequest::remove_backup(name))
+        }
+        let path = try!(self.find_backup(name)).unwrap_or_else(|| self.layout.backup_path(name));
+        try!(self.remove_backup_file(path));
+        self.update_chunk_refs(name, None);
+        Ok(())
+    }
+
 
     pub fn prune_backups(&mut self, prefix: &str, daily: usize, weekly: usize, monthly: usize, yearly: usize, force: bool) -> Result<(), RepositoryError> {
         try!(self.write_mode());
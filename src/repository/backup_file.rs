@@ -5,6 +5,8 @@ use std::fs::{self, File};
 use std::path::{Path, PathBuf};
 use std::collections::HashMap;
 
+use serde_bytes::ByteBuf;
+
 
 static HEADER_STRING: [u8; 7] = *b"zvault\x03";
 static HEADER_VERSION: u8 = 1;
@@ -55,6 +57,14 @@ quick_error!{
             description("Encryption failed")
             display("Backup file error: encryption failed\n\tcaused by: {}", err)
         }
+        Unsigned(path: PathBuf) {
+            description("Backup is not signed")
+            display("Backup file error: backup {:?} is not signed", path)
+        }
+        InvalidSignature(path: PathBuf) {
+            description("Invalid signature")
+            display("Backup file error: backup {:?} has an invalid signature, it might have been modified", path)
+        }
         PartialBackupsList(partial: HashMap<String, Backup>, failed: Vec<PathBuf>) {
             description("Some backups could not be loaded")
             display("Backup file error: some backups could not be loaded: {:?}", failed)
@@ -64,10 +74,16 @@ quick_error!{
 
 #[derive(Default, Debug, Clone)]
 struct BackupHeader {
-    pub encryption: Option<Encryption>
+    pub encryption: Option<Encryption>,
+    pub data_key: Option<ByteBuf>,
+    pub name: Option<ByteBuf>,
+    pub signature: Option<ByteBuf>
 }
-serde_impl!(BackupHeader(u8) {
-    encryption: Option<Encryption> => 0
+serde_impl!(BackupHeader(u8?) {
+    encryption: Option<Encryption> => 0,
+    data_key: Option<ByteBuf> => 1,
+    name: Option<ByteBuf> => 2,
+    signature: Option<ByteBuf> => 3
 });
 
 
@@ -114,8 +130,7 @@ serde_impl!(Backup(u8?) {
 });
 
 impl Backup {
-    pub fn read_from<P: AsRef<Path>>(crypto: &Crypto, path: P) -> Result<Self, BackupFileError> {
-        let path = path.as_ref();
+    fn read_header(path: &Path) -> Result<(BufReader<File>, BackupHeader), BackupFileError> {
         let mut file = BufReader::new(try!(File::open(path).map_err(|err| BackupFileError::Read(err, path.to_path_buf()))));
         let mut header = [0u8; 8];
         try!(file.read_exact(&mut header).map_err(|err| BackupFileError::Read(err, path.to_path_buf())));
@@ -127,31 +142,109 @@ impl Backup {
             return Err(BackupFileError::UnsupportedVersion(path.to_path_buf(), version))
         }
         let header: BackupHeader = try!(msgpack::decode_from_stream(&mut file).context(path));
+        Ok((file, header))
+    }
+
+    /// Reads only the unencrypted header, so no secret key is needed
+    pub fn read_encryption_from<P: AsRef<Path>>(path: P) -> Result<Option<Encryption>, BackupFileError> {
+        let (_, header) = try!(Self::read_header(path.as_ref()));
+        Ok(header.encryption)
+    }
+
+    /// Reads only the unencrypted header and tells whether the file has a hidden name
+    pub fn has_hidden_name<P: AsRef<Path>>(path: P) -> Result<bool, BackupFileError> {
+        let (_, header) = try!(Self::read_header(path.as_ref()));
+        Ok(header.name.is_some())
+    }
+
+    fn decrypt_name(crypto: &Crypto, header: &BackupHeader, path: &Path) -> Result<Option<String>, BackupFileError> {
+        match (&header.encryption, &header.name) {
+            (&Some(ref encryption), &Some(ref name)) => {
+                let name = try!(crypto.decrypt(encryption, &name[..]).context(path));
+                Ok(Some(try!(String::from_utf8(name).map_err(|_| BackupFileError::WrongHeader(path.to_path_buf())))))
+            },
+            (&None, &Some(_)) => Err(BackupFileError::WrongHeader(path.to_path_buf())),
+            _ => Ok(None)
+        }
+    }
+
+    /// Reads the hidden name of a backup file, `None` means that the name is the file path
+    pub fn read_name_from<P: AsRef<Path>>(crypto: &Crypto, path: P) -> Result<Option<String>, BackupFileError> {
+        let path = path.as_ref();
+        let (_, header) = try!(Self::read_header(path));
+        Self::decrypt_name(crypto, &header, path)
+    }
+
+    /// The signature covers the encoded header without the signature, the name of the backup
+    /// and the rest of the file, so backup files can not be renamed without being noticed
+    fn signed_data(header: &BackupHeader, name: &str, data: &[u8], path: &Path) -> Result<Vec<u8>, BackupFileError> {
+        let header = BackupHeader { signature: None, .. header.clone() };
+        let mut signed = try!(msgpack::encode(&(&header, name)).context(path));
+        signed.extend_from_slice(data);
+        Ok(signed)
+    }
+
+    /// Reads the backup file, `name` is the name of the backup unless the file has a hidden name.
+    /// Returns the name of the backup and, if the crypto has a signing key, verifies the signature.
+    pub fn read_with_name_from<P: AsRef<Path>>(crypto: &Crypto, path: P, name: &str) -> Result<(String, Self), BackupFileError> {
+        let path = path.as_ref();
+        let (mut file, header) = try!(Self::read_header(path));
         let mut data = Vec::new();
         try!(file.read_to_end(&mut data).map_err(|err| BackupFileError::Read(err, path.to_path_buf())));
+        let name = try!(Self::decrypt_name(crypto, &header, path)).unwrap_or_else(|| name.to_string());
+        if crypto.signing_key().is_some() {
+            let signature = try!(header.signature.as_ref().ok_or_else(|| BackupFileError::Unsigned(path.to_path_buf())));
+            let signed = try!(Self::signed_data(&header, &name, &data, path));
+            try!(crypto.verify(&signed, signature).map_err(|_| BackupFileError::InvalidSignature(path.to_path_buf())));
+        }
         if let Some(ref encryption) = header.encryption {
-            data = try!(crypto.decrypt(encryption, &data));
+            data = try!(crypto.decrypt_with(encryption, header.data_key.as_ref().map(|k| &k[..]), &data));
         }
-        Ok(try!(msgpack::decode(&data).context(path)))
+        Ok((name, try!(msgpack::decode(&data).context(path))))
     }
 
-    pub fn save_to<P: AsRef<Path>>(&self, crypto: &Crypto, encryption: Option<Encryption>, path: P) -> Result<(), BackupFileError> {
+    #[inline]
+    pub fn read_from<P: AsRef<Path>>(crypto: &Crypto, path: P, name: &str) -> Result<Self, BackupFileError> {
+        Self::read_with_name_from(crypto, path, name).map(|(_, backup)| backup)
+    }
+
+    /// Writes the backup file, with `hide_name` the name is stored encrypted in the header. If
+    /// the crypto has a signing key, the file is signed and the secret signing key is required,
+    /// so machines with only the public signing key can not write backups.
+    pub fn save_to<P: AsRef<Path>>(&self, crypto: &Crypto, encryption: Option<Encryption>, name: &str, hide_name: bool, path: P) -> Result<(), BackupFileError> {
         let path = path.as_ref();
+        if crypto.signing_key().is_some() && !crypto.can_sign() {
+            return Err(EncryptionError::MissingSigningKey.into())
+        }
         let mut data = try!(msgpack::encode(self).context(path));
+        let data_key = match encryption {
+            Some(ref encryption) => try!(crypto.gen_data_key(encryption)),
+            None => None
+        };
+        let mut hidden_name = None;
         if let Some(ref encryption) = encryption {
-            data = try!(crypto.encrypt(encryption, &data));
+            data = try!(crypto.encrypt_with(encryption, data_key.as_ref().map(|k| &k[..]), &data));
+            if hide_name {
+                hidden_name = Some(try!(crypto.encrypt(encryption, name.as_bytes())).into());
+            }
+        }
+        let mut header = BackupHeader { encryption: encryption, data_key: data_key, name: hidden_name, signature: None };
+        if crypto.signing_key().is_some() {
+            let signed = try!(Self::signed_data(&header, name, &data, path));
+            header.signature = Some(try!(crypto.sign(&signed)).into());
         }
         let mut file = BufWriter::new(try!(File::create(path).map_err(|err| BackupFileError::Write(err, path.to_path_buf()))));
         try!(file.write_all(&HEADER_STRING).map_err(|err| BackupFileError::Write(err, path.to_path_buf())));
         try!(file.write_all(&[HEADER_VERSION]).map_err(|err| BackupFileError::Write(err, path.to_path_buf())));
-        let header = BackupHeader { encryption: encryption };
         try!(msgpack::encode_to_stream(&header, &mut file).context(path));
         try!(file.write_all(&data).map_err(|err| BackupFileError::Write(err, path.to_path_buf())));
         Ok(())
     }
 
-    pub fn get_all_from<P: AsRef<Path>>(crypto: &Crypto, path: P) -> Result<HashMap<String, Backup>, BackupFileError> {
-        let mut backups = HashMap::new();
+    /// Lists the names and paths of all backup files below the given folder, backups with hidden
+    /// names are listed by their id
+    pub fn list_from<P: AsRef<Path>>(path: P) -> Result<Vec<(String, PathBuf)>, BackupFileError> {
+        let mut backups = vec![];
         let base_path = path.as_ref();
         let path = path.as_ref();
         if !path.exists() {
@@ -159,7 +252,6 @@ impl Backup {
             return Ok(backups);
         }
         let mut paths = vec![path.to_path_buf()];
-        let mut failed_paths = vec![];
         while let Some(path) = paths.pop() {
             for entry in try!(fs::read_dir(&path).map_err(|e| BackupFileError::Read(e, path.clone()))) {
                 let entry = try!(entry.map_err(|e| BackupFileError::Read(e, path.clone())));
@@ -167,19 +259,42 @@ impl Backup {
                 if path.is_dir() {
                     paths.push(path);
                 } else {
-                    let relpath = path.strip_prefix(&base_path).unwrap();
+                    let relpath = path.strip_prefix(&base_path).unwrap().to_path_buf();
                     if relpath.extension() != Some("backup".as_ref()) {
                         continue
                     }
                     let name = relpath.with_file_name(relpath.file_stem().unwrap()).to_string_lossy().to_string();
-                    if let Ok(backup) = Backup::read_from(crypto, &path) {
-                        backups.insert(name, backup);
-                    } else {
-                        failed_paths.push(path.clone());
-                    }
+                    backups.push((name, path));
                 }
             }
         }
+        Ok(backups)
+    }
+
+    /// Lists the logical names of all backup files, hidden names are decrypted. Files with
+    /// unreadable headers are listed under their path so that loading them reports the error.
+    pub fn list_names_from<P: AsRef<Path>>(crypto: &Crypto, path: P) -> Result<HashMap<String, PathBuf>, BackupFileError> {
+        let mut names = HashMap::new();
+        for (name, path) in try!(Self::list_from(path)) {
+            let hidden_name = match Self::read_header(&path) {
+                Ok((_, header)) => try!(Self::decrypt_name(crypto, &header, &path)),
+                Err(_) => None
+            };
+            names.insert(hidden_name.unwrap_or(name), path);
+        }
+        Ok(names)
+    }
+
+    pub fn get_all_from<P: AsRef<Path>>(crypto: &Crypto, path: P) -> Result<HashMap<String, Backup>, BackupFileError> {
+        let mut backups = HashMap::new();
+        let mut failed_paths = vec![];
+        for (name, path) in try!(Self::list_from(path)) {
+            if let Ok((name, backup)) = Backup::read_with_name_from(crypto, &path, &name) {
+                backups.insert(name, backup);
+            } else {
+                failed_paths.push(path);
+            }
+        }
         if failed_paths.is_empty() {
             Ok(backups)
         } else {
@@ -6,6 +6,10 @@ use std::collections::VecDeque;
 use std::io::{self, Read, Write, Cursor};
 
 
+const PARALLEL_CHUNKING_MIN_SIZE: usize = 16*1024*1024;
+const CHUNKING_THREADS: usize = 4;
+
+
 pub struct ChunkReader<'a> {
     chunks: VecDeque<Chunk>,
     data: Vec<u8>,
@@ -53,6 +57,23 @@ impl<'a> Read for ChunkReader<'a> {
 }
 
 
+/// The open bundle that a chunk is written to, incompressible data chunks get their own bundle
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum BundleSlot {
+    Data, Meta, Raw
+}
+
+impl BundleSlot {
+    #[inline]
+    fn mode(&self) -> BundleMode {
+        match *self {
+            BundleSlot::Data | BundleSlot::Raw => BundleMode::Data,
+            BundleSlot::Meta => BundleMode::Meta
+        }
+    }
+}
+
+
 impl Repository {
     #[inline]
     pub fn get_bundle_id(&self, id: u32) -> Result<BundleId, RepositoryError> {
@@ -60,8 +81,8 @@ impl Repository {
     }
 
     pub fn get_chunk(&mut self, hash: Hash) -> Result<Option<Vec<u8>>, RepositoryError> {
-        // Find bundle and chunk id in index
-        let found = if let Some(found) = self.index.get(&hash) {
+        // Find bundle and chunk id in index, restoring and mounting only need read access
+        let found = if let Some(found) = self.index.reader().get(&hash) {
             found
         } else {
             return Ok(None)
@@ -81,17 +102,25 @@ impl Repository {
         self.put_chunk_override(mode, hash, data)
     }
 
-    fn write_chunk_to_bundle_and_index(&mut self, mode: BundleMode, hash: Hash, data: &[u8]) -> Result<(), RepositoryError> {
-        let writer = match mode {
-            BundleMode::Data => &mut self.data_bundle,
-            BundleMode::Meta => &mut self.meta_bundle
+    fn write_chunk_to_bundle_and_index(&mut self, slot: BundleSlot, hash: Hash, data: &[u8]) -> Result<(), RepositoryError> {
+        let writer = match slot {
+            BundleSlot::Data => &mut self.data_bundle,
+            BundleSlot::Meta => &mut self.meta_bundle,
+            BundleSlot::Raw => &mut self.raw_bundle
         };
         // ...alocate one if needed
         if writer.is_none() {
+            let compression = match (slot, self.config.dictionary) {
+                // Incompressible data is stored uncompressed
+                (BundleSlot::Raw, _) => None,
+                // Meta bundles use the trained dictionary if there is one
+                (BundleSlot::Meta, Some(dictionary)) => self.config.compression.as_ref().map(|c| c.with_dictionary(dictionary)),
+                _ => self.config.compression.clone()
+            };
             *writer = Some(try!(self.bundles.create_bundle(
-                mode,
+                slot.mode(),
                 self.config.hash,
-                self.config.compression.clone(),
+                compression,
                 self.config.encryption.clone()
             )));
         }
@@ -99,28 +128,31 @@ impl Repository {
         // Add chunk to bundle writer and determine the size of the bundle
         let writer_obj = writer.as_mut().unwrap();
         let chunk_id = try!(writer_obj.add(data, hash));
-        let bundle_id = match mode {
-            BundleMode::Data => self.next_data_bundle,
-            BundleMode::Meta => self.next_meta_bundle
+        let bundle_id = match slot {
+            BundleSlot::Data => self.next_data_bundle,
+            BundleSlot::Meta => self.next_meta_bundle,
+            BundleSlot::Raw => self.next_raw_bundle
         };
         // Add location to the index
         try!(self.index.set(&hash, &Location::new(bundle_id, chunk_id as u32)));
         Ok(())
     }
 
-    fn finish_bundle(&mut self, mode: BundleMode) -> Result<(), RepositoryError> {
+    fn finish_bundle(&mut self, slot: BundleSlot) -> Result<(), RepositoryError> {
         // Calculate the next free bundle id now (late lifetime prevents this)
         let next_free_bundle_id = self.next_free_bundle_id();
-        let writer = match mode {
-            BundleMode::Data => &mut self.data_bundle,
-            BundleMode::Meta => &mut self.meta_bundle
+        let writer = match slot {
+            BundleSlot::Data => &mut self.data_bundle,
+            BundleSlot::Meta => &mut self.meta_bundle,
+            BundleSlot::Raw => &mut self.raw_bundle
         };
         if writer.is_none() {
             return Ok(())
         }
-        let bundle_id = match mode {
-            BundleMode::Data => self.next_data_bundle,
-            BundleMode::Meta => self.next_meta_bundle
+        let bundle_id = match slot {
+            BundleSlot::Data => self.next_data_bundle,
+            BundleSlot::Meta => self.next_meta_bundle,
+            BundleSlot::Raw => self.next_raw_bundle
         };
         let mut finished = None;
         mem::swap(writer, &mut finished);
@@ -132,14 +164,18 @@ impl Repository {
         if self.next_data_bundle == bundle_id {
             self.next_data_bundle = next_free_bundle_id
         }
+        if self.next_raw_bundle == bundle_id {
+            self.next_raw_bundle = next_free_bundle_id
+        }
         Ok(())
     }
 
-    fn finish_bundle_if_needed(&mut self, mode: BundleMode) -> Result<(), RepositoryError> {
+    fn finish_bundle_if_needed(&mut self, slot: BundleSlot) -> Result<(), RepositoryError> {
         let (size, raw_size) = {
-            let writer = match mode {
-                BundleMode::Data => &mut self.data_bundle,
-                BundleMode::Meta => &mut self.meta_bundle
+            let writer = match slot {
+                BundleSlot::Data => &mut self.data_bundle,
+                BundleSlot::Meta => &mut self.meta_bundle,
+                BundleSlot::Raw => &mut self.raw_bundle
             };
             if let Some(ref writer) = *writer {
                 (writer.estimate_final_size(), writer.raw_size())
@@ -148,37 +184,74 @@ impl Repository {
             }
         };
         if size >= self.config.bundle_size || raw_size >= 4 * self.config.bundle_size {
-            if mode == BundleMode::Meta {
-                //First store the current data bundle as meta referrs to those chunks
-                try!(self.finish_bundle(BundleMode::Data))
+            if slot == BundleSlot::Meta {
+                //First store the current data bundles as meta referrs to those chunks
+                try!(self.finish_bundle(BundleSlot::Data));
+                try!(self.finish_bundle(BundleSlot::Raw))
             }
-            try!(self.finish_bundle(mode))
+            try!(self.finish_bundle(slot))
         }
         Ok(())
     }
 
     #[inline]
     pub fn put_chunk_override(&mut self, mode: BundleMode, hash: Hash, data: &[u8]) -> Result<(), RepositoryError> {
-        try!(self.write_chunk_to_bundle_and_index(mode, hash, data));
-        self.finish_bundle_if_needed(mode)
+        let slot = match mode {
+            BundleMode::Meta => BundleSlot::Meta,
+            BundleMode::Data if self.config.adaptive_compression && self.config.compression.is_some() && !is_compressible(data) => BundleSlot::Raw,
+            BundleMode::Data => BundleSlot::Data
+        };
+        try!(self.write_chunk_to_bundle_and_index(slot, hash, data));
+        try!(self.finish_bundle_if_needed(slot));
+        // The whole repository is flushed so that the index never refers to unfinished bundles
+        if self.index.needs_flush() {
+            try!(self.flush());
+        }
+        Ok(())
     }
 
-    #[inline]
+    pub fn hash_key(&self) -> Result<Vec<u8>, RepositoryError> {
+        let hash_key = self.crypto.lock().unwrap().hash_key().to_vec();
+        if self.config.hash.is_keyed() && hash_key.is_empty() {
+            return Err(RepositoryError::HashKey("the hash key is not available"))
+        }
+        Ok(hash_key)
+    }
+
+    /// Stores in-memory data, the chunks are hashed in place and big buffers are chunked in
+    /// parallel
     pub fn put_data(&mut self, mode: BundleMode, data: &[u8]) -> Result<ChunkList, RepositoryError> {
-        let mut input = Cursor::new(data);
-        self.put_stream(mode, &mut input)
+        let hash_key = try!(self.hash_key());
+        // Format boundaries depend on the whole data, so it can not be chunked in regions
+        let cuts = if data.len() >= PARALLEL_CHUNKING_MIN_SIZE && !self.config.format_aware_chunking {
+            self.chunker_type.find_cuts_parallel(self.config.chunker_limits, data, CHUNKING_THREADS)
+        } else {
+            find_cuts(&*self.chunker, data)
+        };
+        let mut chunks = Vec::with_capacity(cuts.len() + 1);
+        let mut start = 0;
+        // The data after the last cut point is the final chunk, just like in put_stream
+        for end in cuts.into_iter().chain(Some(data.len())) {
+            let chunk = &data[start..end];
+            let hash = self.config.hash.hash_keyed(&hash_key, chunk);
+            try!(self.put_chunk(mode, hash, chunk));
+            chunks.push((hash, chunk.len() as u32));
+            start = end;
+        }
+        Ok(chunks.into())
     }
 
     pub fn put_stream<R: Read>(&mut self, mode: BundleMode, data: &mut R) -> Result<ChunkList, RepositoryError> {
         let avg_size = self.config.chunker.avg_size();
         let mut chunks = Vec::new();
         let mut chunk = Vec::with_capacity(avg_size * 2);
+        let hash_key = try!(self.hash_key());
         loop {
             chunk.clear();
             let mut output = Cursor::new(chunk);
             let res = try!(self.chunker.chunk(data, &mut output));
             chunk = output.into_inner();
-            let hash = self.config.hash.hash(&chunk);
+            let hash = self.config.hash.hash_keyed(&hash_key, &chunk);
             try!(self.put_chunk(mode, hash, &chunk));
             chunks.push((hash, chunk.len() as u32));
             if res == ChunkerStatus::Finished {
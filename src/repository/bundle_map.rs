@@ -7,7 +7,7 @@ use std::fs::File;
 
 
 static HEADER_STRING: [u8; 7] = *b"zbunmap";
-static HEADER_VERSION: u8 = 1;
+pub static HEADER_VERSION: u8 = 1;
 
 
 quick_error!{
@@ -46,7 +46,17 @@ impl BundleMap {
         BundleMap(Default::default())
     }
 
-    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, BundleMapError> {
+    /// Decodes the bundle map of the given version, older versions are converted to the
+    /// current one here
+    fn decode_version(version: u8, file: &mut Read) -> Result<Self, BundleMapError> {
+        if version == HEADER_VERSION {
+            Ok(BundleMap(try!(msgpack::decode_from_stream(file))))
+        } else {
+            Err(BundleMapError::WrongVersion(version))
+        }
+    }
+
+    fn load_version<P: AsRef<Path>>(path: P) -> Result<(Self, u8), BundleMapError> {
         let mut file = BufReader::new(try!(File::open(path.as_ref())));
         let mut header = [0u8; 8];
         try!(file.read_exact(&mut header));
@@ -54,10 +64,25 @@ impl BundleMap {
             return Err(BundleMapError::WrongHeader)
         }
         let version = header[HEADER_STRING.len()];
-        if version != HEADER_VERSION {
-            return Err(BundleMapError::WrongVersion(version))
+        Ok((try!(Self::decode_version(version, &mut file)), version))
+    }
+
+    /// Loads the bundle map, maps of older versions are migrated in memory and stored in the
+    /// current version when they are saved
+    #[inline]
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, BundleMapError> {
+        Self::load_version(path).map(|(map, _)| map)
+    }
+
+    /// Upgrades the bundle map file to the current version, returns the old version if it has
+    /// been migrated
+    pub fn migrate<P: AsRef<Path>>(path: P) -> Result<Option<u8>, BundleMapError> {
+        let (map, version) = try!(Self::load_version(path.as_ref()));
+        if version == HEADER_VERSION {
+            return Ok(None)
         }
-        Ok(BundleMap(try!(msgpack::decode_from_stream(&mut file))))
+        try!(map.save(path));
+        Ok(Some(version))
     }
 
     pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), BundleMapError> {
@@ -0,0 +1,380 @@
+use ::prelude::*;
+use ::index::IndexReader;
+
+use std::fs::File;
+use std::io::Read;
+use std::mem;
+use std::path::Path;
+
+
+pub const INDEX_MAGIC: [u8; 7] = *b"zvault\x02";
+/// Version 1 stores 128 bit keys, version 2 stores 256 bit keys. Repositories only use the wider
+/// keys once they contain chunks with 256 bit hashes.
+pub const SHORT_INDEX_VERSION: u8 = 1;
+pub const LONG_INDEX_VERSION: u8 = 2;
+
+
+#[repr(packed)]
+#[derive(Clone, Copy, PartialEq, Eq, Default)]
+pub struct ShortKey {
+    high: u64,
+    low: u64
+}
+
+impl ::index::Key for ShortKey {
+    fn hash(&self) -> u64 {
+        self.low
+    }
+
+    fn is_used(&self) -> bool {
+        self.low != 0 || self.high != 0
+    }
+
+    fn clear(&mut self) {
+        *self = ShortKey::default();
+    }
+}
+
+
+/// 128 bit hashes are stored with zero extension fields in the wider keys
+#[repr(packed)]
+#[derive(Clone, Copy, PartialEq, Eq, Default)]
+pub struct LongKey {
+    high: u64,
+    low: u64,
+    ext_high: u64,
+    ext_low: u64
+}
+
+impl LongKey {
+    #[inline]
+    fn new(hash: &Hash) -> Self {
+        LongKey{high: hash.high, low: hash.low, ext_high: hash.ext_high, ext_low: hash.ext_low}
+    }
+
+    #[inline]
+    fn to_hash(&self) -> Hash {
+        if self.ext_high == 0 && self.ext_low == 0 {
+            Hash::new(self.high, self.low)
+        } else {
+            Hash::new_long(self.high, self.low, self.ext_high, self.ext_low)
+        }
+    }
+}
+
+impl ::index::Key for LongKey {
+    fn hash(&self) -> u64 {
+        self.low
+    }
+
+    fn is_used(&self) -> bool {
+        self.low != 0 || self.high != 0 || self.ext_high != 0 || self.ext_low != 0
+    }
+
+    fn clear(&mut self) {
+        *self = LongKey::default();
+    }
+}
+
+
+fn index_v1_is_used(key: &[u8]) -> bool {
+    key.iter().any(|b| *b != 0)
+}
+
+/// Version 1 stored 128 bit keys, they are valid 256 bit keys with zero extension fields
+fn index_v1_convert(key: &[u8], value: &[u8]) -> (Vec<u8>, Vec<u8>) {
+    let mut key = key.to_vec();
+    key.resize(mem::size_of::<LongKey>(), 0);
+    (key, value.to_vec())
+}
+
+pub static INDEX_MIGRATIONS: [Migration; 1] = [
+    Migration {
+        from: SHORT_INDEX_VERSION,
+        to: LONG_INDEX_VERSION,
+        header_size: 24,
+        key_size: 16,
+        value_size: 8,
+        is_used: index_v1_is_used,
+        convert: index_v1_convert
+    }
+];
+
+
+/// The chunk index of a repository, the key size depends on the hash sizes used in the repository
+pub enum ChunkIndex {
+    Short(Index<ShortKey, Location>),
+    Long(Index<LongKey, Location>)
+}
+
+
+/// A read-only view of the chunk index that can be shared between threads
+#[derive(Clone, Copy)]
+pub enum ChunkIndexReader<'a> {
+    Short(IndexReader<'a, ShortKey, Location>),
+    Long(IndexReader<'a, LongKey, Location>)
+}
+
+impl<'a> ChunkIndexReader<'a> {
+    #[inline]
+    pub fn get(&self, hash: &Hash) -> Option<Location> {
+        match *self {
+            ChunkIndexReader::Short(ref reader) => ChunkIndex::short_key(hash).and_then(|key| reader.get(&key)),
+            ChunkIndexReader::Long(ref reader) => reader.get(&LongKey::new(hash))
+        }
+    }
+
+    #[inline]
+    pub fn contains(&self, hash: &Hash) -> bool {
+        match *self {
+            ChunkIndexReader::Short(ref reader) => ChunkIndex::short_key(hash).map(|key| reader.contains(&key)).unwrap_or(false),
+            ChunkIndexReader::Long(ref reader) => reader.contains(&LongKey::new(hash))
+        }
+    }
+}
+
+impl ChunkIndex {
+    pub fn create<P: AsRef<Path>>(path: P, long: bool) -> Result<Self, IndexError> {
+        if long {
+            Ok(ChunkIndex::Long(try!(Index::create(path, &INDEX_MAGIC, LONG_INDEX_VERSION))))
+        } else {
+            Ok(ChunkIndex::Short(try!(Index::create(path, &INDEX_MAGIC, SHORT_INDEX_VERSION))))
+        }
+    }
+
+    /// Reads the version from the header of the index file
+    pub fn file_version<P: AsRef<Path>>(path: P) -> Result<u8, IndexError> {
+        let mut header = [0u8; 8];
+        try!(File::open(path).and_then(|mut file| file.read_exact(&mut header)));
+        if header[..INDEX_MAGIC.len()] != INDEX_MAGIC[..] {
+            return Err(IndexError::WrongMagic)
+        }
+        Ok(header[INDEX_MAGIC.len()])
+    }
+
+    /// Opens the index with the key size of the file.
+    ///
+    /// This method is unsafe as there is no way to guarantee that the contents of the file are
+    /// valid objects.
+    pub unsafe fn open<P: AsRef<Path>>(path: P, writable: bool) -> Result<Self, IndexError> {
+        let path = path.as_ref();
+        match try!(Self::file_version(path)) {
+            SHORT_INDEX_VERSION if writable => Ok(ChunkIndex::Short(try!(Index::open(path, &INDEX_MAGIC, SHORT_INDEX_VERSION)))),
+            SHORT_INDEX_VERSION => Ok(ChunkIndex::Short(try!(Index::open_readonly(path, &INDEX_MAGIC, SHORT_INDEX_VERSION)))),
+            LONG_INDEX_VERSION if writable => Ok(ChunkIndex::Long(try!(Index::open(path, &INDEX_MAGIC, LONG_INDEX_VERSION)))),
+            LONG_INDEX_VERSION => Ok(ChunkIndex::Long(try!(Index::open_readonly(path, &INDEX_MAGIC, LONG_INDEX_VERSION)))),
+            version => Err(IndexError::UnsupportedVersion(version))
+        }
+    }
+
+    /// Converts an index file with 128 bit keys to 256 bit keys, returns the old version if the
+    /// file has been converted
+    #[inline]
+    pub fn migrate_to_long<P: AsRef<Path>>(path: P) -> Result<Option<u8>, IndexError> {
+        unsafe { Index::<LongKey, Location>::migrate(path, &INDEX_MAGIC, LONG_INDEX_VERSION, &INDEX_MIGRATIONS) }
+    }
+
+    /// Switches to 256 bit keys, the index is written to disk and opened again with write access
+    pub fn widen<P: AsRef<Path>>(&mut self, path: P) -> Result<(), IndexError> {
+        if self.is_long() {
+            return Ok(())
+        }
+        if self.is_writable() {
+            try!(self.flush());
+        }
+        try!(Self::migrate_to_long(path.as_ref()));
+        *self = try!(unsafe { Self::open(path, true) });
+        Ok(())
+    }
+
+    #[inline]
+    pub fn is_long(&self) -> bool {
+        match *self {
+            ChunkIndex::Short(_) => false,
+            ChunkIndex::Long(_) => true
+        }
+    }
+
+    #[inline]
+    pub fn version(&self) -> u8 {
+        if self.is_long() {
+            LONG_INDEX_VERSION
+        } else {
+            SHORT_INDEX_VERSION
+        }
+    }
+
+    /// Short indices can not contain long hashes, so these are never found
+    #[inline]
+    fn short_key(hash: &Hash) -> Option<ShortKey> {
+        if hash.is_long() {
+            None
+        } else {
+            Some(ShortKey{high: hash.high, low: hash.low})
+        }
+    }
+
+    /// Returns a view of the index that looks up chunks without write access
+    #[inline]
+    pub fn reader(&self) -> ChunkIndexReader {
+        match *self {
+            ChunkIndex::Short(ref index) => ChunkIndexReader::Short(index.reader()),
+            ChunkIndex::Long(ref index) => ChunkIndexReader::Long(index.reader())
+        }
+    }
+
+    #[inline]
+    pub fn is_writable(&self) -> bool {
+        match *self {
+            ChunkIndex::Short(ref index) => index.is_writable(),
+            ChunkIndex::Long(ref index) => index.is_writable()
+        }
+    }
+
+    #[inline]
+    pub fn set(&mut self, hash: &Hash, location: &Location) -> Result<Option<Location>, IndexError> {
+        match *self {
+            ChunkIndex::Short(ref mut index) => {
+                let key = Self::short_key(hash).expect("Long hashes need an index with long keys");
+                index.set(&key, location)
+            },
+            ChunkIndex::Long(ref mut index) => index.set(&LongKey::new(hash), location)
+        }
+    }
+
+    #[inline]
+    pub fn get(&self, hash: &Hash) -> Option<Location> {
+        match *self {
+            ChunkIndex::Short(ref index) => Self::short_key(hash).and_then(|key| index.get(&key)),
+            ChunkIndex::Long(ref index) => index.get(&LongKey::new(hash))
+        }
+    }
+
+    #[inline]
+    pub fn contains(&self, hash: &Hash) -> bool {
+        match *self {
+            ChunkIndex::Short(ref index) => Self::short_key(hash).map(|key| index.contains(&key)).unwrap_or(false),
+            ChunkIndex::Long(ref index) => index.contains(&LongKey::new(hash))
+        }
+    }
+
+    #[inline]
+    pub fn pos(&self, hash: &Hash) -> Option<usize> {
+        match *self {
+            ChunkIndex::Short(ref index) => Self::short_key(hash).and_then(|key| index.pos(&key)),
+            ChunkIndex::Long(ref index) => index.pos(&LongKey::new(hash))
+        }
+    }
+
+    #[inline]
+    pub fn delete(&mut self, hash: &Hash) -> Result<bool, IndexError> {
+        match *self {
+            ChunkIndex::Short(ref mut index) => match Self::short_key(hash) {
+                Some(key) => index.delete(&key),
+                None => Ok(false)
+            },
+            ChunkIndex::Long(ref mut index) => index.delete(&LongKey::new(hash))
+        }
+    }
+
+    #[inline]
+    pub fn filter<F>(&mut self, mut f: F) -> Result<usize, IndexError> where F: FnMut(&Location) -> bool {
+        match *self {
+            ChunkIndex::Short(ref mut index) => index.filter(|_key, location| f(location)),
+            ChunkIndex::Long(ref mut index) => index.filter(|_key, location| f(location))
+        }
+    }
+
+    #[inline]
+    pub fn iter<'a>(&'a self) -> Box<Iterator<Item=(Hash, Location)> + 'a> {
+        match *self {
+            ChunkIndex::Short(ref index) => Box::new(index.iter().map(|(key, location)| (Hash::new(key.high, key.low), *location))),
+            ChunkIndex::Long(ref index) => Box::new(index.iter().map(|(key, location)| (key.to_hash(), *location)))
+        }
+    }
+
+    #[inline]
+    pub fn clear(&mut self) -> Result<(), IndexError> {
+        match *self {
+            ChunkIndex::Short(ref mut index) => index.clear(),
+            ChunkIndex::Long(ref mut index) => index.clear()
+        }
+    }
+
+    #[inline]
+    pub fn needs_flush(&self) -> bool {
+        match *self {
+            ChunkIndex::Short(ref index) => index.needs_flush(),
+            ChunkIndex::Long(ref index) => index.needs_flush()
+        }
+    }
+
+    #[inline]
+    pub fn flush(&mut self) -> Result<(), IndexError> {
+        match *self {
+            ChunkIndex::Short(ref mut index) => index.flush(),
+            ChunkIndex::Long(ref mut index) => index.flush()
+        }
+    }
+
+    #[inline]
+    pub fn enable_filter(&mut self, fp_rate: f64, max_memory: usize) {
+        match *self {
+            ChunkIndex::Short(ref mut index) => index.enable_filter(fp_rate, max_memory),
+            ChunkIndex::Long(ref mut index) => index.enable_filter(fp_rate, max_memory)
+        }
+    }
+
+    #[inline]
+    pub fn disable_filter(&mut self) -> Result<(), IndexError> {
+        match *self {
+            ChunkIndex::Short(ref mut index) => index.disable_filter(),
+            ChunkIndex::Long(ref mut index) => index.disable_filter()
+        }
+    }
+
+    #[inline]
+    pub fn history(&self) -> Result<Vec<IndexHistoryEntry>, IndexError> {
+        match *self {
+            ChunkIndex::Short(ref index) => index.history(),
+            ChunkIndex::Long(ref index) => index.history()
+        }
+    }
+
+    #[inline]
+    pub fn stats(&self) -> IndexStats {
+        match *self {
+            ChunkIndex::Short(ref index) => index.stats(),
+            ChunkIndex::Long(ref index) => index.stats()
+        }
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        match *self {
+            ChunkIndex::Short(ref index) => index.len(),
+            ChunkIndex::Long(ref index) => index.len()
+        }
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    #[inline]
+    pub fn size(&self) -> usize {
+        match *self {
+            ChunkIndex::Short(ref index) => index.size(),
+            ChunkIndex::Long(ref index) => index.size()
+        }
+    }
+
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        match *self {
+            ChunkIndex::Short(ref index) => index.capacity(),
+            ChunkIndex::Long(ref index) => index.capacity()
+        }
+    }
+}
@@ -0,0 +1,497 @@
+use ::prelude::*;
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::io::{self, BufReader, Read, Write, BufWriter};
+use std::fs::{self, File};
+
+
+static HEADER_STRING: [u8; 7] = *b"zchkref";
+static HEADER_VERSION: u8 = 1;
+
+
+quick_error!{
+    #[derive(Debug)]
+    pub enum ChunkRefsError {
+        Io(err: io::Error) {
+            from()
+            cause(err)
+            description("Failed to read/write chunk references")
+        }
+        Decode(err: msgpack::DecodeError) {
+            from()
+            cause(err)
+            description("Failed to decode chunk references")
+        }
+        Encode(err: msgpack::EncodeError) {
+            from()
+            cause(err)
+            description("Failed to encode chunk references")
+        }
+        WrongHeader {
+            description("Wrong header")
+        }
+        WrongVersion(version: u8) {
+            description("Wrong version")
+            display("Wrong version: {}", version)
+        }
+        Inconsistent {
+            description("Inconsistent chunk references")
+            display("Chunk references are inconsistent with the backups")
+        }
+    }
+}
+
+
+/// The content of a chunk list, this determines which other chunk lists it references
+#[derive(Clone, Copy)]
+enum ListKind {
+    Inode,
+    ChunkList,
+    Data
+}
+
+
+/// Reference counts of all chunks that are used by backups
+///
+/// Inodes and lists of data chunks are counted by the number of backups and inodes that
+/// reference them. Only when such a list is referenced for the first time or loses its last
+/// reference, its chunks and the lists it references are counted, so unchanged subtrees of
+/// backups are never visited. Data chunks are counted once for every referencing list.
+pub struct ChunkRefs {
+    /// Number of references and length of every used chunk
+    chunks: HashMap<Hash, (u32, u32)>,
+    /// Number of references of every inode and indirect chunk list, by the hash of the list
+    lists: HashMap<Hash, u32>,
+    /// Root chunks of all counted backups
+    backups: HashMap<String, ChunkList>,
+    /// Number and size of the chunks that lost their last reference since the last vacuum
+    unused_chunks: u64,
+    unused_size: u64,
+    modified: bool
+}
+
+impl ChunkRefs {
+    pub fn new() -> Self {
+        ChunkRefs {
+            chunks: HashMap::new(),
+            lists: HashMap::new(),
+            backups: HashMap::new(),
+            unused_chunks: 0,
+            unused_size: 0,
+            modified: true
+        }
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, ChunkRefsError> {
+        let mut file = BufReader::new(try!(File::open(path.as_ref())));
+        let mut header = [0u8; 8];
+        try!(file.read_exact(&mut header));
+        if header[..HEADER_STRING.len()] != HEADER_STRING {
+            return Err(ChunkRefsError::WrongHeader)
+        }
+        let version = header[HEADER_STRING.len()];
+        if version != HEADER_VERSION {
+            return Err(ChunkRefsError::WrongVersion(version))
+        }
+        let (chunks, lists, backups, unused_chunks, unused_size) = try!(msgpack::decode_from_stream(&mut file));
+        Ok(ChunkRefs {
+            chunks: chunks,
+            lists: lists,
+            backups: backups,
+            unused_chunks: unused_chunks,
+            unused_size: unused_size,
+            modified: false
+        })
+    }
+
+    /// Writes the references to a temporary file that replaces the old file when it is
+    /// complete, so a crash never leaves truncated references behind
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), ChunkRefsError> {
+        let path = path.as_ref();
+        let temp_path = path.with_extension("refs.tmp");
+        {
+            let mut file = BufWriter::new(try!(File::create(&temp_path)));
+            try!(file.write_all(&HEADER_STRING));
+            try!(file.write_all(&[HEADER_VERSION]));
+            let data = (&self.chunks, &self.lists, &self.backups, self.unused_chunks, self.unused_size);
+            try!(msgpack::encode_to_stream(&data, &mut file).map_err(ChunkRefsError::Encode));
+            try!(file.flush());
+            try!(file.get_ref().sync_all());
+        }
+        try!(fs::rename(&temp_path, path));
+        Ok(())
+    }
+
+    fn list_id(list: &[Chunk]) -> Hash {
+        let mut data = Vec::with_capacity(list.len() * 40);
+        ChunkList::from(list.to_vec()).write_to(&mut data).unwrap();
+        HashMethod::Blake2.hash(&data)
+    }
+
+    fn add_chunks(&mut self, chunks: &[Chunk]) {
+        for &(hash, len) in chunks {
+            self.chunks.entry(hash).or_insert((0, len)).0 += 1;
+        }
+        self.modified = true;
+    }
+
+    fn remove_chunks(&mut self, chunks: &[Chunk]) -> Result<(), ChunkRefsError> {
+        self.modified = true;
+        for &(hash, len) in chunks {
+            let count = match self.chunks.get_mut(&hash) {
+                Some(entry) => {
+                    entry.0 -= 1;
+                    entry.0
+                },
+                None => return Err(ChunkRefsError::Inconsistent)
+            };
+            if count == 0 {
+                self.chunks.remove(&hash);
+                self.unused_chunks += 1;
+                self.unused_size += len as u64;
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds a reference to the list, returns whether this is its first reference
+    fn add_list(&mut self, list: &[Chunk]) -> bool {
+        let count = {
+            let count = self.lists.entry(Self::list_id(list)).or_insert(0);
+            *count += 1;
+            *count
+        };
+        if count == 1 {
+            self.add_chunks(list);
+        }
+        count == 1
+    }
+
+    /// Removes a reference from the list, returns whether this was its last reference
+    fn remove_list(&mut self, list: &[Chunk]) -> Result<bool, ChunkRefsError> {
+        let id = Self::list_id(list);
+        let count = match self.lists.get_mut(&id) {
+            Some(count) => {
+                *count -= 1;
+                *count
+            },
+            None => return Err(ChunkRefsError::Inconsistent)
+        };
+        if count == 0 {
+            self.lists.remove(&id);
+            try!(self.remove_chunks(list));
+        }
+        Ok(count == 0)
+    }
+
+    /// Returns the length of the chunk if it is used by any backup
+    #[inline]
+    pub fn get(&self, hash: &Hash) -> Option<u32> {
+        self.chunks.get(hash).map(|&(_, len)| len)
+    }
+
+    /// Number and size of the chunks that became unused since the last vacuum
+    #[inline]
+    pub fn unused(&self) -> (u64, u64) {
+        (self.unused_chunks, self.unused_size)
+    }
+
+    #[inline]
+    pub fn reset_unused(&mut self) {
+        self.unused_chunks = 0;
+        self.unused_size = 0;
+        self.modified = true;
+    }
+
+    /// Whether both contain the same references, the unused chunk counters are ignored
+    pub fn same_refs(&self, other: &Self) -> bool {
+        self.chunks == other.chunks && self.lists == other.lists && self.backups == other.backups
+    }
+}
+
+
+impl Repository {
+    /// Takes the chunk references out of the repository, the file is only loaded on the first
+    /// call, later calls return the references that have been put back. Broken references are
+    /// ignored, they are rebuilt by `sync_chunk_refs`.
+    fn take_chunk_refs(&mut self) -> Option<ChunkRefs> {
+        if self.chunk_refs_loaded {
+            return self.chunk_refs.take()
+        }
+        self.chunk_refs_loaded = true;
+        let path = self.layout.chunk_refs_path();
+        if !path.exists() {
+            return None
+        }
+        match ChunkRefs::load(&path) {
+            Ok(refs) => Some(refs),
+            Err(err) => {
+                warn!("Failed to load chunk references, they will be rebuilt\n\tcaused by: {}", err);
+                None
+            }
+        }
+    }
+
+    fn discard_chunk_refs(&mut self) {
+        self.chunk_refs = None;
+        let path = self.layout.chunk_refs_path();
+        if path.exists() {
+            if let Err(err) = fs::remove_file(&path) {
+                warn!("Failed to remove chunk references\n\tcaused by: {}", err);
+            }
+        }
+    }
+
+    pub fn save_chunk_refs(&mut self) -> Result<(), RepositoryError> {
+        if let Some(ref mut refs) = self.chunk_refs {
+            if refs.modified {
+                try!(refs.save(self.layout.chunk_refs_path()));
+                refs.modified = false;
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds or removes the references of the tree starting at the given root inode
+    fn count_refs(&mut self, refs: &mut ChunkRefs, root: &[Chunk], add: bool) -> Result<(), RepositoryError> {
+        let mut todo = vec![(root.to_vec(), ListKind::Inode)];
+        while let Some((chunks, kind)) = todo.pop() {
+            if let ListKind::Data = kind {
+                if add {
+                    refs.add_chunks(&chunks);
+                } else {
+                    try!(refs.remove_chunks(&chunks));
+                }
+                continue
+            }
+            let changed = if add {
+                refs.add_list(&chunks)
+            } else {
+                try!(refs.remove_list(&chunks))
+            };
+            if !changed {
+                continue
+            }
+            match kind {
+                ListKind::Inode => {
+                    let inode = try!(self.get_inode(&chunks));
+                    match inode.data {
+                        None | Some(FileData::Inline(_)) => (),
+                        Some(FileData::ChunkedDirect(chunks)) => todo.push((chunks.into_inner(), ListKind::Data)),
+                        Some(FileData::ChunkedIndirect(chunks)) => todo.push((chunks.into_inner(), ListKind::ChunkList))
+                    }
+                    if let Some(children) = inode.children {
+                        for (_name, chunks) in children {
+                            todo.push((chunks.into_inner(), ListKind::Inode));
+                        }
+                    }
+                },
+                ListKind::ChunkList => {
+                    let chunk_data = try!(self.get_data(&chunks));
+                    todo.push((ChunkList::read_from(&chunk_data, chunk_hash_size(&chunks)).into_inner(), ListKind::Data));
+                },
+                ListKind::Data => unreachable!()
+            }
+        }
+        Ok(())
+    }
+
+    /// Counts the references of the backup with its new root, `None` if it has been deleted
+    fn replace_backup_refs(&mut self, refs: &mut ChunkRefs, name: &str, root: Option<&ChunkList>) -> Result<(), RepositoryError> {
+        if refs.backups.get(name) == root {
+            return Ok(())
+        }
+        // The new references are added first, so shared chunks are never counted as unused
+        if let Some(root) = root {
+            try!(self.count_refs(refs, root, true));
+        }
+        let old = if let Some(root) = root {
+            refs.backups.insert(name.to_string(), root.clone())
+        } else {
+            refs.backups.remove(name)
+        };
+        refs.modified = true;
+        if let Some(old) = old {
+            try!(self.count_refs(refs, &old, false));
+        }
+        Ok(())
+    }
+
+    fn sync_backup_refs(&mut self, refs: &mut ChunkRefs, backups: &HashMap<String, Backup>) -> Result<(), RepositoryError> {
+        for (name, backup) in backups {
+            try!(self.replace_backup_refs(refs, name, Some(&backup.root)));
+        }
+        let gone: Vec<String> = refs.backups.keys().filter(|name| !backups.contains_key(*name)).cloned().collect();
+        for name in gone {
+            try!(self.replace_backup_refs(refs, &name, None));
+        }
+        Ok(())
+    }
+
+    /// Updates the chunk references after a backup has been saved or deleted. Failures are not
+    /// fatal as the references are rebuilt by the next vacuum.
+    pub fn update_chunk_refs(&mut self, name: &str, root: Option<&ChunkList>) {
+        let mut refs = match self.take_chunk_refs() {
+            Some(refs) => refs,
+            None => return
+        };
+        match self.replace_backup_refs(&mut refs, name, root) {
+            Ok(()) => self.chunk_refs = Some(refs),
+            Err(err) => {
+                warn!("Failed to update chunk references, they will be rebuilt\n\tcaused by: {}", err);
+                self.discard_chunk_refs();
+            }
+        }
+    }
+
+    /// Brings the chunk references up to date with all backups. Backups that have been added or
+    /// removed by other clients are counted incrementally, the references are only rebuilt
+    /// from all backups when they are missing or inconsistent.
+    pub fn sync_chunk_refs(&mut self) -> Result<(), RepositoryError> {
+        let backups = try!(self.get_all_backups());
+        let mut refs = match self.take_chunk_refs() {
+            Some(refs) => refs,
+            None => {
+                info!("Counting chunk references of all backups");
+                ChunkRefs::new()
+            }
+        };
+        if let Err(err) = self.sync_backup_refs(&mut refs, &backups) {
+            warn!("Chunk references are inconsistent, counting them again\n\tcaused by: {}", err);
+            refs = ChunkRefs::new();
+            try!(self.sync_backup_refs(&mut refs, &backups));
+        }
+        self.chunk_refs = Some(refs);
+        Ok(())
+    }
+
+    /// Number and size of the chunks that became unused since the last vacuum, if the chunk
+    /// references are available
+    pub fn unused_chunks(&mut self) -> Option<(u64, u64)> {
+        let refs = self.take_chunk_refs();
+        let unused = refs.as_ref().map(|refs| refs.unused());
+        self.chunk_refs = refs;
+        unused
+    }
+
+    /// Verifies the stored chunk references by counting them again from all backups
+    pub fn check_chunk_refs(&mut self, repair: bool) -> Result<(), RepositoryError> {
+        if repair {
+            try!(self.write_mode());
+        }
+        info!("Checking chunk references...");
+        try!(self.save_chunk_refs());
+        let backups = try!(self.get_all_backups());
+        let mut fresh = ChunkRefs::new();
+        try!(self.sync_backup_refs(&mut fresh, &backups));
+        let mut stored = match self.take_chunk_refs() {
+            Some(refs) => refs,
+            None => {
+                if repair {
+                    self.chunk_refs = Some(fresh);
+                }
+                return Ok(())
+            }
+        };
+        let consistent = self.sync_backup_refs(&mut stored, &backups).is_ok() && stored.same_refs(&fresh);
+        if consistent {
+            if repair {
+                self.chunk_refs = Some(stored);
+            }
+            return Ok(())
+        }
+        if repair {
+            warn!("Problem detected: chunk references were inconsistent");
+            let (unused_chunks, unused_size) = stored.unused();
+            fresh.unused_chunks = unused_chunks;
+            fresh.unused_size = unused_size;
+            self.chunk_refs = Some(fresh);
+            Ok(())
+        } else {
+            Err(ChunkRefsError::Inconsistent.into())
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_util::temp_dir;
+
+    fn write_file(path: &Path, seed: u32) {
+        let data: Vec<u8> = (0..300_000u32).map(|i| (i.wrapping_mul(seed) ^ (i >> 7)) as u8).collect();
+        File::create(path).and_then(|mut f| f.write_all(&data)).unwrap();
+    }
+
+    fn backup(repo: &mut Repository, path: &Path, name: &str) {
+        let options = BackupOptions { same_device: true, excludes: None };
+        let backup = repo.create_backup_recursively(path, None, &options).unwrap();
+        repo.save_backup(&backup, name).unwrap();
+        repo.flush().unwrap();
+    }
+
+    /// Compares the stored references with a fresh count of all backups and checks that all
+    /// referenced chunks are still stored
+    fn assert_refs(repo: &mut Repository) {
+        repo.check_chunk_refs(false).unwrap();
+        let refs = repo.chunk_refs.as_ref().expect("Chunk references should be loaded");
+        for hash in refs.chunks.keys() {
+            assert!(repo.index.contains(hash));
+        }
+    }
+
+    #[test]
+    fn test_refs_backup_delete_vacuum() {
+        let dir = temp_dir("chunk-refs");
+        let mut config = Config::default();
+        config.compression = None;
+        let mut repo = Repository::create(dir.join("repo"), config, dir.join("remote")).unwrap();
+        for &(name, seeds) in &[("src1", [3, 5, 7]), ("src2", [3, 11, 13])] {
+            let src = dir.join(name);
+            fs::create_dir_all(&src).unwrap();
+            for seed in &seeds {
+                write_file(&src.join(format!("file{}", seed)), *seed);
+            }
+        }
+        backup(&mut repo, &dir.join("src1"), "one");
+        backup(&mut repo, &dir.join("src2"), "two");
+        assert_refs(&mut repo);
+        assert_eq!(repo.unused_chunks(), Some((0, 0)));
+        let used = repo.chunk_refs.as_ref().unwrap().chunks.len();
+
+        // Saving the same backup again does not change the counts
+        let again = repo.get_backup("two").unwrap();
+        repo.save_backup(&again, "two").unwrap();
+        assert_refs(&mut repo);
+        assert_eq!(repo.chunk_refs.as_ref().unwrap().chunks.len(), used);
+
+        // The chunks of the shared file are still used by the second backup
+        repo.delete_backup("one").unwrap();
+        repo.flush().unwrap();
+        assert_refs(&mut repo);
+        let (unused, unused_size) = repo.unused_chunks().unwrap();
+        assert!(unused > 0 && unused_size > 0);
+        assert!(repo.chunk_refs.as_ref().unwrap().chunks.len() < used);
+        assert_eq!(ChunkRefs::load(repo.layout.chunk_refs_path()).unwrap().unused(), (unused, unused_size));
+
+        repo.vacuum(1.0, false, true).unwrap();
+        assert_refs(&mut repo);
+        assert_eq!(repo.unused_chunks(), Some((0, 0)));
+        let backup = repo.get_backup("two").unwrap();
+        let inode = repo.get_inode(&backup.root).unwrap();
+        fs::create_dir_all(dir.join("restored")).unwrap();
+        repo.restore_inode_tree(&backup, inode, dir.join("restored")).unwrap();
+        for seed in &[3, 11, 13] {
+            let name = format!("file{}", seed);
+            let mut restored = Vec::new();
+            File::open(dir.join("restored").join(&name)).and_then(|mut f| f.read_to_end(&mut restored)).unwrap();
+            let mut original = Vec::new();
+            File::open(dir.join("src2").join(&name)).and_then(|mut f| f.read_to_end(&mut original)).unwrap();
+            assert!(restored == original);
+        }
+        drop(repo);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
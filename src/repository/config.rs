@@ -1,10 +1,15 @@
 use ::prelude::*;
 
 use serde_yaml;
+use serde_bytes::ByteBuf;
 
 use std::fs::File;
 use std::path::Path;
 use std::io;
+use std::str::FromStr;
+
+
+pub const DEFAULT_INDEX_FILTER_MEMORY: usize = 256;
 
 
 quick_error!{
@@ -43,33 +48,56 @@ impl HashMethod {
 struct ChunkerYaml {
     method: String,
     avg_size: usize,
-    seed: u64
+    seed: u64,
+    min_size: Option<usize>,
+    max_size: Option<usize>,
+    normalization: Option<usize>
 }
 impl Default for ChunkerYaml {
     fn default() -> Self {
         ChunkerYaml {
             method: "fastcdc".to_string(),
             avg_size: 16*1024,
-            seed: 0
+            seed: 0,
+            min_size: None,
+            max_size: None,
+            normalization: None
         }
     }
 }
-serde_impl!(ChunkerYaml(String) {
+serde_impl!(ChunkerYaml(String?) {
     method: String => "method",
     avg_size: usize => "avg_size",
-    seed: u64 => "seed"
+    seed: u64 => "seed",
+    min_size: Option<usize> => "min_size",
+    max_size: Option<usize> => "max_size",
+    normalization: Option<usize> => "normalization"
 });
 
 impl ChunkerType {
-    fn from_yaml(yaml: ChunkerYaml) -> Result<Self, ConfigError> {
-        ChunkerType::from(&yaml.method, yaml.avg_size, yaml.seed).map_err(ConfigError::Parse)
+    fn from_yaml(yaml: ChunkerYaml) -> Result<(Self, Option<ChunkerLimits>), ConfigError> {
+        let chunker = try!(ChunkerType::from(&yaml.method, yaml.avg_size, yaml.seed).map_err(ConfigError::Parse));
+        if yaml.min_size.is_none() && yaml.max_size.is_none() && yaml.normalization.is_none() {
+            return Ok((chunker, None))
+        }
+        let defaults = chunker.default_limits();
+        let limits = ChunkerLimits {
+            min_size: yaml.min_size.unwrap_or(defaults.min_size),
+            max_size: yaml.max_size.unwrap_or(defaults.max_size),
+            normalization: yaml.normalization.unwrap_or(defaults.normalization)
+        };
+        try!(chunker.check_limits(&limits).map_err(ConfigError::Parse));
+        Ok((chunker, Some(limits)))
     }
 
-    fn to_yaml(&self) -> ChunkerYaml {
+    fn to_yaml(&self, limits: Option<&ChunkerLimits>) -> ChunkerYaml {
         ChunkerYaml {
             method: self.name().to_string(),
             avg_size: self.avg_size(),
-            seed: self.seed()
+            seed: self.seed(),
+            min_size: limits.map(|l| l.min_size),
+            max_size: limits.map(|l| l.max_size),
+            normalization: limits.map(|l| l.normalization)
         }
     }
 }
@@ -102,6 +130,88 @@ impl EncryptionMethod {
 }
 
 
+impl Redundancy {
+    #[inline]
+    fn from_yaml(yaml: String) -> Result<Self, ConfigError> {
+        Redundancy::from_string(&yaml).map_err(|_| ConfigError::Parse("Invalid redundancy"))
+    }
+
+    #[inline]
+    fn to_yaml(&self) -> String {
+        self.to_string()
+    }
+}
+
+
+/// An optional bloom filter in front of the index with its false-positive rate (in lookups per
+/// million) and its maximal memory usage
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct IndexFilter {
+    pub fp_rate: u32,
+    pub max_memory: usize
+}
+serde_impl!(IndexFilter(u8) {
+    fp_rate: u32 => 0,
+    max_memory: usize => 1
+});
+
+impl IndexFilter {
+    pub fn new(fp_rate: f64, max_memory: usize) -> Result<Self, &'static str> {
+        if fp_rate <= 0.0 || fp_rate >= 0.5 {
+            return Err("false-positive rate must be between 0% and 50%")
+        }
+        let fp_rate = (fp_rate * 1_000_000.0).round() as u32;
+        if fp_rate == 0 {
+            return Err("false-positive rate is too small")
+        }
+        if max_memory == 0 {
+            return Err("memory limit must be at least 1 MiB")
+        }
+        Ok(IndexFilter { fp_rate: fp_rate, max_memory: max_memory })
+    }
+
+    /// Parses `RATE%` or `RATE%/MEMORY` with the memory limit given in MiB
+    pub fn from_string(val: &str) -> Result<Self, &'static str> {
+        let (rate, memory) = if let Some(pos) = val.find('/') {
+            (&val[..pos], Some(&val[pos+1..]))
+        } else {
+            (val, None)
+        };
+        if !rate.ends_with('%') {
+            return Err("false-positive rate must be given in percent")
+        }
+        let rate = try!(f64::from_str(&rate[..rate.len()-1]).map_err(|_| "false-positive rate must be a number"));
+        let memory = if let Some(memory) = memory {
+            try!(usize::from_str(memory).map_err(|_| "memory limit must be a number"))
+        } else {
+            DEFAULT_INDEX_FILTER_MEMORY
+        };
+        let memory = try!(memory.checked_mul(1024 * 1024).ok_or("memory limit is too large"));
+        IndexFilter::new(rate / 100.0, memory)
+    }
+
+    /// The rate is stored in millionths, so four decimal places of the percentage are exact
+    pub fn to_string(&self) -> String {
+        format!("{:.4}%/{}", self.fp_rate() * 100.0, self.max_memory / (1024 * 1024))
+    }
+
+    #[inline]
+    pub fn fp_rate(&self) -> f64 {
+        self.fp_rate as f64 / 1_000_000.0
+    }
+
+    #[inline]
+    fn from_yaml(yaml: String) -> Result<Self, ConfigError> {
+        IndexFilter::from_string(&yaml).map_err(|_| ConfigError::Parse("Invalid index filter"))
+    }
+
+    #[inline]
+    fn to_yaml(&self) -> String {
+        self.to_string()
+    }
+}
+
+
 struct EncryptionYaml {
     method: String,
     key: String
@@ -121,30 +231,93 @@ serde_impl!(EncryptionYaml(String) {
 
 
 
+struct KeyDerivationYaml {
+    salt: String,
+    ops_limit: usize,
+    mem_limit: usize
+}
+impl Default for KeyDerivationYaml {
+    fn default() -> Self {
+        KeyDerivationYaml {
+            salt: "".to_string(),
+            ops_limit: 0,
+            mem_limit: 0
+        }
+    }
+}
+serde_impl!(KeyDerivationYaml(String) {
+    salt: String => "salt",
+    ops_limit: usize => "ops_limit",
+    mem_limit: usize => "mem_limit"
+});
+
+impl KeyDerivation {
+    fn from_yaml(yaml: KeyDerivationYaml) -> Result<Self, ConfigError> {
+        let salt = try!(parse_hex(&yaml.salt).map_err(|_| ConfigError::Parse("Invalid key derivation salt")));
+        Ok(KeyDerivation { salt: salt.into(), ops_limit: yaml.ops_limit, mem_limit: yaml.mem_limit })
+    }
+
+    fn to_yaml(&self) -> KeyDerivationYaml {
+        KeyDerivationYaml { salt: to_hex(&self.salt[..]), ops_limit: self.ops_limit, mem_limit: self.mem_limit }
+    }
+}
+
+
+
 struct ConfigYaml {
     compression: Option<String>,
+    adaptive_compression: bool,
     encryption: Option<EncryptionYaml>,
     bundle_size: usize,
     chunker: ChunkerYaml,
     hash: String,
+    redundancy: Option<String>,
+    dictionary: Option<String>,
+    hash_key: Option<String>,
+    keyed_chunker_seed: bool,
+    key_derivation: Option<KeyDerivationYaml>,
+    hide_backup_names: bool,
+    index_filter: Option<String>,
+    format_aware_chunking: bool,
+    admin_key: Option<String>
 }
 impl Default for ConfigYaml {
     fn default() -> Self {
         ConfigYaml {
             compression: Some("brotli/5".to_string()),
+            adaptive_compression: false,
             encryption: None,
             bundle_size: 25*1024*1024,
             chunker: ChunkerYaml::default(),
-            hash: "blake2".to_string()
+            hash: "blake2".to_string(),
+            redundancy: None,
+            dictionary: None,
+            hash_key: None,
+            keyed_chunker_seed: false,
+            key_derivation: None,
+            hide_backup_names: false,
+            index_filter: None,
+            format_aware_chunking: false,
+            admin_key: None
         }
     }
 }
-serde_impl!(ConfigYaml(String) {
+serde_impl!(ConfigYaml(String?) {
     compression: Option<String> => "compression",
+    adaptive_compression: bool => "adaptive_compression",
     encryption: Option<EncryptionYaml> => "encryption",
     bundle_size: usize => "bundle_size",
     chunker: ChunkerYaml => "chunker",
-    hash: String => "hash"
+    hash: String => "hash",
+    redundancy: Option<String> => "redundancy",
+    dictionary: Option<String> => "dictionary",
+    hash_key: Option<String> => "hash_key",
+    keyed_chunker_seed: bool => "keyed_chunker_seed",
+    key_derivation: Option<KeyDerivationYaml> => "key_derivation",
+    hide_backup_names: bool => "hide_backup_names",
+    index_filter: Option<String> => "index_filter",
+    format_aware_chunking: bool => "format_aware_chunking",
+    admin_key: Option<String> => "admin_key"
 });
 
 
@@ -155,7 +328,21 @@ pub struct Config {
     pub encryption: Option<Encryption>,
     pub bundle_size: usize,
     pub chunker: ChunkerType,
-    pub hash: HashMethod
+    pub hash: HashMethod,
+    pub redundancy: Option<Redundancy>,
+    pub dictionary: Option<Hash>,
+    pub adaptive_compression: bool,
+    pub hash_key: Option<ByteBuf>,
+    /// Whether the chunker seed is derived from the hash key, which is only the case for hash
+    /// keys created since then so that older repositories keep their chunk boundaries
+    pub keyed_chunker_seed: bool,
+    pub key_derivation: Option<KeyDerivation>,
+    pub hide_backup_names: bool,
+    pub index_filter: Option<IndexFilter>,
+    pub chunker_limits: Option<ChunkerLimits>,
+    pub format_aware_chunking: bool,
+    /// Public administrator key of append-only repositories, the config signature protects it
+    pub admin_key: Option<ByteBuf>
 }
 impl Default for Config {
     fn default() -> Self {
@@ -164,16 +351,38 @@ impl Default for Config {
             encryption: None,
             bundle_size: 25*1024*1024,
             chunker: ChunkerType::from_string("fastcdc/16").unwrap(),
-            hash: HashMethod::Blake2
+            hash: HashMethod::Blake2,
+            redundancy: None,
+            dictionary: None,
+            adaptive_compression: false,
+            hash_key: None,
+            keyed_chunker_seed: false,
+            key_derivation: None,
+            hide_backup_names: false,
+            index_filter: None,
+            chunker_limits: None,
+            format_aware_chunking: false,
+            admin_key: None
         }
     }
 }
-serde_impl!(Config(u64) {
+serde_impl!(Config(u64?) {
     compression: Option<Compression> => 0,
     encryption: Option<Encryption> => 1,
     bundle_size: usize => 2,
     chunker: ChunkerType => 3,
-    hash: HashMethod => 4
+    hash: HashMethod => 4,
+    redundancy: Option<Redundancy> => 5,
+    dictionary: Option<Hash> => 6,
+    adaptive_compression: bool => 7,
+    hash_key: Option<ByteBuf> => 8,
+    key_derivation: Option<KeyDerivation> => 9,
+    hide_backup_names: bool => 10,
+    index_filter: Option<IndexFilter> => 11,
+    chunker_limits: Option<ChunkerLimits> => 12,
+    format_aware_chunking: bool => 13,
+    admin_key: Option<ByteBuf> => 14,
+    keyed_chunker_seed: bool => 15
 });
 
 impl Config {
@@ -190,22 +399,74 @@ impl Config {
         } else {
             None
         };
+        let redundancy = if let Some(r) = yaml.redundancy {
+            Some(try!(Redundancy::from_yaml(r)))
+        } else {
+            None
+        };
+        let dictionary = if let Some(d) = yaml.dictionary {
+            Some(try!(Hash::from_string(&d).map_err(|_| ConfigError::Parse("Invalid dictionary id"))))
+        } else {
+            None
+        };
+        let hash_key = if let Some(k) = yaml.hash_key {
+            Some(try!(parse_hex(&k).map_err(|_| ConfigError::Parse("Invalid hash key"))).into())
+        } else {
+            None
+        };
+        let key_derivation = if let Some(k) = yaml.key_derivation {
+            Some(try!(KeyDerivation::from_yaml(k)))
+        } else {
+            None
+        };
+        let index_filter = if let Some(f) = yaml.index_filter {
+            Some(try!(IndexFilter::from_yaml(f)))
+        } else {
+            None
+        };
+        let admin_key = if let Some(k) = yaml.admin_key {
+            Some(try!(parse_hex(&k).map_err(|_| ConfigError::Parse("Invalid administrator key"))).into())
+        } else {
+            None
+        };
+        let (chunker, chunker_limits) = try!(ChunkerType::from_yaml(yaml.chunker));
         Ok(Config{
             compression: compression,
             encryption: encryption,
             bundle_size: yaml.bundle_size,
-            chunker: try!(ChunkerType::from_yaml(yaml.chunker)),
-            hash: try!(HashMethod::from_yaml(yaml.hash))
+            chunker: chunker,
+            hash: try!(HashMethod::from_yaml(yaml.hash)),
+            redundancy: redundancy,
+            dictionary: dictionary,
+            adaptive_compression: yaml.adaptive_compression,
+            hash_key: hash_key,
+            keyed_chunker_seed: yaml.keyed_chunker_seed,
+            key_derivation: key_derivation,
+            hide_backup_names: yaml.hide_backup_names,
+            index_filter: index_filter,
+            chunker_limits: chunker_limits,
+            format_aware_chunking: yaml.format_aware_chunking,
+            admin_key: admin_key
         })
     }
 
     fn to_yaml(&self) -> ConfigYaml {
         ConfigYaml {
             compression: self.compression.as_ref().map(|c| c.to_yaml()),
+            adaptive_compression: self.adaptive_compression,
             encryption: self.encryption.as_ref().map(|e| EncryptionYaml{method: e.0.to_yaml(), key: to_hex(&e.1[..])}),
             bundle_size: self.bundle_size,
-            chunker: self.chunker.to_yaml(),
-            hash: self.hash.to_yaml()
+            chunker: self.chunker.to_yaml(self.chunker_limits.as_ref()),
+            hash: self.hash.to_yaml(),
+            redundancy: self.redundancy.as_ref().map(|r| r.to_yaml()),
+            dictionary: self.dictionary.as_ref().map(|d| d.to_string()),
+            hash_key: self.hash_key.as_ref().map(|k| to_hex(&k[..])),
+            keyed_chunker_seed: self.keyed_chunker_seed,
+            key_derivation: self.key_derivation.as_ref().map(|k| k.to_yaml()),
+            hide_backup_names: self.hide_backup_names,
+            index_filter: self.index_filter.as_ref().map(|f| f.to_yaml()),
+            format_aware_chunking: self.format_aware_chunking,
+            admin_key: self.admin_key.as_ref().map(|k| to_hex(&k[..]))
         }
     }
 
@@ -221,3 +482,27 @@ impl Config {
         Ok(())
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_index_filter_roundtrip() {
+        for val in &["1%/16", "0.0001%/1", "33.3333%/1024", "12.5%"] {
+            let filter = IndexFilter::from_string(val).unwrap();
+            let parsed = IndexFilter::from_string(&filter.to_string()).unwrap();
+            assert_eq!(filter.fp_rate, parsed.fp_rate);
+            assert_eq!(filter.max_memory, parsed.max_memory);
+        }
+        assert_eq!(IndexFilter::from_string("1%/16").unwrap().to_string(), "1.0000%/16");
+    }
+
+    #[test]
+    fn test_index_filter_memory_overflow() {
+        let val = format!("1%/{}", usize::max_value() / 1024);
+        assert!(IndexFilter::from_string(&val).is_err());
+        assert!(IndexFilter::from_string("1%/0").is_err());
+    }
+}
@@ -8,6 +8,8 @@ use super::backup::BackupError;
 use super::bundle_map::BundleMapError;
 use super::config::ConfigError;
 use super::metadata::InodeError;
+use super::append_only::AppendOnlyError;
+use super::chunk_refs::ChunkRefsError;
 
 
 quick_error!{
@@ -48,6 +50,12 @@ quick_error!{
             description("Chunker error")
             display("Repository error: failed to chunk data\n\tcaused by: {}", err)
         }
+        Compression(err: CompressionError) {
+            from()
+            cause(err)
+            description("Compression error")
+            display("Repository error: compression error\n\tcaused by: {}", err)
+        }
         Config(err: ConfigError) {
             from()
             cause(err)
@@ -78,6 +86,14 @@ quick_error!{
             description("Integrity error")
             display("Repository error: integrity error\n\tcaused by: {}", err)
         }
+        HashKey(reason: &'static str) {
+            description("Hash key error")
+            display("Repository error: hash key error: {}", reason)
+        }
+        ConfigSignature(reason: &'static str) {
+            description("Config signature error")
+            display("Repository error: config signature error: {}", reason)
+        }
         Dirty {
             description("Dirty repository")
             display("The repository is dirty, please run a check")
@@ -88,6 +104,18 @@ quick_error!{
             description("Failed to create a backup")
             display("Repository error: failed to create backup\n\tcaused by: {}", err)
         }
+        AppendOnly(err: AppendOnlyError) {
+            from()
+            cause(err)
+            description("Append-only error")
+            display("Repository error: append-only error\n\tcaused by: {}", err)
+        }
+        ChunkRefs(err: ChunkRefsError) {
+            from()
+            cause(err)
+            description("Chunk reference error")
+            display("Repository error: chunk reference error\n\tcaused by: {}", err)
+        }
         Lock(err: LockError) {
             from()
             cause(err)
@@ -1,6 +1,6 @@
 use ::prelude::*;
 
-use std::collections::{HashMap, VecDeque};
+use std::collections::{HashMap, HashSet, VecDeque};
 
 
 pub struct BundleAnalysis {
@@ -39,6 +39,23 @@ pub struct RepositoryInfo {
 }
 
 
+#[derive(Default)]
+pub struct KeyUsage {
+    pub secret_key: bool,
+    pub protected: bool,
+    pub config: bool,
+    pub bundles: usize,
+    pub backups: Vec<String>
+}
+
+impl KeyUsage {
+    #[inline]
+    pub fn is_used(&self) -> bool {
+        self.config || self.bundles > 0 || !self.backups.is_empty()
+    }
+}
+
+
 impl Repository {
     fn mark_used(&self, bundles: &mut HashMap<u32, BundleAnalysis>, chunks: &[Chunk]) -> Result<bool, RepositoryError> {
         let mut new = false;
@@ -60,11 +77,113 @@ impl Repository {
         Ok(new)
     }
 
-    pub fn analyze_usage(&mut self) -> Result<HashMap<u32, BundleAnalysis>, RepositoryError> {
-        if self.dirty {
-            return Err(RepositoryError::Dirty)
+    /// Lists how all known public keys are used, i.e. the keys with a key file and all keys that
+    /// are referenced by the config, bundles or backup files
+    pub fn key_usage(&self) -> Result<HashMap<PublicKey, KeyUsage>, RepositoryError> {
+        let crypto = self.crypto.lock().unwrap();
+        let mut usage: HashMap<PublicKey, KeyUsage> = HashMap::new();
+        for key in crypto.public_keys() {
+            usage.entry(key).or_insert_with(KeyUsage::default);
         }
-        try!(self.set_dirty());
+        if let Some(ref encryption) = self.config.encryption {
+            for key in try!(Crypto::recipients(encryption)) {
+                usage.entry(key).or_insert_with(KeyUsage::default).config = true;
+            }
+        }
+        for bundle in self.bundles.list_bundles() {
+            if let Some(ref encryption) = bundle.encryption {
+                for key in try!(Crypto::recipients(encryption)) {
+                    usage.entry(key).or_insert_with(KeyUsage::default).bundles += 1;
+                }
+            }
+        }
+        for (name, path) in try!(Backup::list_from(self.layout.backups_path())) {
+            if let Some(encryption) = try!(Backup::read_encryption_from(&path)) {
+                // Hidden names can only be shown if they can be decrypted
+                let name = Backup::read_name_from(&crypto, &path).ok().and_then(|n| n).unwrap_or(name);
+                for key in try!(Crypto::recipients(&encryption)) {
+                    usage.entry(key).or_insert_with(KeyUsage::default).backups.push(name.clone());
+                }
+            }
+        }
+        for (key, usage) in &mut usage {
+            usage.secret_key = crypto.contains_secret_key(key);
+            usage.protected = crypto.is_protected(key);
+        }
+        Ok(usage)
+    }
+
+    /// Records the missing keys of the bundles that contain the chunks and returns whether all
+    /// chunks can be decrypted. Unknown chunks are skipped, they are reported when loading them.
+    fn check_chunk_keys(&self, chunks: &[Chunk], readable: &mut HashMap<u32, bool>, missing: &mut HashSet<PublicKey>) -> Result<bool, RepositoryError> {
+        let mut all_readable = true;
+        for &(hash, _) in chunks {
+            let bundle = match self.index.get(&hash) {
+                Some(location) => location.bundle,
+                None => continue
+            };
+            if let Some(&ok) = readable.get(&bundle) {
+                all_readable &= ok;
+                continue
+            }
+            let mut ok = true;
+            let bundle_id = try!(self.get_bundle_id(bundle));
+            if let Some(stored) = self.bundles.get_bundle_info(&bundle_id) {
+                if let Some(ref encryption) = stored.info.encryption {
+                    let recipients = try!(Crypto::recipients(encryption));
+                    let crypto = self.crypto.lock().unwrap();
+                    if !recipients.iter().any(|key| crypto.contains_secret_key(key)) {
+                        missing.extend(recipients);
+                        ok = false;
+                    }
+                }
+            }
+            readable.insert(bundle, ok);
+            all_readable &= ok;
+        }
+        Ok(all_readable)
+    }
+
+    fn check_inode_keys(&mut self, inode: &Inode, readable: &mut HashMap<u32, bool>, missing: &mut HashSet<PublicKey>, todo: &mut VecDeque<ChunkList>) -> Result<(), RepositoryError> {
+        match inode.data {
+            None | Some(FileData::Inline(_)) => (),
+            Some(FileData::ChunkedDirect(ref chunks)) => {
+                try!(self.check_chunk_keys(chunks, readable, missing));
+            },
+            Some(FileData::ChunkedIndirect(ref chunks)) => {
+                if try!(self.check_chunk_keys(chunks, readable, missing)) {
+                    let chunk_data = try!(self.get_data(chunks));
+                    let chunks = ChunkList::read_from(&chunk_data, chunks.hash_size());
+                    try!(self.check_chunk_keys(&chunks, readable, missing));
+                }
+            }
+        }
+        if let Some(ref children) = inode.children {
+            for chunks in children.values() {
+                // Children in bundles that can not be decrypted can not be visited
+                if try!(self.check_chunk_keys(chunks, readable, missing)) {
+                    todo.push_back(chunks.clone());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The public keys of bundles that can not be decrypted because the secret keys of all their
+    /// recipients are missing, only the bundles used by the tree of the given inode are checked
+    pub fn missing_keys(&mut self, inode: &Inode) -> Result<Vec<PublicKey>, RepositoryError> {
+        let mut readable = HashMap::new();
+        let mut missing = HashSet::new();
+        let mut todo = VecDeque::new();
+        try!(self.check_inode_keys(inode, &mut readable, &mut missing, &mut todo));
+        while let Some(chunks) = todo.pop_back() {
+            let inode = try!(self.get_inode(&chunks));
+            try!(self.check_inode_keys(&inode, &mut readable, &mut missing, &mut todo));
+        }
+        Ok(missing.into_iter().collect())
+    }
+
+    fn empty_usage(&self) -> Result<HashMap<u32, BundleAnalysis>, RepositoryError> {
         let mut usage = HashMap::new();
         for (id, bundle) in self.bundle_map.bundles() {
             let bundle = try!(self.bundles.get_bundle_info(&bundle).ok_or_else(|| IntegrityError::MissingBundle(bundle)));
@@ -74,6 +193,16 @@ impl Repository {
                 used_raw_size: 0
             });
         }
+        Ok(usage)
+    }
+
+    /// Analyzes the chunk usage by scanning all backups
+    pub fn analyze_usage(&mut self) -> Result<HashMap<u32, BundleAnalysis>, RepositoryError> {
+        if self.dirty {
+            return Err(RepositoryError::Dirty)
+        }
+        try!(self.set_dirty());
+        let mut usage = try!(self.empty_usage());
         let backups = try!(self.get_all_backups());
         let mut todo = VecDeque::new();
         for (_name, backup) in backups {
@@ -93,7 +222,7 @@ impl Repository {
                 Some(FileData::ChunkedIndirect(chunks)) => {
                     if try!(self.mark_used(&mut usage, &chunks)) {
                         let chunk_data = try!(self.get_data(&chunks));
-                        let chunks = ChunkList::read_from(&chunk_data);
+                        let chunks = ChunkList::read_from(&chunk_data, chunks.hash_size());
                         try!(self.mark_used(&mut usage, &chunks));
                     }
                 }
@@ -109,6 +238,56 @@ impl Repository {
         Ok(usage)
     }
 
+    /// Analyzes the chunk usage using the chunk reference counts, this only needs to scan the
+    /// backups that changed since the references have been updated
+    pub fn analyze_usage_from_refs(&mut self) -> Result<HashMap<u32, BundleAnalysis>, RepositoryError> {
+        if self.dirty {
+            return Err(RepositoryError::Dirty)
+        }
+        try!(self.set_dirty());
+        try!(self.sync_chunk_refs());
+        let mut usage = try!(self.empty_usage());
+        if let Some(ref refs) = self.chunk_refs {
+            for (hash, location) in self.index.iter() {
+                if let Some(len) = refs.get(&hash) {
+                    if let Some(bundle) = usage.get_mut(&location.bundle) {
+                        bundle.chunk_usage.set(location.chunk as usize);
+                        bundle.used_raw_size += len as usize;
+                    } else {
+                        return Err(IntegrityError::MissingBundleId(location.bundle).into());
+                    }
+                }
+            }
+        }
+        self.dirty = false;
+        Ok(usage)
+    }
+
+    #[inline]
+    pub fn index_stats(&self) -> IndexStats {
+        self.index.stats()
+    }
+
+    #[inline]
+    pub fn index_history(&self) -> Result<Vec<IndexHistoryEntry>, RepositoryError> {
+        Ok(try!(self.index.history()))
+    }
+
+    /// Returns the number of index entries and the number of chunks of every bundle
+    pub fn index_bundle_usage(&self) -> Vec<(BundleId, usize, usize)> {
+        let mut counts = HashMap::new();
+        for (_hash, location) in self.index.iter() {
+            *counts.entry(location.bundle).or_insert(0) += 1;
+        }
+        let mut usage: Vec<_> = self.bundle_map.bundles().into_iter().map(|(num, id)| {
+            let chunk_count = self.bundles.get_bundle_info(&id).map(|b| b.info.chunk_count).unwrap_or(0);
+            let indexed = counts.get(&num).cloned().unwrap_or(0);
+            (id, indexed, chunk_count)
+        }).collect();
+        usage.sort_by(|a, b| a.0.cmp(&b.0));
+        usage
+    }
+
     #[inline]
     pub fn list_bundles(&self) -> Vec<&BundleInfo> {
         self.bundles.list_bundles()
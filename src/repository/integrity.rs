@@ -1,11 +1,19 @@
 use ::prelude::*;
 
 use super::*;
+use super::chunk_index::ChunkIndex;
+use ::index::{Key, Value, IndexReader};
 
 use std::path::{Path, PathBuf};
 use std::time::Duration;
+use std::cmp::min;
 
 use pbr::ProgressBar;
+use crossbeam;
+
+
+// Number of threads that check the index structure in parallel
+const INDEX_CHECK_THREADS: usize = 4;
 
 
 quick_error!{
@@ -46,7 +54,36 @@ quick_error!{
     }
 }
 
+fn check_index_ranges<K: Key, V: Value>(reader: IndexReader<K, V>) -> Result<(), RepositoryError> {
+    let step = (reader.capacity() + INDEX_CHECK_THREADS - 1) / INDEX_CHECK_THREADS;
+    let results: Vec<Result<usize, IndexError>> = crossbeam::scope(|scope| {
+        let handles: Vec<_> = (0..INDEX_CHECK_THREADS).map(|i| {
+            let (start, end) = (min(i * step, reader.capacity()), min((i + 1) * step, reader.capacity()));
+            scope.spawn(move || reader.check_range(start, end))
+        }).collect();
+        handles.into_iter().map(|handle| handle.join()).collect()
+    });
+    let mut entries = 0;
+    for result in results {
+        entries += try!(result);
+    }
+    if entries != reader.len() {
+        return Err(IndexError::WrongEntryCount(reader.len(), entries).into())
+    }
+    Ok(())
+}
+
+
 impl Repository {
+    /// Checks that all index entries can be found, the index is split into ranges that are
+    /// checked in parallel
+    fn check_index_structure(&self) -> Result<(), RepositoryError> {
+        match self.index {
+            ChunkIndex::Short(ref index) => check_index_ranges(index.reader()),
+            ChunkIndex::Long(ref index) => check_index_ranges(index.reader())
+        }
+    }
+
     fn check_index_chunks(&self) -> Result<(), RepositoryError> {
         let mut progress = ProgressBar::new(self.index.len() as u64);
         progress.message("checking index: ");
@@ -98,7 +135,7 @@ impl Repository {
             Some(FileData::ChunkedIndirect(ref chunks)) => {
                 if try!(self.check_chunks(checked, chunks, true)) {
                     let chunk_data = try!(self.get_data(chunks));
-                    let chunks = ChunkList::read_from(&chunk_data);
+                    let chunks = ChunkList::read_from(&chunk_data, chunks.hash_size());
                     try!(self.check_chunks(checked, &chunks, true));
                 }
             }
@@ -159,8 +196,9 @@ impl Repository {
     }
 
     fn evacuate_broken_backup(&self, name: &str) -> Result<(), RepositoryError> {
+        try!(self.check_may_delete("moving broken backups"));
         warn!("The backup {} was corrupted and needed to be modified.", name);
-        let src = self.layout.backup_path(name);
+        let src = try!(self.find_backup(name)).unwrap_or_else(|| self.layout.backup_path(name));
         let mut dst = src.with_extension("backup.broken");
         let mut num = 1;
         while dst.exists() {
@@ -171,6 +209,7 @@ impl Repository {
             try!(fs::copy(&src, &dst));
             try!(fs::remove_file(&src));
         }
+        self.update_backup_names(None, &src);
         info!("The original backup was renamed to {:?}", dst);
         Ok(())
     }
@@ -362,16 +401,20 @@ impl Repository {
 
     pub fn rebuild_index(&mut self) -> Result<(), RepositoryError> {
         info!("Rebuilding index from bundles");
-        self.index.clear();
         let mut bundles = self.bundle_map.bundles();
         bundles.sort_by_key(|&(_, ref v)| v.clone());
+        let hash_methods: Vec<_> = bundles.iter().filter_map(|&(_, ref id)| self.bundles.get_bundle_info(id)).map(|b| b.info.hash_method).collect();
+        for hash_method in hash_methods {
+            try!(self.widen_index_for(hash_method));
+        }
+        try!(self.index.clear());
         for (num, id) in bundles {
             let chunks = try!(self.bundles.get_chunk_list(&id));
             for (i, (hash, _len)) in chunks.into_inner().into_iter().enumerate() {
                 try!(self.index.set(&hash, &Location{bundle: num as u32, chunk: i as u32}));
             }
         }
-        Ok(())
+        Ok(try!(self.index.flush()))
     }
 
     #[inline]
@@ -380,7 +423,7 @@ impl Repository {
             try!(self.write_mode());
         }
         info!("Checking index integrity...");
-        if let Err(err) = self.index.check() {
+        if let Err(err) = self.check_index_structure() {
             if repair {
                 warn!("Problem detected: index was corrupted\n\tcaused by: {}", err);
                 return self.rebuild_index();
@@ -403,6 +446,8 @@ impl Repository {
     #[inline]
     pub fn check_bundles(&mut self, full: bool, repair: bool) -> Result<(), RepositoryError> {
         if repair {
+            // Repairs move broken bundles away and replace damaged ones
+            try!(self.check_may_delete("repairing bundles"));
             try!(self.write_mode());
         }
         info!("Checking bundle integrity...");
@@ -20,6 +20,16 @@ impl RepositoryLayout {
         self.0.join("config.yaml")
     }
 
+    #[inline]
+    pub fn config_signature_path(&self) -> PathBuf {
+        self.0.join("config.yaml.sig")
+    }
+
+    #[inline]
+    pub fn signing_key_path(&self) -> PathBuf {
+        self.0.join("signing.yaml")
+    }
+
     #[inline]
     pub fn excludes_path(&self) -> PathBuf {
         self.0.join("excludes")
@@ -40,6 +50,11 @@ impl RepositoryLayout {
         self.0.join("bundles.map")
     }
 
+    #[inline]
+    pub fn chunk_refs_path(&self) -> PathBuf {
+        self.0.join("chunks.refs")
+    }
+
     #[inline]
     pub fn local_locks_path(&self) -> PathBuf {
         self.0.join("locks")
@@ -60,6 +75,16 @@ impl RepositoryLayout {
         self.0.join("remote")
     }
 
+    #[inline]
+    pub fn dictionaries_path(&self) -> PathBuf {
+        self.0.join("remote/dictionaries")
+    }
+
+    #[inline]
+    pub fn dictionary_path(&self, id: &Hash) -> PathBuf {
+        self.dictionaries_path().join(format!("{}.dict", id))
+    }
+
     #[inline]
     pub fn remote_exists(&self) -> bool {
         self.remote_bundles_path().exists() && self.backups_path().exists() && self.remote_locks_path().exists()
@@ -70,6 +95,16 @@ impl RepositoryLayout {
         self.0.join("remote/README.md")
     }
 
+    #[inline]
+    pub fn append_only_path(&self) -> PathBuf {
+        self.0.join("remote/append-only.yaml")
+    }
+
+    #[inline]
+    pub fn deletions_path(&self) -> PathBuf {
+        self.0.join("remote/deletions")
+    }
+
     #[inline]
     pub fn remote_locks_path(&self) -> PathBuf {
         self.0.join("remote/locks")
@@ -80,6 +115,16 @@ impl RepositoryLayout {
         self.0.join("remote/bundles")
     }
 
+    #[inline]
+    pub fn remote_parity_path(&self) -> PathBuf {
+        self.0.join("remote/parity")
+    }
+
+    #[inline]
+    pub fn parity_path(&self) -> PathBuf {
+        self.remote_parity_path().join(BundleId::random().to_string().to_owned() + ".parity")
+    }
+
     #[inline]
     pub fn local_bundles_path(&self) -> PathBuf {
         self.0.join("bundles/cached")
@@ -118,6 +163,11 @@ impl RepositoryLayout {
         self.temp_bundles_path().join(BundleId::random().to_string().to_owned() + ".bundle")
     }
 
+    #[inline]
+    pub fn temp_parity_path(&self) -> PathBuf {
+        self.temp_bundles_path().join(BundleId::random().to_string().to_owned() + ".parity")
+    }
+
     #[inline]
     pub fn local_bundle_cache_path(&self) -> PathBuf {
         self.0.join("bundles/local.cache")
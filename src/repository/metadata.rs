@@ -306,7 +306,7 @@ impl Repository {
                     },
                     FileData::ChunkedIndirect(ref chunks) => {
                         let chunk_data = try!(self.get_data(chunks));
-                        let chunks = ChunkList::read_from(&chunk_data);
+                        let chunks = ChunkList::read_from(&chunk_data, chunks.hash_size());
                         try!(self.get_stream(&chunks, &mut file));
                     }
                 }
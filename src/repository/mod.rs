@@ -10,34 +10,42 @@ mod vacuum;
 mod backup_file;
 mod tarfile;
 mod layout;
+mod append_only;
+mod chunk_refs;
+mod upgrade;
+mod chunk_index;
 
 use ::prelude::*;
 
 use std::mem;
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::cmp::max;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::fs::{self, File};
 use std::sync::{Arc, Mutex};
 use std::os::unix::fs::symlink;
-use std::io::Write;
+use std::io::{Read, Write};
 
 pub use self::error::RepositoryError;
-pub use self::config::Config;
+pub use self::config::{Config, IndexFilter};
 pub use self::metadata::{Inode, FileType, FileData, InodeError};
 pub use self::backup::{BackupError, BackupOptions, DiffType};
 pub use self::backup_file::{Backup, BackupFileError};
 pub use self::integrity::IntegrityError;
-pub use self::info::{RepositoryInfo, BundleAnalysis};
+pub use self::info::{RepositoryInfo, BundleAnalysis, KeyUsage};
 pub use self::layout::RepositoryLayout;
+pub use self::append_only::{AppendOnlyError, DeletionRequest};
+pub use self::chunk_refs::ChunkRefsError;
+pub use self::upgrade::MigratedFile;
 use self::bundle_map::BundleMap;
+use self::chunk_refs::ChunkRefs;
+use self::chunk_index::ChunkIndex;
 
 
 const REPOSITORY_README: &'static [u8] = include_bytes!("../../docs/repository_readme.md");
 const DEFAULT_EXCLUDES: &'static [u8] = include_bytes!("../../docs/excludes.default");
 
-const INDEX_MAGIC: [u8; 7] = *b"zvault\x02";
-const INDEX_VERSION: u8 = 1;
-
 
 #[repr(packed)]
 #[derive(Clone, Copy, PartialEq, Debug, Default)]
@@ -53,37 +61,34 @@ impl Location {
 
 impl ::index::Value for Location {}
 
-impl ::index::Key for Hash {
-    fn hash(&self) -> u64 {
-        self.low
-    }
-
-    fn is_used(&self) -> bool {
-        self.low != 0 || self.high != 0
-    }
-
-    fn clear(&mut self) {
-        self.low = 0;
-        self.high = 0;
-    }
-}
-
 pub struct Repository {
     pub layout: RepositoryLayout,
     pub config: Config,
-    index: Index<Hash, Location>,
+    index: ChunkIndex,
     crypto: Arc<Mutex<Crypto>>,
     bundle_map: BundleMap,
     next_data_bundle: u32,
     next_meta_bundle: u32,
+    next_raw_bundle: u32,
     bundles: BundleDb,
     data_bundle: Option<BundleWriter>,
     meta_bundle: Option<BundleWriter>,
+    raw_bundle: Option<BundleWriter>,
     chunker: Box<Chunker>,
+    // The configured chunker with the seed derived from the hash key
+    chunker_type: ChunkerType,
     remote_locks: LockFolder,
     local_locks: LockFolder,
     lock: LockHandle,
-    dirty: bool
+    dirty: bool,
+    // Loaded lazily when backups are saved or deleted and kept for the whole session
+    chunk_refs: Option<ChunkRefs>,
+    // Whether the chunk references file has been read, it is never read twice
+    chunk_refs_loaded: bool,
+    // Logical names of all backup files, loaded once as hidden names need to be decrypted
+    backup_names: RefCell<Option<HashMap<String, PathBuf>>>,
+    // Whether the administrator key of an append-only repository has been presented
+    admin: bool
 }
 
 
@@ -99,8 +104,9 @@ impl Repository {
         try!(fs::create_dir_all(layout.remote_locks_path()));
         try!(config.save(layout.config_path()));
         try!(BundleDb::create(layout.clone()));
-        try!(Index::<Hash, Location>::create(layout.index_path(), &INDEX_MAGIC, INDEX_VERSION));
+        try!(ChunkIndex::create(layout.index_path(), config.hash.hash_size() == LONG_HASH_SIZE));
         try!(BundleMap::create().save(layout.bundle_map_path()));
+        try!(ChunkRefs::new().save(layout.chunk_refs_path()));
         try!(fs::create_dir_all(layout.backups_path()));
         Self::open(path)
     }
@@ -115,14 +121,31 @@ impl Repository {
         let remote_locks = LockFolder::new(layout.remote_locks_path());
         try!(fs::create_dir_all(layout.local_locks_path())); // Added after v0.1.0
         let local_locks = LockFolder::new(layout.local_locks_path());
-        let lock = try!(local_locks.lock(false));
+        let mut lock = try!(local_locks.lock(false));
         let crypto = Arc::new(Mutex::new(try!(Crypto::open(layout.keys_path()))));
-        let (bundles, new, gone) = try!(BundleDb::open(layout.clone(), crypto.clone()));
-        let (index, mut rebuild_index) = match unsafe { Index::open(layout.index_path(), &INDEX_MAGIC, INDEX_VERSION) } {
+        if layout.signing_key_path().exists() {
+            try!(crypto.lock().unwrap().load_signing_key(layout.signing_key_path()));
+            try!(Self::verify_config(&layout, &crypto.lock().unwrap()));
+        }
+        try!(Self::load_dictionaries(&layout));
+        try!(Self::load_hash_key(&config, &mut crypto.lock().unwrap()));
+        let (bundles, new, gone) = try!(BundleDb::open(layout.clone(), crypto.clone(), config.redundancy));
+        // The index is opened read-only so that other processes can use the repository at the
+        // same time, write_mode reopens it with write access
+        // A pending journal is recovered with write access, if that fails the index is rebuilt
+        let index = match unsafe { ChunkIndex::open(layout.index_path(), false) } {
+            Err(IndexError::PendingJournal) => {
+                try!(local_locks.upgrade(&mut lock));
+                unsafe { ChunkIndex::open(layout.index_path(), true) }
+            },
+            result => result
+        };
+        let (index, mut rebuild_index) = match index {
             Ok(index) => (index, false),
             Err(err) => {
                 error!("Failed to load local index:\n\tcaused by: {}", err);
-                (try!(Index::create(layout.index_path(), &INDEX_MAGIC, INDEX_VERSION)), true)
+                try!(local_locks.upgrade(&mut lock));
+                (try!(ChunkIndex::create(layout.index_path(), config.hash.hash_size() == LONG_HASH_SIZE)), true)
             }
         };
         let (bundle_map, rebuild_bundle_map) = match BundleMap::load(layout.bundle_map_path()) {
@@ -133,23 +156,36 @@ impl Repository {
             }
         };
         let dirty = layout.dirtyfile_path().exists();
+        let chunker_type = Self::seeded_chunker(&config, &crypto.lock().unwrap());
+        let mut chunker = chunker_type.create(config.chunker_limits);
+        if config.format_aware_chunking {
+            chunker = Box::new(FormatChunker::new(chunker));
+        }
         let mut repo = Repository {
             layout: layout,
             dirty: true,
-            chunker: config.chunker.create(),
+            chunker: chunker,
+            chunker_type: chunker_type,
             config: config,
             index: index,
             crypto: crypto,
             bundle_map: bundle_map,
             next_data_bundle: 0,
             next_meta_bundle: 0,
+            next_raw_bundle: 0,
             bundles: bundles,
             data_bundle: None,
             meta_bundle: None,
+            raw_bundle: None,
             lock: lock,
             remote_locks: remote_locks,
-            local_locks: local_locks
+            local_locks: local_locks,
+            chunk_refs: None,
+            chunk_refs_loaded: false,
+            backup_names: RefCell::new(None),
+            admin: false
         };
+        try!(repo.configure_index_filter());
         if !rebuild_bundle_map {
             let mut save_bundle_map = false;
             if !gone.is_empty() {
@@ -175,6 +211,7 @@ impl Repository {
         }
         repo.next_meta_bundle = repo.next_free_bundle_id();
         repo.next_data_bundle = repo.next_free_bundle_id();
+        repo.next_raw_bundle = repo.next_free_bundle_id();
         if rebuild_bundle_map {
             try!(repo.write_mode());
             try!(repo.rebuild_bundle_map());
@@ -188,13 +225,24 @@ impl Repository {
         Ok(repo)
     }
 
-    pub fn import<P: AsRef<Path>, R: AsRef<Path>>(path: P, remote: R, key_files: Vec<String>) -> Result<Self, RepositoryError> {
+    pub fn import<P: AsRef<Path>, R: AsRef<Path>>(path: P, remote: R, key_files: Vec<String>, signing_key: Option<String>) -> Result<Self, RepositoryError> {
         let path = path.as_ref();
+        if let Some(ref file) = signing_key {
+            // The imported config has to be signed, so only the public key is not enough
+            let mut key = Crypto::dummy();
+            try!(key.load_signing_key(file));
+            if !key.can_sign() {
+                return Err(RepositoryError::ConfigSignature("the config can only be signed with the secret signing key"))
+            }
+        }
         let mut repo = try!(Repository::create(path, Config::default(), remote));
         for file in key_files {
             try!(repo.crypto.lock().unwrap().register_keyfile(file));
         }
         repo = try!(Repository::open(path));
+        if let Some(file) = signing_key {
+            try!(repo.import_signing_key(file));
+        }
         let mut backups: Vec<(String, Backup)> = try!(repo.get_all_backups()).into_iter().collect();
         backups.sort_by_key(|&(_, ref b)| b.timestamp);
         if let Some((name, backup)) = backups.pop() {
@@ -208,41 +256,265 @@ impl Repository {
     }
 
     #[inline]
-    pub fn register_key(&mut self, public: PublicKey, secret: SecretKey) -> Result<(), RepositoryError> {
+    pub fn register_key(&mut self, public: PublicKey, secret: SecretKey, passphrase: Option<&str>) -> Result<(), RepositoryError> {
         try!(self.write_mode());
-        Ok(try!(self.crypto.lock().unwrap().register_secret_key(public, secret)))
+        Ok(try!(self.crypto.lock().unwrap().register_secret_key(public, secret, passphrase)))
+    }
+
+    /// Returns the secret key of a registered key pair, protected key files are unlocked
+    pub fn get_secret_key(&self, public: &PublicKey) -> Result<SecretKey, RepositoryError> {
+        Ok(try!(self.crypto.lock().unwrap().get_secret_key(public)))
+    }
+
+    /// Returns the parameters for deriving key pairs from passwords. When the repository does
+    /// not have any yet, new parameters with a random salt are created and stored in the config.
+    pub fn key_derivation(&mut self) -> Result<KeyDerivation, RepositoryError> {
+        if let Some(ref kdf) = self.config.key_derivation {
+            return Ok(kdf.clone())
+        }
+        let kdf = KeyDerivation::new(DEFAULT_KDF_COST).unwrap();
+        self.config.key_derivation = Some(kdf.clone());
+        try!(self.save_config());
+        Ok(kdf)
+    }
+
+    /// Protects all key files and the secret signing key of the repository with the given
+    /// passphrase or removes the protection
+    pub fn change_passphrase(&mut self, passphrase: Option<&str>) -> Result<(), RepositoryError> {
+        try!(self.write_mode());
+        let mut crypto = self.crypto.lock().unwrap();
+        for public in crypto.public_keys() {
+            try!(crypto.change_passphrase(&public, passphrase));
+        }
+        if crypto.can_sign() {
+            try!(crypto.change_signing_passphrase(passphrase));
+            try!(crypto.save_signing_key(self.layout.signing_key_path(), false));
+        }
+        Ok(())
     }
 
     #[inline]
     pub fn save_config(&mut self) -> Result<(), RepositoryError> {
         try!(self.write_mode());
+        {
+            let crypto = self.crypto.lock().unwrap();
+            if crypto.signing_key().is_some() && !crypto.can_sign() {
+                return Err(RepositoryError::ConfigSignature("the config can only be changed with the secret signing key"))
+            }
+        }
         try!(self.config.save(self.layout.config_path()));
+        try!(self.sign_config());
+        try!(self.bundles.set_redundancy(self.config.redundancy));
+        try!(self.configure_index_filter());
         Ok(())
     }
 
+    /// Signs the config file if the repository has a signing key. On machines that only have
+    /// the public signing key the config can not be changed, as it would lose its signature.
+    fn sign_config(&self) -> Result<(), RepositoryError> {
+        let crypto = self.crypto.lock().unwrap();
+        if crypto.signing_key().is_none() {
+            return Ok(())
+        }
+        let mut data = Vec::new();
+        try!(File::open(self.layout.config_path()).and_then(|mut f| f.read_to_end(&mut data)));
+        let signature = try!(crypto.sign(&data));
+        try!(File::create(self.layout.config_signature_path()).and_then(|mut f| f.write_all(&signature)));
+        Ok(())
+    }
+
+    /// Checks the signature of the config file, a config without signature is never accepted
+    /// when the repository has a signing key
+    fn verify_config(layout: &RepositoryLayout, crypto: &Crypto) -> Result<(), RepositoryError> {
+        if !layout.config_signature_path().exists() {
+            return Err(RepositoryError::ConfigSignature("the config is not signed"))
+        }
+        let mut data = Vec::new();
+        try!(File::open(layout.config_path()).and_then(|mut f| f.read_to_end(&mut data)));
+        let mut signature = Vec::new();
+        try!(File::open(layout.config_signature_path()).and_then(|mut f| f.read_to_end(&mut signature)));
+        crypto.verify(&data, &signature).map_err(|_| RepositoryError::ConfigSignature("the config has been modified"))
+    }
+
     #[inline]
-    pub fn set_encryption(&mut self, public: Option<&PublicKey>) {
-        if let Some(key) = public {
+    pub fn signing_key(&self) -> Option<SigningKey> {
+        self.crypto.lock().unwrap().signing_key()
+    }
+
+    /// Generates a new signing key and signs the config and all backup files with it. The
+    /// backup files are verified with the old signing key if there is one, otherwise they are
+    /// trusted as they are. The secret signing key is protected with the passphrase if given.
+    pub fn gen_signing_key(&mut self, passphrase: Option<&str>) -> Result<SigningKey, RepositoryError> {
+        try!(self.write_mode());
+        let _lock = try!(self.lock(true));
+        let mut backups = vec![];
+        for (name, path) in try!(Backup::list_from(self.layout.backups_path())) {
+            backups.push(try!(Backup::read_with_name_from(&self.crypto.lock().unwrap(), &path, &name)));
+        }
+        let public = {
+            let mut crypto = self.crypto.lock().unwrap();
+            let public = crypto.gen_signing_key();
+            try!(crypto.change_signing_passphrase(passphrase));
+            public
+        };
+        for (name, backup) in ProgressIter::new("signing backups", backups.len(), backups.into_iter()) {
+            try!(self.save_backup(&backup, &name));
+        }
+        try!(self.crypto.lock().unwrap().save_signing_key(self.layout.signing_key_path(), false));
+        try!(self.save_config());
+        Ok(public)
+    }
+
+    /// Uses the signing key from the given file, the file may contain only the public key to
+    /// verify signatures without being able to sign. With the secret key, the config is signed
+    /// again, otherwise the config must already carry a valid signature of this key.
+    pub fn import_signing_key<P: AsRef<Path>>(&mut self, path: P) -> Result<(), RepositoryError> {
+        try!(self.write_mode());
+        let path = path.as_ref();
+        let mut key = Crypto::dummy();
+        try!(key.load_signing_key(path));
+        if !key.can_sign() {
+            try!(Self::verify_config(&self.layout, &key));
+        }
+        {
+            let mut crypto = self.crypto.lock().unwrap();
+            try!(crypto.load_signing_key(path));
+            try!(crypto.save_signing_key(self.layout.signing_key_path(), false));
+        }
+        if key.can_sign() {
+            try!(self.save_config());
+        }
+        Ok(())
+    }
+
+    pub fn export_signing_key<P: AsRef<Path>>(&self, path: P, public_only: bool) -> Result<(), RepositoryError> {
+        Ok(try!(self.crypto.lock().unwrap().save_signing_key(path, public_only)))
+    }
+
+    /// Encrypts new data for the given public keys, any one of the secret keys can decrypt it.
+    /// Without keys, encryption is disabled.
+    pub fn set_encryption(&mut self, keys: &[PublicKey]) -> Result<(), RepositoryError> {
+        if keys.is_empty() && self.config.hash.is_keyed() {
+            return Err(RepositoryError::HashKey("keyed hashing requires encryption"))
+        }
+        for key in keys {
             if !self.crypto.lock().unwrap().contains_secret_key(key) {
-                warn!("The secret key for that public key is not stored in the repository.")
+                warn!("The secret key for the public key {} is not stored in the repository.", to_hex(&key[..]))
             }
-            let mut key_bytes = Vec::new();
-            key_bytes.extend_from_slice(&key[..]);
-            self.config.encryption = Some((EncryptionMethod::Sodium, key_bytes.into()))
+        }
+        self.config.encryption = Crypto::encryption_for(keys);
+        self.reseal_hash_key()
+    }
+
+    fn load_hash_key(config: &Config, crypto: &mut Crypto) -> Result<(), RepositoryError> {
+        if !config.hash.is_keyed() {
+            return Ok(())
+        }
+        if let (&Some(ref encryption), &Some(ref sealed)) = (&config.encryption, &config.hash_key) {
+            Ok(try!(crypto.load_hash_key(encryption, sealed)))
+        } else {
+            Err(RepositoryError::HashKey("the config contains no hash key"))
+        }
+    }
+
+    /// The seed of the chunker determines its cut points, with a hash key the seed is derived
+    /// from it so that the chunk sizes do not reveal the data. Hash keys from before that change
+    /// keep the configured seed, otherwise all existing chunks would be cut differently.
+    fn seeded_chunker(config: &Config, crypto: &Crypto) -> ChunkerType {
+        if !config.keyed_chunker_seed || !crypto.has_hash_key() {
+            return config.chunker
+        }
+        let seed = HashMethod::Blake2Keyed.hash_keyed(crypto.hash_key(), b"zvault chunker seed").low;
+        config.chunker.with_seed(config.chunker.seed() ^ seed)
+    }
+
+    fn reseal_hash_key(&mut self) -> Result<(), RepositoryError> {
+        let crypto = self.crypto.lock().unwrap();
+        if !crypto.has_hash_key() {
+            return Ok(())
+        }
+        if let Some(ref encryption) = self.config.encryption {
+            self.config.hash_key = Some(try!(crypto.encrypt(encryption, crypto.hash_key())).into());
+            Ok(())
         } else {
-            self.config.encryption = None
+            Err(RepositoryError::HashKey("keyed hashing requires encryption"))
         }
     }
 
+    /// Changes the hash method, a key is generated when switching to a keyed method for the first time.
+    /// Existing chunks keep their old hashes until the repository is rekeyed, which hashes them
+    /// again with the new method and rewrites all backups.
+    pub fn set_hash(&mut self, hash: HashMethod) -> Result<(), RepositoryError> {
+        if hash.is_keyed() && !self.crypto.lock().unwrap().has_hash_key() {
+            let encryption = if let Some(ref encryption) = self.config.encryption {
+                encryption.clone()
+            } else {
+                return Err(RepositoryError::HashKey("keyed hashing requires encryption"))
+            };
+            let sealed = try!(self.crypto.lock().unwrap().gen_hash_key(&encryption));
+            self.config.hash_key = Some(sealed.into());
+            self.config.keyed_chunker_seed = true;
+        }
+        self.config.hash = hash;
+        Ok(())
+    }
+
+    fn load_dictionaries(layout: &RepositoryLayout) -> Result<(), RepositoryError> {
+        let path = layout.dictionaries_path();
+        if !path.exists() {
+            return Ok(())
+        }
+        for entry in try!(fs::read_dir(path)) {
+            let path = try!(entry).path();
+            if path.extension() != Some("dict".as_ref()) {
+                continue
+            }
+            let mut data = Vec::new();
+            try!(File::open(&path).and_then(|mut f| f.read_to_end(&mut data)));
+            Compression::register_dictionary(data);
+        }
+        Ok(())
+    }
+
+    /// Trains a compression dictionary on the chunks of the meta bundles and configures it
+    /// for all meta bundles written from now on.
+    pub fn train_dictionary(&mut self, max_size: usize) -> Result<Hash, RepositoryError> {
+        try!(self.write_mode());
+        let max_samples_size = max_size * 100;
+        let mut samples = vec![];
+        let mut samples_size = 0;
+        let bundles: Vec<_> = self.bundles.list_bundles().into_iter().filter(|b| b.mode == BundleMode::Meta).map(|b| (b.id.clone(), b.chunk_count)).collect();
+        'outer: for (id, chunk_count) in ProgressIter::new("loading samples", bundles.len(), bundles.into_iter()) {
+            for chunk in 0..chunk_count {
+                let data = try!(self.bundles.get_chunk(&id, chunk));
+                samples_size += data.len();
+                samples.push(data);
+                if samples_size >= max_samples_size {
+                    break 'outer
+                }
+            }
+        }
+        info!("Training dictionary on {} samples ({})", samples.len(), to_file_size(samples_size as u64));
+        let dictionary = try!(Compression::train_dictionary(&samples, max_size));
+        try!(fs::create_dir_all(self.layout.dictionaries_path()));
+        let id = Compression::register_dictionary(dictionary.clone());
+        try!(File::create(self.layout.dictionary_path(&id)).and_then(|mut f| f.write_all(&dictionary)));
+        self.config.dictionary = Some(id);
+        try!(self.save_config());
+        Ok(id)
+    }
+
     #[inline]
-    fn save_bundle_map(&self) -> Result<(), RepositoryError> {
+    fn save_bundle_map(&mut self) -> Result<(), RepositoryError> {
+        // The index refers to bundles by their number in the map, so both are saved together
+        try!(self.index.flush());
         try!(self.bundle_map.save(self.layout.bundle_map_path()));
         Ok(())
     }
 
     #[inline]
     fn next_free_bundle_id(&self) -> u32 {
-        let mut id = max(self.next_data_bundle, self.next_meta_bundle) + 1;
+        let mut id = max(max(self.next_data_bundle, self.next_meta_bundle), self.next_raw_bundle) + 1;
         while self.bundle_map.get(id).is_some() {
             id += 1;
         }
@@ -272,6 +544,15 @@ impl Repository {
             }
             self.next_data_bundle = self.next_free_bundle_id()
         }
+        if self.raw_bundle.is_some() {
+            let mut finished = None;
+            mem::swap(&mut self.raw_bundle, &mut finished);
+            {
+                let bundle = try!(self.bundles.add_bundle(finished.unwrap()));
+                self.bundle_map.set(self.next_raw_bundle, bundle.id.clone());
+            }
+            self.next_raw_bundle = self.next_free_bundle_id()
+        }
         if self.meta_bundle.is_some() {
             let mut finished = None;
             mem::swap(&mut self.meta_bundle, &mut finished);
@@ -283,6 +564,7 @@ impl Repository {
         }
         try!(self.bundles.flush());
         try!(self.save_bundle_map());
+        try!(self.save_chunk_refs());
         if !self.dirty && dirtyfile.exists() {
             try!(fs::remove_file(&dirtyfile));
         }
@@ -299,6 +581,7 @@ impl Repository {
             BundleMode::Meta => self.next_meta_bundle
         };
         let chunks = try!(self.bundles.get_chunk_list(&bundle.id));
+        try!(self.widen_index_for(bundle.hash_method));
         self.bundle_map.set(bundle_id, bundle.id.clone());
         if self.next_meta_bundle == bundle_id {
             self.next_meta_bundle = self.next_free_bundle_id()
@@ -322,7 +605,7 @@ impl Repository {
         if let Some(id) = self.bundle_map.find(&bundle.id) {
             debug!("Removing bundle from index: {}", bundle.id);
             try!(self.bundles.delete_local_bundle(&bundle.id));
-            try!(self.index.filter(|_key, data| data.bundle != id));
+            try!(self.index.filter(|location| location.bundle != id));
             self.bundle_map.remove(id);
         }
         Ok(())
@@ -330,7 +613,49 @@ impl Repository {
 
     #[inline]
     fn write_mode(&mut self) -> Result<(), RepositoryError> {
-        Ok(try!(self.local_locks.upgrade(&mut self.lock)))
+        try!(self.local_locks.upgrade(&mut self.lock));
+        if !self.index.is_writable() {
+            self.index = try!(unsafe { ChunkIndex::open(self.layout.index_path(), true) });
+            try!(self.configure_index_filter());
+        }
+        let hash_method = self.config.hash;
+        self.widen_index_for(hash_method)
+    }
+
+    /// Indices with 128 bit keys can not store chunks with 256 bit hashes, so they are converted
+    /// before the first such chunk is added
+    fn widen_index_for(&mut self, hash_method: HashMethod) -> Result<(), RepositoryError> {
+        if hash_method.hash_size() == LONG_HASH_SIZE && !self.index.is_long() {
+            info!("Converting the index to 256 bit keys");
+            try!(self.index.widen(self.layout.index_path()));
+            try!(self.configure_index_filter());
+        }
+        Ok(())
+    }
+
+    fn configure_index_filter(&mut self) -> Result<(), RepositoryError> {
+        match self.config.index_filter {
+            Some(ref filter) => self.index.enable_filter(filter.fp_rate(), filter.max_memory),
+            None => try!(self.index.disable_filter())
+        }
+        Ok(())
+    }
+
+    /// Whether data may be deleted right away instead of queueing a deletion request, a missing
+    /// or changed append-only flag is an error
+    #[inline]
+    fn may_delete(&self) -> Result<bool, RepositoryError> {
+        let admin_key = try!(self.admin_key());
+        Ok(self.admin || admin_key.is_none())
+    }
+
+    #[inline]
+    fn check_may_delete(&self, action: &'static str) -> Result<(), RepositoryError> {
+        if try!(self.may_delete()) {
+            Ok(())
+        } else {
+            Err(AppendOnlyError::Denied(action).into())
+        }
     }
 
     #[inline]
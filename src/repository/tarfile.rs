@@ -326,7 +326,7 @@ impl Repository {
                 Some(FileData::Inline(data)) => try!(tarfile.append(&header, Cursor::new(data))),
                 Some(FileData::ChunkedDirect(chunks)) => try!(tarfile.append(&header, self.get_reader(chunks))),
                 Some(FileData::ChunkedIndirect(chunks)) => {
-                    let chunks = ChunkList::read_from(&try!(self.get_data(&chunks)));
+                    let chunks = ChunkList::read_from(&try!(self.get_data(&chunks)), chunks.hash_size());
                     try!(tarfile.append(&header, self.get_reader(chunks)))
                 }
             }
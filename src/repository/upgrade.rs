@@ -0,0 +1,46 @@
+use ::prelude::*;
+
+use super::bundle_map::{self, BundleMap};
+use super::chunk_index::{ChunkIndex, LONG_INDEX_VERSION};
+
+use std::path::Path;
+
+
+/// A local file that has been upgraded to a newer version of its format
+pub struct MigratedFile {
+    pub name: &'static str,
+    pub from: u8,
+    pub to: u8
+}
+
+
+impl Repository {
+    /// Upgrades all local files of the repository to the current versions of their formats
+    /// in place. Returns the files that have been migrated.
+    pub fn upgrade<P: AsRef<Path>>(path: P) -> Result<Vec<MigratedFile>, RepositoryError> {
+        let layout = RepositoryLayout::new(path.as_ref().to_path_buf());
+        let local_locks = LockFolder::new(layout.local_locks_path());
+        let _lock = try!(local_locks.lock(true));
+        let mut migrated = vec![];
+        // Indices with 128 bit keys are only converted when the repository uses 256 bit hashes
+        let config = try!(Config::load(layout.config_path()));
+        if layout.index_path().exists() && config.hash.hash_size() == LONG_HASH_SIZE {
+            if let Some(version) = try!(ChunkIndex::migrate_to_long(layout.index_path())) {
+                migrated.push(MigratedFile { name: "index", from: version, to: LONG_INDEX_VERSION });
+            }
+        }
+        if layout.bundle_map_path().exists() {
+            if let Some(version) = try!(BundleMap::migrate(layout.bundle_map_path())) {
+                migrated.push(MigratedFile { name: "bundle map", from: version, to: bundle_map::HEADER_VERSION });
+            }
+        }
+        for &(name, ref path) in &[("local bundle cache", layout.local_bundle_cache_path()), ("remote bundle cache", layout.remote_bundle_cache_path())] {
+            if path.exists() {
+                if let Some(version) = try!(StoredBundle::migrate_list(path).map_err(BundleDbError::from)) {
+                    migrated.push(MigratedFile { name: name, from: version, to: CACHE_FILE_VERSION });
+                }
+            }
+        }
+        Ok(migrated)
+    }
+}
@@ -1,6 +1,11 @@
 use ::prelude::*;
 
-use std::collections::HashSet;
+use std::path::PathBuf;
+use std::collections::{HashMap, HashSet, BTreeMap};
+
+
+// Number of bundles that are rewritten before the old bundles are deleted when rekeying
+const REKEY_BATCH_SIZE: usize = 16;
 
 
 impl Repository {
@@ -13,14 +18,47 @@ impl Repository {
         }
     }
 
+    /// Copies all used chunks of the bundle into new bundles, the bundle itself is only deleted
+    /// by `remove_rewritten_bundles`
+    fn rewrite_bundle(&mut self, id: u32, bundle: &BundleAnalysis) -> Result<(), RepositoryError> {
+        let bundle_id = self.bundle_map.get(id).unwrap();
+        let chunks = try!(self.bundles.get_chunk_list(&bundle_id));
+        let mode = bundle.info.mode;
+        for (chunk, &(hash, _len)) in chunks.into_iter().enumerate() {
+            if !bundle.chunk_usage.get(chunk) {
+                try!(self.index.delete(&hash));
+                continue
+            }
+            let data = try!(self.bundles.get_chunk(&bundle_id, chunk));
+            try!(self.put_chunk_override(mode, hash, &data));
+        }
+        Ok(())
+    }
+
+    fn remove_rewritten_bundles(&mut self, bundles: &HashSet<u32>) -> Result<(), RepositoryError> {
+        try!(self.flush());
+        debug!("Checking index");
+        for (hash, location) in self.index.iter() {
+            if bundles.contains(&location.bundle) {
+                panic!("Removed bundle is still referenced in index: hash:{}, bundle:{}, chunk:{}", hash, location.bundle, location.chunk);
+            }
+        }
+        debug!("Deleting {} bundles", bundles.len());
+        for id in bundles {
+            try!(self.delete_bundle(*id));
+        }
+        try!(self.save_bundle_map());
+        Ok(())
+    }
+
     pub fn vacuum(&mut self, ratio: f32, combine: bool, force: bool) -> Result<(), RepositoryError> {
         try!(self.flush());
         info!("Locking repository");
         try!(self.write_mode());
         let _lock = try!(self.lock(true));
-        // analyze_usage will set the dirty flag
+        // analyze_usage_from_refs will set the dirty flag
         info!("Analyzing chunk usage");
-        let usage = try!(self.analyze_usage());
+        let usage = try!(self.analyze_usage_from_refs());
         let mut data_total = 0;
         let mut data_used = 0;
         for bundle in usage.values() {
@@ -63,33 +101,217 @@ impl Repository {
             self.dirty = false;
             return Ok(())
         }
+        if !try!(self.may_delete()) {
+            info!("The repository is append-only, queueing the vacuum for the administrator");
+            self.dirty = false;
+            return self.queue_deletion(&DeletionRequest::vacuum(ratio, combine))
+        }
         for id in ProgressIter::new("rewriting bundles", rewrite_bundles.len(), rewrite_bundles.iter()) {
-            let bundle = &usage[id];
-            let bundle_id = self.bundle_map.get(*id).unwrap();
-            let chunks = try!(self.bundles.get_chunk_list(&bundle_id));
-            let mode = usage[id].info.mode;
-            for (chunk, &(hash, _len)) in chunks.into_iter().enumerate() {
-                if !bundle.chunk_usage.get(chunk) {
+            try!(self.rewrite_bundle(*id, &usage[id]));
+        }
+        info!("Deleting {} bundles", rewrite_bundles.len());
+        try!(self.remove_rewritten_bundles(&rewrite_bundles));
+        if let Some(ref mut refs) = self.chunk_refs {
+            refs.reset_unused();
+        }
+        self.dirty = false;
+        Ok(())
+    }
+
+    fn chunk_hash_method(&self, hash: &Hash) -> Result<HashMethod, RepositoryError> {
+        let location = try!(self.index.get(hash).ok_or_else(|| IntegrityError::MissingChunk(*hash)));
+        // Bundles that are still being written use the current hash method
+        if location.bundle == self.next_data_bundle || location.bundle == self.next_meta_bundle || location.bundle == self.next_raw_bundle {
+            return Ok(self.config.hash)
+        }
+        let bundle_id = try!(self.get_bundle_id(location.bundle));
+        let bundle = try!(self.bundles.get_bundle_info(&bundle_id).ok_or_else(|| IntegrityError::MissingBundle(bundle_id.clone())));
+        Ok(bundle.info.hash_method)
+    }
+
+    /// Hashes all chunks that are stored with another hash method again and stores them under
+    /// their new hashes
+    fn rehash_chunks(&mut self, mode: BundleMode, chunks: &[Chunk], hashes: &mut HashMap<Hash, Hash>) -> Result<ChunkList, RepositoryError> {
+        let hash_key = try!(self.hash_key());
+        let mut new_chunks = ChunkList::with_capacity(chunks.len());
+        for &(hash, len) in chunks {
+            if let Some(new_hash) = hashes.get(&hash) {
+                new_chunks.push((*new_hash, len));
+                continue
+            }
+            let new_hash = if try!(self.chunk_hash_method(&hash)) == self.config.hash {
+                hash
+            } else {
+                let data = try!(try!(self.get_chunk(hash)).ok_or_else(|| IntegrityError::MissingChunk(hash)));
+                let new_hash = self.config.hash.hash_keyed(&hash_key, &data);
+                try!(self.put_chunk(mode, new_hash, &data));
+                new_hash
+            };
+            hashes.insert(hash, new_hash);
+            new_chunks.push((new_hash, len));
+        }
+        Ok(new_chunks)
+    }
+
+    /// Stores the inode and all inodes below it again with the current hash method
+    fn rehash_inode(&mut self, chunks: &ChunkList, hashes: &mut HashMap<Hash, Hash>, inodes: &mut HashMap<ChunkList, ChunkList>) -> Result<ChunkList, RepositoryError> {
+        if let Some(new_chunks) = inodes.get(chunks) {
+            return Ok(new_chunks.clone())
+        }
+        let mut inode = try!(self.get_inode(chunks));
+        inode.data = match inode.data.take() {
+            Some(FileData::ChunkedDirect(data_chunks)) => {
+                Some(FileData::ChunkedDirect(try!(self.rehash_chunks(BundleMode::Data, &data_chunks, hashes))))
+            },
+            Some(FileData::ChunkedIndirect(list_chunks)) => {
+                let data_chunks = ChunkList::read_from(&try!(self.get_data(&list_chunks)), list_chunks.hash_size());
+                let data_chunks = try!(self.rehash_chunks(BundleMode::Data, &data_chunks, hashes));
+                let mut chunk_data = Vec::with_capacity(data_chunks.encoded_size());
+                data_chunks.write_to(&mut chunk_data).unwrap();
+                Some(FileData::ChunkedIndirect(try!(self.put_data(BundleMode::Meta, &chunk_data))))
+            },
+            data => data
+        };
+        if let Some(children) = inode.children.take() {
+            let mut new_children = BTreeMap::new();
+            for (name, child) in children {
+                let child = try!(self.rehash_inode(&child, hashes, inodes));
+                new_children.insert(name, child);
+            }
+            inode.children = Some(new_children);
+        }
+        let new_chunks = try!(self.put_inode(&inode));
+        inodes.insert(chunks.clone(), new_chunks.clone());
+        Ok(new_chunks)
+    }
+
+    /// Rewrites all backups so that they only reference chunks with the current hash method and
+    /// removes the given bundles afterwards. Returns the old paths of all rewritten backup files.
+    fn rehash(&mut self, bundles: &HashSet<u32>) -> Result<HashSet<PathBuf>, RepositoryError> {
+        let mut hashes = HashMap::new();
+        let mut inodes = HashMap::new();
+        let mut rewritten = HashSet::new();
+        let backups = try!(Backup::list_from(self.layout.backups_path()));
+        for (name, path) in ProgressIter::new("rehashing backups", backups.len(), backups.into_iter()) {
+            let (name, mut backup) = try!(Backup::read_with_name_from(&self.crypto.lock().unwrap(), &path, &name));
+            let root = try!(self.rehash_inode(&backup.root, &mut hashes, &mut inodes));
+            if root != backup.root {
+                debug!("Rehashing backup {}", name);
+                backup.root = root;
+                backup.config = self.config.clone();
+                try!(self.save_backup(&backup, &name));
+                rewritten.insert(path);
+            }
+        }
+        try!(self.flush());
+        // All chunks of the old bundles are now stored under new hashes
+        for &id in bundles {
+            let bundle_id = try!(self.get_bundle_id(id));
+            for (hash, _) in try!(self.bundles.get_chunk_list(&bundle_id)).into_inner() {
+                if self.index.get(&hash).map(|location| location.bundle) == Some(id) {
                     try!(self.index.delete(&hash));
-                    continue
                 }
-                let data = try!(self.bundles.get_chunk(&bundle_id, chunk));
-                try!(self.put_chunk_override(mode, hash, &data));
             }
         }
+        try!(self.remove_rewritten_bundles(bundles));
+        Ok(rewritten)
+    }
+
+    fn check_secret_key(&self, encryption: &Option<Encryption>) -> Result<(), RepositoryError> {
+        if let Some(ref encryption) = *encryption {
+            let recipients = try!(Crypto::recipients(encryption));
+            let crypto = self.crypto.lock().unwrap();
+            if !recipients.iter().any(|key| crypto.contains_secret_key(key)) {
+                return Err(EncryptionError::MissingKey(recipients[0]).into())
+            }
+        }
+        Ok(())
+    }
+
+    /// Rewrites all bundles and backup files that are not encrypted with the current encryption
+    /// setting. The old bundles are deleted after every few bundles, so an interrupted run can
+    /// just be started again and continues with the remaining bundles. Backup files are also
+    /// moved to or from hidden ids when they do not match the current name hiding setting.
+    /// Chunks in bundles with another hash method are hashed again and all backups are rewritten
+    /// to use the new hashes, those bundles are only deleted after all backups have been rewritten.
+    pub fn rekey(&mut self, force: bool) -> Result<(), RepositoryError> {
+        if force {
+            try!(self.check_may_delete("rewriting bundles and backups"));
+        }
         try!(self.flush());
-        info!("Checking index");
-        for (hash, location) in self.index.iter() {
-            if rewrite_bundles.contains(&location.bundle) {
-                panic!("Removed bundle is still referenced in index: hash:{}, bundle:{}, chunk:{}", hash, location.bundle, location.chunk);
+        info!("Locking repository");
+        try!(self.write_mode());
+        let _lock = try!(self.lock(true));
+        let mut encryptions = HashSet::new();
+        for bundle in self.bundles.list_bundles() {
+            if bundle.encryption != self.config.encryption {
+                encryptions.insert(bundle.encryption.clone());
             }
         }
-        info!("Deleting {} bundles", rewrite_bundles.len());
-        for id in rewrite_bundles {
-            try!(self.delete_bundle(id));
+        let hide_names = self.config.hide_backup_names && self.config.encryption.is_some();
+        let mut rewrite_backups = vec![];
+        for (name, path) in try!(Backup::list_from(self.layout.backups_path())) {
+            let encryption = try!(Backup::read_encryption_from(&path));
+            if encryption != self.config.encryption || try!(Backup::has_hidden_name(&path)) != hide_names {
+                rewrite_backups.push((name, path));
+                encryptions.insert(encryption);
+            }
         }
-        try!(self.save_bundle_map());
+        for encryption in &encryptions {
+            try!(self.check_secret_key(encryption));
+        }
+        // analyze_usage_from_refs will set the dirty flag
+        info!("Analyzing chunk usage");
+        let usage = try!(self.analyze_usage_from_refs());
+        let rehash_bundles: HashSet<u32> = usage.iter()
+            .filter(|&(_, bundle)| bundle.info.hash_method != self.config.hash)
+            .map(|(id, _)| *id).collect();
+        let mut rewrite_bundles: Vec<u32> = usage.iter()
+            .filter(|&(id, bundle)| bundle.info.encryption != self.config.encryption && !rehash_bundles.contains(id))
+            .map(|(id, _)| *id).collect();
+        rewrite_bundles.sort();
+        if !rehash_bundles.is_empty() {
+            info!("Rehashing {} bundles with {} and rewriting all backups", rehash_bundles.len(), self.config.hash.name());
+        }
+        info!("Reencrypting {} bundles and {} backups", rewrite_bundles.len(), rewrite_backups.len());
+        if !force {
+            self.dirty = false;
+            return Ok(())
+        }
+        if !rehash_bundles.is_empty() {
+            let rehashed = try!(self.rehash(&rehash_bundles));
+            rewrite_backups.retain(|&(_, ref path)| !rehashed.contains(path));
+        }
+        let mut rewritten = HashSet::new();
+        for id in ProgressIter::new("rewriting bundles", rewrite_bundles.len(), rewrite_bundles.iter()) {
+            try!(self.rewrite_bundle(*id, &usage[id]));
+            rewritten.insert(*id);
+            if rewritten.len() >= REKEY_BATCH_SIZE {
+                try!(self.remove_rewritten_bundles(&rewritten));
+                rewritten.clear();
+            }
+        }
+        try!(self.remove_rewritten_bundles(&rewritten));
+        for (name, path) in ProgressIter::new("rewriting backups", rewrite_backups.len(), rewrite_backups.into_iter()) {
+            let (name, backup) = try!(Backup::read_with_name_from(&self.crypto.lock().unwrap(), &path, &name));
+            debug!("Reencrypting backup {}", name);
+            try!(self.save_backup(&backup, &name));
+        }
+        try!(self.reseal_hash_key());
+        try!(self.save_config());
         self.dirty = false;
         Ok(())
     }
+
+    /// All registered key pairs that are neither used for new data nor by any bundle or backup
+    pub fn unused_keys(&self) -> Result<Vec<PublicKey>, RepositoryError> {
+        Ok(try!(self.key_usage()).into_iter()
+            .filter(|&(_, ref usage)| usage.secret_key && !usage.is_used())
+            .map(|(key, _)| key).collect())
+    }
+
+    pub fn remove_key(&mut self, public: &PublicKey) -> Result<(), RepositoryError> {
+        try!(self.write_mode());
+        Ok(try!(self.crypto.lock().unwrap().remove_secret_key(public)))
+    }
 }
@@ -2,15 +2,28 @@ use std::io::{self, Write, Read, Cursor};
 use std::ops::{Deref, DerefMut};
 
 use serde::{self, Serialize, Deserialize};
+use serde::ser::SerializeTuple;
+use serde::de::{Error, Visitor, SeqAccess};
 use serde_bytes::{Bytes, ByteBuf};
-use serde::de::Error;
 
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 
-use super::Hash;
+use std::fmt;
+
+use super::{Hash, SHORT_HASH_SIZE, LONG_HASH_SIZE};
 
 pub type Chunk = (Hash, u32);
 
+/// Lists with long hashes write all hashes with 32 bytes, short hashes are padded
+#[inline]
+pub fn chunk_hash_size(chunks: &[Chunk]) -> usize {
+    if chunks.iter().any(|c| c.0.is_long()) {
+        LONG_HASH_SIZE
+    } else {
+        SHORT_HASH_SIZE
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, Hash, Clone)]
 pub struct ChunkList(Vec<Chunk>);
 
@@ -40,18 +53,25 @@ impl ChunkList {
         self.0.push(chunk)
     }
 
+    #[inline]
+    pub fn hash_size(&self) -> usize {
+        chunk_hash_size(&self.0)
+    }
+
+    /// The encoding does not contain the hash size, it has to be stored by the caller
     pub fn write_to(&self, dst: &mut Write) -> Result<(), io::Error> {
+        let hash_size = self.hash_size();
         for chunk in &self.0 {
-            try!(chunk.0.write_to(dst));
+            try!(chunk.0.write_to(dst, hash_size));
             try!(dst.write_u32::<LittleEndian>(chunk.1));
         }
         Ok(())
     }
 
-    pub fn read_n_from(n: usize, src: &mut Read) -> Result<Self, io::Error> {
+    pub fn read_n_from(n: usize, hash_size: usize, src: &mut Read) -> Result<Self, io::Error> {
         let mut chunks = Vec::with_capacity(n);
         for _ in 0..n {
-            let hash = try!(Hash::read_from(src));
+            let hash = try!(Hash::read_from(src, hash_size));
             let len = try!(src.read_u32::<LittleEndian>());
             chunks.push((hash, len));
         }
@@ -59,16 +79,17 @@ impl ChunkList {
     }
 
     #[inline]
-    pub fn read_from(src: &[u8]) -> Self {
-        if src.len() % 20 != 0 {
+    pub fn read_from(src: &[u8], hash_size: usize) -> Self {
+        let entry_size = hash_size + 4;
+        if src.len() % entry_size != 0 {
             warn!("Reading truncated chunk list");
         }
-        ChunkList::read_n_from(src.len()/20, &mut Cursor::new(src)).unwrap()
+        ChunkList::read_n_from(src.len() / entry_size, hash_size, &mut Cursor::new(src)).unwrap()
     }
 
     #[inline]
     pub fn encoded_size(&self) -> usize {
-        self.0.len() * 20
+        self.0.len() * (self.hash_size() + 4)
     }
 
     #[inline]
@@ -109,22 +130,63 @@ impl DerefMut for ChunkList {
     }
 }
 
+/// Lists with short hashes are serialized as bytes, lists with long hashes are serialized as a
+/// pair of the hash size and the bytes
 impl Serialize for ChunkList {
     #[inline]
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: serde::Serializer {
         let mut buf = Vec::with_capacity(self.encoded_size());
         self.write_to(&mut buf).unwrap();
-        Bytes::from(&buf as &[u8]).serialize(serializer)
+        let hash_size = self.hash_size();
+        if hash_size == SHORT_HASH_SIZE {
+            return Bytes::from(&buf as &[u8]).serialize(serializer)
+        }
+        let mut tuple = try!(serializer.serialize_tuple(2));
+        try!(tuple.serialize_element(&(hash_size as u8)));
+        try!(tuple.serialize_element(&Bytes::from(&buf as &[u8])));
+        tuple.end()
+    }
+}
+
+struct ChunkListVisitor;
+
+impl ChunkListVisitor {
+    fn decode<E: Error>(hash_size: usize, data: &[u8]) -> Result<ChunkList, E> {
+        if hash_size != SHORT_HASH_SIZE && hash_size != LONG_HASH_SIZE {
+            return Err(E::custom("Invalid chunk list hash size"));
+        }
+        if data.len() % (hash_size + 4) != 0 {
+            return Err(E::custom("Invalid chunk list length"));
+        }
+        Ok(ChunkList::read_from(data, hash_size))
+    }
+}
+
+impl<'a> Visitor<'a> for ChunkListVisitor {
+    type Value = ChunkList;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("an encoded chunk list")
+    }
+
+    fn visit_bytes<E: Error>(self, data: &[u8]) -> Result<ChunkList, E> {
+        Self::decode(SHORT_HASH_SIZE, data)
+    }
+
+    fn visit_byte_buf<E: Error>(self, data: Vec<u8>) -> Result<ChunkList, E> {
+        Self::decode(SHORT_HASH_SIZE, &data)
+    }
+
+    fn visit_seq<A: SeqAccess<'a>>(self, mut seq: A) -> Result<ChunkList, A::Error> {
+        let hash_size: u8 = try!(try!(seq.next_element()).ok_or_else(|| A::Error::invalid_length(0, &self)));
+        let data: Vec<u8> = try!(try!(seq.next_element::<ByteBuf>()).ok_or_else(|| A::Error::invalid_length(1, &self))).into();
+        Self::decode(hash_size as usize, &data)
     }
 }
 
 impl<'a> Deserialize<'a> for ChunkList {
     #[inline]
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error> where D: serde::Deserializer<'a> {
-        let data: Vec<u8> = try!(ByteBuf::deserialize(deserializer)).into();
-        if data.len() % 20 != 0 {
-            return Err(D::Error::custom("Invalid chunk list length"));
-        }
-        Ok(ChunkList::read_n_from(data.len()/20, &mut Cursor::new(data)).unwrap())
+        deserializer.deserialize_any(ChunkListVisitor)
     }
 }
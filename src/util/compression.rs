@@ -1,10 +1,32 @@
 use std::ptr;
+use std::cmp::min;
 use std::ffi::{CStr, CString};
 use std::io::{self, Write};
 use std::str::FromStr;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
 
 use libc;
 use squash::*;
+use zstd;
+use zstd::stream::{raw, zio};
+
+use super::{Hash, HashMethod};
+
+
+// Zstd is not used through squash as squash does not support dictionaries or the long
+// distance mode. The window of the long distance mode can still be decoded with the default
+// decoder settings.
+const ZSTD_MAX_LEVEL: u8 = 22;
+const ZSTD_LONG_WINDOW_LOG: u32 = 27;
+
+// Compressed and encrypted data has an entropy of almost 8 bits per byte
+const ENTROPY_SAMPLE_SIZE: usize = 16*1024;
+const MAX_COMPRESSIBLE_ENTROPY: f64 = 7.5;
+
+lazy_static! {
+    static ref DICTIONARIES: Mutex<HashMap<Hash, Arc<Vec<u8>>>> = Mutex::new(HashMap::new());
+}
 
 
 quick_error!{
@@ -32,6 +54,15 @@ quick_error!{
             cause(err)
             description("Failed to write to output")
         }
+        Zstd(err: io::Error) {
+            cause(err)
+            description("Zstd operation failed")
+            display("Zstd operation failed: {}", err)
+        }
+        MissingDictionary(id: Hash) {
+            description("Missing dictionary")
+            display("Missing compression dictionary: {}", id)
+        }
     }
 }
 
@@ -40,54 +71,74 @@ pub enum CompressionMethod {
     Deflate, // Standardized
     Brotli, // Good speed and ratio
     Lzma, // Very good ratio, slow
-    Lz4 // Very fast, low ratio
+    Lz4, // Very fast, low ratio
+    Zstd // Fast with good ratio, supports dictionaries
 }
 serde_impl!(CompressionMethod(u8) {
     Deflate => 0,
     Brotli => 1,
     Lzma => 2,
-    Lz4 => 3
+    Lz4 => 3,
+    Zstd => 4
 });
 
 
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct Compression {
     method: CompressionMethod,
-    level: u8
+    level: u8,
+    long: bool,
+    dictionary: Option<Hash>
 }
 impl Default for Compression {
     fn default() -> Self {
-        Compression { method: CompressionMethod::Brotli, level: 3 }
+        Compression { method: CompressionMethod::Brotli, level: 3, long: false, dictionary: None }
     }
 }
-serde_impl!(Compression(u64) {
+serde_impl!(Compression(u64?) {
     method: CompressionMethod => 0,
-    level: u8 => 1
+    level: u8 => 1,
+    long: bool => 2,
+    dictionary: Option<Hash> => 3
 });
 
 
 impl Compression {
     #[inline]
     pub fn to_string(&self) -> String {
-        format!("{}/{}", self.name(), self.level)
+        if self.long {
+            format!("{}/{}/long", self.name(), self.level)
+        } else {
+            format!("{}/{}", self.name(), self.level)
+        }
     }
 
     pub fn from_string(name: &str) -> Result<Self, CompressionError> {
-        let (name, level) = if let Some(pos) = name.find('/') {
-            let level = try!(u8::from_str(&name[pos+1..]).map_err(|_| CompressionError::UnsupportedCodec(name.to_string())));
-            let name = &name[..pos];
-            (name, level)
-        } else {
-            (name, 5)
-        };
-        let method = match name {
+        let mut parts = name.split('/');
+        let method = match parts.next().unwrap() {
             "deflate" | "zlib" | "gzip" => CompressionMethod::Deflate,
             "brotli" => CompressionMethod::Brotli,
             "lzma" | "lzma2" | "xz" => CompressionMethod::Lzma,
             "lz4" => CompressionMethod::Lz4,
+            "zstd" => CompressionMethod::Zstd,
             _ => return Err(CompressionError::UnsupportedCodec(name.to_string()))
         };
-        Ok(Compression { method: method, level: level })
+        let mut part = parts.next();
+        let level = if let Some(level) = part.and_then(|l| u8::from_str(l).ok()) {
+            part = parts.next();
+            level
+        } else {
+            5
+        };
+        let long = match part {
+            None => false,
+            Some("long") if method == CompressionMethod::Zstd => true,
+            Some(_) => return Err(CompressionError::UnsupportedCodec(name.to_string()))
+        };
+        if parts.next().is_some() || (method == CompressionMethod::Zstd && (level == 0 || level > ZSTD_MAX_LEVEL)) {
+            return Err(CompressionError::UnsupportedCodec(name.to_string()))
+        }
+        Ok(Compression { method: method, level: level, long: long, dictionary: None })
     }
 
     pub fn name(&self) -> &'static str {
@@ -96,6 +147,46 @@ impl Compression {
             CompressionMethod::Brotli => "brotli",
             CompressionMethod::Lzma => "lzma",
             CompressionMethod::Lz4 => "lz4",
+            CompressionMethod::Zstd => "zstd",
+        }
+    }
+
+    #[inline]
+    pub fn dictionary(&self) -> Option<&Hash> {
+        self.dictionary.as_ref()
+    }
+
+    /// Returns this compression with the given dictionary attached.
+    /// Only zstd supports dictionaries, all other compressions are returned unchanged.
+    pub fn with_dictionary(&self, dictionary: Hash) -> Self {
+        let mut compression = self.clone();
+        if compression.method == CompressionMethod::Zstd {
+            compression.dictionary = Some(dictionary);
+        }
+        compression
+    }
+
+    /// Registers a dictionary so that it can be used by all compressions that reference it.
+    /// Dictionaries are identified by the hash of their contents.
+    pub fn register_dictionary(data: Vec<u8>) -> Hash {
+        let id = HashMethod::Blake2.hash(&data);
+        DICTIONARIES.lock().unwrap().insert(id, Arc::new(data));
+        id
+    }
+
+    pub fn train_dictionary(samples: &[Vec<u8>], max_size: usize) -> Result<Vec<u8>, CompressionError> {
+        zstd::dict::from_samples(samples, max_size).map_err(CompressionError::Zstd)
+    }
+
+    fn get_dictionary(&self) -> Result<Option<Arc<Vec<u8>>>, CompressionError> {
+        if let Some(ref id) = self.dictionary {
+            if let Some(dict) = DICTIONARIES.lock().unwrap().get(id) {
+                Ok(Some(dict.clone()))
+            } else {
+                Err(CompressionError::MissingDictionary(*id))
+            }
+        } else {
+            Ok(None)
         }
     }
 
@@ -139,6 +230,13 @@ impl Compression {
     }
 
     pub fn compress(&self, data: &[u8]) -> Result<Vec<u8>, CompressionError> {
+        if self.method == CompressionMethod::Zstd {
+            let mut buf = Vec::with_capacity(data.len());
+            let mut stream = try!(self.compress_stream());
+            try!(stream.process(data, &mut buf));
+            try!(stream.finish(&mut buf));
+            return Ok(buf)
+        }
         let codec = try!(self.codec());
         let options = try!(self.options());
         let mut size = data.len() * 2 + 500;
@@ -166,6 +264,13 @@ impl Compression {
     }
 
     pub fn decompress(&self, data: &[u8]) -> Result<Vec<u8>, CompressionError> {
+        if self.method == CompressionMethod::Zstd {
+            let mut buf = Vec::with_capacity(data.len() * 4);
+            let mut stream = try!(self.decompress_stream());
+            try!(stream.process(data, &mut buf));
+            try!(stream.finish(&mut buf));
+            return Ok(buf)
+        }
         let codec = try!(self.codec());
         let mut size = unsafe { squash_codec_get_uncompressed_size(
             codec,
@@ -191,7 +296,28 @@ impl Compression {
         Ok(buf)
     }
 
+    fn zstd_compress_stream(&self) -> Result<CompressionStream, CompressionError> {
+        let dictionary = try!(self.get_dictionary());
+        let dictionary: &[u8] = dictionary.as_ref().map(|d| &d[..]).unwrap_or(&[]);
+        let mut encoder = try!(raw::Encoder::with_dictionary(self.level as i32, dictionary).map_err(CompressionError::Zstd));
+        if self.long {
+            try!(encoder.set_parameter(raw::CParameter::EnableLongDistanceMatching(true)).map_err(CompressionError::Zstd));
+            try!(encoder.set_parameter(raw::CParameter::WindowLog(ZSTD_LONG_WINDOW_LOG)).map_err(CompressionError::Zstd));
+        }
+        Ok(CompressionStream::ZstdCompress(zio::Writer::new(Vec::new(), encoder)))
+    }
+
+    fn zstd_decompress_stream(&self) -> Result<CompressionStream, CompressionError> {
+        let dictionary = try!(self.get_dictionary());
+        let dictionary: &[u8] = dictionary.as_ref().map(|d| &d[..]).unwrap_or(&[]);
+        let decoder = try!(raw::Decoder::with_dictionary(dictionary).map_err(CompressionError::Zstd));
+        Ok(CompressionStream::ZstdDecompress(zio::Writer::new(Vec::new(), decoder)))
+    }
+
     pub fn compress_stream(&self) -> Result<CompressionStream, CompressionError> {
+        if self.method == CompressionMethod::Zstd {
+            return self.zstd_compress_stream()
+        }
         let codec = try!(self.codec());
         let options = try!(self.options());
         let stream = unsafe { squash_stream_new_with_options(
@@ -200,10 +326,13 @@ impl Compression {
         if stream.is_null() {
             return Err(CompressionError::InitializeStream);
         }
-        Ok(CompressionStream::new(stream))
+        Ok(CompressionStream::Squash(SquashCodecStream::new(stream)))
     }
 
     pub fn decompress_stream(&self) -> Result<CompressionStream, CompressionError> {
+        if self.method == CompressionMethod::Zstd {
+            return self.zstd_decompress_stream()
+        }
         let codec = try!(self.codec());
         let stream = unsafe { squash_stream_new(
             codec, SQUASH_STREAM_DECOMPRESS, ptr::null::<()>()
@@ -211,20 +340,80 @@ impl Compression {
         if stream.is_null() {
             return Err(CompressionError::InitializeStream);
         }
-        Ok(CompressionStream::new(stream))
+        Ok(CompressionStream::Squash(SquashCodecStream::new(stream)))
+    }
+}
+
+
+/// Estimates whether the data can be compressed from the byte entropy of a sample.
+pub fn is_compressible(data: &[u8]) -> bool {
+    let sample = &data[..min(data.len(), ENTROPY_SAMPLE_SIZE)];
+    if sample.is_empty() {
+        return true
+    }
+    let mut counts = [0usize; 256];
+    for byte in sample {
+        counts[*byte as usize] += 1;
+    }
+    let len = sample.len() as f64;
+    let entropy: f64 = counts.iter().filter(|c| **c > 0).map(|c| {
+        let p = *c as f64 / len;
+        -p * p.log2()
+    }).sum();
+    entropy < MAX_COMPRESSIBLE_ENTROPY
+}
+
+
+pub enum CompressionStream {
+    Squash(SquashCodecStream),
+    ZstdCompress(zio::Writer<Vec<u8>, raw::Encoder>),
+    ZstdDecompress(zio::Writer<Vec<u8>, raw::Decoder>)
+}
+
+impl CompressionStream {
+    pub fn process<W: Write>(&mut self, input: &[u8], output: &mut W) -> Result<(), CompressionError> {
+        match *self {
+            CompressionStream::Squash(ref mut stream) => stream.process(input, output),
+            CompressionStream::ZstdCompress(ref mut encoder) => {
+                try!(encoder.write_all(input).map_err(CompressionError::Zstd));
+                try!(output.write_all(encoder.writer()));
+                encoder.writer_mut().clear();
+                Ok(())
+            },
+            CompressionStream::ZstdDecompress(ref mut decoder) => {
+                try!(decoder.write_all(input).map_err(CompressionError::Zstd));
+                try!(output.write_all(decoder.writer()));
+                decoder.writer_mut().clear();
+                Ok(())
+            }
+        }
+    }
+
+    pub fn finish<W: Write>(self, output: &mut W) -> Result<(), CompressionError> {
+        match self {
+            CompressionStream::Squash(stream) => stream.finish(output),
+            CompressionStream::ZstdCompress(mut encoder) => {
+                try!(encoder.finish().map_err(CompressionError::Zstd));
+                Ok(try!(output.write_all(&encoder.into_inner().0)))
+            },
+            CompressionStream::ZstdDecompress(mut decoder) => {
+                try!(decoder.flush().map_err(CompressionError::Zstd));
+                Ok(try!(output.write_all(&decoder.into_inner().0)))
+            }
+        }
     }
 }
 
 
-pub struct CompressionStream {
+pub struct SquashCodecStream {
     stream: *mut SquashStream,
     buffer: [u8; 16*1024]
 }
 
-impl CompressionStream {
+impl SquashCodecStream {
     #[inline]
     fn new(stream: *mut SquashStream) -> Self {
-        CompressionStream {
+        SquashCodecStream {
             stream: stream,
             buffer: [0; 16*1024]
         }
@@ -269,7 +458,7 @@ impl CompressionStream {
     }
 }
 
-impl Drop for CompressionStream {
+impl Drop for SquashCodecStream {
     fn drop(&mut self) {
         unsafe { squash_object_unref(self.stream as *mut libc::c_void); }
     }
@@ -1,18 +1,32 @@
 use std::collections::HashMap;
 use std::path::{Path, PathBuf};
-use std::io;
+use std::io::{self, Write, BufRead, BufReader};
 use std::fs::{self, File};
 use std::sync::{Once, ONCE_INIT};
+use std::cell::RefCell;
+use std::env;
+use std::str::FromStr;
+use std::cmp::max;
+use std::os::unix::io::{FromRawFd, RawFd};
 
+use serde::Serialize;
 use serde_yaml;
 use serde_bytes::ByteBuf;
+use byteorder::{BigEndian, ByteOrder};
 
 use libsodium_sys;
 use sodiumoxide;
 use sodiumoxide::crypto::sealedbox;
 use sodiumoxide::crypto::box_;
 use sodiumoxide::crypto::pwhash;
+use sodiumoxide::crypto::secretbox;
+use sodiumoxide::crypto::sign;
+use sodiumoxide::crypto::scalarmult::curve25519;
+use sodiumoxide::randombytes::randombytes;
+use rpassword;
+use libc;
 pub use sodiumoxide::crypto::box_::{SecretKey, PublicKey};
+pub use sodiumoxide::crypto::sign::PublicKey as SigningKey;
 
 use ::util::*;
 
@@ -37,6 +51,10 @@ quick_error!{
             description("Missing key")
             display("Missing key: {}", to_hex(&key[..]))
         }
+        WrongPassphrase {
+            description("Wrong passphrase")
+            display("Wrong passphrase")
+        }
         Operation(reason: &'static str) {
             description("Operation failed")
             display("Operation failed: {}", reason)
@@ -53,6 +71,24 @@ quick_error!{
             description("Yaml format error")
             display("Yaml format error: {}", err)
         }
+        Shamir(err: ShamirError) {
+            from()
+            cause(err)
+            description("Secret sharing failed")
+            display("Secret sharing failed: {}", err)
+        }
+        InvalidShare(reason: &'static str) {
+            description("Invalid key share")
+            display("Invalid key share: {}", reason)
+        }
+        MissingSigningKey {
+            description("Missing signing key")
+            display("Missing signing key: only the public signing key is available")
+        }
+        InvalidSignature {
+            description("Invalid signature")
+            display("Invalid signature: the data has not been signed with the signing key")
+        }
     }
 }
 
@@ -61,22 +97,26 @@ quick_error!{
 #[allow(unknown_lints,non_camel_case_types)]
 pub enum EncryptionMethod {
     Sodium,
+    Stream
 }
 serde_impl!(EncryptionMethod(u64) {
-    Sodium => 0
+    Sodium => 0,
+    Stream => 2
 });
 
 impl EncryptionMethod {
     pub fn from_string(val: &str) -> Result<Self, &'static str> {
         match val {
             "sodium" => Ok(EncryptionMethod::Sodium),
+            "stream" => Ok(EncryptionMethod::Stream),
             _ => Err("Unsupported encryption method")
         }
     }
 
     pub fn to_string(&self) -> String {
         match *self {
-            EncryptionMethod::Sodium => "sodium".to_string()
+            EncryptionMethod::Sodium => "sodium".to_string(),
+            EncryptionMethod::Stream => "stream".to_string()
         }
     }
 }
@@ -84,126 +124,834 @@ impl EncryptionMethod {
 
 pub type Encryption = (EncryptionMethod, ByteBuf);
 
+pub const HASH_KEY_SIZE: usize = 32;
+
+// A data key sealed for one recipient
+const WRAPPED_KEY_SIZE: usize = secretbox::KEYBYTES + sealedbox::SEALBYTES;
+
+pub const STREAM_BLOCK_SIZE: usize = 64 * 1024;
+pub const STREAM_ENCRYPTED_BLOCK_SIZE: usize = STREAM_BLOCK_SIZE + secretbox::MACBYTES;
+// The rest of the nonce is the block counter and the flag for the last block
+pub const STREAM_PREFIX_SIZE: usize = secretbox::NONCEBYTES - 9;
+
 
+const KEY_SHARE_PREFIX: &'static str = "ZVAULT-KEY-SHARE";
+
+pub const PASSPHRASE_ENV: &'static str = "ZVAULT_PASSPHRASE";
+pub const NEW_PASSPHRASE_ENV: &'static str = "ZVAULT_NEW_PASSPHRASE";
+
+/// Reads a passphrase from the environment variable `env`, from the file descriptor given in
+/// `env` + `_FD` or interactively from the terminal (in that order)
+pub fn read_passphrase(prompt: &str, env: &str) -> Result<String, EncryptionError> {
+    if let Ok(passphrase) = env::var(env) {
+        return Ok(passphrase)
+    }
+    if let Ok(fd) = env::var(format!("{}_FD", env)) {
+        let fd: RawFd = try!(fd.parse().map_err(|_| EncryptionError::Operation("Invalid passphrase file descriptor")));
+        // The descriptor is duplicated as the file closes it when it is dropped
+        let fd = unsafe { libc::dup(fd) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error().into())
+        }
+        let mut line = String::new();
+        try!(BufReader::new(unsafe { File::from_raw_fd(fd) }).read_line(&mut line));
+        return Ok(line.trim_right_matches(|c| c == '\n' || c == '\r').to_string())
+    }
+    Ok(try!(rpassword::prompt_password_stderr(prompt)))
+}
+
+/// Like `read_passphrase` but asks twice when reading interactively
+pub fn read_new_passphrase(env: &str) -> Result<String, EncryptionError> {
+    if env::var(env).is_ok() || env::var(format!("{}_FD", env)).is_ok() {
+        return read_passphrase("", env)
+    }
+    let passphrase = try!(read_passphrase("New passphrase: ", env));
+    if passphrase != try!(read_passphrase("Repeat passphrase: ", env)) {
+        return Err(EncryptionError::Operation("Passphrases do not match"))
+    }
+    Ok(passphrase)
+}
+
+
+struct PassphraseYaml {
+    salt: String,
+    nonce: String,
+    ops_limit: usize,
+    mem_limit: usize
+}
+impl Default for PassphraseYaml {
+    fn default() -> Self {
+        PassphraseYaml {
+            salt: "".to_string(),
+            nonce: "".to_string(),
+            ops_limit: pwhash::OPSLIMIT_INTERACTIVE.0,
+            mem_limit: pwhash::MEMLIMIT_INTERACTIVE.0
+        }
+    }
+}
+serde_impl!(PassphraseYaml(String) {
+    salt: String => "salt",
+    nonce: String => "nonce",
+    ops_limit: usize => "ops_limit",
+    mem_limit: usize => "mem_limit"
+});
+
+impl PassphraseYaml {
+    fn derive_key(&self, passphrase: &str) -> Result<secretbox::Key, EncryptionError> {
+        let salt = try!(parse_hex(&self.salt).map_err(|_| EncryptionError::InvalidKey));
+        let salt = try!(pwhash::Salt::from_slice(&salt).ok_or(EncryptionError::InvalidKey));
+        let mut key = [0u8; secretbox::KEYBYTES];
+        try!(pwhash::derive_key(&mut key, passphrase.as_bytes(), &salt, pwhash::OpsLimit(self.ops_limit), pwhash::MemLimit(self.mem_limit))
+            .map_err(|_| EncryptionError::Operation("Key derivation failed")));
+        Ok(secretbox::Key(key))
+    }
+
+    /// Encrypts the secret with a key derived from the passphrase and a new salt
+    fn seal(secret: &[u8], passphrase: &str) -> Result<(Self, Vec<u8>), EncryptionError> {
+        let nonce = secretbox::gen_nonce();
+        let params = PassphraseYaml {
+            salt: to_hex(&pwhash::gen_salt()[..]),
+            nonce: to_hex(&nonce[..]),
+            .. PassphraseYaml::default()
+        };
+        let key = try!(params.derive_key(passphrase));
+        let sealed = secretbox::seal(secret, &nonce, &key);
+        Ok((params, sealed))
+    }
+
+    fn open(&self, sealed: &[u8], passphrase: Option<&str>) -> Result<Vec<u8>, EncryptionError> {
+        let passphrase = try!(passphrase.ok_or(EncryptionError::WrongPassphrase));
+        let nonce = try!(parse_hex(&self.nonce).map_err(|_| EncryptionError::InvalidKey));
+        let nonce = try!(secretbox::Nonce::from_slice(&nonce).ok_or(EncryptionError::InvalidKey));
+        let key = try!(self.derive_key(passphrase));
+        secretbox::open(sealed, &nonce, &key).map_err(|_| EncryptionError::WrongPassphrase)
+    }
+}
+
+
+/// Key files are written to a temporary file first, so an existing key is never lost
+fn save_keyfile<T: Serialize, P: AsRef<Path>>(value: &T, path: P) -> Result<(), EncryptionError> {
+    let path = path.as_ref();
+    let temp_path = path.with_extension("yaml.tmp");
+    {
+        let mut f = try!(File::create(&temp_path));
+        try!(serde_yaml::to_writer(&mut f, value));
+        try!(f.sync_all());
+    }
+    try!(fs::rename(&temp_path, path));
+    Ok(())
+}
+
+
+/// A key file, the secret key is either stored as plain hex or, if `passphrase` is set,
+/// encrypted with a key derived from a passphrase
 struct KeyfileYaml {
     public: String,
-    secret: String
+    secret: String,
+    passphrase: Option<PassphraseYaml>
 }
 impl Default for KeyfileYaml {
     fn default() -> Self {
         KeyfileYaml {
             public: "".to_string(),
-            secret: "".to_string()
+            secret: "".to_string(),
+            passphrase: None
         }
     }
 }
-serde_impl!(KeyfileYaml(String) {
+serde_impl!(KeyfileYaml(String?) {
     public: String => "public",
-    secret: String => "secret"
+    secret: String => "secret",
+    passphrase: Option<PassphraseYaml> => "passphrase"
 });
 
 impl KeyfileYaml {
+    fn new(public: &PublicKey, secret: &SecretKey, passphrase: Option<&str>) -> Result<Self, EncryptionError> {
+        let passphrase = match passphrase {
+            Some(passphrase) => passphrase,
+            None => return Ok(KeyfileYaml { public: to_hex(&public[..]), secret: to_hex(&secret[..]), passphrase: None })
+        };
+        let (params, sealed) = try!(PassphraseYaml::seal(&secret[..], passphrase));
+        Ok(KeyfileYaml { public: to_hex(&public[..]), secret: to_hex(&sealed), passphrase: Some(params) })
+    }
+
     pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, EncryptionError> {
         let f = try!(File::open(path));
         Ok(try!(serde_yaml::from_reader(f)))
     }
 
+    #[inline]
     pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), EncryptionError> {
-        let mut f = try!(File::create(path));
-        Ok(try!(serde_yaml::to_writer(&mut f, &self)))
+        save_keyfile(self, path)
+    }
+
+    #[inline]
+    fn is_protected(&self) -> bool {
+        self.passphrase.is_some()
+    }
+
+    fn public_key(&self) -> Result<PublicKey, EncryptionError> {
+        let public = try!(parse_hex(&self.public).map_err(|_| EncryptionError::InvalidKey));
+        PublicKey::from_slice(&public).ok_or(EncryptionError::InvalidKey)
+    }
+
+    fn secret_key(&self, passphrase: Option<&str>) -> Result<SecretKey, EncryptionError> {
+        let mut secret = try!(parse_hex(&self.secret).map_err(|_| EncryptionError::InvalidKey));
+        if let Some(ref params) = self.passphrase {
+            secret = try!(params.open(&secret, passphrase));
+        }
+        SecretKey::from_slice(&secret).ok_or(EncryptionError::InvalidKey)
+    }
+}
+
+
+/// The Ed25519 key pair that signs backup files and the config, the secret key is only present
+/// on machines that are allowed to write to the repository. Like in key files, the secret key
+/// is encrypted with a key derived from a passphrase if `passphrase` is set.
+struct SigningKeyYaml {
+    public: String,
+    secret: Option<String>,
+    passphrase: Option<PassphraseYaml>
+}
+impl Default for SigningKeyYaml {
+    fn default() -> Self {
+        SigningKeyYaml {
+            public: "".to_string(),
+            secret: None,
+            passphrase: None
+        }
+    }
+}
+serde_impl!(SigningKeyYaml(String?) {
+    public: String => "public",
+    secret: Option<String> => "secret",
+    passphrase: Option<PassphraseYaml> => "passphrase"
+});
+
+impl SigningKeyYaml {
+    fn new(public: &sign::PublicKey, secret: Option<&sign::SecretKey>, passphrase: Option<&str>) -> Result<Self, EncryptionError> {
+        let (secret, passphrase) = match (secret, passphrase) {
+            (Some(secret), Some(passphrase)) => {
+                let (params, sealed) = try!(PassphraseYaml::seal(&secret[..], passphrase));
+                (Some(to_hex(&sealed)), Some(params))
+            },
+            (secret, _) => (secret.map(|s| to_hex(&s[..])), None)
+        };
+        Ok(SigningKeyYaml { public: to_hex(&public[..]), secret: secret, passphrase: passphrase })
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, EncryptionError> {
+        let f = try!(File::open(path));
+        Ok(try!(serde_yaml::from_reader(f)))
+    }
+
+    #[inline]
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), EncryptionError> {
+        save_keyfile(self, path)
+    }
+
+    #[inline]
+    fn is_protected(&self) -> bool {
+        self.secret.is_some() && self.passphrase.is_some()
+    }
+
+    fn public_key(&self) -> Result<sign::PublicKey, EncryptionError> {
+        let public = try!(parse_hex(&self.public).map_err(|_| EncryptionError::InvalidKey));
+        sign::PublicKey::from_slice(&public).ok_or(EncryptionError::InvalidKey)
+    }
+
+    fn secret_key(&self, passphrase: Option<&str>) -> Result<Option<sign::SecretKey>, EncryptionError> {
+        let mut secret = match self.secret {
+            Some(ref secret) => try!(parse_hex(secret).map_err(|_| EncryptionError::InvalidKey)),
+            None => return Ok(None)
+        };
+        if let Some(ref params) = self.passphrase {
+            secret = try!(params.open(&secret, passphrase));
+        }
+        Ok(Some(try!(sign::SecretKey::from_slice(&secret).ok_or(EncryptionError::InvalidKey))))
+    }
+}
+
+
+/// Encrypts or decrypts data of the stream method block by block. Every block is authenticated
+/// on its own with a nonce that contains the block number, the last block is marked so that
+/// truncated data is detected.
+pub struct CipherStream {
+    key: secretbox::Key,
+    prefix: Vec<u8>,
+    counter: u64,
+    buffer: Vec<u8>,
+    started: bool,
+    encrypt: bool
+}
+
+impl CipherStream {
+    fn encrypt(key: secretbox::Key) -> Self {
+        CipherStream {
+            key: key,
+            prefix: randombytes(STREAM_PREFIX_SIZE),
+            counter: 0,
+            buffer: Vec::with_capacity(STREAM_BLOCK_SIZE),
+            started: false,
+            encrypt: true
+        }
+    }
+
+    fn decrypt(key: secretbox::Key) -> Self {
+        CipherStream {
+            key: key,
+            prefix: vec![],
+            counter: 0,
+            buffer: Vec::with_capacity(STREAM_BLOCK_SIZE + secretbox::MACBYTES),
+            started: false,
+            encrypt: false
+        }
+    }
+
+    // The random nonce prefix is the first part of the encrypted data
+    fn start<W: Write>(&mut self, output: &mut W) -> Result<bool, EncryptionError> {
+        if !self.started {
+            if self.encrypt {
+                try!(output.write_all(&self.prefix));
+            } else if self.buffer.len() >= STREAM_PREFIX_SIZE {
+                self.prefix = self.buffer.drain(..STREAM_PREFIX_SIZE).collect();
+            } else {
+                return Ok(false)
+            }
+            self.started = true;
+        }
+        Ok(true)
+    }
+
+    fn transform(&self, block: &[u8], last: bool) -> Result<Vec<u8>, EncryptionError> {
+        let mut nonce = [0u8; secretbox::NONCEBYTES];
+        nonce[..STREAM_PREFIX_SIZE].copy_from_slice(&self.prefix);
+        BigEndian::write_u64(&mut nonce[STREAM_PREFIX_SIZE..STREAM_PREFIX_SIZE+8], self.counter);
+        nonce[secretbox::NONCEBYTES-1] = last as u8;
+        let nonce = secretbox::Nonce(nonce);
+        if self.encrypt {
+            Ok(secretbox::seal(block, &nonce, &self.key))
+        } else {
+            secretbox::open(block, &nonce, &self.key).map_err(|_| EncryptionError::Operation("Decryption failed"))
+        }
+    }
+
+    pub fn process<W: Write>(&mut self, input: &[u8], output: &mut W) -> Result<(), EncryptionError> {
+        self.buffer.extend_from_slice(input);
+        if !try!(self.start(output)) {
+            return Ok(())
+        }
+        let block_size = if self.encrypt { STREAM_BLOCK_SIZE } else { STREAM_BLOCK_SIZE + secretbox::MACBYTES };
+        let mut pos = 0;
+        // The last block stays in the buffer until finish is called, as it has to be marked
+        while self.buffer.len() - pos > block_size {
+            let data = try!(self.transform(&self.buffer[pos..pos+block_size], false));
+            try!(output.write_all(&data));
+            self.counter += 1;
+            pos += block_size;
+        }
+        self.buffer.drain(..pos);
+        Ok(())
+    }
+
+    pub fn finish<W: Write>(mut self, output: &mut W) -> Result<(), EncryptionError> {
+        if !try!(self.start(output)) {
+            return Err(EncryptionError::Operation("Decryption failed"))
+        }
+        let data = try!(self.transform(&self.buffer, true));
+        try!(output.write_all(&data));
+        Ok(())
+    }
+
+    /// The number of blocks of a stream with the given plain size, the last block is always
+    /// present even if it is empty
+    #[inline]
+    pub fn block_count(size: usize) -> usize {
+        max(1, (size + STREAM_BLOCK_SIZE - 1) / STREAM_BLOCK_SIZE)
+    }
+
+    /// The size of the encrypted stream for the given plain size
+    #[inline]
+    pub fn encrypted_size(size: usize) -> usize {
+        STREAM_PREFIX_SIZE + size + Self::block_count(size) * secretbox::MACBYTES
+    }
+
+    /// Decrypts the consecutive encrypted blocks in `data` without the blocks in front of them.
+    /// `prefix` is the start of the encrypted stream, `first` is the number of the first block
+    /// and `last` tells whether `data` ends with the last block of the stream.
+    pub fn decrypt_blocks(mut self, prefix: &[u8], first: usize, data: &[u8], last: bool) -> Result<Vec<u8>, EncryptionError> {
+        if self.encrypt || prefix.len() != STREAM_PREFIX_SIZE || data.is_empty() {
+            return Err(EncryptionError::Operation("Decryption failed"))
+        }
+        self.prefix = prefix.to_vec();
+        self.counter = first as u64;
+        let blocks: Vec<&[u8]> = data.chunks(STREAM_ENCRYPTED_BLOCK_SIZE).collect();
+        let mut out = Vec::with_capacity(data.len());
+        for (i, block) in blocks.iter().enumerate() {
+            let is_last = last && i == blocks.len() - 1;
+            // Only the last block of the stream can be shorter
+            if !is_last && block.len() != STREAM_ENCRYPTED_BLOCK_SIZE {
+                return Err(EncryptionError::Operation("Decryption failed"))
+            }
+            out.extend_from_slice(&try!(self.transform(block, is_last)));
+            self.counter += 1;
+        }
+        Ok(out)
     }
 }
 
 
 pub struct Crypto {
     path: PathBuf,
-    keys: HashMap<PublicKey, SecretKey>
+    keys: RefCell<HashMap<PublicKey, SecretKey>>,
+    // Protected key files that have not been unlocked yet
+    locked: RefCell<HashMap<PublicKey, KeyfileYaml>>,
+    // The last passphrase that unlocked a key file, tried first for the other key files
+    passphrase: RefCell<Option<String>>,
+    // Unwrapped data keys by their wrapped form
+    data_keys: RefCell<HashMap<Vec<u8>, secretbox::Key>>,
+    hash_key: Vec<u8>,
+    signing_key: Option<sign::PublicKey>,
+    // The secret signing key, a protected one is unlocked on first use
+    signing_secret: RefCell<Option<sign::SecretKey>>,
+    // The stored form of a protected secret signing key
+    locked_signing_key: Option<SigningKeyYaml>
 }
 
 impl Crypto {
     #[inline]
     pub fn dummy() -> Self {
         sodium_init();
-        Crypto { path: PathBuf::new(), keys: HashMap::new() }
+        Crypto {
+            path: PathBuf::new(),
+            keys: RefCell::new(HashMap::new()),
+            locked: RefCell::new(HashMap::new()),
+            passphrase: RefCell::new(None),
+            data_keys: RefCell::new(HashMap::new()),
+            hash_key: vec![],
+            signing_key: None,
+            signing_secret: RefCell::new(None),
+            locked_signing_key: None
+        }
     }
 
     pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, EncryptionError> {
         sodium_init();
         let path = path.as_ref().to_owned();
         let mut keys: HashMap<PublicKey, SecretKey> = HashMap::default();
+        let mut locked: HashMap<PublicKey, KeyfileYaml> = HashMap::default();
         for entry in try!(fs::read_dir(&path)) {
             let entry = try!(entry);
             let keyfile = try!(KeyfileYaml::load(entry.path()));
-            let public = try!(parse_hex(&keyfile.public).map_err(|_| EncryptionError::InvalidKey));
-            let public = try!(PublicKey::from_slice(&public).ok_or(EncryptionError::InvalidKey));
-            let secret = try!(parse_hex(&keyfile.secret).map_err(|_| EncryptionError::InvalidKey));
-            let secret = try!(SecretKey::from_slice(&secret).ok_or(EncryptionError::InvalidKey));
-            keys.insert(public, secret);
+            let public = try!(keyfile.public_key());
+            if keyfile.is_protected() {
+                locked.insert(public, keyfile);
+            } else {
+                keys.insert(public, try!(keyfile.secret_key(None)));
+            }
         }
-        Ok(Crypto { path: path, keys: keys })
+        Ok(Crypto {
+            path: path,
+            keys: RefCell::new(keys),
+            locked: RefCell::new(locked),
+            passphrase: RefCell::new(None),
+            data_keys: RefCell::new(HashMap::new()),
+            hash_key: vec![],
+            signing_key: None,
+            signing_secret: RefCell::new(None),
+            locked_signing_key: None
+        })
     }
 
     #[inline]
     pub fn add_secret_key(&mut self, public: PublicKey, secret: SecretKey) {
-        self.keys.insert(public, secret);
+        self.keys.borrow_mut().insert(public, secret);
     }
 
-    #[inline]
+    /// Copies the key file into the key folder, protected key files stay protected.
+    /// Files with key shares are restored and stored as unprotected key file.
     pub fn register_keyfile<P: AsRef<Path>>(&mut self, path: P) -> Result<(), EncryptionError> {
-        let (public, secret) = try!(Self::load_keypair_from_file(path));
-        self.register_secret_key(public, secret)
+        let path = path.as_ref();
+        if Self::is_shares_file(path) {
+            let (public, secret) = try!(Self::load_keypair_from_shares_file(path));
+            return self.register_secret_key(public, secret, None)
+        }
+        let keyfile = try!(KeyfileYaml::load(path));
+        let public = try!(keyfile.public_key());
+        if !keyfile.is_protected() {
+            return self.register_secret_key(public, try!(keyfile.secret_key(None)), None)
+        }
+        try!(keyfile.save(self.path.join(to_hex(&public[..]) + ".yaml")));
+        self.locked.borrow_mut().insert(public, keyfile);
+        Ok(())
     }
 
+    /// Loads a key pair, asking for the passphrase if the key file is protected.
+    /// Files with key shares are also accepted.
     pub fn load_keypair_from_file<P: AsRef<Path>>(path: P) -> Result<(PublicKey, SecretKey), EncryptionError> {
+        let path = path.as_ref();
+        if Self::is_shares_file(path) {
+            return Self::load_keypair_from_shares_file(path)
+        }
         let keyfile = try!(KeyfileYaml::load(path));
-        let public = try!(parse_hex(&keyfile.public).map_err(|_| EncryptionError::InvalidKey));
-        let public = try!(PublicKey::from_slice(&public).ok_or(EncryptionError::InvalidKey));
-        let secret = try!(parse_hex(&keyfile.secret).map_err(|_| EncryptionError::InvalidKey));
-        let secret = try!(SecretKey::from_slice(&secret).ok_or(EncryptionError::InvalidKey));
-        Ok((public, secret))
+        let public = try!(keyfile.public_key());
+        if !keyfile.is_protected() {
+            return Ok((public, try!(keyfile.secret_key(None))))
+        }
+        let passphrase = try!(read_passphrase(&format!("Passphrase for key {}: ", keyfile.public), PASSPHRASE_ENV));
+        Ok((public, try!(keyfile.secret_key(Some(&passphrase[..])))))
+    }
+
+    #[inline]
+    pub fn save_keypair_to_file<P: AsRef<Path>>(public: &PublicKey, secret: &SecretKey, passphrase: Option<&str>, path: P) -> Result<(), EncryptionError> {
+        try!(KeyfileYaml::new(public, secret, passphrase)).save(path)
     }
 
     #[inline]
-    pub fn save_keypair_to_file<P: AsRef<Path>>(public: &PublicKey, secret: &SecretKey, path: P) -> Result<(), EncryptionError> {
-        KeyfileYaml { public: to_hex(&public[..]), secret: to_hex(&secret[..]) }.save(path)
+    pub fn register_secret_key(&mut self, public: PublicKey, secret: SecretKey, passphrase: Option<&str>) -> Result<(), EncryptionError> {
+        let path = self.path.join(to_hex(&public[..]) + ".yaml");
+        try!(Self::save_keypair_to_file(&public, &secret, passphrase, path));
+        self.locked.borrow_mut().remove(&public);
+        self.keys.borrow_mut().insert(public, secret);
+        Ok(())
     }
 
     #[inline]
-    pub fn register_secret_key(&mut self, public: PublicKey, secret: SecretKey) -> Result<(), EncryptionError> {
+    pub fn contains_secret_key(&self, public: &PublicKey) -> bool {
+        self.keys.borrow().contains_key(public) || self.locked.borrow().contains_key(public)
+    }
+
+    /// All public keys with a key file, including locked ones
+    pub fn public_keys(&self) -> Vec<PublicKey> {
+        let mut keys: Vec<PublicKey> = self.keys.borrow().keys().cloned().collect();
+        keys.extend(self.locked.borrow().keys().cloned());
+        keys
+    }
+
+    /// Whether the key file of the key pair is protected with a passphrase
+    pub fn is_protected(&self, public: &PublicKey) -> bool {
         let path = self.path.join(to_hex(&public[..]) + ".yaml");
-        try!(Self::save_keypair_to_file(&public, &secret, path));
-        self.keys.insert(public, secret);
+        KeyfileYaml::load(path).map(|keyfile| keyfile.is_protected()).unwrap_or(false)
+    }
+
+    /// A short fingerprint of the public key that is easy to compare, e.g. `0A1B:2C3D:4E5F:6A7B`
+    pub fn fingerprint(public: &PublicKey) -> String {
+        let hex = format!("{:016X}", HashMethod::Blake2.hash(&public[..]).high);
+        let groups: Vec<&str> = (0..4).map(|i| &hex[i*4..(i+1)*4]).collect();
+        groups.join(":")
+    }
+
+    /// Deletes the key file and forgets the secret key
+    pub fn remove_secret_key(&mut self, public: &PublicKey) -> Result<(), EncryptionError> {
+        let path = self.path.join(to_hex(&public[..]) + ".yaml");
+        if path.exists() {
+            try!(fs::remove_file(path));
+        }
+        self.keys.borrow_mut().remove(public);
+        self.locked.borrow_mut().remove(public);
+        Ok(())
+    }
+
+    /// Stores the key file again, protected with the given passphrase or unprotected
+    pub fn change_passphrase(&mut self, public: &PublicKey, passphrase: Option<&str>) -> Result<(), EncryptionError> {
+        let secret = try!(self.get_secret_key(public));
+        self.register_secret_key(*public, secret, passphrase)
+    }
+
+    fn unlock(&self, keyfile: &KeyfileYaml) -> Result<SecretKey, EncryptionError> {
+        if let Some(ref passphrase) = *self.passphrase.borrow() {
+            if let Ok(secret) = keyfile.secret_key(Some(&passphrase[..])) {
+                return Ok(secret)
+            }
+        }
+        let passphrase = try!(read_passphrase(&format!("Passphrase for key {}: ", keyfile.public), PASSPHRASE_ENV));
+        let secret = try!(keyfile.secret_key(Some(&passphrase[..])));
+        *self.passphrase.borrow_mut() = Some(passphrase);
+        Ok(secret)
+    }
+
+    /// Protected key files are unlocked on first use
+    pub fn get_secret_key(&self, public: &PublicKey) -> Result<SecretKey, EncryptionError> {
+        if let Some(secret) = self.keys.borrow().get(public) {
+            return Ok(secret.clone())
+        }
+        let secret = match self.locked.borrow().get(public) {
+            Some(keyfile) => try!(self.unlock(keyfile)),
+            None => return Err(EncryptionError::MissingKey(*public))
+        };
+        self.locked.borrow_mut().remove(public);
+        self.keys.borrow_mut().insert(*public, secret.clone());
+        Ok(secret)
+    }
+
+    /// Loads the signing key file, afterwards signatures are checked by `verify`. A protected
+    /// secret key is unlocked when it is first used.
+    pub fn load_signing_key<P: AsRef<Path>>(&mut self, path: P) -> Result<(), EncryptionError> {
+        let yaml = try!(SigningKeyYaml::load(path));
+        let public = try!(yaml.public_key());
+        let secret = if yaml.is_protected() {
+            None
+        } else {
+            try!(yaml.secret_key(None))
+        };
+        self.signing_key = Some(public);
+        *self.signing_secret.borrow_mut() = secret;
+        self.locked_signing_key = if yaml.is_protected() { Some(yaml) } else { None };
+        Ok(())
+    }
+
+    /// Stores the signing key file, with `public_only` the secret key is left out. A protected
+    /// secret key stays protected.
+    pub fn save_signing_key<P: AsRef<Path>>(&self, path: P, public_only: bool) -> Result<(), EncryptionError> {
+        let public = try!(self.signing_key.ok_or(EncryptionError::MissingSigningKey));
+        if public_only {
+            return try!(SigningKeyYaml::new(&public, None, None)).save(path)
+        }
+        if let Some(ref yaml) = self.locked_signing_key {
+            return yaml.save(path)
+        }
+        try!(SigningKeyYaml::new(&public, self.signing_secret.borrow().as_ref(), None)).save(path)
+    }
+
+    /// Protects the secret signing key with the given passphrase or removes the protection,
+    /// nothing happens without secret signing key
+    pub fn change_signing_passphrase(&mut self, passphrase: Option<&str>) -> Result<(), EncryptionError> {
+        let public = match self.signing_key {
+            Some(public) => public,
+            None => return Ok(())
+        };
+        if !self.can_sign() {
+            return Ok(())
+        }
+        let secret = try!(self.signing_secret());
+        self.locked_signing_key = match passphrase {
+            Some(_) => Some(try!(SigningKeyYaml::new(&public, Some(&secret), passphrase))),
+            None => None
+        };
         Ok(())
     }
 
     #[inline]
-    pub fn contains_secret_key(&mut self, public: &PublicKey) -> bool {
-        self.keys.contains_key(public)
+    pub fn gen_signing_key(&mut self) -> SigningKey {
+        sodium_init();
+        let (public, secret) = sign::gen_keypair();
+        self.signing_key = Some(public);
+        *self.signing_secret.borrow_mut() = Some(secret);
+        self.locked_signing_key = None;
+        public
     }
 
-    fn get_secret_key(&self, public: &PublicKey) -> Result<&SecretKey, EncryptionError> {
-        self.keys.get(public).ok_or_else(|| EncryptionError::MissingKey(*public))
+    #[inline]
+    pub fn signing_key(&self) -> Option<SigningKey> {
+        self.signing_key
+    }
+
+    #[inline]
+    pub fn can_sign(&self) -> bool {
+        self.signing_secret.borrow().is_some() || self.locked_signing_key.is_some()
+    }
+
+    fn signing_secret(&self) -> Result<sign::SecretKey, EncryptionError> {
+        if let Some(ref secret) = *self.signing_secret.borrow() {
+            return Ok(secret.clone())
+        }
+        let yaml = try!(self.locked_signing_key.as_ref().ok_or(EncryptionError::MissingSigningKey));
+        let cached = self.passphrase.borrow().as_ref().and_then(|passphrase| yaml.secret_key(Some(&passphrase[..])).ok());
+        let secret = match cached {
+            Some(secret) => secret,
+            None => {
+                let passphrase = try!(read_passphrase("Passphrase for the signing key: ", PASSPHRASE_ENV));
+                let secret = try!(yaml.secret_key(Some(&passphrase[..])));
+                *self.passphrase.borrow_mut() = Some(passphrase);
+                secret
+            }
+        };
+        let secret = try!(secret.ok_or(EncryptionError::MissingSigningKey));
+        *self.signing_secret.borrow_mut() = Some(secret.clone());
+        Ok(secret)
+    }
+
+    pub fn sign(&self, data: &[u8]) -> Result<Vec<u8>, EncryptionError> {
+        let secret = try!(self.signing_secret());
+        Ok(sign::sign_detached(data, &secret).0.to_vec())
+    }
+
+    /// Checks the signature with the signing key, without a signing key nothing is checked
+    pub fn verify(&self, data: &[u8], signature: &[u8]) -> Result<(), EncryptionError> {
+        if let Some(ref public) = self.signing_key {
+            let signature = try!(sign::Signature::from_slice(signature).ok_or(EncryptionError::InvalidSignature));
+            if !sign::verify_detached(&signature, data, public) {
+                return Err(EncryptionError::InvalidSignature)
+            }
+        }
+        Ok(())
+    }
+
+    /// The key used by keyed hash methods, empty if it has not been loaded
+    #[inline]
+    pub fn hash_key(&self) -> &[u8] {
+        &self.hash_key
+    }
+
+    #[inline]
+    pub fn has_hash_key(&self) -> bool {
+        !self.hash_key.is_empty()
+    }
+
+    /// Decrypts the sealed hash key from the repository config and uses it for keyed hashing
+    pub fn load_hash_key(&mut self, enc: &Encryption, sealed: &[u8]) -> Result<(), EncryptionError> {
+        let key = try!(self.decrypt(enc, sealed));
+        if key.len() != HASH_KEY_SIZE {
+            return Err(EncryptionError::InvalidKey)
+        }
+        self.hash_key = key;
+        Ok(())
+    }
+
+    /// Creates a new random hash key and returns it sealed for the given encryption
+    pub fn gen_hash_key(&mut self, enc: &Encryption) -> Result<Vec<u8>, EncryptionError> {
+        let key = randombytes(HASH_KEY_SIZE);
+        let sealed = try!(self.encrypt(enc, &key));
+        self.hash_key = key;
+        Ok(sealed)
+    }
+
+    /// Stream encryption for the given public keys, any one of the secret keys can decrypt the data
+    pub fn encryption_for(keys: &[PublicKey]) -> Option<Encryption> {
+        if keys.is_empty() {
+            return None
+        }
+        let mut key_bytes = Vec::with_capacity(keys.len() * box_::PUBLICKEYBYTES);
+        for key in keys {
+            key_bytes.extend_from_slice(&key[..]);
+        }
+        Some((EncryptionMethod::Stream, key_bytes.into()))
+    }
+
+    /// The public keys of all recipients, any one of their secret keys can decrypt the data
+    pub fn recipients(enc: &Encryption) -> Result<Vec<PublicKey>, EncryptionError> {
+        let &(_, ref keys) = enc;
+        if keys.is_empty() || keys.len() % box_::PUBLICKEYBYTES != 0 {
+            return Err(EncryptionError::InvalidKey)
+        }
+        keys.chunks(box_::PUBLICKEYBYTES).map(|key| PublicKey::from_slice(key).ok_or(EncryptionError::InvalidKey)).collect()
+    }
+
+    fn new_data_key(&self, enc: &Encryption) -> Result<(secretbox::Key, Vec<u8>), EncryptionError> {
+        let key = secretbox::gen_key();
+        let recipients = try!(Self::recipients(enc));
+        let mut wrapped = Vec::with_capacity(recipients.len() * WRAPPED_KEY_SIZE);
+        for public in &recipients {
+            wrapped.extend_from_slice(&sealedbox::seal(&key[..], public));
+        }
+        Ok((key, wrapped))
+    }
+
+    /// Creates a new random data key and returns it sealed for every recipient, so it can be
+    /// stored in a file header. Encryption methods without data keys return `None`.
+    pub fn gen_data_key(&self, enc: &Encryption) -> Result<Option<ByteBuf>, EncryptionError> {
+        match enc.0 {
+            EncryptionMethod::Sodium => Ok(None),
+            EncryptionMethod::Stream => {
+                let (key, wrapped) = try!(self.new_data_key(enc));
+                self.data_keys.borrow_mut().insert(wrapped.clone(), key);
+                Ok(Some(wrapped.into()))
+            }
+        }
+    }
+
+    fn open_data_key(&self, enc: &Encryption, wrapped: &[u8]) -> Result<secretbox::Key, EncryptionError> {
+        let recipients = try!(Self::recipients(enc));
+        if wrapped.len() != recipients.len() * WRAPPED_KEY_SIZE {
+            return Err(EncryptionError::InvalidKey)
+        }
+        // Prefer secret keys that are already unlocked, so no passphrase is needed for them
+        let mut candidates: Vec<usize> = (0..recipients.len()).filter(|&i| self.contains_secret_key(&recipients[i])).collect();
+        candidates.sort_by_key(|&i| !self.keys.borrow().contains_key(&recipients[i]));
+        let i = match candidates.first() {
+            Some(&i) => i,
+            None => return Err(EncryptionError::MissingKey(recipients[0]))
+        };
+        let public = &recipients[i];
+        let secret = try!(self.get_secret_key(public));
+        let key = try!(sealedbox::open(&wrapped[i*WRAPPED_KEY_SIZE..(i+1)*WRAPPED_KEY_SIZE], public, &secret)
+            .map_err(|_| EncryptionError::Operation("Decryption failed")));
+        secretbox::Key::from_slice(&key).ok_or(EncryptionError::InvalidKey)
+    }
+
+    fn unwrap_data_key(&self, enc: &Encryption, wrapped: &[u8]) -> Result<secretbox::Key, EncryptionError> {
+        if let Some(key) = self.data_keys.borrow().get(wrapped) {
+            return Ok(key.clone())
+        }
+        let key = try!(self.open_data_key(enc, wrapped));
+        self.data_keys.borrow_mut().insert(wrapped.to_vec(), key.clone());
+        Ok(key)
     }
 
     #[inline]
     pub fn encrypt(&self, enc: &Encryption, data: &[u8]) -> Result<Vec<u8>, EncryptionError> {
-        let &(ref method, ref public) = enc;
-        let public = try!(PublicKey::from_slice(public).ok_or(EncryptionError::InvalidKey));
-        match *method {
+        self.encrypt_with(enc, None, data)
+    }
+
+    /// Encrypts the data using the wrapped data key from `gen_data_key`. Without a data key,
+    /// methods that need one create a new key and store it in front of the encrypted data.
+    pub fn encrypt_with(&self, enc: &Encryption, data_key: Option<&[u8]>, data: &[u8]) -> Result<Vec<u8>, EncryptionError> {
+        match enc.0 {
             EncryptionMethod::Sodium => {
+                let public = try!(PublicKey::from_slice(&enc.1).ok_or(EncryptionError::InvalidKey));
                 Ok(sealedbox::seal(data, &public))
+            },
+            EncryptionMethod::Stream => {
+                let (key, mut out) = match data_key {
+                    Some(wrapped) => (try!(self.unwrap_data_key(enc, wrapped)), vec![]),
+                    None => try!(self.new_data_key(enc))
+                };
+                let mut stream = CipherStream::encrypt(key);
+                try!(stream.process(data, &mut out));
+                try!(stream.finish(&mut out));
+                Ok(out)
             }
         }
     }
 
+    /// Stream to decrypt data of the stream method incrementally using the wrapped data key
+    /// from the file header, other methods return `None`
+    pub fn decrypt_stream(&self, enc: &Encryption, data_key: Option<&[u8]>) -> Result<Option<CipherStream>, EncryptionError> {
+        match (&enc.0, data_key) {
+            (&EncryptionMethod::Stream, Some(wrapped)) => Ok(Some(CipherStream::decrypt(try!(self.unwrap_data_key(enc, wrapped))))),
+            _ => Ok(None)
+        }
+    }
+
     #[inline]
     pub fn decrypt(&self, enc: &Encryption, data: &[u8]) -> Result<Vec<u8>, EncryptionError> {
-        let &(ref method, ref public) = enc;
-        let public = try!(PublicKey::from_slice(public).ok_or(EncryptionError::InvalidKey));
-        let secret = try!(self.get_secret_key(&public));
-        match *method {
+        self.decrypt_with(enc, None, data)
+    }
+
+    /// Decrypts data that has been encrypted by `encrypt_with` with the same data key
+    pub fn decrypt_with(&self, enc: &Encryption, data_key: Option<&[u8]>, data: &[u8]) -> Result<Vec<u8>, EncryptionError> {
+        match enc.0 {
             EncryptionMethod::Sodium => {
-                sealedbox::open(data, &public, secret).map_err(|_| EncryptionError::Operation("Decryption failed"))
+                let public = try!(PublicKey::from_slice(&enc.1).ok_or(EncryptionError::InvalidKey));
+                let secret = try!(self.get_secret_key(&public));
+                sealedbox::open(data, &public, &secret).map_err(|_| EncryptionError::Operation("Decryption failed"))
+            },
+            EncryptionMethod::Stream => {
+                let (key, data) = match data_key {
+                    Some(wrapped) => (try!(self.unwrap_data_key(enc, wrapped)), data),
+                    None => {
+                        let size = try!(Self::recipients(enc)).len() * WRAPPED_KEY_SIZE;
+                        if data.len() < size {
+                            return Err(EncryptionError::Operation("Decryption failed"))
+                        }
+                        (try!(self.open_data_key(enc, &data[..size])), &data[size..])
+                    }
+                };
+                let mut stream = CipherStream::decrypt(key);
+                let mut out = Vec::with_capacity(data.len());
+                try!(stream.process(data, &mut out));
+                try!(stream.finish(&mut out));
+                Ok(out)
             }
         }
     }
@@ -214,6 +962,8 @@ impl Crypto {
         box_::gen_keypair()
     }
 
+    /// Derives a key pair with the old scheme that uses a fixed salt, new key pairs should be
+    /// derived with `KeyDerivation::derive_keypair`
     pub fn keypair_from_password(password: &str) -> (PublicKey, SecretKey) {
         let salt = pwhash::Salt::from_slice(b"the_great_zvault_password_salt_1").unwrap();
         let mut key = [0u8; pwhash::HASHEDPASSWORDBYTES];
@@ -223,11 +973,339 @@ impl Crypto {
         for (i, b) in seed.iter_mut().enumerate() {
             *b = key[i+offset];
         }
+        Self::keypair_from_seed(&seed)
+    }
+
+    fn keypair_from_seed(seed: &[u8; 32]) -> (PublicKey, SecretKey) {
         let mut pk = [0u8; 32];
         let mut sk = [0u8; 32];
-        if unsafe { libsodium_sys::crypto_box_seed_keypair(&mut pk, &mut sk, &seed) } != 0 {
+        if unsafe { libsodium_sys::crypto_box_seed_keypair(&mut pk, &mut sk, seed) } != 0 {
             panic!("Libsodium failed");
         }
         (PublicKey::from_slice(&pk).unwrap(), SecretKey::from_slice(&sk).unwrap())
     }
+
+    fn key_share_checksum(text: &str) -> String {
+        format!("{:08X}", HashMethod::Blake2.hash(text.as_bytes()).high >> 32)
+    }
+
+    /// Splits the secret key into shares so that any `threshold` of them can restore the key pair.
+    /// The shares are encoded as lines of upper case letters, digits and colons, so they can be
+    /// printed or stored in QR codes.
+    pub fn split_keypair(public: &PublicKey, secret: &SecretKey, threshold: u8, count: u8) -> Result<Vec<String>, EncryptionError> {
+        let shares = try!(Share::split(&secret[..], threshold, count));
+        Ok(shares.into_iter().map(|share| {
+            let text = format!("{}:{}:{}:{}:{}", KEY_SHARE_PREFIX, share.threshold, share.index,
+                to_hex(&public[..]).to_uppercase(), to_hex(&share.data).to_uppercase());
+            let checksum = Self::key_share_checksum(&text);
+            format!("{}:{}", text, checksum)
+        }).collect())
+    }
+
+    fn parse_key_share(line: &str) -> Result<(PublicKey, Share), EncryptionError> {
+        let line = line.trim().to_uppercase();
+        let pos = try!(line.rfind(':').ok_or(EncryptionError::InvalidShare("invalid format")));
+        if Self::key_share_checksum(&line[..pos]) != line[pos+1..] {
+            return Err(EncryptionError::InvalidShare("wrong checksum, please check for typos"))
+        }
+        let parts: Vec<&str> = line[..pos].split(':').collect();
+        if parts.len() != 5 || parts[0] != KEY_SHARE_PREFIX {
+            return Err(EncryptionError::InvalidShare("invalid format"))
+        }
+        let threshold = try!(u8::from_str(parts[1]).map_err(|_| EncryptionError::InvalidShare("invalid threshold")));
+        let index = try!(u8::from_str(parts[2]).map_err(|_| EncryptionError::InvalidShare("invalid index")));
+        let public = try!(parse_hex(parts[3]).map_err(|_| EncryptionError::InvalidShare("invalid public key")));
+        let public = try!(PublicKey::from_slice(&public).ok_or(EncryptionError::InvalidShare("invalid public key")));
+        let data = try!(parse_hex(parts[4]).map_err(|_| EncryptionError::InvalidShare("invalid share data")));
+        Ok((public, Share { threshold: threshold, index: index, data: data }))
+    }
+
+    #[inline]
+    pub fn is_key_share(line: &str) -> bool {
+        line.trim().to_uppercase().starts_with(KEY_SHARE_PREFIX)
+    }
+
+    /// Restores a key pair from the lines of enough shares, lines that are not shares are ignored
+    pub fn keypair_from_shares<S: AsRef<str>>(lines: &[S]) -> Result<(PublicKey, SecretKey), EncryptionError> {
+        let mut public = None;
+        let mut shares = vec![];
+        for line in lines.iter().map(|l| l.as_ref()).filter(|l| Self::is_key_share(l)) {
+            let (key, share) = try!(Self::parse_key_share(line));
+            if public.is_some() && public != Some(key) {
+                return Err(EncryptionError::InvalidShare("shares of different keys"))
+            }
+            public = Some(key);
+            shares.push(share);
+        }
+        let public = try!(public.ok_or(EncryptionError::InvalidShare("no shares found")));
+        let secret = try!(Share::combine(&shares));
+        let secret = try!(SecretKey::from_slice(&secret).ok_or(EncryptionError::InvalidKey));
+        // The public key is stored in every share, so the restored secret key can be verified
+        if !Self::keypair_matches(&public, &secret) {
+            return Err(EncryptionError::InvalidShare("the restored key does not match the public key"))
+        }
+        Ok((public, secret))
+    }
+
+    /// Whether the secret key belongs to the public key
+    pub fn keypair_matches(public: &PublicKey, secret: &SecretKey) -> bool {
+        curve25519::scalarmult_base(&curve25519::Scalar(secret.0)).0 == public.0
+    }
+
+    pub fn load_keypair_from_shares_file<P: AsRef<Path>>(path: P) -> Result<(PublicKey, SecretKey), EncryptionError> {
+        let file = BufReader::new(try!(File::open(path)));
+        let mut lines = vec![];
+        for line in file.lines() {
+            lines.push(try!(line));
+        }
+        Self::keypair_from_shares(&lines)
+    }
+
+    /// Checks whether the file contains key shares instead of a key pair
+    pub fn is_shares_file<P: AsRef<Path>>(path: P) -> bool {
+        match File::open(path) {
+            Ok(file) => BufReader::new(file).lines().filter_map(|l| l.ok()).any(|l| Self::is_key_share(&l)),
+            Err(_) => false
+        }
+    }
+}
+
+
+// Cost levels for password derivation (scrypt), interactive and sensitive are the libsodium
+// presets, moderate is in between
+const KDF_COSTS: [(&'static str, usize, usize); 3] = [
+    ("interactive", 524_288, 16 * 1024 * 1024),
+    ("moderate", 4_194_304, 128 * 1024 * 1024),
+    ("sensitive", 33_554_432, 1024 * 1024 * 1024)
+];
+pub const DEFAULT_KDF_COST: &'static str = "moderate";
+
+
+/// Parameters to derive key pairs from passwords, those are not secret and stored in the
+/// repository config so that the same password yields the same key pair again
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct KeyDerivation {
+    pub salt: ByteBuf,
+    pub ops_limit: usize,
+    pub mem_limit: usize
+}
+serde_impl!(KeyDerivation(u8) {
+    salt: ByteBuf => 0,
+    ops_limit: usize => 1,
+    mem_limit: usize => 2
+});
+
+impl KeyDerivation {
+    /// Creates new parameters with a random salt
+    pub fn new(cost: &str) -> Result<Self, &'static str> {
+        sodium_init();
+        let (ops_limit, mem_limit) = try!(Self::parse_cost(cost));
+        Ok(KeyDerivation {
+            salt: pwhash::gen_salt()[..].to_vec().into(),
+            ops_limit: ops_limit,
+            mem_limit: mem_limit
+        })
+    }
+
+    /// Parses a named cost level or `OPS/MEM` with the memory limit in MiB
+    pub fn parse_cost(cost: &str) -> Result<(usize, usize), &'static str> {
+        if let Some(&(_, ops, mem)) = KDF_COSTS.iter().find(|c| c.0 == cost) {
+            return Ok((ops, mem))
+        }
+        let pos = try!(cost.find('/').ok_or("Invalid key derivation cost"));
+        let ops = try!(cost[..pos].parse::<usize>().map_err(|_| "Invalid operations limit"));
+        let mem = try!(cost[pos+1..].parse::<usize>().map_err(|_| "Invalid memory limit"));
+        let mem = try!(mem.checked_mul(1024 * 1024).ok_or("Memory limit is too large"));
+        if ops < KDF_COSTS[0].1 || mem < KDF_COSTS[0].2 {
+            return Err("Key derivation cost is lower than interactive")
+        }
+        Ok((ops, mem))
+    }
+
+    pub fn cost_string(&self) -> String {
+        if let Some(&(name, _, _)) = KDF_COSTS.iter().find(|c| c.1 == self.ops_limit && c.2 == self.mem_limit) {
+            return name.to_string()
+        }
+        format!("{}/{}", self.ops_limit, self.mem_limit / (1024 * 1024))
+    }
+
+    pub fn set_cost(&mut self, cost: &str) -> Result<(), &'static str> {
+        let (ops_limit, mem_limit) = try!(Self::parse_cost(cost));
+        self.ops_limit = ops_limit;
+        self.mem_limit = mem_limit;
+        Ok(())
+    }
+
+    pub fn derive_keypair(&self, password: &str) -> Result<(PublicKey, SecretKey), EncryptionError> {
+        sodium_init();
+        let salt = try!(pwhash::Salt::from_slice(&self.salt).ok_or(EncryptionError::Operation("Invalid salt")));
+        let mut seed = [0u8; 32];
+        try!(pwhash::derive_key(&mut seed, password.as_bytes(), &salt, pwhash::OpsLimit(self.ops_limit), pwhash::MemLimit(self.mem_limit))
+            .map_err(|_| EncryptionError::Operation("Key derivation failed")));
+        Ok(Crypto::keypair_from_seed(&seed))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::cmp::min;
+
+    fn stream_key() -> secretbox::Key {
+        sodium_init();
+        secretbox::gen_key()
+    }
+
+    fn test_data(size: usize) -> Vec<u8> {
+        (0..size).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    fn encrypt(key: &secretbox::Key, data: &[u8]) -> Vec<u8> {
+        let mut out = vec![];
+        let mut stream = CipherStream::encrypt(key.clone());
+        stream.process(data, &mut out).unwrap();
+        stream.finish(&mut out).unwrap();
+        out
+    }
+
+    fn decrypt(key: &secretbox::Key, data: &[u8]) -> Result<Vec<u8>, EncryptionError> {
+        let mut out = vec![];
+        let mut stream = CipherStream::decrypt(key.clone());
+        try!(stream.process(data, &mut out));
+        try!(stream.finish(&mut out));
+        Ok(out)
+    }
+
+    // The start of the encrypted block with the given number
+    fn block_start(block: usize) -> usize {
+        STREAM_PREFIX_SIZE + block * STREAM_ENCRYPTED_BLOCK_SIZE
+    }
+
+    #[test]
+    fn test_stream_roundtrip() {
+        let key = stream_key();
+        for &size in &[0, 1, STREAM_BLOCK_SIZE - 1, STREAM_BLOCK_SIZE, STREAM_BLOCK_SIZE + 1, 3 * STREAM_BLOCK_SIZE] {
+            let data = test_data(size);
+            let encrypted = encrypt(&key, &data);
+            assert_eq!(encrypted.len(), CipherStream::encrypted_size(size));
+            assert_eq!(decrypt(&key, &encrypted).unwrap(), data);
+            // Feeding the data in small pieces gives the same result
+            let mut out = vec![];
+            let mut stream = CipherStream::decrypt(key.clone());
+            for part in encrypted.chunks(1000) {
+                stream.process(part, &mut out).unwrap();
+            }
+            stream.finish(&mut out).unwrap();
+            assert_eq!(out, data);
+        }
+    }
+
+    #[test]
+    fn test_stream_truncated() {
+        let key = stream_key();
+        let encrypted = encrypt(&key, &test_data(3 * STREAM_BLOCK_SIZE + 100));
+        // Missing last block, the block in front of it is not marked as last
+        assert!(decrypt(&key, &encrypted[..block_start(3)]).is_err());
+        assert!(decrypt(&key, &encrypted[..block_start(1)]).is_err());
+        // Cut inside of a block
+        assert!(decrypt(&key, &encrypted[..encrypted.len() - 1]).is_err());
+        assert!(decrypt(&key, &encrypted[..block_start(2) + 10]).is_err());
+        // Only the prefix or not even that
+        assert!(decrypt(&key, &encrypted[..STREAM_PREFIX_SIZE]).is_err());
+        assert!(decrypt(&key, &encrypted[..STREAM_PREFIX_SIZE - 1]).is_err());
+        assert!(decrypt(&key, &[]).is_err());
+    }
+
+    #[test]
+    fn test_stream_reordered() {
+        let key = stream_key();
+        let encrypted = encrypt(&key, &test_data(3 * STREAM_BLOCK_SIZE + 100));
+        let mut reordered = encrypted[..STREAM_PREFIX_SIZE].to_vec();
+        reordered.extend_from_slice(&encrypted[block_start(1)..block_start(2)]);
+        reordered.extend_from_slice(&encrypted[block_start(0)..block_start(1)]);
+        reordered.extend_from_slice(&encrypted[block_start(2)..]);
+        assert_eq!(reordered.len(), encrypted.len());
+        assert!(decrypt(&key, &reordered).is_err());
+        // Dropping a block in the middle shifts all following block numbers
+        let mut dropped = encrypted[..block_start(1)].to_vec();
+        dropped.extend_from_slice(&encrypted[block_start(2)..]);
+        assert!(decrypt(&key, &dropped).is_err());
+        // A block from another stream with the same key has a different nonce prefix
+        let other = encrypt(&key, &test_data(3 * STREAM_BLOCK_SIZE + 100));
+        let mut mixed = encrypted[..block_start(1)].to_vec();
+        mixed.extend_from_slice(&other[block_start(1)..]);
+        assert!(decrypt(&key, &mixed).is_err());
+    }
+
+    #[test]
+    fn test_stream_final_block() {
+        let key = stream_key();
+        // With a multiple of the block size, the last block is a full block
+        let data = test_data(2 * STREAM_BLOCK_SIZE);
+        let encrypted = encrypt(&key, &data);
+        assert_eq!(encrypted.len(), block_start(2));
+        assert_eq!(decrypt(&key, &encrypted).unwrap(), data);
+        assert!(decrypt(&key, &encrypted[..block_start(1)]).is_err());
+        // Nothing may follow the last block
+        let mut extended = encrypted.clone();
+        extended.extend_from_slice(&encrypted[block_start(1)..]);
+        assert!(decrypt(&key, &extended).is_err());
+        // Empty data still has a marked last block
+        let encrypted = encrypt(&key, &[]);
+        assert_eq!(encrypted.len(), STREAM_PREFIX_SIZE + secretbox::MACBYTES);
+        assert_eq!(decrypt(&key, &encrypted).unwrap(), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn test_stream_decrypt_blocks() {
+        let key = stream_key();
+        let data = test_data(3 * STREAM_BLOCK_SIZE + 100);
+        let encrypted = encrypt(&key, &data);
+        let prefix = &encrypted[..STREAM_PREFIX_SIZE];
+        let blocks = CipherStream::block_count(data.len());
+        assert_eq!(blocks, 4);
+        for block in 0..blocks {
+            let last = block == blocks - 1;
+            let end = if last { encrypted.len() } else { block_start(block + 1) };
+            let plain = CipherStream::decrypt(key.clone()).decrypt_blocks(prefix, block, &encrypted[block_start(block)..end], last).unwrap();
+            assert_eq!(&plain[..], &data[block * STREAM_BLOCK_SIZE..min(data.len(), (block + 1) * STREAM_BLOCK_SIZE)]);
+            // The block number and the last block flag are authenticated
+            assert!(CipherStream::decrypt(key.clone()).decrypt_blocks(prefix, block, &encrypted[block_start(block)..end], !last).is_err());
+            assert!(CipherStream::decrypt(key.clone()).decrypt_blocks(prefix, block + 1, &encrypted[block_start(block)..end], last).is_err());
+        }
+        // A range of blocks, truncated ranges are rejected
+        let plain = CipherStream::decrypt(key.clone()).decrypt_blocks(prefix, 1, &encrypted[block_start(1)..], true).unwrap();
+        assert_eq!(&plain[..], &data[STREAM_BLOCK_SIZE..]);
+        assert!(CipherStream::decrypt(key.clone()).decrypt_blocks(prefix, 1, &encrypted[block_start(1)..block_start(3) - 1], false).is_err());
+    }
+
+    #[test]
+    fn test_parse_cost_overflow() {
+        let cost = format!("{}/{}", KDF_COSTS[0].1, usize::max_value() / 1024);
+        assert!(KeyDerivation::parse_cost(&cost).is_err());
+        let cost = format!("{}/{}", KDF_COSTS[0].1, KDF_COSTS[0].2 / (1024 * 1024));
+        assert_eq!(KeyDerivation::parse_cost(&cost), Ok((KDF_COSTS[0].1, KDF_COSTS[0].2)));
+    }
+
+    #[test]
+    fn test_signing_key_passphrase() {
+        sodium_init();
+        let (public, secret) = sign::gen_keypair();
+        let yaml = SigningKeyYaml::new(&public, Some(&secret), Some("passphrase")).unwrap();
+        assert!(yaml.is_protected());
+        assert!(yaml.secret.as_ref() != Some(&to_hex(&secret[..])));
+        assert_eq!(yaml.public_key().unwrap(), public);
+        assert_eq!(yaml.secret_key(Some("passphrase")).unwrap(), Some(secret.clone()));
+        assert!(yaml.secret_key(Some("wrong")).is_err());
+        assert!(yaml.secret_key(None).is_err());
+        // Public keys and unprotected secret keys are stored as hex
+        let yaml = SigningKeyYaml::new(&public, None, Some("passphrase")).unwrap();
+        assert!(!yaml.is_protected());
+        assert_eq!(yaml.secret_key(None).unwrap(), None);
+        let yaml = SigningKeyYaml::new(&public, Some(&secret), None).unwrap();
+        assert!(!yaml.is_protected());
+        assert_eq!(yaml.secret_key(None).unwrap(), Some(secret));
+    }
 }
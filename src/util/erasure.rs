@@ -0,0 +1,194 @@
+use std::str::FromStr;
+
+use super::galois::GF;
+
+// Cauchy Reed-Solomon erasure code over GF(2^8)
+// Paper: "An XOR-Based Erasure-Resilient Coding Scheme"
+// Paper-URL: http://www.icsi.berkeley.edu/~luby/PAPERS/cauchypap.pdf
+
+
+quick_error!{
+    #[derive(Debug)]
+    pub enum ErasureError {
+        TooManyErasures(missing: usize, available: usize) {
+            description("Too many erasures")
+            display("Erasure error: {} shards are missing but only {} parity shards are available", missing, available)
+        }
+        InvalidRedundancy(reason: &'static str) {
+            description("Invalid redundancy")
+            display("Erasure error: invalid redundancy: {}", reason)
+        }
+    }
+}
+
+
+// Data and parity shards get distinct field elements so that every square submatrix of the
+// coding matrix is invertible
+const MAX_SHARDS: usize = 128;
+
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Redundancy {
+    pub bundles: usize,
+    pub parity: usize
+}
+serde_impl!(Redundancy(u8) {
+    bundles: usize => 0,
+    parity: usize => 1
+});
+
+impl Redundancy {
+    pub fn new(bundles: usize, parity: usize) -> Result<Self, ErasureError> {
+        if bundles == 0 || bundles > MAX_SHARDS {
+            return Err(ErasureError::InvalidRedundancy("group size must be between 1 and 128"))
+        }
+        if parity == 0 || parity > MAX_SHARDS {
+            return Err(ErasureError::InvalidRedundancy("parity count must be between 1 and 128"))
+        }
+        Ok(Redundancy { bundles: bundles, parity: parity })
+    }
+
+    pub fn from_string(val: &str) -> Result<Self, ErasureError> {
+        let pos = try!(val.find('/').ok_or(ErasureError::InvalidRedundancy("format must be bundles/parity")));
+        let bundles = try!(usize::from_str(&val[..pos]).map_err(|_| ErasureError::InvalidRedundancy("group size must be a number")));
+        let parity = try!(usize::from_str(&val[pos+1..]).map_err(|_| ErasureError::InvalidRedundancy("parity count must be a number")));
+        Self::new(bundles, parity)
+    }
+
+    #[inline]
+    pub fn to_string(&self) -> String {
+        format!("{}/{}", self.bundles, self.parity)
+    }
+}
+
+
+pub struct ErasureCode {
+    parity: usize
+}
+
+impl ErasureCode {
+    #[inline]
+    pub fn new(parity: usize) -> Self {
+        debug_assert!(parity <= MAX_SHARDS);
+        ErasureCode { parity: parity }
+    }
+
+    #[inline]
+    pub fn parity(&self) -> usize {
+        self.parity
+    }
+
+    #[inline]
+    fn coefficient(parity: usize, data: usize) -> u8 {
+        debug_assert!(parity < MAX_SHARDS && data < MAX_SHARDS);
+        GF.inv((parity ^ (MAX_SHARDS + data)) as u8)
+    }
+
+    /// Adds the contribution of the given data shard to all parity shards.
+    /// As addition is XOR, calling this a second time with the same data removes it again.
+    pub fn update(&self, index: usize, data: &[u8], parity: &mut [Vec<u8>]) {
+        debug_assert_eq!(parity.len(), self.parity);
+        for (j, shard) in parity.iter_mut().enumerate() {
+            if shard.len() < data.len() {
+                shard.resize(data.len(), 0);
+            }
+            let table = GF.mul_table(Self::coefficient(j, index));
+            for (p, d) in shard.iter_mut().zip(data) {
+                *p ^= table[*d as usize];
+            }
+        }
+    }
+
+    pub fn encode(&self, data: &[&[u8]]) -> Vec<Vec<u8>> {
+        let mut parity = vec![vec![]; self.parity];
+        for (i, shard) in data.iter().enumerate() {
+            self.update(i, shard, &mut parity);
+        }
+        let len = parity.iter().map(|p| p.len()).max().unwrap_or(0);
+        for shard in &mut parity {
+            shard.resize(len, 0);
+        }
+        parity
+    }
+
+    /// Reconstructs all missing data shards from the available data and parity shards.
+    /// The reconstructed shards are padded to the length of the parity shards.
+    pub fn reconstruct(&self, data: &mut [Option<Vec<u8>>], parity: &[Option<Vec<u8>>]) -> Result<(), ErasureError> {
+        let missing: Vec<usize> = (0..data.len()).filter(|i| data[*i].is_none()).collect();
+        if missing.is_empty() {
+            return Ok(())
+        }
+        let rows: Vec<usize> = (0..parity.len()).filter(|j| parity[*j].is_some()).take(missing.len()).collect();
+        if rows.len() < missing.len() {
+            return Err(ErasureError::TooManyErasures(missing.len(), rows.len()))
+        }
+        let len = parity.iter().filter_map(|p| p.as_ref()).map(|p| p.len()).max().unwrap_or(0);
+        // Remove the contribution of all known data shards from the parity shards
+        let mut syndromes = Vec::with_capacity(rows.len());
+        for &j in &rows {
+            let mut syndrome = parity[j].as_ref().unwrap().clone();
+            syndrome.resize(len, 0);
+            for (i, shard) in data.iter().enumerate() {
+                if let Some(ref shard) = *shard {
+                    let table = GF.mul_table(Self::coefficient(j, i));
+                    for (s, d) in syndrome.iter_mut().zip(shard) {
+                        *s ^= table[*d as usize];
+                    }
+                }
+            }
+            syndromes.push(syndrome);
+        }
+        // Solve the remaining linear system for the missing shards
+        let n = missing.len();
+        let mut matrix = vec![vec![0u8; n]; n];
+        for (r, &j) in rows.iter().enumerate() {
+            for (c, &i) in missing.iter().enumerate() {
+                matrix[r][c] = Self::coefficient(j, i);
+            }
+        }
+        let inverse = invert(matrix);
+        for (c, &i) in missing.iter().enumerate() {
+            let mut shard = vec![0u8; len];
+            for (r, syndrome) in syndromes.iter().enumerate() {
+                let table = GF.mul_table(inverse[c][r]);
+                for (s, d) in shard.iter_mut().zip(syndrome) {
+                    *s ^= table[*d as usize];
+                }
+            }
+            data[i] = Some(shard);
+        }
+        Ok(())
+    }
+}
+
+
+/// Gauss-Jordan elimination, the matrix must be invertible (which is true for Cauchy matrices)
+fn invert(mut matrix: Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+    let n = matrix.len();
+    let mut inverse = vec![vec![0u8; n]; n];
+    for (i, row) in inverse.iter_mut().enumerate() {
+        row[i] = 1;
+    }
+    for col in 0..n {
+        let pivot = (col..n).find(|r| matrix[*r][col] != 0).expect("Singular matrix");
+        matrix.swap(col, pivot);
+        inverse.swap(col, pivot);
+        let factor = GF.inv(matrix[col][col]);
+        for c in 0..n {
+            matrix[col][c] = GF.mul(matrix[col][c], factor);
+            inverse[col][c] = GF.mul(inverse[col][c], factor);
+        }
+        for r in 0..n {
+            if r == col || matrix[r][col] == 0 {
+                continue
+            }
+            let factor = matrix[r][col];
+            for c in 0..n {
+                let (m, i) = (GF.mul(matrix[col][c], factor), GF.mul(inverse[col][c], factor));
+                matrix[r][c] ^= m;
+                inverse[r][c] ^= i;
+            }
+        }
+    }
+    inverse
+}
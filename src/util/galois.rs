@@ -0,0 +1,127 @@
+// Arithmetic in GF(2^8) with the polynomial 0x11d
+
+pub struct Galois {
+    exp: [u8; 512],
+    log: [u8; 256]
+}
+
+impl Galois {
+    fn new() -> Self {
+        let mut exp = [0u8; 512];
+        let mut log = [0u8; 256];
+        let mut x = 1usize;
+        for i in 0..255 {
+            exp[i] = x as u8;
+            log[x] = i as u8;
+            x <<= 1;
+            if x & 0x100 != 0 {
+                x ^= 0x11d;
+            }
+        }
+        for i in 255..512 {
+            exp[i] = exp[i-255];
+        }
+        Galois { exp: exp, log: log }
+    }
+
+    #[inline]
+    pub fn mul(&self, a: u8, b: u8) -> u8 {
+        if a == 0 || b == 0 {
+            return 0
+        }
+        self.exp[self.log[a as usize] as usize + self.log[b as usize] as usize]
+    }
+
+    #[inline]
+    pub fn inv(&self, a: u8) -> u8 {
+        debug_assert!(a != 0);
+        self.exp[255 - self.log[a as usize] as usize]
+    }
+
+    #[inline]
+    pub fn div(&self, a: u8, b: u8) -> u8 {
+        self.mul(a, self.inv(b))
+    }
+
+    pub fn mul_table(&self, c: u8) -> [u8; 256] {
+        let mut table = [0u8; 256];
+        for (i, t) in table.iter_mut().enumerate() {
+            *t = self.mul(c, i as u8);
+        }
+        table
+    }
+}
+
+lazy_static! {
+    pub static ref GF: Galois = Galois::new();
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Carry-less multiplication with reduction by the polynomial, bit by bit
+    fn slow_mul(mut a: u8, mut b: u8) -> u8 {
+        let mut result = 0u8;
+        while b != 0 {
+            if b & 1 != 0 {
+                result ^= a;
+            }
+            b >>= 1;
+            let carry = a & 0x80 != 0;
+            a <<= 1;
+            if carry {
+                a ^= 0x1d;
+            }
+        }
+        result
+    }
+
+    #[test]
+    fn test_known_answers() {
+        assert_eq!(GF.mul(0x80, 0x02), 0x1d);
+        assert_eq!(GF.mul(0x03, 0x07), 0x09);
+        assert_eq!(GF.mul(0x53, 0xca), 0x8f);
+        assert_eq!(GF.mul(0x57, 0x83), 0x31);
+        assert_eq!(GF.mul(0xff, 0xff), 0xe2);
+        assert_eq!(GF.inv(0x02), 0x8e);
+        assert_eq!(GF.inv(0x1d), 0x83);
+        assert_eq!(GF.inv(0x53), 0x8c);
+        assert_eq!(GF.div(0x1d, 0x03), 0x0b);
+        assert_eq!(GF.div(0x1d, 0x02), 0x80);
+    }
+
+    #[test]
+    fn test_mul() {
+        for a in 0..256usize {
+            for b in 0..256usize {
+                assert_eq!(GF.mul(a as u8, b as u8), slow_mul(a as u8, b as u8));
+            }
+        }
+    }
+
+    #[test]
+    fn test_inv_div() {
+        assert_eq!(GF.inv(1), 1);
+        for a in 1..256usize {
+            let a = a as u8;
+            assert_eq!(GF.mul(a, GF.inv(a)), 1);
+            for b in 1..256usize {
+                let b = b as u8;
+                assert_eq!(GF.div(GF.mul(a, b), b), a);
+            }
+        }
+        assert_eq!(GF.div(0, 0x53), 0);
+    }
+
+    #[test]
+    fn test_mul_table() {
+        for c in 0..256usize {
+            let table = GF.mul_table(c as u8);
+            for (i, &t) in table.iter().enumerate() {
+                assert_eq!(t, GF.mul(c as u8, i as u8));
+            }
+        }
+    }
+}
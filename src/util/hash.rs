@@ -4,19 +4,28 @@ use serde_bytes::{ByteBuf, Bytes};
 
 use murmurhash3::murmurhash3_x64_128;
 use blake2::blake2b::blake2b;
-use byteorder::{LittleEndian, ByteOrder, WriteBytesExt, ReadBytesExt};
+use sha2::{Sha256, Digest};
+use blake3;
+use byteorder::{LittleEndian, BigEndian, ByteOrder, WriteBytesExt, ReadBytesExt};
 
-use std::mem;
 use std::fmt;
 use std::u64;
 use std::io::{self, Read, Write};
 
 
-#[repr(packed)]
+pub const SHORT_HASH_SIZE: usize = 16;
+pub const LONG_HASH_SIZE: usize = 32;
+
+
+/// Hashes are either 128 or 256 bits long, the length is stored explicitly. For 128 bit hashes
+/// the extension fields are zero.
 #[derive(Clone, Copy, PartialEq, Hash, Eq, Default, Ord, PartialOrd)]
 pub struct Hash {
     pub high: u64,
-    pub low: u64
+    pub low: u64,
+    pub ext_high: u64,
+    pub ext_low: u64,
+    long: bool
 }
 
 impl Hash {
@@ -27,55 +36,115 @@ impl Hash {
 
     #[inline]
     pub fn empty() -> Self {
-        Hash{high: 0, low: 0}
+        Hash::new(0, 0)
+    }
+
+    #[inline]
+    pub fn new(high: u64, low: u64) -> Self {
+        Hash{high: high, low: low, ext_high: 0, ext_low: 0, long: false}
+    }
+
+    #[inline]
+    pub fn new_long(high: u64, low: u64, ext_high: u64, ext_low: u64) -> Self {
+        Hash{high: high, low: low, ext_high: ext_high, ext_low: ext_low, long: true}
+    }
+
+    /// Interprets the digest bytes as big-endian numbers, the digest must be 16 or 32 bytes long
+    #[inline]
+    pub fn from_digest(digest: &[u8]) -> Self {
+        let (high, low) = (BigEndian::read_u64(&digest[..8]), BigEndian::read_u64(&digest[8..16]));
+        if digest.len() == LONG_HASH_SIZE {
+            Hash::new_long(high, low, BigEndian::read_u64(&digest[16..24]), BigEndian::read_u64(&digest[24..]))
+        } else {
+            Hash::new(high, low)
+        }
+    }
+
+    #[inline]
+    pub fn is_long(&self) -> bool {
+        self.long
+    }
+
+    #[inline]
+    pub fn size(&self) -> usize {
+        if self.long {
+            LONG_HASH_SIZE
+        } else {
+            SHORT_HASH_SIZE
+        }
     }
 
     #[inline]
     pub fn to_string(&self) -> String {
-        format!("{:016x}{:016x}", self.high, self.low)
+        format!("{}", self)
     }
 
+    /// Writes the hash with the given size, long hashes can not be written as short ones
     #[inline]
-    pub fn write_to(&self, dst: &mut Write) -> Result<(), io::Error> {
+    pub fn write_to(&self, dst: &mut Write, size: usize) -> Result<(), io::Error> {
+        debug_assert!(size >= self.size());
         try!(dst.write_u64::<LittleEndian>(self.high));
-        dst.write_u64::<LittleEndian>(self.low)
+        try!(dst.write_u64::<LittleEndian>(self.low));
+        if size == LONG_HASH_SIZE {
+            try!(dst.write_u64::<LittleEndian>(self.ext_high));
+            try!(dst.write_u64::<LittleEndian>(self.ext_low));
+        }
+        Ok(())
     }
 
     #[inline]
-    pub fn read_from(src: &mut Read) -> Result<Self, io::Error> {
+    pub fn read_from(src: &mut Read, size: usize) -> Result<Self, io::Error> {
         let high = try!(src.read_u64::<LittleEndian>());
         let low = try!(src.read_u64::<LittleEndian>());
-        Ok(Hash { high: high, low: low })
+        if size == LONG_HASH_SIZE {
+            let ext_high = try!(src.read_u64::<LittleEndian>());
+            let ext_low = try!(src.read_u64::<LittleEndian>());
+            Ok(Hash::new_long(high, low, ext_high, ext_low))
+        } else {
+            Ok(Hash::new(high, low))
+        }
     }
 
     #[inline]
     pub fn from_string(val: &str) -> Result<Self, ()> {
-        let high = try!(u64::from_str_radix(&val[..16], 16).map_err(|_| ()));
-        let low = try!(u64::from_str_radix(&val[16..], 16).map_err(|_| ()));
-        Ok(Self { high: high, low: low })
+        if val.len() != 2 * SHORT_HASH_SIZE && val.len() != 2 * LONG_HASH_SIZE {
+            return Err(())
+        }
+        let mut parts = [0u64; 4];
+        for (i, part) in parts.iter_mut().enumerate().take(val.len() / 16) {
+            *part = try!(u64::from_str_radix(&val[i*16..(i+1)*16], 16).map_err(|_| ()));
+        }
+        if val.len() == 2 * LONG_HASH_SIZE {
+            Ok(Hash::new_long(parts[0], parts[1], parts[2], parts[3]))
+        } else {
+            Ok(Hash::new(parts[0], parts[1]))
+        }
     }
 }
 
 impl fmt::Display for Hash {
     #[inline]
     fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
-        write!(fmt, "{:016x}{:016x}", self.high, self.low)
+        if self.is_long() {
+            write!(fmt, "{:016x}{:016x}{:016x}{:016x}", self.high, self.low, self.ext_high, self.ext_low)
+        } else {
+            write!(fmt, "{:016x}{:016x}", self.high, self.low)
+        }
     }
 }
 
 impl fmt::Debug for Hash {
     #[inline]
     fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
-        write!(fmt, "{:016x}{:016x}", self.high, self.low)
+        fmt::Display::fmt(self, fmt)
     }
 }
 
 
 impl Serialize for Hash {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: serde::Serializer {
-        let mut dat = [0u8; 16];
-        LittleEndian::write_u64(&mut dat[..8], self.high);
-        LittleEndian::write_u64(&mut dat[8..], self.low);
+        let mut dat = Vec::with_capacity(LONG_HASH_SIZE);
+        self.write_to(&mut dat, self.size()).unwrap();
         Bytes::from(&dat as &[u8]).serialize(serializer)
     }
 }
@@ -83,41 +152,60 @@ impl Serialize for Hash {
 impl<'a> Deserialize<'a> for Hash {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error> where D: serde::Deserializer<'a> {
         let dat: Vec<u8> = try!(ByteBuf::deserialize(deserializer)).into();
-        if dat.len() != 16 {
+        if dat.len() != SHORT_HASH_SIZE && dat.len() != LONG_HASH_SIZE {
             return Err(D::Error::custom("Invalid key length"));
         }
-        Ok(Hash{
-            high: LittleEndian::read_u64(&dat[..8]),
-            low: LittleEndian::read_u64(&dat[8..])
-        })
+        Ok(Hash::read_from(&mut &dat[..], dat.len()).unwrap())
     }
 }
 
 
+#[inline]
+fn blake2_hash(key: &[u8], data: &[u8]) -> Hash {
+    Hash::from_digest(blake2b(SHORT_HASH_SIZE, key, data).as_bytes())
+}
+
+
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub enum HashMethod {
     Blake2,
-    Murmur3
+    Murmur3,
+    Blake2Keyed,
+    Sha256,
+    Blake3
 }
 serde_impl!(HashMethod(u64) {
     Blake2 => 1,
-    Murmur3 => 2
+    Murmur3 => 2,
+    Blake2Keyed => 3,
+    Sha256 => 4,
+    Blake3 => 5
 });
 
 
 impl HashMethod {
     #[inline]
     pub fn hash(&self, data: &[u8]) -> Hash {
+        assert!(!self.is_keyed(), "Keyed hash methods can not be used without a key");
+        self.hash_keyed(&[], data)
+    }
+
+    /// Keyed methods compute a MAC with the given key, all other methods ignore the key
+    #[inline]
+    pub fn hash_keyed(&self, key: &[u8], data: &[u8]) -> Hash {
         match *self {
-            HashMethod::Blake2 => {
-                let hash = blake2b(16, &[], data);
-                let hash = unsafe { &*mem::transmute::<_, *mut (u64, u64)>(hash.as_bytes().as_ptr()) };
-                Hash { high: u64::from_be(hash.0), low: u64::from_be(hash.1) }
+            HashMethod::Blake2 => blake2_hash(&[], data),
+            HashMethod::Blake2Keyed => {
+                // An empty key would silently produce unkeyed hashes
+                assert!(!key.is_empty(), "Keyed hash methods can not be used without a key");
+                blake2_hash(key, data)
             },
             HashMethod::Murmur3 => {
                 let (a, b) = murmurhash3_x64_128(data, 0);
-                Hash { high: a, low: b }
-            }
+                Hash::new(a, b)
+            },
+            HashMethod::Sha256 => Hash::from_digest(&Sha256::digest(data)),
+            HashMethod::Blake3 => Hash::from_digest(blake3::hash(data).as_bytes())
         }
     }
 
@@ -126,6 +214,9 @@ impl HashMethod {
         match name {
             "blake2" => Ok(HashMethod::Blake2),
             "murmur3" => Ok(HashMethod::Murmur3),
+            "blake2-keyed" => Ok(HashMethod::Blake2Keyed),
+            "sha256" => Ok(HashMethod::Sha256),
+            "blake3" => Ok(HashMethod::Blake3),
             _ => Err("Unsupported hash method")
         }
     }
@@ -134,8 +225,24 @@ impl HashMethod {
     pub fn name(&self) -> &'static str {
         match *self {
             HashMethod::Blake2 => "blake2",
-            HashMethod::Murmur3 => "murmur3"
+            HashMethod::Murmur3 => "murmur3",
+            HashMethod::Blake2Keyed => "blake2-keyed",
+            HashMethod::Sha256 => "sha256",
+            HashMethod::Blake3 => "blake3"
         }
     }
 
+    /// The size of the produced hashes in bytes
+    #[inline]
+    pub fn hash_size(&self) -> usize {
+        match *self {
+            HashMethod::Blake2 | HashMethod::Murmur3 | HashMethod::Blake2Keyed => SHORT_HASH_SIZE,
+            HashMethod::Sha256 | HashMethod::Blake3 => LONG_HASH_SIZE
+        }
+    }
+
+    #[inline]
+    pub fn is_keyed(&self) -> bool {
+        *self == HashMethod::Blake2Keyed
+    }
 }
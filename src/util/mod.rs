@@ -10,6 +10,9 @@ mod cli;
 mod hostname;
 mod fs;
 mod lock;
+mod erasure;
+mod galois;
+mod shamir;
 pub mod msgpack;
 
 pub use self::fs::*;
@@ -23,3 +26,5 @@ pub use self::hex::*;
 pub use self::cli::*;
 pub use self::hostname::*;
 pub use self::lock::*;
+pub use self::erasure::*;
+pub use self::shamir::*;
@@ -0,0 +1,193 @@
+use sodiumoxide::randombytes::randombytes;
+
+use super::galois::GF;
+
+// Shamir's secret sharing over GF(2^8), every byte of the secret is shared with its own
+// random polynomial. The share with index x contains the values of the polynomials at x.
+// Paper: "How to share a secret"
+// Paper-URL: https://dl.acm.org/citation.cfm?id=359176
+
+
+quick_error!{
+    #[derive(Debug)]
+    pub enum ShamirError {
+        InvalidParameters(reason: &'static str) {
+            description("Invalid parameters")
+            display("Secret sharing error: invalid parameters: {}", reason)
+        }
+        NotEnoughShares(needed: u8, available: usize) {
+            description("Not enough shares")
+            display("Secret sharing error: {} shares are needed but only {} are available", needed, available)
+        }
+        InconsistentShares(reason: &'static str) {
+            description("Inconsistent shares")
+            display("Secret sharing error: the shares do not belong together: {}", reason)
+        }
+    }
+}
+
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Share {
+    pub threshold: u8,
+    pub index: u8,
+    pub data: Vec<u8>
+}
+
+impl Share {
+    /// Splits the secret into `count` shares so that any `threshold` of them can reconstruct it
+    pub fn split(secret: &[u8], threshold: u8, count: u8) -> Result<Vec<Share>, ShamirError> {
+        if threshold < 2 {
+            return Err(ShamirError::InvalidParameters("threshold must be at least 2"))
+        }
+        if count < threshold {
+            return Err(ShamirError::InvalidParameters("share count must not be smaller than the threshold"))
+        }
+        // Coefficients of all polynomials, the constant coefficient is the secret itself
+        let coefficients = randombytes(secret.len() * (threshold as usize - 1));
+        let mut shares = Vec::with_capacity(count as usize);
+        for index in 1..(count as usize + 1) {
+            let x = index as u8;
+            let mut data = Vec::with_capacity(secret.len());
+            for (i, &s) in secret.iter().enumerate() {
+                let poly = &coefficients[i * (threshold as usize - 1)..(i + 1) * (threshold as usize - 1)];
+                // Horner's method, starting with the highest coefficient
+                let mut y = 0;
+                for &c in poly.iter().rev() {
+                    y = GF.mul(y, x) ^ c;
+                }
+                data.push(GF.mul(y, x) ^ s);
+            }
+            shares.push(Share { threshold: threshold, index: x, data: data });
+        }
+        Ok(shares)
+    }
+
+    /// Reconstructs the secret from at least `threshold` distinct shares
+    pub fn combine(shares: &[Share]) -> Result<Vec<u8>, ShamirError> {
+        let first = try!(shares.first().ok_or(ShamirError::NotEnoughShares(2, 0)));
+        let mut used: Vec<&Share> = vec![];
+        for share in shares {
+            if share.threshold != first.threshold {
+                return Err(ShamirError::InconsistentShares("different thresholds"))
+            }
+            if share.data.len() != first.data.len() {
+                return Err(ShamirError::InconsistentShares("different sizes"))
+            }
+            if share.index == 0 {
+                return Err(ShamirError::InconsistentShares("invalid share index"))
+            }
+            if let Some(other) = used.iter().find(|s| s.index == share.index) {
+                if other.data != share.data {
+                    return Err(ShamirError::InconsistentShares("different shares with the same index"))
+                }
+                continue
+            }
+            used.push(share);
+        }
+        if used.len() < first.threshold as usize {
+            return Err(ShamirError::NotEnoughShares(first.threshold, used.len()))
+        }
+        used.truncate(first.threshold as usize);
+        // Lagrange interpolation at x=0, subtraction is xor in GF(2^8)
+        let mut factors = Vec::with_capacity(used.len());
+        for share in &used {
+            let mut factor = 1;
+            for other in &used {
+                if other.index != share.index {
+                    factor = GF.mul(factor, GF.div(other.index, other.index ^ share.index));
+                }
+            }
+            factors.push(factor);
+        }
+        let mut secret = vec![0u8; first.data.len()];
+        for (share, &factor) in used.iter().zip(&factors) {
+            for (s, &y) in secret.iter_mut().zip(&share.data) {
+                *s ^= GF.mul(y, factor);
+            }
+        }
+        Ok(secret)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use sodiumoxide;
+
+    fn split(secret: &[u8], threshold: u8, count: u8) -> Result<Vec<Share>, ShamirError> {
+        assert!(sodiumoxide::init());
+        Share::split(secret, threshold, count)
+    }
+
+    fn subsets(shares: &[Share]) -> Vec<Vec<Share>> {
+        (1..(1usize << shares.len())).map(|mask| {
+            shares.iter().enumerate().filter(|&(i, _)| mask & (1 << i) != 0).map(|(_, s)| s.clone()).collect()
+        }).collect()
+    }
+
+    #[test]
+    fn test_all_subsets() {
+        let secret: Vec<u8> = (0..32).map(|i| (i * 37 + 11) as u8).collect();
+        for count in 2..7 {
+            for threshold in 2..(count + 1) {
+                let shares = split(&secret, threshold, count).unwrap();
+                assert_eq!(shares.len(), count as usize);
+                for subset in subsets(&shares) {
+                    if subset.len() >= threshold as usize {
+                        assert_eq!(Share::combine(&subset).unwrap(), secret);
+                    } else {
+                        match Share::combine(&subset) {
+                            Err(ShamirError::NotEnoughShares(needed, available)) => {
+                                assert_eq!(needed, threshold);
+                                assert_eq!(available, subset.len());
+                            },
+                            _ => panic!("Combined {} of {} shares with threshold {}", subset.len(), count, threshold)
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_not_enough_shares() {
+        let secret = vec![0x42; 16];
+        let shares = split(&secret, 3, 5).unwrap();
+        // Duplicates do not count as additional shares
+        let duplicates = vec![shares[0].clone(), shares[1].clone(), shares[0].clone()];
+        assert!(Share::combine(&duplicates).is_err());
+        assert!(Share::combine(&[]).is_err());
+        // Too few shares with a threshold that has been changed do not reveal the secret
+        let mut forged: Vec<Share> = shares[..2].to_vec();
+        for share in &mut forged {
+            share.threshold = 2;
+        }
+        assert!(Share::combine(&forged).unwrap() != secret);
+    }
+
+    #[test]
+    fn test_invalid_shares() {
+        let secret = vec![1, 2, 3, 4];
+        assert!(split(&secret, 1, 3).is_err());
+        assert!(split(&secret, 4, 3).is_err());
+        let shares = split(&secret, 2, 3).unwrap();
+        let mut other = shares[1].clone();
+        other.data[0] ^= 1;
+        assert!(Share::combine(&[shares[1].clone(), other, shares[2].clone()]).is_err());
+        let mut other = shares[1].clone();
+        other.data.pop();
+        assert!(Share::combine(&[shares[0].clone(), other]).is_err());
+        let mut other = shares[1].clone();
+        other.index = 0;
+        assert!(Share::combine(&[shares[0].clone(), other]).is_err());
+    }
+
+    #[test]
+    fn test_empty_secret() {
+        let shares = split(&[], 2, 2).unwrap();
+        assert_eq!(Share::combine(&shares).unwrap(), Vec::<u8>::new());
+    }
+}